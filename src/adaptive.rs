@@ -0,0 +1,601 @@
+//adaptive.rs
+
+//HLS and DASH. ffmpeg's demuxers expose every audio rendition as its own stream and only fetch
+//the ones that aren't discarded, so choosing a variant means choosing a stream. A switch takes
+//effect on the first packet of the new variant that starts where the old one got to, so playback
+//carries on without a gap and nothing is heard twice
+
+use crate::{enums::PlayerError, source::MediaInput, streams::audio_format};
+
+use ffmpeg_next::{Packet, Rescale, media, rescale, sys};
+
+use std::time::{Duration, Instant};
+
+//Auto mode steps up a variant after this long without an underrun
+const STEP_UP_AFTER: Duration = Duration::from_secs(20);
+
+//Timestamps get rounded on the way to microseconds, so a switch packet may start this much early
+const SWITCH_TOLERANCE_US: i64 = 1000;
+
+#[derive(Clone, PartialEq, Debug, uniffi::Record)]
+pub struct StreamVariant {
+    pub id: u32,      // Pass to select_variant
+    pub bitrate: u64, // Bits per second from the manifest, 0 if it doesn't say
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum VariantSelection {
+    Auto, // Start low, step down on underruns and back up once things are stable
+    Fixed { id: u32 },
+}
+
+///Whether the input came from an HLS or DASH manifest
+pub fn is_adaptive(input: &MediaInput) -> bool {
+    input
+        .format()
+        .name()
+        .split(',')
+        .any(|name| name == "hls" || name == "dash")
+}
+
+///Every audio rendition in the input, lowest bitrate first
+pub fn list_variants(input: &MediaInput) -> Vec<StreamVariant> {
+    let mut variants: Vec<StreamVariant> = input
+        .streams()
+        .filter(|stream| stream.parameters().medium() == media::Type::Audio)
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let (sample_rate, channels) = audio_format(&parameters);
+            StreamVariant {
+                id: stream.index() as u32,
+                bitrate: stream
+                    .metadata()
+                    .get("variant_bitrate")
+                    .and_then(|bitrate| bitrate.parse().ok())
+                    .unwrap_or(0),
+                codec: parameters.id().name().to_string(),
+                sample_rate,
+                channels,
+            }
+        })
+        .collect();
+
+    variants.sort_by_key(|variant| variant.bitrate);
+    variants
+}
+
+//Discarded streams aren't downloaded at all
+fn set_discard(input: &mut MediaInput, index: u32, discard: bool) {
+    unsafe {
+        let format_ctx = input.as_mut_ptr();
+        if index >= (*format_ctx).nb_streams {
+            return;
+        }
+        let stream = *(*format_ctx).streams.add(index as usize);
+        (*stream).discard = if discard {
+            sys::AVDiscard::AVDISCARD_ALL
+        } else {
+            sys::AVDiscard::AVDISCARD_DEFAULT
+        };
+    }
+}
+
+///Start and end of a packet in microseconds. None if it has no timestamp
+pub fn packet_span(input: &MediaInput, packet: &Packet) -> Option<(i64, i64)> {
+    let time_base = input.stream(packet.stream())?.time_base();
+    let start = packet.pts().or(packet.dts())?;
+    let end = start + packet.duration().max(0);
+    Some((
+        start.rescale(time_base, rescale::TIME_BASE),
+        end.rescale(time_base, rescale::TIME_BASE),
+    ))
+}
+
+///Variant state for a loaded manifest. Lives with the decoder
+pub struct Adaptive {
+    variants: Vec<StreamVariant>, // Lowest bitrate first
+    selection: VariantSelection,
+    current: u32,               // Variant being decoded
+    pending: Option<u32>,       // Variant being fetched to switch to
+    decoded_until: Option<i64>, // End of the current variant's latest packet, in microseconds
+    stable_since: Instant,
+    was_buffering: bool,
+}
+
+impl Adaptive {
+    ///Picks the starting variant and discards the rest. None if there's nothing to choose between
+    pub fn new(input: &mut MediaInput, selection: VariantSelection) -> Option<Self> {
+        if !is_adaptive(input) {
+            return None;
+        }
+        let adaptive = Self::with_variants(list_variants(input), selection)?;
+        adaptive.apply(input);
+        Some(adaptive)
+    }
+
+    fn with_variants(variants: Vec<StreamVariant>, selection: VariantSelection) -> Option<Self> {
+        if variants.len() < 2 {
+            return None;
+        }
+
+        // A fixed choice from another stream falls back to auto
+        let selection = match selection {
+            VariantSelection::Fixed { id } if variants.iter().any(|variant| variant.id == id) => {
+                selection
+            }
+            _ => VariantSelection::Auto,
+        };
+        let current = match selection {
+            VariantSelection::Fixed { id } => id,
+            VariantSelection::Auto => variants[0].id,
+        };
+
+        Some(Adaptive {
+            variants,
+            selection,
+            current,
+            pending: None,
+            decoded_until: None,
+            stable_since: Instant::now(),
+            was_buffering: false,
+        })
+    }
+
+    pub fn variants(&self) -> Vec<StreamVariant> {
+        self.variants.clone()
+    }
+
+    pub fn selection(&self) -> VariantSelection {
+        self.selection
+    }
+
+    ///The variant being played
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    pub fn select(&mut self, selection: VariantSelection) -> Result<(), i32> {
+        if let VariantSelection::Fixed { id } = selection
+            && !self.variants.iter().any(|variant| variant.id == id)
+        {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        self.selection = selection;
+        self.stable_since = Instant::now();
+        Ok(())
+    }
+
+    ///Only fetches the current variant. For a freshly opened input, e.g. after a reconnect
+    pub fn apply(&self, input: &mut MediaInput) {
+        for variant in &self.variants {
+            set_discard(input, variant.id, variant.id != self.current);
+        }
+    }
+
+    ///Called for every packet. Starts or cancels fetching another variant as the selection and buffer say
+    pub fn update(&mut self, input: &mut MediaInput, buffering: bool) {
+        let previous = self.pending;
+        self.retarget(buffering);
+        if self.pending != previous {
+            if let Some(abandoned) = previous {
+                set_discard(input, abandoned, true);
+            }
+            if let Some(next) = self.pending {
+                set_discard(input, next, false);
+            }
+        }
+    }
+
+    ///Called for every packet with its packet_span. Whether it's the one to switch to the pending variant on.
+    ///Packets of the new variant that start before the old one's last packet ends would play twice, so those are
+    ///passed over and the old variant carries on until they line up
+    pub fn is_switch(&mut self, stream_index: usize, span: Option<(i64, i64)>) -> bool {
+        let index = stream_index as u32;
+        if index == self.current {
+            self.decoded_until = span.map(|(_, end)| end);
+            return false;
+        }
+        if self.pending != Some(index) {
+            return false;
+        }
+        match (span, self.decoded_until) {
+            (Some((start, _)), Some(until)) => start >= until - SWITCH_TOLERANCE_US,
+            _ => true, // Nothing to line up against
+        }
+    }
+
+    ///Call once the decoder is set up for the pending variant. Stops fetching the old one
+    pub fn commit(&mut self, input: &mut MediaInput) {
+        let previous = self.current;
+        if self.switch() {
+            set_discard(input, previous, true);
+        }
+    }
+
+    //Works out which variant should be on its way
+    fn retarget(&mut self, buffering: bool) {
+        let wanted = match self.selection {
+            VariantSelection::Fixed { id } => id,
+            VariantSelection::Auto => self.auto_target(buffering),
+        };
+        self.pending = (wanted != self.current).then_some(wanted);
+    }
+
+    //Makes the pending variant the current one
+    fn switch(&mut self) -> bool {
+        let Some(next) = self.pending.take() else {
+            return false;
+        };
+        self.current = next;
+        self.stable_since = Instant::now();
+        true
+    }
+
+    //Buffer based. An underrun steps down straight away, a long stretch without one steps up
+    fn auto_target(&mut self, buffering: bool) -> u32 {
+        let started_buffering = buffering && !self.was_buffering;
+        self.was_buffering = buffering;
+        if buffering {
+            self.stable_since = Instant::now();
+        }
+
+        let position = self
+            .variants
+            .iter()
+            .position(|variant| variant.id == self.current)
+            .unwrap_or(0);
+        // A step up that was on its way is dropped too
+        if started_buffering {
+            return self.variants[position.saturating_sub(1)].id;
+        }
+        if self.stable_since.elapsed() >= STEP_UP_AFTER && position + 1 < self.variants.len() {
+            return self.variants[position + 1].id;
+        }
+
+        // Otherwise stick with whatever is already on its way
+        self.pending.unwrap_or(self.current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::NetworkConfig,
+        source::MediaSource,
+        testing::{TestServer, respond},
+    };
+
+    use ffmpeg_next::{self as av, frame::Audio as AudioFrame};
+
+    use std::{collections::HashMap, f32::consts::PI, fs, sync::Arc};
+
+    fn variant(id: u32, bitrate: u64) -> StreamVariant {
+        StreamVariant {
+            id,
+            bitrate,
+            codec: "aac".to_string(),
+            sample_rate: 48000,
+            channels: 2,
+        }
+    }
+
+    fn ladder() -> Adaptive {
+        let variants = vec![variant(2, 64_000), variant(0, 128_000), variant(1, 256_000)];
+        Adaptive::with_variants(variants, VariantSelection::Auto).unwrap()
+    }
+
+    //As if nothing had buffered for long enough to step up
+    fn settle(adaptive: &mut Adaptive) {
+        adaptive.stable_since = Instant::now() - STEP_UP_AFTER;
+    }
+
+    #[test]
+    fn auto_starts_on_the_lowest_variant() {
+        let mut adaptive = ladder();
+        assert_eq!(adaptive.current(), 2);
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, None);
+
+        // One variant is nothing to choose between
+        assert!(Adaptive::with_variants(vec![variant(0, 0)], VariantSelection::Auto).is_none());
+    }
+
+    #[test]
+    fn steps_up_one_variant_at_a_time_once_stable() {
+        let mut adaptive = ladder();
+        settle(&mut adaptive);
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, Some(0));
+        assert!(adaptive.switch());
+        assert_eq!(adaptive.current(), 0);
+
+        // The switch starts the wait over
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, None);
+
+        settle(&mut adaptive);
+        adaptive.retarget(false);
+        assert!(adaptive.switch());
+        assert_eq!(adaptive.current(), 1);
+
+        // Nothing above the top
+        settle(&mut adaptive);
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, None);
+    }
+
+    #[test]
+    fn an_underrun_steps_down_once() {
+        let mut adaptive = ladder();
+        adaptive.current = 1;
+
+        adaptive.retarget(true);
+        assert_eq!(adaptive.pending, Some(0));
+        assert!(adaptive.switch());
+
+        // Still the same underrun, so no further step
+        adaptive.retarget(true);
+        assert_eq!(adaptive.pending, None);
+
+        // A new one steps down again
+        adaptive.retarget(false);
+        adaptive.retarget(true);
+        assert_eq!(adaptive.pending, Some(2));
+        assert!(adaptive.switch());
+
+        // Nothing below the bottom
+        adaptive.retarget(false);
+        adaptive.retarget(true);
+        assert_eq!(adaptive.pending, None);
+    }
+
+    #[test]
+    fn buffering_holds_off_stepping_up() {
+        let mut adaptive = ladder();
+        settle(&mut adaptive);
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, Some(0));
+
+        // An underrun drops the step up on its way
+        adaptive.retarget(true);
+        assert_eq!(adaptive.pending, None);
+
+        // And the wait starts over once it's done
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, None);
+        assert!(adaptive.stable_since.elapsed() < STEP_UP_AFTER);
+    }
+
+    #[test]
+    fn a_pending_step_stays_until_it_arrives() {
+        let mut adaptive = ladder();
+        settle(&mut adaptive);
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, Some(0));
+
+        adaptive.stable_since = Instant::now();
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, Some(0));
+    }
+
+    #[test]
+    fn fixed_selections_switch_and_ignore_the_buffer() {
+        let mut adaptive = ladder();
+        assert_eq!(
+            adaptive.select(VariantSelection::Fixed { id: 7 }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(adaptive.selection(), VariantSelection::Auto);
+
+        adaptive.select(VariantSelection::Fixed { id: 1 }).unwrap();
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, Some(1));
+
+        // Changing back before it arrives cancels it
+        adaptive.select(VariantSelection::Fixed { id: 2 }).unwrap();
+        adaptive.retarget(false);
+        assert_eq!(adaptive.pending, None);
+
+        adaptive.select(VariantSelection::Fixed { id: 1 }).unwrap();
+        adaptive.retarget(false);
+        assert!(adaptive.switch());
+        adaptive.retarget(true);
+        settle(&mut adaptive);
+        adaptive.retarget(false);
+        assert_eq!((adaptive.current(), adaptive.pending), (1, None));
+    }
+
+    #[test]
+    fn unknown_fixed_choices_fall_back_to_auto() {
+        let variants = vec![variant(0, 64_000), variant(1, 128_000)];
+        let adaptive = Adaptive::with_variants(variants.clone(), VariantSelection::Fixed { id: 5 });
+        let adaptive = adaptive.unwrap();
+        assert_eq!(
+            (adaptive.selection(), adaptive.current()),
+            (VariantSelection::Auto, 0)
+        );
+
+        let adaptive =
+            Adaptive::with_variants(variants, VariantSelection::Fixed { id: 1 }).unwrap();
+        assert_eq!(adaptive.current(), 1);
+    }
+
+    #[test]
+    fn switches_where_the_new_variant_lines_up() {
+        let mut adaptive = ladder();
+        adaptive.select(VariantSelection::Fixed { id: 0 }).unwrap();
+        adaptive.retarget(false);
+
+        assert!(!adaptive.is_switch(2, Some((0, 24_000))));
+        assert!(!adaptive.is_switch(2, Some((24_000, 48_000))));
+
+        // Already played from the old variant
+        assert!(!adaptive.is_switch(0, Some((0, 24_000))));
+        assert!(!adaptive.is_switch(0, Some((24_000, 48_000))));
+        // Other streams never switch
+        assert!(!adaptive.is_switch(1, Some((48_000, 72_000))));
+        assert!(adaptive.is_switch(0, Some((48_000, 72_000))));
+
+        // Without timestamps there's nothing to wait for
+        let mut adaptive = ladder();
+        adaptive.select(VariantSelection::Fixed { id: 0 }).unwrap();
+        adaptive.retarget(false);
+        assert!(!adaptive.is_switch(2, None));
+        assert!(adaptive.is_switch(0, Some((0, 24_000))));
+    }
+
+    const RATE: u32 = 48000;
+    const SEGMENTS: usize = 3;
+    const SEGMENT_FRAMES: usize = 84; // Of 1152 samples, about 2 s
+
+    //Encodes a tone as MP2 in MPEG-TS segments, the way an HLS packager would, and adds the files and the
+    //variant's playlist to files
+    fn write_variant(files: &mut HashMap<String, Vec<u8>>, name: &str, bit_rate: usize) {
+        let dir = std::env::temp_dir().join(format!("aurex-hls-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let codec = av::encoder::find(av::codec::Id::MP2).unwrap();
+        let context = av::codec::context::Context::new_with_codec(codec);
+        let mut encoder = context.encoder().audio().unwrap();
+        encoder.set_rate(RATE as i32);
+        encoder.set_channel_layout(av::ChannelLayout::STEREO);
+        encoder.set_format(av::format::Sample::I16(av::format::sample::Type::Packed));
+        encoder.set_bit_rate(bit_rate);
+        encoder.set_time_base((1, RATE as i32));
+        let mut encoder = encoder.open_as(codec).unwrap();
+        let frame_size = encoder.frame_size() as usize;
+
+        let mut playlist = "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:3\n".to_string();
+        playlist += "#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MEDIA-SEQUENCE:0\n";
+        let mut pts = 0;
+        for segment in 0..SEGMENTS {
+            let file = format!("{name}{segment}.ts");
+            let path = dir.join(&file);
+            let mut output = av::format::output_as(&path, "mpegts").unwrap();
+            output.add_stream(codec).unwrap().set_parameters(&encoder);
+            output.write_header().unwrap();
+            let time_base = output.stream(0).unwrap().time_base();
+
+            for _ in 0..SEGMENT_FRAMES {
+                let mut frame =
+                    AudioFrame::new(encoder.format(), frame_size, av::ChannelLayout::STEREO);
+                frame.set_rate(RATE);
+                for (n, bytes) in frame
+                    .data_mut(0)
+                    .chunks_exact_mut(4)
+                    .take(frame_size)
+                    .enumerate()
+                {
+                    let t = (pts + n as i64) as f32 / RATE as f32;
+                    let sample = (0.5 * (2.0 * PI * 440.0 * t).sin() * i16::MAX as f32) as i16;
+                    bytes[..2].copy_from_slice(&sample.to_le_bytes());
+                    bytes[2..].copy_from_slice(&sample.to_le_bytes());
+                }
+                frame.set_pts(Some(pts));
+                pts += frame_size as i64;
+
+                encoder.send_frame(&frame).unwrap();
+                let mut packet = Packet::empty();
+                while encoder.receive_packet(&mut packet).is_ok() {
+                    packet.set_stream(0);
+                    packet.rescale_ts((1, RATE as i32), time_base);
+                    packet.write_interleaved(&mut output).unwrap();
+                }
+            }
+            output.write_trailer().unwrap();
+            drop(output);
+
+            let seconds = (SEGMENT_FRAMES * frame_size) as f64 / RATE as f64;
+            playlist += &format!("#EXTINF:{seconds:.3},\n{file}\n");
+            files.insert(format!("/{file}"), fs::read(&path).unwrap());
+        }
+        playlist += "#EXT-X-ENDLIST\n";
+        files.insert(format!("/{name}.m3u8"), playlist.into_bytes());
+        _ = fs::remove_dir_all(&dir);
+    }
+
+    fn hls_server() -> TestServer {
+        let mut files = HashMap::new();
+        write_variant(&mut files, "low", 128_000);
+        write_variant(&mut files, "high", 256_000);
+        let master = "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=128000\nlow.m3u8\n\
+                      #EXT-X-STREAM-INF:BANDWIDTH=256000\nhigh.m3u8\n";
+        files.insert("/master.m3u8".to_string(), master.as_bytes().to_vec());
+
+        let files = Arc::new(files);
+        TestServer::new(move |request, stream| match files.get(&request.path) {
+            Some(body) if request.path.ends_with(".m3u8") => {
+                respond(stream, request, "application/vnd.apple.mpegurl", body)
+            }
+            Some(body) => respond(stream, request, "video/mp2t", body),
+            None => {
+                _ = std::io::Write::write_all(
+                    stream,
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+                );
+            }
+        })
+    }
+
+    #[test]
+    fn switching_variants_neither_gaps_nor_overlaps() {
+        let server = hls_server();
+        let source = MediaSource::from(server.url("/master.m3u8"));
+        let mut input = source.open(&NetworkConfig::default()).unwrap();
+        assert!(is_adaptive(&input));
+
+        let mut adaptive = Adaptive::new(&mut input, VariantSelection::Auto).unwrap();
+        let variants = adaptive.variants();
+        let bitrates: Vec<u64> = variants.iter().map(|variant| variant.bitrate).collect();
+        assert_eq!(bitrates, [128_000, 256_000]);
+        let (low, high) = (variants[0].id, variants[1].id);
+        assert_eq!(adaptive.current(), low);
+
+        // Stream and span of every packet that would reach the decoder, going through the same steps as the
+        // decoding loop
+        let mut decoded: Vec<(u32, (i64, i64))> = Vec::new();
+        let mut packet = Packet::empty();
+        while packet.read(&mut input).is_ok() {
+            if decoded.len() >= 50 {
+                adaptive
+                    .select(VariantSelection::Fixed { id: high })
+                    .unwrap();
+            }
+            adaptive.update(&mut input, false);
+            let span = packet_span(&input, &packet);
+            if adaptive.is_switch(packet.stream(), span) {
+                adaptive.commit(&mut input);
+            }
+            if packet.stream() as u32 == adaptive.current() {
+                decoded.push((packet.stream() as u32, span.unwrap()));
+            }
+        }
+
+        assert_eq!(adaptive.current(), high);
+        assert_eq!(decoded.first().unwrap().0, low);
+        let switches = decoded
+            .windows(2)
+            .filter(|pair| pair[0].0 != pair[1].0)
+            .count();
+        assert_eq!(switches, 1);
+
+        // Every packet starts where the one before it ended, across the switch too
+        for pair in decoded.windows(2) {
+            let ((_, (start, end)), (_, (next, _))) = (pair[0], pair[1]);
+            assert!(end > start);
+            assert!((next - end).abs() < SWITCH_TOLERANCE_US, "{pair:?}");
+        }
+
+        // And all of it is there
+        let played = decoded.last().unwrap().1.1 - decoded[0].1.0;
+        let expected = (SEGMENTS * SEGMENT_FRAMES * 1152) as i64 * 1_000_000 / RATE as i64;
+        assert!(
+            (played - expected).abs() < SWITCH_TOLERANCE_US,
+            "{played} {expected}"
+        );
+    }
+}
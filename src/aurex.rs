@@ -1,20 +1,42 @@
 //This is an ffi safe public api wrapper
 use crate::{
-    engine::AudioEngine, enums::EngineSignal, enums::PlayerError, enums::ResamplingQuality,
+    adaptive::{StreamVariant, VariantSelection},
+    channels::ChannelMatrix,
+    convolution::{ConvolutionKernel, ImpulseResponse, ImpulseResponseInfo},
+    dsp::AudioProcessor,
+    engine::{AudioEngine, FadeDurations, join_threads},
+    enums::{
+        BuiltinEffect, CrossfeedPreset, EqPreset, NoiseShaping, PlayerError, PlayerState,
+        ReplayGainMode, ResamplingQuality,
+    },
+    equalizer::{EqBand, GRAPHIC_EQ_FREQUENCIES},
+    events::{PlayerEvent, TrackInfo},
+    loudness::LoudnessInfo,
+    meter::{Levels, MeterReadings},
+    network::NetworkConfig,
+    replaygain::ReplayGainInfo,
+    resampler::ResamplerConfig,
+    source::{ByteSource, MediaSource, ReadSeek},
+    spectrum::{SpectrumAnalyzer, SpectrumConfig, SpectrumTap},
+    streams::AudioStreamInfo,
 };
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use tokio::sync::Mutex as async_Mutex;
+use tokio::sync::{Mutex as async_Mutex, broadcast};
 
 #[derive(uniffi::Object)]
 pub struct Player {
     engine: Arc<async_Mutex<AudioEngine>>,
+    levels: Arc<MeterReadings>, // Read straight from the audio thread's atomics, no engine lock
+    spectrum: Arc<SpectrumTap>,
+    analyzer: Mutex<SpectrumAnalyzer>, // Only ever locked by readers
+    events: broadcast::Sender<PlayerEvent>,
 }
 
 #[uniffi::export(callback_interface)]
 pub trait PlayerCallback: Send + Sync {
-    fn on_player_event(&self, event: EngineSignal, player: Arc<Player>);
+    fn on_player_event(&self, event: PlayerEvent, player: Arc<Player>);
 }
 
 //Tech debt here. It does not need to be async but once I had a function that needed async but then I figured out a simpler way. Now I'm leaving it as it is
@@ -35,9 +57,22 @@ impl Player {
         if engine.is_err() {
             return Err(PlayerError::Code(engine.err().unwrap_or(-1)));
         }
+        let engine = engine.unwrap();
+        let (levels, spectrum, events) = {
+            let engine = engine.lock().await;
+            (
+                engine.levels(),
+                engine.spectrum_tap(),
+                engine.event_sender(),
+            )
+        };
 
         Ok(Arc::new(Player {
-            engine: engine.unwrap(),
+            engine,
+            levels,
+            spectrum,
+            analyzer: Mutex::new(SpectrumAnalyzer::default()),
+            events,
         }))
     }
 
@@ -48,9 +83,56 @@ impl Player {
 
     ///An Arc clone is needed to call this function cause the player object is also passed as context to the callback
     pub async fn load(self: Arc<Self>, file: &str) -> Result<(), PlayerError> {
-        let player_clone = Arc::clone(&self);
-        _ = AudioEngine::load(self.engine.clone(), file, Arc::downgrade(&player_clone)).await;
-        Ok(())
+        let player = Arc::downgrade(&self);
+        AudioEngine::load(self.engine.clone(), MediaSource::from(file), None, player)
+            .await
+            .map_err(PlayerError::from)
+    }
+
+    ///Loads a file starting on one of its audio streams, e.g. a dub or commentary track. Indexes come from probe_audio_streams
+    pub async fn load_with_stream(
+        self: Arc<Self>,
+        file: &str,
+        stream_index: u32,
+    ) -> Result<(), PlayerError> {
+        let player = Arc::downgrade(&self);
+        AudioEngine::load(
+            self.engine.clone(),
+            MediaSource::from(file),
+            Some(stream_index),
+            player,
+        )
+        .await
+        .map_err(PlayerError::from)
+    }
+
+    ///Plays an in-memory file, e.g. a decrypted cache entry or a bundled asset
+    pub async fn load_bytes(self: Arc<Self>, bytes: Vec<u8>) -> Result<(), PlayerError> {
+        let player = Arc::downgrade(&self);
+        AudioEngine::load(
+            self.engine.clone(),
+            MediaSource::from_bytes(bytes),
+            None,
+            player,
+        )
+        .await
+        .map_err(PlayerError::from)
+    }
+
+    ///Plays whatever the app side supplies through the callback
+    pub async fn load_source(
+        self: Arc<Self>,
+        source: Box<dyn ByteSource>,
+    ) -> Result<(), PlayerError> {
+        let player = Arc::downgrade(&self);
+        AudioEngine::load(
+            self.engine.clone(),
+            MediaSource::from_callback(source),
+            None,
+            player,
+        )
+        .await
+        .map_err(PlayerError::from)
     }
 
     pub async fn get_progress(&self) -> Result<f64, PlayerError> {
@@ -62,29 +144,41 @@ impl Player {
         Ok(res.unwrap())
     }
 
+    ///Loading, loaded, playing, paused or empty. Changes also arrive as StateChanged events
+    pub async fn get_state(&self) -> PlayerState {
+        let engine = self.engine.lock().await;
+        engine.get_state()
+    }
+
     pub async fn clear(&self) -> Result<(), PlayerError> {
         let mut engine = self.engine.lock().await;
-        _ = engine.clear();
-        Ok(())
+        engine.clear().map_err(PlayerError::from)
     }
 
+    ///Stops for good. Returns once decoding is cancelled, the player's threads have ended and the output device
+    ///is released. Loading afterwards fails with InvalidState. Dropping the player does the same
+    pub async fn shutdown(&self) {
+        let threads = self.engine.lock().await.shutdown();
+        // Unlocked, the listening thread may need the engine to finish what it's doing
+        join_threads(threads);
+    }
+
+    ///Waits for the load if one is in progress. InvalidState with nothing loaded
     pub async fn play(&self) -> Result<(), PlayerError> {
         let mut engine = self.engine.lock().await;
-        _ = engine.play();
-        Ok(())
+        engine.play().map_err(PlayerError::from)
     }
 
+    ///Does nothing unless playing. InvalidState with nothing loaded
     pub async fn pause(&self) -> Result<(), PlayerError> {
         let mut engine = self.engine.lock().await;
-        _ = engine.pause();
-        Ok(())
+        engine.pause().map_err(PlayerError::from)
     }
 
+    ///Keeps playing or paused as it was. InvalidState while loading or with nothing loaded
     pub async fn seek(&self, time_s: f64) -> Result<(), PlayerError> {
         let mut engine = self.engine.lock().await;
-        _ = engine.seek(time_s);
-
-        Ok(())
+        engine.seek(time_s).map_err(PlayerError::from)
     }
 
     pub async fn get_volume(&self) -> f32 {
@@ -92,13 +186,634 @@ impl Player {
         engine.get_volume()
     }
 
+    ///Linear volume. Goes up to +12 dB (about 3.98), values above 1.0 are boost
     pub async fn set_volume(&self, volume: f32) {
         let engine = self.engine.lock().await;
-        let mut m_volume = volume;
-        if m_volume > 1.0 {
-            m_volume = 1.0;
+        engine.set_volume(volume);
+    }
+
+    ///Ramps the volume to a new linear level over duration_s seconds
+    pub async fn fade_to(&self, volume: f32, duration_s: f32) {
+        let engine = self.engine.lock().await;
+        engine.fade_to(volume, duration_s);
+    }
+
+    pub async fn get_fade_durations(&self) -> FadeDurations {
+        let engine = self.engine.lock().await;
+        engine.get_fade_durations()
+    }
+
+    ///Fades applied on play, pause, seek and clear
+    pub async fn set_fade_durations(&self, fade_durations: FadeDurations) {
+        let mut engine = self.engine.lock().await;
+        engine.set_fade_durations(fade_durations);
+    }
+
+    ///Format and tags of the loaded track. Also sent as a Loaded event
+    pub async fn get_track_info(&self) -> Option<TrackInfo> {
+        let engine = self.engine.lock().await;
+        engine.get_track_info()
+    }
+
+    pub async fn get_position_interval(&self) -> u32 {
+        let engine = self.engine.lock().await;
+        engine.get_position_interval()
+    }
+
+    ///How often PositionUpdate events are sent while playing, in milliseconds. At least 50, or 0 to turn them off
+    pub async fn set_position_interval(&self, interval_ms: u32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_position_interval(interval_ms)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn get_resampler_config(&self) -> ResamplerConfig {
+        let engine = self.engine.lock().await;
+        engine.get_resampler_config()
+    }
+
+    ///soxr's filter, precision and threads. Start from resampler_preset. Applies from the next load
+    pub async fn set_resampler_config(&self, config: ResamplerConfig) -> Result<(), PlayerError> {
+        let mut engine = self.engine.lock().await;
+        engine
+            .set_resampler_config(config)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn get_network_config(&self) -> NetworkConfig {
+        let engine = self.engine.lock().await;
+        engine.get_network_config()
+    }
+
+    ///Timeouts, reconnects, headers and buffering for http(s) URLs. Applies from the next load
+    pub async fn set_network_config(&self, network: NetworkConfig) -> Result<(), PlayerError> {
+        let mut engine = self.engine.lock().await;
+        engine
+            .set_network_config(network)
+            .map_err(PlayerError::Code)
+    }
+
+    ///True while output is held back waiting for a network source. Also reported through Buffering events
+    pub async fn is_buffering(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_buffering()
+    }
+
+    ///Live streams have no duration and can't seek. Dropped connections are reopened
+    pub async fn is_live(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_live()
+    }
+
+    pub async fn is_bit_perfect_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_bit_perfect_enabled()
+    }
+
+    ///From the next load, tracks the device supports as they are play at their own rate and bit depth, with
+    ///resampling, DSP, volume and fades bypassed. Anything else is resampled as usual
+    pub async fn set_bit_perfect_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_bit_perfect_enabled(enabled);
+    }
+
+    ///Whether the current playback reaches the device untouched. Set once playback starts
+    pub async fn is_bit_perfect(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_bit_perfect()
+    }
+
+    ///Latest ICY title of a live stream. Changes also arrive as MetadataChanged events
+    pub async fn get_stream_title(&self) -> Option<String> {
+        let engine = self.engine.lock().await;
+        engine.get_stream_title()
+    }
+
+    ///Bitrate variants of an HLS/DASH stream, lowest first. Empty for anything else
+    pub async fn get_variants(&self) -> Vec<StreamVariant> {
+        let engine = self.engine.lock().await;
+        engine.get_variants()
+    }
+
+    pub async fn get_current_variant(&self) -> Option<u32> {
+        let engine = self.engine.lock().await;
+        engine.get_current_variant()
+    }
+
+    pub async fn get_variant_selection(&self) -> VariantSelection {
+        let engine = self.engine.lock().await;
+        engine.get_variant_selection()
+    }
+
+    ///Auto or a fixed variant. Switches happen at the next segment without a gap
+    pub async fn select_variant(&self, selection: VariantSelection) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.select_variant(selection).map_err(PlayerError::Code)
+    }
+
+    ///Audio streams of the loaded file, e.g. languages or a commentary track
+    pub async fn get_audio_streams(&self) -> Vec<AudioStreamInfo> {
+        let engine = self.engine.lock().await;
+        engine.get_audio_streams()
+    }
+
+    pub async fn get_current_audio_stream(&self) -> Option<u32> {
+        let engine = self.engine.lock().await;
+        engine.get_current_audio_stream()
+    }
+
+    ///Switches audio stream mid playback, keeping the position
+    pub async fn select_audio_stream(&self, index: u32) -> Result<(), PlayerError> {
+        let mut engine = self.engine.lock().await;
+        engine.select_audio_stream(index).map_err(PlayerError::from)
+    }
+
+    pub async fn get_volume_db(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_volume_db()
+    }
+
+    ///Volume in dB, up to +12. -100 dB or less mutes
+    pub async fn set_volume_db(&self, volume_db: f32) {
+        let engine = self.engine.lock().await;
+        engine.set_volume_db(volume_db);
+    }
+
+    pub async fn is_crossfeed_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_crossfeed_enabled()
+    }
+
+    ///Headphone crossfeed. Off by default
+    pub async fn set_crossfeed_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_crossfeed_enabled(enabled);
+    }
+
+    pub async fn get_crossfeed_cutoff(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_crossfeed_cutoff()
+    }
+
+    ///Cutoff in Hz, between 300 and 2000. Defaults to 700
+    pub async fn set_crossfeed_cutoff(&self, cutoff: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_crossfeed_cutoff(cutoff)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn get_crossfeed_feed(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_crossfeed_feed()
+    }
+
+    ///Feed level in dB, between 1 and 15. Defaults to 4.5
+    pub async fn set_crossfeed_feed(&self, feed_db: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_crossfeed_feed(feed_db)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn apply_crossfeed_preset(&self, preset: CrossfeedPreset) {
+        let engine = self.engine.lock().await;
+        engine.apply_crossfeed_preset(preset);
+    }
+
+    ///Loads a mono or stereo WAV impulse response for room or headphone correction and enables convolution.
+    ///Resampled to the output rate, and again whenever that changes. Up to half a second long. Adds 256 frames of
+    ///latency
+    pub async fn load_impulse_response(
+        &self,
+        path: String,
+    ) -> Result<ImpulseResponseInfo, PlayerError> {
+        let (sample_rate, resampler_config) = {
+            let engine = self.engine.lock().await;
+            (
+                engine.get_output_sample_rate(),
+                engine.get_resampler_config(),
+            )
+        };
+
+        // Decoding, resampling and the FFTs take a while. Nothing else has to wait for them
+        let impulse_response = ImpulseResponse::load(&path).map_err(PlayerError::Code)?;
+        let kernel = ConvolutionKernel::new(&impulse_response, sample_rate, resampler_config)
+            .map_err(PlayerError::Code)?;
+
+        let mut engine = self.engine.lock().await;
+        engine
+            .set_impulse_response(impulse_response, kernel)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn clear_impulse_response(&self) {
+        let mut engine = self.engine.lock().await;
+        engine.clear_impulse_response();
+    }
+
+    pub async fn get_impulse_response_info(&self) -> Option<ImpulseResponseInfo> {
+        let engine = self.engine.lock().await;
+        engine.get_impulse_response_info()
+    }
+
+    pub async fn is_convolution_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_convolution_enabled()
+    }
+
+    ///Bypasses the loaded impulse response without unloading it
+    pub async fn set_convolution_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_convolution_enabled(enabled);
+    }
+
+    ///Peak, RMS and true-peak of the output in dBFS. Lock free, fine to poll every UI frame
+    pub fn get_levels(&self) -> Levels {
+        self.levels.get()
+    }
+
+    pub fn is_true_peak_metering_enabled(&self) -> bool {
+        self.levels.is_true_peak_enabled()
+    }
+
+    ///True-peak metering. Off by default since it oversamples on the audio thread
+    pub fn set_true_peak_metering_enabled(&self, enabled: bool) {
+        self.levels.set_true_peak_enabled(enabled);
+    }
+
+    pub fn is_spectrum_enabled(&self) -> bool {
+        self.spectrum.is_enabled()
+    }
+
+    ///Starts or stops feeding the visualiser tap. Off by default
+    pub fn set_spectrum_enabled(&self, enabled: bool) {
+        self.spectrum.set_enabled(enabled);
+    }
+
+    pub fn get_spectrum_config(&self) -> SpectrumConfig {
+        self.analyzer.lock().unwrap().get_config()
+    }
+
+    pub fn set_spectrum_config(&self, config: SpectrumConfig) -> Result<(), PlayerError> {
+        self.analyzer
+            .lock()
+            .unwrap()
+            .set_config(config)
+            .map_err(PlayerError::Code)
+    }
+
+    ///FFT magnitudes in dBFS of what's coming out of the speakers right now. Empty until the tap is enabled and audio plays
+    pub fn get_spectrum(&self) -> Vec<f32> {
+        self.analyzer.lock().unwrap().analyze(&self.spectrum)
+    }
+
+    ///Centre frequency in Hz of each value get_spectrum returns
+    pub fn get_spectrum_frequencies(&self) -> Vec<f32> {
+        let sample_rate = self.spectrum.sample_rate();
+        if sample_rate == 0 {
+            return Vec::new();
         }
-        engine.set_volume(m_volume);
+        self.analyzer.lock().unwrap().frequencies(sample_rate)
+    }
+
+    pub async fn get_balance(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_balance()
+    }
+
+    ///Stereo balance from -1.0 (left only) to 1.0 (right only)
+    pub async fn set_balance(&self, balance: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.set_balance(balance).map_err(PlayerError::Code)
+    }
+
+    pub async fn is_mono(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_mono()
+    }
+
+    ///Downmixes to mono and plays it on both sides
+    pub async fn set_mono(&self, mono: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_mono(mono);
+    }
+
+    pub async fn is_channels_swapped(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_channels_swapped()
+    }
+
+    ///Swaps left and right
+    pub async fn set_channels_swapped(&self, swap: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_channels_swapped(swap);
+    }
+
+    pub async fn is_left_muted(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.get_channel_mute().0
+    }
+
+    pub async fn is_right_muted(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.get_channel_mute().1
+    }
+
+    pub async fn set_channel_mute(&self, left: bool, right: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_channel_mute(left, right);
+    }
+
+    pub async fn get_channel_routing(&self) -> ChannelMatrix {
+        let engine = self.engine.lock().await;
+        engine.get_channel_routing()
+    }
+
+    ///Routing matrix applied before swap, mono, balance and mute. Coefficients up to 4.0
+    pub async fn set_channel_routing(&self, routing: ChannelMatrix) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_channel_routing(routing)
+            .map_err(PlayerError::Code)
+    }
+
+    ///Clears balance, mono, swap, mute and routing
+    pub async fn reset_channels(&self) {
+        let engine = self.engine.lock().await;
+        engine.reset_channels();
+    }
+
+    pub async fn is_limiter_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_limiter_enabled()
+    }
+
+    ///Keeps the output limiter on. It engages by itself whenever volume, track gain, EQ or routing can push the
+    ///level over full scale, so this is only needed to have it catch hot masters at unity gain too
+    pub async fn set_limiter_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_limiter_enabled(enabled);
+    }
+
+    pub async fn get_limiter_ceiling(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_limiter_ceiling()
+    }
+
+    ///Ceiling in dBFS, between -12 and 0. Defaults to -0.1
+    pub async fn set_limiter_ceiling(&self, ceiling_db: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_limiter_ceiling(ceiling_db)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn get_limiter_knee(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_limiter_knee()
+    }
+
+    ///Soft knee width in dB, between 0 and 12. Defaults to 1
+    pub async fn set_limiter_knee(&self, knee_db: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.set_limiter_knee(knee_db).map_err(PlayerError::Code)
+    }
+
+    pub async fn get_limiter_release(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_limiter_release()
+    }
+
+    ///Release time in ms, between 1 and 2000. Defaults to 50
+    pub async fn set_limiter_release(&self, release_ms: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_limiter_release(release_ms)
+            .map_err(PlayerError::Code)
+    }
+
+    ///Gain reduction in dB over the last block the device pulled
+    pub async fn get_limiter_reduction(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_limiter_reduction()
+    }
+
+    pub async fn is_dither_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_dither_enabled()
+    }
+
+    ///TPDF dither when the device takes 16 or 24-bit samples. On by default, never applied while bit-perfect
+    pub async fn set_dither_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_dither_enabled(enabled);
+    }
+
+    pub async fn get_noise_shaping(&self) -> NoiseShaping {
+        let engine = self.engine.lock().await;
+        engine.get_noise_shaping()
+    }
+
+    ///Shapes the dither noise away from where the ear is most sensitive. Flat by default
+    pub async fn set_noise_shaping(&self, noise_shaping: NoiseShaping) {
+        let engine = self.engine.lock().await;
+        engine.set_noise_shaping(noise_shaping);
+    }
+
+    ///Adds a built-in effect to the processing chain. Appended at the end if no index is given. Returns the processor id
+    pub async fn add_effect(
+        &self,
+        effect: BuiltinEffect,
+        index: Option<u32>,
+    ) -> Result<u64, PlayerError> {
+        let processor = effect.build().map_err(PlayerError::Code)?;
+        let engine = self.engine.lock().await;
+        engine
+            .add_processor(processor, index.map(|i| i as usize))
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn remove_processor(&self, id: u64) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.remove_processor(id).map_err(PlayerError::Code)
+    }
+
+    pub async fn move_processor(&self, id: u64, index: u32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .move_processor(id, index as usize)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn set_processor_bypassed(&self, id: u64, bypassed: bool) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_processor_bypassed(id, bypassed)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn is_processor_bypassed(&self, id: u64) -> Result<bool, PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.is_processor_bypassed(id).map_err(PlayerError::Code)
+    }
+
+    ///Processor ids in processing order
+    pub async fn get_processor_ids(&self) -> Vec<u64> {
+        let engine = self.engine.lock().await;
+        engine.get_processor_ids()
+    }
+
+    pub async fn clear_processors(&self) {
+        let engine = self.engine.lock().await;
+        engine.clear_processors();
+    }
+
+    ///Latency added by the processing chain in seconds
+    pub async fn get_processing_latency(&self) -> f64 {
+        let engine = self.engine.lock().await;
+        engine.get_processing_latency()
+    }
+
+    pub async fn is_eq_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_eq_enabled()
+    }
+
+    pub async fn set_eq_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_eq_enabled(enabled);
+    }
+
+    pub async fn get_eq_bands(&self) -> Vec<EqBand> {
+        let engine = self.engine.lock().await;
+        engine.get_eq_bands()
+    }
+
+    ///Replaces all EQ bands. Up to 32 bands, gains within +-24 dB
+    pub async fn set_eq_bands(&self, bands: Vec<EqBand>) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.set_eq_bands(bands).map_err(PlayerError::Code)
+    }
+
+    pub async fn set_eq_band(&self, index: u32, band: EqBand) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_eq_band(index as usize, band)
+            .map_err(PlayerError::Code)
+    }
+
+    ///10 band graphic EQ mode. Gains in dB, lowest band first. See get_graphic_eq_frequencies
+    pub async fn set_graphic_eq(&self, gains: Vec<f32>) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.set_graphic_eq(&gains).map_err(PlayerError::Code)
+    }
+
+    pub fn get_graphic_eq_frequencies(&self) -> Vec<f32> {
+        GRAPHIC_EQ_FREQUENCIES.to_vec()
+    }
+
+    pub async fn apply_eq_preset(&self, preset: EqPreset) {
+        let engine = self.engine.lock().await;
+        engine.apply_eq_preset(preset);
+    }
+
+    pub async fn get_eq_preamp(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_eq_preamp()
+    }
+
+    pub async fn set_eq_preamp(&self, preamp_db: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.set_eq_preamp(preamp_db).map_err(PlayerError::Code)
+    }
+
+    pub async fn get_replay_gain_mode(&self) -> ReplayGainMode {
+        let engine = self.engine.lock().await;
+        engine.get_replay_gain_mode()
+    }
+
+    ///Applies REPLAYGAIN_* / R128_* tags. Album mode falls back to track gain and vice versa
+    pub async fn set_replay_gain_mode(&self, mode: ReplayGainMode) {
+        let engine = self.engine.lock().await;
+        engine.set_replay_gain_mode(mode);
+    }
+
+    pub async fn get_replay_gain_preamp(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_replay_gain_preamp()
+    }
+
+    ///Extra gain in dB for tagged tracks, within +-15 dB
+    pub async fn set_replay_gain_preamp(&self, preamp_db: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_replay_gain_preamp(preamp_db)
+            .map_err(PlayerError::Code)
+    }
+
+    pub async fn get_replay_gain_prevent_clipping(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.get_replay_gain_prevent_clipping()
+    }
+
+    ///Caps the gain so the tagged peak doesn't exceed full scale
+    pub async fn set_replay_gain_prevent_clipping(&self, prevent_clipping: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_replay_gain_prevent_clipping(prevent_clipping);
+    }
+
+    pub async fn get_replay_gain_info(&self) -> Option<ReplayGainInfo> {
+        let engine = self.engine.lock().await;
+        engine.get_replay_gain_info()
+    }
+
+    ///Linear gain ReplayGain is applying right now, on top of the volume
+    pub async fn get_replay_gain(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_replay_gain()
+    }
+
+    pub async fn is_loudness_normalization_enabled(&self) -> bool {
+        let engine = self.engine.lock().await;
+        engine.is_loudness_normalization_enabled()
+    }
+
+    ///Measures tracks without ReplayGain tags (EBU R128) in the background and normalises them to the target.
+    ///Tagged tracks keep using their tags while ReplayGain is on. Network sources aren't measured
+    pub async fn set_loudness_normalization_enabled(&self, enabled: bool) {
+        let engine = self.engine.lock().await;
+        engine.set_loudness_normalization_enabled(enabled);
+    }
+
+    pub async fn get_loudness_target(&self) -> f32 {
+        let engine = self.engine.lock().await;
+        engine.get_loudness_target()
+    }
+
+    ///Target loudness in LUFS, between -40 and -5. Defaults to -18
+    pub async fn set_loudness_target(&self, target_lufs: f32) -> Result<(), PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .set_loudness_target(target_lufs)
+            .map_err(PlayerError::Code)
+    }
+
+    ///Loudness of the loaded track. None until the background scan finishes
+    pub async fn get_measured_loudness(&self) -> Option<LoudnessInfo> {
+        let engine = self.engine.lock().await;
+        engine.get_measured_loudness()
+    }
+
+    pub async fn get_cached_loudness(&self, path: String) -> Option<LoudnessInfo> {
+        let engine = self.engine.lock().await;
+        engine.get_cached_loudness(&path)
+    }
+
+    pub async fn clear_loudness_cache(&self) {
+        let engine = self.engine.lock().await;
+        engine.clear_loudness_cache();
     }
 }
 
@@ -107,15 +822,200 @@ impl Player {
     //Rust only constructor with closures
     pub fn new(
         resampling_quality: Option<ResamplingQuality>,
-        callback: Box<dyn FnMut(EngineSignal, Arc<Player>) -> ()>,
+        callback: Box<dyn FnMut(PlayerEvent, Arc<Player>) + Send>,
     ) -> Result<Arc<Self>, PlayerError> {
         let engine = AudioEngine::new(resampling_quality, callback);
         if engine.is_err() {
             return Err(PlayerError::Code(engine.err().unwrap_or(-1)));
         }
+        let engine = engine.unwrap();
+        // Nothing else has the engine yet so this can't fail
+        let (levels, spectrum, events) = {
+            let engine = engine.try_lock().map_err(|_| PlayerError::Code(-1))?;
+            (
+                engine.levels(),
+                engine.spectrum_tap(),
+                engine.event_sender(),
+            )
+        };
 
         Ok(Arc::new(Player {
-            engine: engine.unwrap(),
+            engine,
+            levels,
+            spectrum,
+            analyzer: Mutex::new(SpectrumAnalyzer::default()),
+            events,
         }))
     }
+
+    ///Every event the callback gets, for select! loops and the like. A subscriber that falls more than 256
+    ///events behind gets RecvError::Lagged and carries on from the oldest one still queued
+    pub fn events(&self) -> broadcast::Receiver<PlayerEvent> {
+        self.events.subscribe()
+    }
+
+    ///Plays from any Read + Seek. Read from the decoder thread
+    pub async fn load_reader<R: ReadSeek + 'static>(
+        self: Arc<Self>,
+        reader: R,
+    ) -> Result<(), PlayerError> {
+        let player = Arc::downgrade(&self);
+        AudioEngine::load(
+            self.engine.clone(),
+            MediaSource::from_reader(reader),
+            None,
+            player,
+        )
+        .await
+        .map_err(PlayerError::from)
+    }
+
+    ///Adds a custom processor to the processing chain. Appended at the end if no index is given. Returns the processor id
+    pub async fn add_processor(
+        &self,
+        processor: Box<dyn AudioProcessor>,
+        index: Option<usize>,
+    ) -> Result<u64, PlayerError> {
+        let engine = self.engine.lock().await;
+        engine
+            .add_processor(processor, index)
+            .map_err(PlayerError::Code)
+    }
+
+    ///Removes a processor from the chain and hands it back
+    pub async fn take_processor(&self, id: u64) -> Result<Box<dyn AudioProcessor>, PlayerError> {
+        let engine = self.engine.lock().await;
+        engine.take_processor(id).map_err(PlayerError::Code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocking::{BlockingPlayer, block_on},
+        testing::wav,
+    };
+
+    use tokio::sync::broadcast::error::RecvError;
+
+    use std::{
+        fs,
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    //A local file with loudness normalisation on, so the decoder has a scan going too
+    fn loaded_player(path: &str) -> BlockingPlayer {
+        let player = BlockingPlayer::new(None, Box::new(|_, _| {})).unwrap();
+        block_on(player.player().set_loudness_normalization_enabled(true));
+        player.load(path).unwrap();
+        player.play().unwrap();
+        player
+    }
+
+    fn thread_count() -> usize {
+        fs::read_dir("/proc/self/task").unwrap().count()
+    }
+
+    #[test]
+    #[ignore = "needs an output device"]
+    fn subscribers_get_every_event() {
+        let player = BlockingPlayer::new(None, Box::new(|_, _| {})).unwrap();
+        let mut first = player.player().events();
+        let mut second = player.player().events();
+        player.load_bytes(wav(0.5, 8000, 440.0)).unwrap();
+        player.play().unwrap();
+
+        // Both see the same events in the same order, ending with the track finishing
+        let mut seen = Vec::new();
+        loop {
+            let event = block_on(first.recv()).unwrap();
+            assert_eq!(block_on(second.recv()).unwrap(), event);
+            if event == PlayerEvent::MediaEnd {
+                break;
+            }
+            seen.push(event);
+        }
+        let states: Vec<_> = seen
+            .iter()
+            .filter_map(|event| match event {
+                PlayerEvent::StateChanged { state } => Some(*state),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            states[..3],
+            [
+                PlayerState::LOADING,
+                PlayerState::LOADED,
+                PlayerState::PLAYING
+            ]
+        );
+        assert!(
+            seen.iter()
+                .any(|event| matches!(event, PlayerEvent::Loaded { .. }))
+        );
+
+        // Shutting down drops the sender, which ends every subscription
+        player.shutdown();
+        drop(player);
+        loop {
+            match block_on(first.recv()) {
+                Ok(_) => {}
+                Err(error) => {
+                    assert_eq!(error, RecvError::Closed);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an output device"]
+    fn shutdown_ends_every_thread() {
+        let path = std::env::temp_dir().join("aurex-shutdown-test.wav");
+        fs::write(&path, wav(5.0, 44100, 440.0)).unwrap();
+        let player = loaded_player(path.to_str().unwrap());
+        thread::sleep(Duration::from_millis(200));
+
+        // Decoder, listener and ticker at least, plus the scan if it's still going
+        let threads = block_on(player.player().engine.lock()).shutdown();
+        assert!(threads.len() >= 3);
+
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            join_threads(threads);
+            _ = done_tx.send(());
+        });
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        _ = fs::remove_file(path);
+    }
+
+    #[test]
+    #[ignore = "needs an output device"]
+    fn players_leave_no_threads_behind() {
+        let path = std::env::temp_dir().join("aurex-leak-test.wav");
+        fs::write(&path, wav(5.0, 44100, 440.0)).unwrap();
+        let path = path.to_str().unwrap();
+
+        // The first player may start threads the audio backend keeps for the whole process
+        loaded_player(path).shutdown();
+        let before = thread_count();
+
+        for _ in 0..10 {
+            let player = loaded_player(path);
+            thread::sleep(Duration::from_millis(50));
+            player.shutdown();
+        }
+
+        // Other tests come and go meanwhile, so allow a little slack. A leak would be at least one per player
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while thread_count() > before + 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(thread_count() <= before + 3);
+        _ = fs::remove_file(path);
+    }
 }
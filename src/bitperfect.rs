@@ -0,0 +1,211 @@
+//bitperfect.rs
+
+//Bit-perfect output. The device is reopened at the track's own rate in an integer format at least as deep as the
+//source, soxr is skipped and the output stage leaves the samples alone, so the DAC gets exactly what's in the file.
+//Needs stereo integer PCM (FLAC, WAV, ALAC and the like) and a device that takes its rate. Anything else is
+//resampled to the device's default as usual
+
+use cpal::{SampleFormat, SupportedBufferSize, SupportedStreamConfigRange, traits::DeviceTrait};
+use ffmpeg_next::{codec::decoder::Audio, format::Sample};
+
+//Most frames buffers are sized for up front. Drivers that claim more are worked through in pieces
+const MAX_BUFFER_FRAMES: usize = 16384;
+
+///How the output device is opened
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OutputFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    pub bit_perfect: bool, // At the source's own rate and depth, with nothing in between
+}
+
+impl OutputFormat {
+    ///The device's default. Sample formats the output stage can't write fall back to i32
+    pub fn default_for(device: &cpal::Device) -> Result<Self, i32> {
+        let config = device.default_output_config().map_err(|_| -1)?;
+        let sample_format = match config.sample_format() {
+            format @ (SampleFormat::I16
+            | SampleFormat::I24
+            | SampleFormat::I32
+            | SampleFormat::F32) => format,
+            _ => SampleFormat::I32,
+        };
+
+        Ok(OutputFormat {
+            sample_rate: config.sample_rate(),
+            channels: config.channels(),
+            sample_format,
+            bit_perfect: false,
+        })
+    }
+
+    pub fn config(&self) -> cpal::StreamConfig {
+        cpal::StreamConfig {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+
+    ///Largest block the device may ask for in this format. None if it doesn't say
+    pub fn max_buffer_frames(&self, device: &cpal::Device) -> Option<usize> {
+        device
+            .supported_output_configs()
+            .ok()?
+            .filter(|range| {
+                range.channels() == self.channels
+                    && range.sample_format() == self.sample_format
+                    && (range.min_sample_rate()..=range.max_sample_rate())
+                        .contains(&self.sample_rate)
+            })
+            .find_map(|range| match range.buffer_size() {
+                SupportedBufferSize::Range { max, .. } => Some(*max as usize),
+                SupportedBufferSize::Unknown => None,
+            })
+            .map(|frames| frames.min(MAX_BUFFER_FRAMES))
+    }
+}
+
+//Bits the source really carries. None for float and anything else that doesn't survive as i32 unchanged
+fn source_bits(decoder: &Audio) -> Option<u32> {
+    let raw_bits = unsafe { (*decoder.as_ptr()).bits_per_raw_sample };
+    sample_depth(decoder.format(), raw_bits)
+}
+
+//Bits in a decoded sample format. raw_bits is what the codec reports, 0 or less if it doesn't say
+fn sample_depth(format: Sample, raw_bits: i32) -> Option<u32> {
+    let raw_bits = raw_bits.max(0) as u32;
+    match format {
+        Sample::U8(_) => Some(8),
+        Sample::I16(_) => Some(16),
+        Sample::I32(_) if (1..=32).contains(&raw_bits) => Some(raw_bits),
+        Sample::I32(_) => Some(32),
+        _ => None,
+    }
+}
+
+///A device format that plays the decoder's output as it is, if the device has one
+pub fn native_format(device: &cpal::Device, decoder: &Audio) -> Option<OutputFormat> {
+    if decoder.channels() != 2 {
+        return None;
+    }
+    let bits = source_bits(decoder)?;
+    let supported: Vec<_> = device.supported_output_configs().ok()?.collect();
+    pick_format(bits, decoder.rate(), &supported)
+}
+
+//The shallowest integer format among the device's stereo configs that holds every source bit at this rate
+fn pick_format(
+    bits: u32,
+    sample_rate: u32,
+    supported: &[SupportedStreamConfigRange],
+) -> Option<OutputFormat> {
+    let candidates: &[SampleFormat] = match bits {
+        0..=16 => &[SampleFormat::I16, SampleFormat::I24, SampleFormat::I32],
+        17..=24 => &[SampleFormat::I24, SampleFormat::I32],
+        _ => &[SampleFormat::I32],
+    };
+
+    candidates
+        .iter()
+        .copied()
+        .find(|format| {
+            supported.iter().any(|range| {
+                range.channels() == 2
+                    && range.sample_format() == *format
+                    && (range.min_sample_rate()..=range.max_sample_rate()).contains(&sample_rate)
+            })
+        })
+        .map(|sample_format| OutputFormat {
+            sample_rate,
+            channels: 2,
+            sample_format,
+            bit_perfect: true,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ffmpeg_next::format::sample::Type;
+
+    fn stereo(sample_format: SampleFormat) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            2,
+            44100,
+            192000,
+            SupportedBufferSize::Unknown,
+            sample_format,
+        )
+    }
+
+    fn picked(
+        bits: u32,
+        sample_rate: u32,
+        supported: &[SupportedStreamConfigRange],
+    ) -> Option<SampleFormat> {
+        pick_format(bits, sample_rate, supported).map(|format| format.sample_format)
+    }
+
+    #[test]
+    fn depth_follows_the_decoder() {
+        assert_eq!(sample_depth(Sample::U8(Type::Packed), 0), Some(8));
+        assert_eq!(sample_depth(Sample::I16(Type::Packed), 0), Some(16));
+        assert_eq!(sample_depth(Sample::I16(Type::Planar), 16), Some(16));
+        // 24-bit FLAC and WAV decode to 32-bit samples and say how many bits are real
+        assert_eq!(sample_depth(Sample::I32(Type::Packed), 24), Some(24));
+        assert_eq!(sample_depth(Sample::I32(Type::Planar), 0), Some(32));
+        assert_eq!(sample_depth(Sample::I32(Type::Packed), -1), Some(32));
+        assert_eq!(sample_depth(Sample::I32(Type::Packed), 40), Some(32));
+        assert_eq!(sample_depth(Sample::F32(Type::Packed), 24), None);
+        assert_eq!(sample_depth(Sample::F64(Type::Planar), 0), None);
+    }
+
+    #[test]
+    fn picks_the_shallowest_format_that_holds_the_source() {
+        let all = [
+            stereo(SampleFormat::I32),
+            stereo(SampleFormat::I16),
+            stereo(SampleFormat::I24),
+            stereo(SampleFormat::F32),
+        ];
+        assert_eq!(picked(8, 44100, &all), Some(SampleFormat::I16));
+        assert_eq!(picked(16, 44100, &all), Some(SampleFormat::I16));
+        assert_eq!(picked(24, 96000, &all), Some(SampleFormat::I24));
+        assert_eq!(picked(32, 192000, &all), Some(SampleFormat::I32));
+
+        // Deeper is fine, shallower or float isn't
+        assert_eq!(picked(16, 44100, &all[..1]), Some(SampleFormat::I32));
+        assert_eq!(picked(24, 44100, &[stereo(SampleFormat::I16)]), None);
+        assert_eq!(picked(16, 44100, &[stereo(SampleFormat::F32)]), None);
+
+        let format = pick_format(24, 88200, &all).unwrap();
+        assert_eq!(
+            format,
+            OutputFormat {
+                sample_rate: 88200,
+                channels: 2,
+                sample_format: SampleFormat::I24,
+                bit_perfect: true,
+            }
+        );
+    }
+
+    #[test]
+    fn needs_the_rate_in_stereo() {
+        let all = [stereo(SampleFormat::I16), stereo(SampleFormat::I32)];
+        assert_eq!(picked(16, 22050, &all), None);
+        assert_eq!(picked(16, 384000, &all), None);
+
+        let mono = SupportedStreamConfigRange::new(
+            1,
+            44100,
+            44100,
+            SupportedBufferSize::Unknown,
+            SampleFormat::I16,
+        );
+        assert_eq!(picked(16, 44100, &[mono]), None);
+    }
+}
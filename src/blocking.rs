@@ -0,0 +1,234 @@
+//blocking.rs
+
+//Synchronous API. The player's futures only ever wait on the player's own lock, never on IO or timers, so they
+//don't need tokio or any other runtime. Any executor can drive them, and block_on drives them on the calling
+//thread for plain threads, game loops and FFI
+
+use crate::{
+    aurex::Player,
+    enums::{PlayerError, PlayerState, ResamplingQuality},
+    events::{PlayerEvent, TrackInfo},
+    source::ReadSeek,
+};
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+///Runs a future to completion on the calling thread. Meant for the player's futures. Blocks the thread, so not
+///for use inside an async task
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        // Woken by the lock being released. A spurious wakeup just polls again
+        thread::park();
+    }
+}
+
+///The player without async. Each call waits for the engine on the calling thread. Anything not covered here is
+///one block_on(blocking.player().method()) away
+pub struct BlockingPlayer {
+    player: Arc<Player>,
+}
+
+impl BlockingPlayer {
+    pub fn new(
+        resampling_quality: Option<ResamplingQuality>,
+        callback: Box<dyn FnMut(PlayerEvent, Arc<Player>) + Send>,
+    ) -> Result<Self, PlayerError> {
+        Ok(BlockingPlayer {
+            player: Player::new(resampling_quality, callback)?,
+        })
+    }
+
+    ///Wraps a player that's also used asynchronously elsewhere
+    pub fn from_player(player: Arc<Player>) -> Self {
+        BlockingPlayer { player }
+    }
+
+    ///The async player underneath, the same one callbacks get
+    pub fn player(&self) -> &Arc<Player> {
+        &self.player
+    }
+
+    pub fn load(&self, file: &str) -> Result<(), PlayerError> {
+        block_on(self.player.clone().load(file))
+    }
+
+    pub fn load_with_stream(&self, file: &str, stream_index: u32) -> Result<(), PlayerError> {
+        block_on(self.player.clone().load_with_stream(file, stream_index))
+    }
+
+    pub fn load_bytes(&self, bytes: Vec<u8>) -> Result<(), PlayerError> {
+        block_on(self.player.clone().load_bytes(bytes))
+    }
+
+    pub fn load_reader<R: ReadSeek + 'static>(&self, reader: R) -> Result<(), PlayerError> {
+        block_on(self.player.clone().load_reader(reader))
+    }
+
+    pub fn play(&self) -> Result<(), PlayerError> {
+        block_on(self.player.play())
+    }
+
+    pub fn pause(&self) -> Result<(), PlayerError> {
+        block_on(self.player.pause())
+    }
+
+    pub fn seek(&self, time_s: f64) -> Result<(), PlayerError> {
+        block_on(self.player.seek(time_s))
+    }
+
+    pub fn clear(&self) -> Result<(), PlayerError> {
+        block_on(self.player.clear())
+    }
+
+    pub fn shutdown(&self) {
+        block_on(self.player.shutdown())
+    }
+
+    pub fn get_state(&self) -> PlayerState {
+        block_on(self.player.get_state())
+    }
+
+    pub fn get_progress(&self) -> Result<f64, PlayerError> {
+        block_on(self.player.get_progress())
+    }
+
+    pub fn get_duration(&self) -> f64 {
+        block_on(self.player.get_duration())
+    }
+
+    pub fn get_track_info(&self) -> Option<TrackInfo> {
+        block_on(self.player.get_track_info())
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        block_on(self.player.get_volume())
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        block_on(self.player.set_volume(volume))
+    }
+
+    pub fn fade_to(&self, volume: f32, duration_s: f32) {
+        block_on(self.player.fade_to(volume, duration_s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::{
+            Mutex,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::sync::{Mutex as async_Mutex, broadcast};
+
+    //Pending until another thread sets the flag and wakes whoever polled it last
+    struct Flag {
+        set: Arc<AtomicBool>,
+        waker: Arc<Mutex<Option<Waker>>>,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl Future for Flag {
+        type Output = usize;
+
+        fn poll(self: std::pin::Pin<&mut Self>, context: &mut Context<'_>) -> Poll<usize> {
+            let polls = self.polls.fetch_add(1, Ordering::Relaxed) + 1;
+            *self.waker.lock().unwrap() = Some(context.waker().clone());
+            if self.set.load(Ordering::Relaxed) {
+                Poll::Ready(polls)
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn returns_ready_futures_straight_away() {
+        assert_eq!(block_on(async { 1 + 1 }), 2);
+    }
+
+    #[test]
+    fn sleeps_until_woken() {
+        let set = Arc::new(AtomicBool::new(false));
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let polls = Arc::new(AtomicUsize::new(0));
+        let flag = Flag {
+            set: set.clone(),
+            waker: waker.clone(),
+            polls: polls.clone(),
+        };
+
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            set.store(true, Ordering::Relaxed);
+            waker.lock().unwrap().take().unwrap().wake();
+        });
+        // Parked in between rather than spinning, so only a handful of polls
+        let total = block_on(flag);
+        setter.join().unwrap();
+        assert!((2..10).contains(&total), "{total}");
+    }
+
+    #[test]
+    fn waits_for_a_lock_held_elsewhere() {
+        let lock = Arc::new(async_Mutex::new(0));
+        let guard = lock.clone().try_lock_owned().unwrap();
+
+        let holder = thread::spawn(move || {
+            let mut guard = guard;
+            thread::sleep(Duration::from_millis(50));
+            *guard = 1;
+        });
+        assert_eq!(*block_on(lock.lock()), 1);
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn receives_events_without_a_runtime() {
+        let (events, mut receiver) = broadcast::channel(16);
+        let sender = thread::spawn(move || {
+            for n in 0..3 {
+                thread::sleep(Duration::from_millis(10));
+                events.send(n).unwrap();
+            }
+        });
+
+        for n in 0..3 {
+            assert_eq!(block_on(receiver.recv()).unwrap(), n);
+        }
+        sender.join().unwrap();
+        // Every sender gone ends the stream
+        assert!(block_on(receiver.recv()).is_err());
+    }
+}
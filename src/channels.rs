@@ -0,0 +1,371 @@
+//channels.rs
+
+//Balance, mono downmix, channel swap, per-channel mute and a user routing matrix. All of it
+//folds into one 2x2 matrix, so the audio thread only ever does a single multiply per frame
+
+use crate::{
+    dsp::AudioProcessor,
+    handoff::Stage,
+    ramp::{Ramp, ms_to_frames},
+};
+
+//Largest coefficient allowed in a routing matrix. +12 dB, the limiter catches the rest
+pub const MAX_ROUTING_GAIN: f32 = 4.0;
+
+//Matrix changes glide over this so toggling mono or swap doesn't click
+const MATRIX_RAMP_MS: u32 = 20;
+
+///Stereo routing matrix. Each output channel is a weighted sum of both inputs
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct ChannelMatrix {
+    pub left_from_left: f32,
+    pub left_from_right: f32,
+    pub right_from_left: f32,
+    pub right_from_right: f32,
+}
+
+impl ChannelMatrix {
+    pub const IDENTITY: ChannelMatrix = ChannelMatrix {
+        left_from_left: 1.0,
+        left_from_right: 0.0,
+        right_from_left: 0.0,
+        right_from_right: 1.0,
+    };
+
+    pub fn validate(&self) -> Result<(), i32> {
+        let valid = self
+            .to_array()
+            .iter()
+            .all(|gain| gain.is_finite() && gain.abs() <= MAX_ROUTING_GAIN);
+        if !valid {
+            return Err(-1);
+        }
+        Ok(())
+    }
+
+    fn to_array(self) -> [f32; 4] {
+        [
+            self.left_from_left,
+            self.left_from_right,
+            self.right_from_left,
+            self.right_from_right,
+        ]
+    }
+
+    //self applied after other
+    fn then(self, other: ChannelMatrix) -> ChannelMatrix {
+        ChannelMatrix {
+            left_from_left: self.left_from_left * other.left_from_left
+                + self.left_from_right * other.right_from_left,
+            left_from_right: self.left_from_left * other.left_from_right
+                + self.left_from_right * other.right_from_right,
+            right_from_left: self.right_from_left * other.left_from_left
+                + self.right_from_right * other.right_from_left,
+            right_from_right: self.right_from_left * other.left_from_right
+                + self.right_from_right * other.right_from_right,
+        }
+    }
+}
+
+impl Default for ChannelMatrix {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+#[derive(Clone)]
+pub struct ChannelMixer {
+    balance: f32,
+    mono: bool,
+    swap: bool,
+    mute_left: bool,
+    mute_right: bool,
+    routing: ChannelMatrix,
+
+    target: ChannelMatrix, // Everything above combined
+    gains: [Ramp; 4],
+}
+
+impl ChannelMixer {
+    pub fn new() -> Self {
+        ChannelMixer {
+            balance: 0.0,
+            mono: false,
+            swap: false,
+            mute_left: false,
+            mute_right: false,
+            routing: ChannelMatrix::IDENTITY,
+            target: ChannelMatrix::IDENTITY,
+            gains: ChannelMatrix::IDENTITY.to_array().map(Ramp::new),
+        }
+    }
+
+    pub fn get_balance(&self) -> f32 {
+        self.balance
+    }
+
+    ///-1.0 is left only, 1.0 is right only. The louder side stays at full level
+    pub fn set_balance(&mut self, balance: f32) -> Result<(), i32> {
+        if !balance.is_finite() || !(-1.0..=1.0).contains(&balance) {
+            return Err(-1);
+        }
+        self.balance = balance;
+        self.update();
+        Ok(())
+    }
+
+    pub fn is_mono(&self) -> bool {
+        self.mono
+    }
+
+    pub fn set_mono(&mut self, mono: bool) {
+        self.mono = mono;
+        self.update();
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        self.swap
+    }
+
+    pub fn set_swapped(&mut self, swap: bool) {
+        self.swap = swap;
+        self.update();
+    }
+
+    ///Mute state as (left, right)
+    pub fn get_muted(&self) -> (bool, bool) {
+        (self.mute_left, self.mute_right)
+    }
+
+    pub fn set_muted(&mut self, left: bool, right: bool) {
+        self.mute_left = left;
+        self.mute_right = right;
+        self.update();
+    }
+
+    pub fn get_routing(&self) -> ChannelMatrix {
+        self.routing
+    }
+
+    ///Applied first, before swap, mono, balance and mute
+    pub fn set_routing(&mut self, routing: ChannelMatrix) -> Result<(), i32> {
+        routing.validate()?;
+        self.routing = routing;
+        self.update();
+        Ok(())
+    }
+
+    ///Back to plain stereo
+    pub fn reset_all(&mut self) {
+        self.balance = 0.0;
+        self.mono = false;
+        self.swap = false;
+        self.mute_left = false;
+        self.mute_right = false;
+        self.routing = ChannelMatrix::IDENTITY;
+        self.update();
+    }
+
+    ///Most either output channel can be above full scale for full scale inputs. Above 1.0 the routing boosts
+    pub fn max_gain(&self) -> f32 {
+        let target = self.target;
+        (target.left_from_left.abs() + target.left_from_right.abs())
+            .max(target.right_from_left.abs() + target.right_from_right.abs())
+    }
+
+    ///True while the output differs from the input. The gains only pick up a new target in process, so they're
+    ///checked against plain stereo too, or going back to it would skip the glide
+    pub fn is_active(&self) -> bool {
+        let identity = ChannelMatrix::IDENTITY.to_array();
+        self.target != ChannelMatrix::IDENTITY
+            || self
+                .gains
+                .iter()
+                .zip(identity)
+                .any(|(gain, unity)| !gain.is_settled() || gain.current() != unity)
+    }
+
+    fn update(&mut self) {
+        let mut matrix = self.routing;
+
+        if self.swap {
+            let swap = ChannelMatrix {
+                left_from_left: 0.0,
+                left_from_right: 1.0,
+                right_from_left: 1.0,
+                right_from_right: 0.0,
+            };
+            matrix = swap.then(matrix);
+        }
+
+        if self.mono {
+            let mono = ChannelMatrix {
+                left_from_left: 0.5,
+                left_from_right: 0.5,
+                right_from_left: 0.5,
+                right_from_right: 0.5,
+            };
+            matrix = mono.then(matrix);
+        }
+
+        let left = if self.mute_left {
+            0.0
+        } else {
+            (1.0 - self.balance).min(1.0)
+        };
+        let right = if self.mute_right {
+            0.0
+        } else {
+            (1.0 + self.balance).min(1.0)
+        };
+        let levels = ChannelMatrix {
+            left_from_left: left,
+            left_from_right: 0.0,
+            right_from_left: 0.0,
+            right_from_right: right,
+        };
+
+        self.target = levels.then(matrix);
+    }
+}
+
+impl Default for ChannelMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioProcessor for ChannelMixer {
+    fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        let ramp_frames = ms_to_frames(MATRIX_RAMP_MS, sample_rate);
+        for (gain, target) in self.gains.iter_mut().zip(self.target.to_array()) {
+            gain.set_target(target, ramp_frames);
+        }
+
+        let [ll, lr, rl, rr] = &mut self.gains;
+        for frame in frames.iter_mut() {
+            let [left, right] = *frame;
+            frame[0] = left * ll.tick() + right * lr.tick();
+            frame[1] = left * rl.tick() + right * rr.tick();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.gains = self.target.to_array().map(Ramp::new);
+    }
+}
+
+impl Stage for ChannelMixer {
+    fn follow(&mut self, control: &Self) {
+        // Only the settings, the gains glide to the new target on their own
+        self.balance = control.balance;
+        self.mono = control.mono;
+        self.swap = control.swap;
+        self.mute_left = control.mute_left;
+        self.mute_right = control.mute_right;
+        self.routing = control.routing;
+        self.target = control.target;
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    const SWAP: ChannelMatrix = ChannelMatrix {
+        left_from_left: 0.0,
+        left_from_right: 1.0,
+        right_from_left: 1.0,
+        right_from_right: 0.0,
+    };
+
+    const MONO: ChannelMatrix = ChannelMatrix {
+        left_from_left: 0.5,
+        left_from_right: 0.5,
+        right_from_left: 0.5,
+        right_from_right: 0.5,
+    };
+
+    //Left input only, to both outputs at different levels
+    const ROUTING: ChannelMatrix = ChannelMatrix {
+        left_from_left: 1.0,
+        left_from_right: 0.0,
+        right_from_left: 0.5,
+        right_from_right: 0.0,
+    };
+
+    //Runs the mixer well past its ramp and returns where a frame ends up
+    fn settle(mixer: &mut ChannelMixer, frame: [f32; 2]) -> [f32; 2] {
+        let mut frames = vec![frame; ms_to_frames(MATRIX_RAMP_MS, RATE) as usize * 2];
+        mixer.process(&mut frames, RATE);
+        *frames.last().unwrap()
+    }
+
+    #[test]
+    fn composes_matrices() {
+        for matrix in [SWAP, MONO, ROUTING] {
+            assert_eq!(ChannelMatrix::IDENTITY.then(matrix), matrix);
+            assert_eq!(matrix.then(ChannelMatrix::IDENTITY), matrix);
+        }
+        assert_eq!(SWAP.then(SWAP), ChannelMatrix::IDENTITY);
+        assert_eq!(MONO.then(SWAP), MONO);
+
+        // Swapping after the routing moves its outputs across
+        let swapped = SWAP.then(ROUTING);
+        assert_eq!(swapped.left_from_left, 0.5);
+        assert_eq!(swapped.right_from_left, 1.0);
+        assert_eq!(swapped.left_from_right + swapped.right_from_right, 0.0);
+    }
+
+    #[test]
+    fn mixer_reaches_its_target() {
+        let mut mixer = ChannelMixer::new();
+        assert!(!mixer.is_active());
+        assert_eq!(settle(&mut mixer, [1.0, 0.25]), [1.0, 0.25]);
+
+        mixer.set_swapped(true);
+        assert_eq!(settle(&mut mixer, [1.0, 0.25]), [0.25, 1.0]);
+
+        mixer.set_mono(true);
+        assert_eq!(settle(&mut mixer, [1.0, 0.0]), [0.5, 0.5]);
+
+        mixer.reset_all();
+        mixer.set_balance(0.5).unwrap();
+        mixer.set_muted(false, true);
+        assert_eq!(settle(&mut mixer, [1.0, 1.0]), [0.5, 0.0]);
+
+        mixer.reset_all();
+        assert!(mixer.is_active()); // Still gliding back
+        assert_eq!(settle(&mut mixer, [1.0, 0.25]), [1.0, 0.25]);
+        assert!(!mixer.is_active());
+    }
+
+    #[test]
+    fn routing_applies_before_the_rest() {
+        let mut mixer = ChannelMixer::new();
+        mixer.set_routing(ROUTING).unwrap();
+        mixer.set_swapped(true);
+        assert_eq!(settle(&mut mixer, [1.0, 1.0]), [0.5, 1.0]);
+        assert_eq!(mixer.max_gain(), 1.0);
+
+        let loud = ChannelMatrix {
+            left_from_right: 1.0,
+            ..ROUTING
+        };
+        mixer.set_routing(loud).unwrap();
+        assert_eq!(mixer.max_gain(), 2.0);
+
+        let too_loud = ChannelMatrix {
+            left_from_left: MAX_ROUTING_GAIN * 2.0,
+            ..ROUTING
+        };
+        assert!(mixer.set_routing(too_loud).is_err());
+        assert_eq!(mixer.get_routing(), loud);
+    }
+}
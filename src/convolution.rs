@@ -0,0 +1,634 @@
+//convolution.rs
+
+//Uniformly partitioned FFT convolution for room correction and headphone EQ impulse responses.
+//The impulse response is split into blocks the size of the processing block, so latency is one
+//block no matter how long the filter is
+
+use crate::{
+    dsp::AudioProcessor,
+    enums::PlayerError,
+    handoff::Stage,
+    ramp::{Ramp, ms_to_frames},
+    resampler::ResamplerConfig,
+};
+
+use ffmpeg_next::{self as av, frame::Audio as AudioFrame, media};
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex, num_complex::Complex};
+use soxr_ax::{Soxr, format::Interleaved};
+
+use std::sync::Arc;
+
+//Frames per partition. Also the latency the convolver adds
+pub const CONVOLUTION_BLOCK: usize = 256;
+
+//Longest impulse response accepted. Every partition is multiplied in on every block, so the cost grows with the
+//length and the rate. Half a second is plenty for headphone EQ and most room correction filters
+pub const MAX_IMPULSE_RESPONSE_S: f64 = 0.5;
+
+const TOGGLE_RAMP_MS: u32 = 50;
+
+///Details of the loaded impulse response
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct ImpulseResponseInfo {
+    pub channels: u32,    // 1 or 2 in the file, mono is used for both sides
+    pub sample_rate: u32, // Rate of the file before resampling
+    pub frames: u32,      // Length at the output rate
+}
+
+///Decoded impulse response at the file's own rate. Mono files are copied to both channels. Kept around so a kernel
+///can be built again when the output rate changes
+pub struct ImpulseResponse {
+    pub channels: u32,
+    pub sample_rate: u32,
+    samples: Vec<[f32; 2]>,
+}
+
+impl ImpulseResponse {
+    ///Reads a WAV (or anything ffmpeg opens)
+    pub fn load(path: &str) -> Result<Self, i32> {
+        let mut format_ctx = av::format::input(path).map_err(|_| -1)?;
+        let stream = format_ctx.streams().best(media::Type::Audio).ok_or(-1)?;
+        let stream_index = stream.index();
+
+        let codec_ctx =
+            av::codec::context::Context::from_parameters(stream.parameters()).map_err(|_| -1)?;
+        let mut decoder = codec_ctx.decoder().audio().map_err(|_| -1)?;
+
+        let channels = decoder.channels();
+        if channels != 1 && channels != 2 {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+
+        // Same layout in and out, this only converts the sample format
+        let layout = if decoder.channel_layout().is_empty() {
+            av::ChannelLayout::default(channels as i32)
+        } else {
+            decoder.channel_layout()
+        };
+        let mut resampler = av::software::resampling::Context::get(
+            decoder.format(),
+            layout,
+            decoder.rate(),
+            av::format::Sample::F32(av::format::sample::Type::Packed),
+            layout,
+            decoder.rate(),
+        )
+        .map_err(|_| -1)?;
+
+        let mut samples: Vec<[f32; 2]> = Vec::new();
+        let mut frame = AudioFrame::empty();
+
+        for (stream, packet) in format_ctx.packets() {
+            if stream.index() != stream_index || decoder.send_packet(&packet).is_err() {
+                continue;
+            }
+            while decoder.receive_frame(&mut frame).is_ok() {
+                push_frame(&mut samples, &mut resampler, &frame, channels as usize);
+            }
+        }
+
+        _ = decoder.send_eof();
+        while decoder.receive_frame(&mut frame).is_ok() {
+            push_frame(&mut samples, &mut resampler, &frame, channels as usize);
+        }
+
+        if samples.is_empty() {
+            return Err(-1);
+        }
+
+        // Checked before resampling so the same file fits at every output rate
+        let sample_rate = decoder.rate();
+        if samples.len() > max_frames(sample_rate) {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+
+        Ok(ImpulseResponse {
+            channels: channels as u32,
+            sample_rate,
+            samples,
+        })
+    }
+}
+
+//Longest impulse response at a rate, in frames
+fn max_frames(sample_rate: u32) -> usize {
+    (MAX_IMPULSE_RESPONSE_S * sample_rate as f64).ceil() as usize
+}
+
+fn push_frame(
+    samples: &mut Vec<[f32; 2]>,
+    resampler: &mut av::software::resampling::Context,
+    frame: &AudioFrame,
+    channels: usize,
+) {
+    let mut converted = AudioFrame::empty();
+    if resampler.run(frame, &mut converted).is_err() {
+        return;
+    }
+
+    // The plane is padded, only take the frames that are actually there
+    let bytes = converted.samples() * channels * std::mem::size_of::<f32>();
+    let data: &[f32] = bytemuck::cast_slice(&converted.data(0)[..bytes]);
+
+    if channels == 1 {
+        samples.extend(data.iter().map(|sample| [*sample; 2]));
+    } else {
+        samples.extend(data.chunks_exact(2).map(|pair| [pair[0], pair[1]]));
+    }
+}
+
+fn resample(
+    samples: &[[f32; 2]],
+    from: u32,
+    to: u32,
+    resampler_config: ResamplerConfig,
+) -> Result<Vec<[f32; 2]>, i32> {
+    let mut soxr = Soxr::<Interleaved<f32, 2>>::new_with_params(
+        from as f64,
+        to as f64,
+        resampler_config.quality_spec(),
+        resampler_config.runtime_spec(),
+    )
+    .map_err(|_| -1)?;
+
+    let expected = (samples.len() as u64 * to as u64 / from as u64) as usize;
+    let mut output = vec![[0.0f32; 2]; expected + 1024];
+
+    let processed = soxr.process(samples, &mut output).map_err(|_| -1)?;
+    let mut produced = processed.output_frames;
+
+    // Flush the filter tail
+    loop {
+        let drained = soxr.drain(&mut output[produced..]).map_err(|_| -1)?;
+        if drained == 0 || produced + drained >= output.len() {
+            produced += drained;
+            break;
+        }
+        produced += drained;
+    }
+
+    output.truncate(produced);
+    Ok(output)
+}
+
+///Frequency domain partitions of an impulse response at one output rate. Built off the audio thread
+pub struct ConvolutionKernel {
+    info: ImpulseResponseInfo,
+    sample_rate: u32,
+    partitions: Vec<[Vec<Complex<f32>>; 2]>,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+}
+
+impl ConvolutionKernel {
+    ///Resamples the impulse response to sample_rate with soxr and splits it into partitions
+    pub fn new(
+        impulse_response: &ImpulseResponse,
+        sample_rate: u32,
+        resampler_config: ResamplerConfig,
+    ) -> Result<Self, i32> {
+        if sample_rate == 0 {
+            return Err(-1);
+        }
+        let mut samples = if impulse_response.sample_rate == sample_rate {
+            impulse_response.samples.clone()
+        } else {
+            resample(
+                &impulse_response.samples,
+                impulse_response.sample_rate,
+                sample_rate,
+                resampler_config,
+            )?
+        };
+        // Only the resampler's ringing can go past the limit
+        samples.truncate(max_frames(sample_rate));
+
+        let fft_size = CONVOLUTION_BLOCK * 2;
+        let mut planner = RealFftPlanner::<f32>::new();
+        let forward = planner.plan_fft_forward(fft_size);
+        let inverse = planner.plan_fft_inverse(fft_size);
+
+        let partitions = samples
+            .chunks(CONVOLUTION_BLOCK)
+            .map(|chunk| {
+                [0, 1].map(|channel| {
+                    // Zero padded to the FFT size
+                    let mut input = forward.make_input_vec();
+                    for (sample, frame) in input.iter_mut().zip(chunk) {
+                        *sample = frame[channel];
+                    }
+                    let mut spectrum = forward.make_output_vec();
+                    _ = forward.process(&mut input, &mut spectrum);
+                    spectrum
+                })
+            })
+            .collect();
+
+        Ok(ConvolutionKernel {
+            info: ImpulseResponseInfo {
+                channels: impulse_response.channels,
+                sample_rate: impulse_response.sample_rate,
+                frames: samples.len() as u32,
+            },
+            sample_rate,
+            partitions,
+            forward,
+            inverse,
+        })
+    }
+
+    pub fn info(&self) -> ImpulseResponseInfo {
+        self.info
+    }
+
+    ///Output rate the kernel was built for
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+//Per-channel running state, sized for the longest kernel
+#[derive(Clone)]
+struct ChannelState {
+    input: Vec<f32>,                 // Previous block then current block
+    history: Vec<Vec<Complex<f32>>>, // Spectra of past input blocks, one per partition
+    output: Vec<f32>,
+}
+
+#[derive(Clone)]
+pub struct Convolver {
+    enabled: bool,
+    kernel: Option<Arc<ConvolutionKernel>>, // Shared with the engine's copy, which drops it
+    channels: Vec<ChannelState>,
+    position: usize,      // Frames into the current block
+    history_index: usize, // Slot in history the newest block goes into
+    time_scratch: Vec<f32>,
+    spectrum_scratch: Vec<Complex<f32>>,
+    accumulator: Vec<Complex<f32>>,
+    fft_scratch: Vec<Complex<f32>>, // Lets the FFTs run without allocating
+    mix: Ramp,                      // 0.0 dry, 1.0 convolved
+}
+
+impl Convolver {
+    pub fn new() -> Self {
+        Convolver {
+            enabled: false,
+            kernel: None,
+            channels: Vec::new(),
+            position: 0,
+            history_index: 0,
+            time_scratch: Vec::new(),
+            spectrum_scratch: Vec::new(),
+            accumulator: Vec::new(),
+            fft_scratch: Vec::new(),
+            mix: Ramp::new(0.0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        // Stale blocks from before it went idle would play back as a burst
+        if enabled && !self.is_active() {
+            self.reset();
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn get_info(&self) -> Option<ImpulseResponseInfo> {
+        self.kernel.as_ref().map(|kernel| kernel.info)
+    }
+
+    ///Output rate the loaded kernel was built for
+    pub fn kernel_rate(&self) -> Option<u32> {
+        self.kernel.as_ref().map(|kernel| kernel.sample_rate)
+    }
+
+    pub fn kernel(&self) -> Option<Arc<ConvolutionKernel>> {
+        self.kernel.clone()
+    }
+
+    ///Swaps in a new kernel, or removes it. Buffers are sized in prepare, for the longest kernel at the rate
+    pub fn set_kernel(&mut self, kernel: Option<Arc<ConvolutionKernel>>) {
+        self.kernel = kernel;
+        self.reset();
+    }
+
+    //Partitions the buffers have room for
+    fn capacity(&self) -> usize {
+        self.channels.first().map_or(0, |state| state.history.len())
+    }
+
+    ///Running while enabled with a kernel, or fading out after being switched off
+    pub fn is_active(&self) -> bool {
+        self.kernel.is_some() && (self.enabled || self.mix.current() != 0.0)
+    }
+
+    ///Latency in frames while active
+    pub fn block_size(&self) -> u32 {
+        CONVOLUTION_BLOCK as u32
+    }
+
+    //Convolves the block that just filled up into each channel's output buffer
+    fn run_block(&mut self) {
+        let Some(kernel) = self.kernel.as_ref() else {
+            return;
+        };
+        let partitions = kernel.partitions.len();
+        let scale = 1.0 / (CONVOLUTION_BLOCK * 2) as f32;
+
+        for (channel, state) in self.channels.iter_mut().enumerate() {
+            self.time_scratch.copy_from_slice(&state.input);
+            let newest = &mut state.history[self.history_index];
+            _ = kernel.forward.process_with_scratch(
+                &mut self.time_scratch,
+                newest,
+                &mut self.fft_scratch,
+            );
+
+            // Multiply and add every partition against the matching past input block
+            self.accumulator.fill(Complex::default());
+            for (age, partition) in kernel.partitions.iter().enumerate() {
+                let slot = (self.history_index + partitions - age) % partitions;
+                for ((acc, x), h) in self
+                    .accumulator
+                    .iter_mut()
+                    .zip(&state.history[slot])
+                    .zip(&partition[channel])
+                {
+                    *acc += x * h;
+                }
+            }
+
+            // A real signal's spectrum has no imaginary part at DC or nyquist, drop the rounding error
+            self.spectrum_scratch.copy_from_slice(&self.accumulator);
+            if let Some(first) = self.spectrum_scratch.first_mut() {
+                first.im = 0.0;
+            }
+            if let Some(last) = self.spectrum_scratch.last_mut() {
+                last.im = 0.0;
+            }
+            _ = kernel.inverse.process_with_scratch(
+                &mut self.spectrum_scratch,
+                &mut self.time_scratch,
+                &mut self.fft_scratch,
+            );
+
+            // Overlap-save, the second half is the valid output
+            for (out, sample) in state
+                .output
+                .iter_mut()
+                .zip(&self.time_scratch[CONVOLUTION_BLOCK..])
+            {
+                *out = sample * scale;
+            }
+
+            state.input.copy_within(CONVOLUTION_BLOCK.., 0);
+        }
+
+        self.history_index = (self.history_index + 1) % partitions;
+    }
+}
+
+impl Default for Convolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioProcessor for Convolver {
+    fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        // Kernels are built for one rate, don't play them back at another. The engine builds a new one when the
+        // output rate changes
+        let capacity = self.capacity();
+        if self.kernel.as_ref().is_none_or(|kernel| {
+            kernel.sample_rate != sample_rate || kernel.partitions.len() > capacity
+        }) {
+            return;
+        }
+
+        let target = if self.enabled { 1.0 } else { 0.0 };
+        self.mix
+            .set_target(target, ms_to_frames(TOGGLE_RAMP_MS, sample_rate));
+
+        for frame in frames.iter_mut() {
+            let position = self.position;
+            let mix = self.mix.tick();
+
+            for (channel, state) in self.channels.iter_mut().enumerate() {
+                // The dry signal is delayed by a block too so toggling doesn't jump in time
+                let dry = state.input[position];
+                let wet = state.output[position];
+                state.input[CONVOLUTION_BLOCK + position] = frame[channel];
+                frame[channel] = dry + (wet - dry) * mix;
+            }
+
+            self.position += 1;
+            if self.position == CONVOLUTION_BLOCK {
+                self.run_block();
+                self.position = 0;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for state in &mut self.channels {
+            state.input.fill(0.0);
+            state.output.fill(0.0);
+            for spectrum in &mut state.history {
+                spectrum.fill(Complex::default());
+            }
+        }
+        self.position = 0;
+        self.history_index = 0;
+    }
+
+    fn latency(&self) -> u32 {
+        if self.is_active() {
+            self.block_size()
+        } else {
+            0
+        }
+    }
+}
+
+impl Stage for Convolver {
+    fn follow(&mut self, control: &Self) {
+        self.set_enabled(control.enabled);
+
+        let same = match (&self.kernel, &control.kernel) {
+            (Some(current), Some(new)) => Arc::ptr_eq(current, new),
+            (current, new) => current.is_none() == new.is_none(),
+        };
+        if !same {
+            // The engine holds on to the old kernel, so it's never freed here
+            self.set_kernel(control.kernel.clone());
+        }
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+    }
+
+    fn prepare(&mut self, sample_rate: u32) {
+        let fft_size = CONVOLUTION_BLOCK * 2;
+        let bins = fft_size / 2 + 1;
+        let partitions = max_frames(sample_rate).div_ceil(CONVOLUTION_BLOCK);
+
+        self.channels = (0..2)
+            .map(|_| ChannelState {
+                input: vec![0.0; fft_size],
+                history: vec![vec![Complex::default(); bins]; partitions],
+                output: vec![0.0; CONVOLUTION_BLOCK],
+            })
+            .collect();
+        self.time_scratch = vec![0.0; fft_size];
+        self.spectrum_scratch = vec![Complex::default(); bins];
+        self.accumulator = vec![Complex::default(); bins];
+
+        let mut planner = RealFftPlanner::<f32>::new();
+        let scratch_len = planner
+            .plan_fft_forward(fft_size)
+            .get_scratch_len()
+            .max(planner.plan_fft_inverse(fft_size).get_scratch_len());
+        self.fft_scratch = vec![Complex::default(); scratch_len];
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    //Deterministic noise in -1.0..1.0
+    fn noise(frames: usize, seed: u32) -> Vec<[f32; 2]> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32 * 2.0 - 1.0
+        };
+        (0..frames).map(|_| [next(), next()]).collect()
+    }
+
+    fn impulse_response(samples: Vec<[f32; 2]>) -> ImpulseResponse {
+        ImpulseResponse {
+            channels: 2,
+            sample_rate: RATE,
+            samples,
+        }
+    }
+
+    fn convolver(impulse_response: &ImpulseResponse) -> Convolver {
+        let mut convolver = Convolver::new();
+        Stage::prepare(&mut convolver, RATE);
+        let kernel = ConvolutionKernel::new(impulse_response, RATE, ResamplerConfig::default());
+        convolver.set_kernel(Some(Arc::new(kernel.unwrap())));
+        convolver.set_enabled(true);
+        // Skip the fade in so every frame is fully convolved
+        convolver.mix = Ramp::new(1.0);
+        convolver
+    }
+
+    fn direct(input: &[[f32; 2]], impulse_response: &[[f32; 2]], channel: usize) -> Vec<f64> {
+        (0..input.len())
+            .map(|n| {
+                impulse_response
+                    .iter()
+                    .take(n + 1)
+                    .enumerate()
+                    .map(|(k, h)| h[channel] as f64 * input[n - k][channel] as f64)
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        // Several partitions, the last one partly filled
+        for length in [1, 100, CONVOLUTION_BLOCK, CONVOLUTION_BLOCK * 3 + 17] {
+            let samples: Vec<[f32; 2]> = noise(length, 7)
+                .into_iter()
+                .map(|[left, right]| [left * 0.1, right * 0.1])
+                .collect();
+            let impulse_response = impulse_response(samples);
+            let mut convolver = convolver(&impulse_response);
+
+            let input = noise(CONVOLUTION_BLOCK * 8, 11);
+            let mut output = input.clone();
+            // Blocks that don't line up with the partitions
+            for block in output.chunks_mut(100) {
+                convolver.process(block, RATE);
+            }
+
+            let latency = convolver.latency() as usize;
+            assert_eq!(latency, CONVOLUTION_BLOCK);
+            assert!(output[..latency].iter().all(|frame| *frame == [0.0; 2]));
+            for channel in 0..2 {
+                let expected = direct(&input, &impulse_response.samples, channel);
+                for (out, expected) in output[latency..].iter().zip(&expected) {
+                    assert!((out[channel] as f64 - expected).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unit_impulse_only_delays() {
+        let mut samples = vec![[0.0; 2]; CONVOLUTION_BLOCK * 2];
+        samples[0] = [1.0, 1.0];
+        let mut convolver = convolver(&impulse_response(samples));
+
+        let input = noise(CONVOLUTION_BLOCK * 4, 3);
+        let mut output = input.clone();
+        convolver.process(&mut output, RATE);
+
+        for (out, source) in output[CONVOLUTION_BLOCK..].iter().zip(&input) {
+            assert!((out[0] - source[0]).abs() < 1e-5);
+            assert!((out[1] - source[1]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn kernels_are_built_for_the_output_rate() {
+        let impulse_response = ImpulseResponse {
+            channels: 1,
+            sample_rate: 44100,
+            samples: noise(max_frames(44100), 1),
+        };
+
+        let kernel =
+            ConvolutionKernel::new(&impulse_response, RATE, ResamplerConfig::default()).unwrap();
+        assert_eq!(kernel.sample_rate(), RATE);
+        let info = kernel.info();
+        assert_eq!((info.channels, info.sample_rate), (1, 44100));
+        // Resampled to the same length in time, and never past the limit at the new rate
+        assert!(info.frames as usize <= max_frames(RATE));
+        assert!(info.frames as usize > max_frames(RATE) - 64);
+
+        assert!(ConvolutionKernel::new(&impulse_response, 0, ResamplerConfig::default()).is_err());
+    }
+
+    #[test]
+    fn other_rates_and_no_kernel_pass_through() {
+        let mut samples = vec![[0.0; 2]; 16];
+        samples[4] = [0.5, 0.5];
+        let mut convolver = convolver(&impulse_response(samples));
+
+        let input = noise(1024, 5);
+        let mut output = input.clone();
+        convolver.process(&mut output, 44100);
+        assert_eq!(output, input);
+
+        convolver.set_kernel(None);
+        assert!(!convolver.is_active());
+        assert_eq!(convolver.latency(), 0);
+        convolver.process(&mut output, RATE);
+        assert_eq!(output, input);
+    }
+}
@@ -0,0 +1,306 @@
+//crossfeed.rs
+
+//Bauer stereophonic-to-binaural crossfeed, after the bs2b design. Each ear gets a low passed,
+//delayed copy of the other channel, and the direct signal gets a matching high shelf so the
+//overall response stays flat. Makes hard panned recordings less tiring on headphones
+
+use crate::{
+    dsp::AudioProcessor,
+    enums::CrossfeedPreset,
+    handoff::Stage,
+    ramp::{Ramp, ms_to_frames},
+};
+
+use std::f64::consts::PI;
+
+pub const MIN_CROSSFEED_CUTOFF: f32 = 300.0;
+pub const MAX_CROSSFEED_CUTOFF: f32 = 2000.0;
+pub const MIN_CROSSFEED_FEED_DB: f32 = 1.0;
+pub const MAX_CROSSFEED_FEED_DB: f32 = 15.0;
+
+//Switching crossfeed on or off glides over this instead of jumping
+const TOGGLE_RAMP_MS: u32 = 50;
+
+#[derive(Clone, Copy, Default)]
+struct Coefficients {
+    a0_lo: f64,
+    b1_lo: f64,
+    a0_hi: f64,
+    a1_hi: f64,
+    b1_hi: f64,
+}
+
+impl Coefficients {
+    fn new(cutoff: f32, feed_db: f32, sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+        let level = feed_db as f64;
+
+        let gb_lo = level * -5.0 / 6.0 - 3.0;
+        let gb_hi = level / 6.0 - 3.0;
+        let g_lo = 10f64.powf(gb_lo / 20.0);
+        let g_hi = 1.0 - 10f64.powf(gb_hi / 20.0);
+        let fc_lo = cutoff as f64;
+        let fc_hi = fc_lo * 2f64.powf((gb_lo - 20.0 * g_hi.log10()) / 12.0);
+
+        let x = (-2.0 * PI * fc_lo / rate).exp();
+        let b1_lo = x;
+        let a0_lo = g_lo * (1.0 - x);
+
+        let x = (-2.0 * PI * fc_hi / rate).exp();
+        let b1_hi = x;
+        let a0_hi = 1.0 - g_hi * (1.0 - x);
+        let a1_hi = -x;
+
+        // Normalise so the summed response peaks at unity
+        let gain = 1.0 / (1.0 - g_hi + g_lo);
+
+        Coefficients {
+            a0_lo: a0_lo * gain,
+            b1_lo,
+            a0_hi: a0_hi * gain,
+            a1_hi: a1_hi * gain,
+            b1_hi,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Crossfeed {
+    enabled: bool,
+    cutoff: f32, // Hz
+    feed_db: f32,
+
+    sample_rate: u32,
+    dirty: bool,
+    coefficients: Coefficients,
+    low: [f64; 2],
+    high: [f64; 2],
+    last_input: [f64; 2],
+    mix: Ramp, // 0.0 dry, 1.0 crossfed
+}
+
+impl Crossfeed {
+    pub fn new() -> Self {
+        let (cutoff, feed_db) = CrossfeedPreset::Default.settings();
+        Crossfeed {
+            enabled: false,
+            cutoff,
+            feed_db,
+            sample_rate: 0,
+            dirty: true,
+            coefficients: Coefficients::default(),
+            low: [0.0; 2],
+            high: [0.0; 2],
+            last_input: [0.0; 2],
+            mix: Ramp::new(0.0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        // Filter state went stale while idle
+        if enabled && !self.is_active() {
+            self.reset();
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn get_cutoff(&self) -> f32 {
+        self.cutoff
+    }
+
+    ///Low pass cutoff of the crossfed signal, 300 to 2000 Hz
+    pub fn set_cutoff(&mut self, cutoff: f32) -> Result<(), i32> {
+        if !cutoff.is_finite() || !(MIN_CROSSFEED_CUTOFF..=MAX_CROSSFEED_CUTOFF).contains(&cutoff) {
+            return Err(-1);
+        }
+        self.cutoff = cutoff;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn get_feed(&self) -> f32 {
+        self.feed_db
+    }
+
+    ///How far below the direct signal the other channel is fed in at low frequencies, 1 to 15 dB. Lower is a
+    ///stronger effect
+    pub fn set_feed(&mut self, feed_db: f32) -> Result<(), i32> {
+        if !feed_db.is_finite()
+            || !(MIN_CROSSFEED_FEED_DB..=MAX_CROSSFEED_FEED_DB).contains(&feed_db)
+        {
+            return Err(-1);
+        }
+        self.feed_db = feed_db;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn apply_preset(&mut self, preset: CrossfeedPreset) {
+        (self.cutoff, self.feed_db) = preset.settings();
+        self.dirty = true;
+    }
+
+    ///Still running while fading out after being switched off
+    pub fn is_active(&self) -> bool {
+        self.enabled || self.mix.current() != 0.0
+    }
+}
+
+impl Default for Crossfeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioProcessor for Crossfeed {
+    fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        if sample_rate == 0 {
+            return;
+        }
+        if self.dirty || sample_rate != self.sample_rate {
+            self.coefficients = Coefficients::new(self.cutoff, self.feed_db, sample_rate);
+            self.sample_rate = sample_rate;
+            self.dirty = false;
+        }
+
+        let target = if self.enabled { 1.0 } else { 0.0 };
+        self.mix
+            .set_target(target, ms_to_frames(TOGGLE_RAMP_MS, sample_rate));
+
+        let c = self.coefficients;
+        for frame in frames.iter_mut() {
+            let input = [frame[0] as f64, frame[1] as f64];
+
+            for (channel, low) in self.low.iter_mut().enumerate() {
+                *low = c.a0_lo * input[channel] + c.b1_lo * *low;
+                self.high[channel] = c.a0_hi * input[channel]
+                    + c.a1_hi * self.last_input[channel]
+                    + c.b1_hi * self.high[channel];
+            }
+            self.last_input = input;
+
+            let mix = self.mix.tick();
+            if mix == 0.0 {
+                continue;
+            }
+
+            let left = (self.high[0] + self.low[1]) as f32;
+            let right = (self.high[1] + self.low[0]) as f32;
+            frame[0] += (left - frame[0]) * mix;
+            frame[1] += (right - frame[1]) * mix;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.low = [0.0; 2];
+        self.high = [0.0; 2];
+        self.last_input = [0.0; 2];
+    }
+}
+
+impl Stage for Crossfeed {
+    fn follow(&mut self, control: &Self) {
+        self.set_enabled(control.enabled);
+        if (self.cutoff, self.feed_db) != (control.cutoff, control.feed_db) {
+            self.cutoff = control.cutoff;
+            self.feed_db = control.feed_db;
+            self.dirty = true;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+    }
+}
+
+impl CrossfeedPreset {
+    ///Cutoff in Hz and feed in dB
+    pub fn settings(&self) -> (f32, f32) {
+        match self {
+            Self::Default => (700.0, 4.5),
+            Self::ChuMoy => (700.0, 6.0),
+            Self::JanMeier => (650.0, 9.5),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn settle(crossfeed: &mut Crossfeed, frame: [f32; 2]) -> [f32; 2] {
+        let mut frames = vec![frame; RATE as usize / 2];
+        crossfeed.process(&mut frames, RATE);
+        frames[frames.len() - 1]
+    }
+
+    fn enabled(preset: CrossfeedPreset) -> Crossfeed {
+        let mut crossfeed = Crossfeed::new();
+        crossfeed.apply_preset(preset);
+        crossfeed.set_enabled(true);
+        crossfeed
+    }
+
+    #[test]
+    fn centred_low_frequencies_pass_at_unity() {
+        for preset in [
+            CrossfeedPreset::Default,
+            CrossfeedPreset::ChuMoy,
+            CrossfeedPreset::JanMeier,
+        ] {
+            let mut crossfeed = enabled(preset);
+            let out = settle(&mut crossfeed, [0.5, 0.5]);
+            assert!((out[0] - 0.5).abs() < 1e-4, "{out:?}");
+            assert!((out[1] - 0.5).abs() < 1e-4, "{out:?}");
+        }
+    }
+
+    #[test]
+    fn hard_panned_sound_reaches_the_other_ear() {
+        let mut crossfeed = enabled(CrossfeedPreset::Default);
+        let out = settle(&mut crossfeed, [0.5, 0.0]);
+        assert!(out[1] > 0.05);
+        assert!(out[0] < 0.5);
+        assert!((out[0] + out[1] - 0.5).abs() < 1e-4);
+
+        // A higher feed level keeps the other channel further down
+        let mut subtle = enabled(CrossfeedPreset::JanMeier);
+        assert!(settle(&mut subtle, [0.5, 0.0])[1] < out[1]);
+    }
+
+    #[test]
+    fn fades_out_when_disabled() {
+        let mut crossfeed = Crossfeed::new();
+        let mut frames = vec![[0.5, 0.0]; 64];
+        crossfeed.process(&mut frames, RATE);
+        assert!(frames.iter().all(|frame| *frame == [0.5, 0.0]));
+
+        crossfeed.set_enabled(true);
+        settle(&mut crossfeed, [0.5, 0.0]);
+        crossfeed.set_enabled(false);
+        assert!(crossfeed.is_active());
+
+        let out = settle(&mut crossfeed, [0.5, 0.0]);
+        assert_eq!(out, [0.5, 0.0]);
+        assert!(!crossfeed.is_active());
+    }
+
+    #[test]
+    fn rejects_out_of_range_settings() {
+        let mut crossfeed = Crossfeed::new();
+        assert!(crossfeed.set_cutoff(MIN_CROSSFEED_CUTOFF - 1.0).is_err());
+        assert!(crossfeed.set_cutoff(MAX_CROSSFEED_CUTOFF + 1.0).is_err());
+        assert!(crossfeed.set_feed(0.0).is_err());
+        assert!(crossfeed.set_feed(f32::NAN).is_err());
+        assert_eq!(
+            (crossfeed.get_cutoff(), crossfeed.get_feed()),
+            CrossfeedPreset::Default.settings()
+        );
+    }
+}
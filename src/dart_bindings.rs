@@ -1,30 +1,83 @@
+use crate::adaptive::VariantSelection;
 use crate::aurex::{Player, PlayerCallback};
-use crate::enums::{EngineSignal, PlayerError, ResamplingQuality};
+use crate::blocking::block_on;
+use crate::channels::ChannelMatrix;
+use crate::engine::FadeDurations;
+use crate::enums::{
+    BuiltinEffect, CrossfeedPreset, EqPreset, NoiseShaping, ReplayGainMode, ResamplingQuality,
+    SpectrumScale,
+};
+use crate::equalizer::graphic_bands;
+use crate::events::PlayerEvent;
+use crate::network::{HttpHeader, NetworkConfig};
+use crate::resampler::{PhaseResponse, ResamplerConfig, ResamplerRolloff};
+use crate::spectrum::SpectrumConfig;
+use crate::waveform::WaveformJob;
 use std::collections::VecDeque;
 use std::ffi::CStr;
 use std::os::raw::c_char;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
 
 // === GLOBAL STATE ===
-static PLAYER: OnceLock<Arc<Player>> = OnceLock::new();
-static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+// Replaced by player_new, so a Flutter hot restart gets a fresh player instead of the old one
+static PLAYER: Mutex<Option<Arc<Player>>> = Mutex::new(None);
 
-// Simple event queue - Dart polls this
-static EVENT_QUEUE: Mutex<VecDeque<i32>> = Mutex::new(VecDeque::new());
+// Simple event queue - Dart polls this. The last polled event stays around so its payload can be read
+static EVENT_QUEUE: Mutex<VecDeque<PlayerEvent>> = Mutex::new(VecDeque::new());
+static POLLED_EVENT: Mutex<Option<PlayerEvent>> = Mutex::new(None);
+
+// Oldest events are dropped past this, so a Dart side that stops polling doesn't grow the queue forever
+const EVENT_QUEUE_LIMIT: usize = 1024;
+
+// Waveform jobs by handle. They don't need a player
+static WAVEFORM_JOBS: Mutex<Vec<(i32, Arc<WaveformJob>)>> = Mutex::new(Vec::new());
+static NEXT_WAVEFORM_ID: AtomicI32 = AtomicI32::new(1);
 
 // === CALLBACK ADAPTER ===
 struct FFICallback;
 
 impl PlayerCallback for FFICallback {
-    fn on_player_event(&self, event: EngineSignal, _player: Arc<Player>) {
-        let event_code: i32;
-        if event == EngineSignal::MediaEnd {
-            event_code = 0;
-        } else {
-            event_code = -1;
-        }
+    fn on_player_event(&self, event: PlayerEvent, _player: Arc<Player>) {
         // Just push to queue, Dart will poll it
-        EVENT_QUEUE.lock().unwrap().push_back(event_code);
+        let mut queue = EVENT_QUEUE.lock().unwrap();
+        if queue.len() >= EVENT_QUEUE_LIMIT {
+            queue.pop_front();
+        }
+        queue.push_back(event);
+    }
+}
+
+fn current_player() -> Option<Arc<Player>> {
+    PLAYER.lock().unwrap().clone()
+}
+
+fn event_code(event: &PlayerEvent) -> i32 {
+    match event {
+        PlayerEvent::MediaEnd => 0,
+        PlayerEvent::Buffering => 1,
+        PlayerEvent::BufferingEnded => 2,
+        PlayerEvent::LoadFailed => 3,
+        PlayerEvent::MetadataChanged { .. } => 4,
+        PlayerEvent::StateChanged { .. } => 5,
+        PlayerEvent::PositionUpdate { .. } => 6,
+        PlayerEvent::Seeked { .. } => 7,
+        PlayerEvent::Loaded { .. } => 8,
+        PlayerEvent::Error { .. } => 9,
+        PlayerEvent::BufferUnderrun => 10,
+        PlayerEvent::DeviceChanged { .. } => 11,
+        PlayerEvent::VolumeChanged { .. } => 12,
+    }
+}
+
+// 0 to 3 are low to very high
+fn dart_quality(quality: i32) -> Option<ResamplingQuality> {
+    match quality {
+        0 => Some(ResamplingQuality::Low),
+        1 => Some(ResamplingQuality::Medium),
+        2 => Some(ResamplingQuality::High),
+        3 => Some(ResamplingQuality::VeryHigh),
+        _ => None,
     }
 }
 
@@ -32,38 +85,105 @@ impl PlayerCallback for FFICallback {
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_new(resampling_quality: i32) -> i32 {
-    let rt = RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().unwrap());
-
-    rt.block_on(async {
-        let quality = match resampling_quality {
-            0 => Some(ResamplingQuality::Low),
-            1 => Some(ResamplingQuality::Medium),
-            2 => Some(ResamplingQuality::High),
-            3 => Some(ResamplingQuality::VeryHigh),
-            _ => None,
-        };
+    // After a hot restart the old player is still here and holding the device
+    if let Some(old_player) = PLAYER.lock().unwrap().take() {
+        block_on(old_player.shutdown());
+    }
+    EVENT_QUEUE.lock().unwrap().clear();
+    *POLLED_EVENT.lock().unwrap() = None;
+
+    block_on(async {
+        let quality = dart_quality(resampling_quality);
 
         let ffi_callback = Box::new(FFICallback);
 
         match Player::create(quality, ffi_callback).await {
             Ok(player) => {
-                PLAYER.set(player).ok();
+                *PLAYER.lock().unwrap() = Some(player);
                 0
             }
-            Err(PlayerError::Code(c)) => c,
+            Err(e) => e.code(),
         }
     })
 }
 
-// Poll for events - returns -1 if no events, otherwise returns event code
+// Stops the player for good and releases the output device. Returns once its threads are done. player_new makes
+// a new one
+#[unsafe(no_mangle)]
+pub extern "C" fn player_shutdown() -> i32 {
+    let player = match PLAYER.lock().unwrap().take() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(player.shutdown());
+    0
+}
+
+// Poll for events - returns -1 if no events, otherwise returns event code. Payloads are read with
+// player_event_value and player_event_text until the next poll
 #[unsafe(no_mangle)]
 pub extern "C" fn player_poll_event() -> i32 {
-    EVENT_QUEUE.lock().unwrap().pop_front().unwrap_or(-1)
+    let event = EVENT_QUEUE.lock().unwrap().pop_front();
+    let code = event.as_ref().map_or(-1, event_code);
+    *POLLED_EVENT.lock().unwrap() = event;
+    code
+}
+
+// Number carried by the last polled event: the state as in player_get_state for StateChanged, seconds for PositionUpdate, Seeked
+// and Loaded (duration), the error code for Error and linear volume for VolumeChanged. 0 for the rest
+#[unsafe(no_mangle)]
+pub extern "C" fn player_event_value() -> f64 {
+    match POLLED_EVENT.lock().unwrap().as_ref() {
+        Some(PlayerEvent::StateChanged { state }) => *state as i32 as f64,
+        Some(PlayerEvent::PositionUpdate { position_s, .. }) => *position_s,
+        Some(PlayerEvent::Seeked { position_s }) => *position_s,
+        Some(PlayerEvent::Loaded { info }) => info.duration_s,
+        Some(PlayerEvent::Error { error }) => error.code() as f64,
+        Some(PlayerEvent::VolumeChanged { volume }) => *volume as f64,
+        _ => 0.0,
+    }
+}
+
+// Copies the text of the last polled event like player_get_stream_title: the title for MetadataChanged,
+// the device name for DeviceChanged and the path for Loaded. Returns its length, 0 if there isn't any
+#[unsafe(no_mangle)]
+pub extern "C" fn player_event_text(out_text: *mut u8, len: i32) -> i32 {
+    let event = POLLED_EVENT.lock().unwrap();
+    let text = match event.as_ref() {
+        Some(PlayerEvent::MetadataChanged { title }) => title.as_str(),
+        Some(PlayerEvent::DeviceChanged { name }) => name.as_str(),
+        Some(PlayerEvent::Loaded { info }) => info.path.as_deref().unwrap_or(""),
+        _ => "",
+    };
+
+    if !out_text.is_null() && len >= 0 && text.len() <= len as usize {
+        unsafe {
+            std::ptr::copy_nonoverlapping(text.as_ptr(), out_text, text.len());
+        }
+    }
+    text.len() as i32
+}
+
+// 0 turns position updates off, anything else has to be at least 50 ms
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_position_interval(interval_ms: u32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_position_interval(interval_ms).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_load(file_path: *const c_char) -> i32 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1,
     };
@@ -75,99 +195,148 @@ pub extern "C" fn player_load(file_path: *const c_char) -> i32 {
         }
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async {
+    block_on(async {
         match player.clone().load(path).await {
             Ok(_) => 0,
-            Err(PlayerError::Code(c)) => c,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+// Loads a file starting on the audio stream with this container index
+#[unsafe(no_mangle)]
+pub extern "C" fn player_load_with_stream(file_path: *const c_char, stream_index: u32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let path = unsafe {
+        match CStr::from_ptr(file_path).to_str() {
+            Ok(s) => s,
+            Err(_) => return -2,
+        }
+    };
+
+    block_on(async {
+        match player.clone().load_with_stream(path, stream_index).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+// Plays an in-memory file. The bytes are copied so the caller can free them straight away
+#[unsafe(no_mangle)]
+pub extern "C" fn player_load_bytes(data: *const u8, len: usize) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if data.is_null() || len == 0 {
+        return -2;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(data, len) }.to_vec();
+
+    block_on(async {
+        match player.clone().load_bytes(bytes).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
         }
     })
 }
 
+// 0 loading, 1 loaded, 2 playing, 3 paused, 4 empty
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_state() -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async { player.get_state().await as i32 })
+}
+
+// -3 with nothing loaded, like pause and seek. Seek also returns it while loading
 #[unsafe(no_mangle)]
 pub extern "C" fn player_play() -> i32 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async {
+    block_on(async {
         match player.play().await {
             Ok(_) => 0,
-            Err(PlayerError::Code(c)) => c,
+            Err(e) => e.code(),
         }
     })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_pause() -> i32 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async {
+    block_on(async {
         match player.pause().await {
             Ok(_) => 0,
-            Err(PlayerError::Code(c)) => c,
+            Err(e) => e.code(),
         }
     })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_seek(time_s: f64) -> i32 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async {
+    block_on(async {
         match player.seek(time_s).await {
             Ok(_) => 0,
-            Err(PlayerError::Code(c)) => c,
+            Err(e) => e.code(),
         }
     })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_clear() -> i32 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async {
+    block_on(async {
         match player.clear().await {
             Ok(_) => 0,
-            Err(PlayerError::Code(c)) => c,
+            Err(e) => e.code(),
         }
     })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_get_duration() -> f64 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1.0,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async { player.get_duration().await })
+    block_on(async { player.get_duration().await })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_get_progress() -> f64 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return -1.0,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async {
+    block_on(async {
         match player.get_progress().await {
             Ok(v) => v,
             Err(_) => -1.0,
@@ -177,22 +346,1181 @@ pub extern "C" fn player_get_progress() -> f64 {
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_get_volume() -> f32 {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
         Some(p) => p,
         None => return 0.0,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async { player.get_volume().await })
+    block_on(async { player.get_volume().await })
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn player_set_volume(volume: f32) {
-    let player = match PLAYER.get() {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_volume(volume).await });
+}
+
+// Adds a gain stage to the processing chain. A negative index appends it. Returns the processor id or an error code
+#[unsafe(no_mangle)]
+pub extern "C" fn player_add_gain_effect(gain_db: f32, index: i32) -> i64 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let index = if index < 0 { None } else { Some(index as u32) };
+
+    block_on(async {
+        match player
+            .add_effect(BuiltinEffect::Gain { gain_db }, index)
+            .await
+        {
+            Ok(id) => id as i64,
+            Err(e) => e.code() as i64,
+        }
+    })
+}
+
+// Adds a 10 band graphic EQ to the processing chain, lowest band first. A negative index appends it. Returns the
+// processor id or an error code
+#[unsafe(no_mangle)]
+pub extern "C" fn player_add_graphic_eq_effect(
+    gains: *const f32,
+    len: i32,
+    preamp_db: f32,
+    index: i32,
+) -> i64 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if gains.is_null() || len < 0 {
+        return -2;
+    }
+    let gains = unsafe { std::slice::from_raw_parts(gains, len as usize) };
+    let bands = match graphic_bands(gains) {
+        Ok(bands) => bands,
+        Err(e) => return e as i64,
+    };
+    let index = if index < 0 { None } else { Some(index as u32) };
+
+    block_on(async {
+        match player
+            .add_effect(BuiltinEffect::Equalizer { bands, preamp_db }, index)
+            .await
+        {
+            Ok(id) => id as i64,
+            Err(e) => e.code() as i64,
+        }
+    })
+}
+
+// Adds a crossfeed stage to the processing chain. A negative index appends it. Returns the processor id or an
+// error code
+#[unsafe(no_mangle)]
+pub extern "C" fn player_add_crossfeed_effect(cutoff: f32, feed_db: f32, index: i32) -> i64 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let index = if index < 0 { None } else { Some(index as u32) };
+
+    block_on(async {
+        match player
+            .add_effect(BuiltinEffect::Crossfeed { cutoff, feed_db }, index)
+            .await
+        {
+            Ok(id) => id as i64,
+            Err(e) => e.code() as i64,
+        }
+    })
+}
+
+// Adds a limiter to the processing chain. A negative index appends it. Returns the processor id or an error code
+#[unsafe(no_mangle)]
+pub extern "C" fn player_add_limiter_effect(
+    ceiling_db: f32,
+    knee_db: f32,
+    release_ms: f32,
+    index: i32,
+) -> i64 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let index = if index < 0 { None } else { Some(index as u32) };
+    let effect = BuiltinEffect::Limiter {
+        ceiling_db,
+        knee_db,
+        release_ms,
+    };
+
+    block_on(async {
+        match player.add_effect(effect, index).await {
+            Ok(id) => id as i64,
+            Err(e) => e.code() as i64,
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_remove_processor(id: u64) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.remove_processor(id).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_move_processor(id: u64, index: u32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.move_processor(id, index).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_processor_bypassed(id: u64, bypassed: bool) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_processor_bypassed(id, bypassed).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_eq_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_eq_enabled(enabled).await });
+}
+
+// Gains for the 10 band graphic EQ, lowest band first
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_graphic_eq(gains: *const f32, len: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if gains.is_null() || len < 0 {
+        return -2;
+    }
+    let gains = unsafe { std::slice::from_raw_parts(gains, len as usize) }.to_vec();
+
+    block_on(async {
+        match player.set_graphic_eq(gains).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_apply_eq_preset(preset: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let preset = match preset {
+        0 => EqPreset::Flat,
+        1 => EqPreset::BassBoost,
+        2 => EqPreset::BassCut,
+        3 => EqPreset::TrebleBoost,
+        4 => EqPreset::TrebleCut,
+        5 => EqPreset::Vocal,
+        6 => EqPreset::Rock,
+        7 => EqPreset::Pop,
+        8 => EqPreset::Jazz,
+        9 => EqPreset::Classical,
+        10 => EqPreset::Electronic,
+        11 => EqPreset::Loudness,
+        _ => return -2,
+    };
+
+    block_on(async { player.apply_eq_preset(preset).await });
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_eq_preamp(preamp_db: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_eq_preamp(preamp_db).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+// 0 = off, 1 = track, 2 = album
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_replay_gain_mode(mode: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let mode = match mode {
+        0 => ReplayGainMode::Off,
+        1 => ReplayGainMode::Track,
+        2 => ReplayGainMode::Album,
+        _ => return -2,
+    };
+
+    block_on(async { player.set_replay_gain_mode(mode).await });
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_replay_gain_preamp(preamp_db: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_replay_gain_preamp(preamp_db).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_replay_gain_prevent_clipping(prevent_clipping: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async {
+        player
+            .set_replay_gain_prevent_clipping(prevent_clipping)
+            .await
+    });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_loudness_normalization_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_loudness_normalization_enabled(enabled).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_loudness_target(target_lufs: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_loudness_target(target_lufs).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+// Integrated loudness of the loaded track in LUFS. Returns 0.0 until it's been measured
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_measured_loudness() -> f32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return 0.0,
+    };
+
+    block_on(async {
+        player
+            .get_measured_loudness()
+            .await
+            .map(|info| info.integrated_lufs)
+            .unwrap_or(0.0)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_volume_db() -> f32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return 0.0,
+    };
+
+    block_on(async { player.get_volume_db().await })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_volume_db(volume_db: f32) {
+    let player = match current_player() {
         Some(p) => p,
         None => return,
     };
 
-    let rt = RUNTIME.get().unwrap();
-    rt.block_on(async { player.set_volume(volume).await });
+    block_on(async { player.set_volume_db(volume_db).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_limiter_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_limiter_enabled(enabled).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_limiter_ceiling(ceiling_db: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_limiter_ceiling(ceiling_db).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_dither_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_dither_enabled(enabled).await });
+}
+
+// 0 flat, 1 simple, 2 lipshitz, 3 f-weighted
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_noise_shaping(noise_shaping: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let noise_shaping = match noise_shaping {
+        0 => NoiseShaping::Flat,
+        1 => NoiseShaping::Simple,
+        2 => NoiseShaping::Lipshitz,
+        3 => NoiseShaping::FWeighted,
+        _ => return -2,
+    };
+
+    block_on(async { player.set_noise_shaping(noise_shaping).await });
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_fade_to(volume: f32, duration_s: f32) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.fade_to(volume, duration_s).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_fade_durations(
+    play_ms: u32,
+    pause_ms: u32,
+    seek_ms: u32,
+    stop_ms: u32,
+) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    let fade_durations = FadeDurations {
+        play_ms,
+        pause_ms,
+        seek_ms,
+        stop_ms,
+    };
+
+    block_on(async { player.set_fade_durations(fade_durations).await });
+}
+
+// Applies from the next load. Headers set with player_add_http_header are kept
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_network_config(
+    timeout_ms: u32,
+    reconnect: bool,
+    reconnect_delay_max_s: u32,
+    buffer_s: u32,
+    rebuffer_s: u32,
+) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        let network = NetworkConfig {
+            timeout_ms,
+            reconnect,
+            reconnect_delay_max_s,
+            buffer_s,
+            rebuffer_s,
+            ..player.get_network_config().await
+        };
+        match player.set_network_config(network).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+// Fills in soxr's own passband end, stopband begin and precision for a quality level, to tune from
+#[unsafe(no_mangle)]
+pub extern "C" fn player_resampler_preset(
+    quality: i32,
+    out_passband_end: *mut f64,
+    out_stopband_begin: *mut f64,
+    out_precision: *mut f64,
+) -> i32 {
+    let quality = match dart_quality(quality) {
+        Some(q) => q,
+        None => return -2,
+    };
+    if out_passband_end.is_null() || out_stopband_begin.is_null() || out_precision.is_null() {
+        return -2;
+    }
+
+    let preset = ResamplerConfig::preset(quality);
+    unsafe {
+        *out_passband_end = preset.passband_end;
+        *out_stopband_begin = preset.stopband_begin;
+        *out_precision = preset.precision;
+    }
+    0
+}
+
+// Quality as in player_new. phase is 0 linear, 1 intermediate, 2 minimum. rolloff is 0 small, 1 medium,
+// 2 none (chebyshev). Applies from the next load
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_resampler_config(
+    quality: i32,
+    passband_end: f64,
+    stopband_begin: f64,
+    phase: i32,
+    rolloff: i32,
+    precision: f64,
+    threads: u32,
+) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let quality = match dart_quality(quality) {
+        Some(q) => q,
+        None => return -2,
+    };
+    let phase = match phase {
+        0 => PhaseResponse::Linear,
+        1 => PhaseResponse::Intermediate,
+        2 => PhaseResponse::Minimum,
+        _ => return -2,
+    };
+    let rolloff = match rolloff {
+        0 => ResamplerRolloff::Small,
+        1 => ResamplerRolloff::Medium,
+        2 => ResamplerRolloff::Chebyshev,
+        _ => return -2,
+    };
+
+    let config = ResamplerConfig {
+        quality,
+        passband_end,
+        stopband_begin,
+        phase,
+        rolloff,
+        precision,
+        threads,
+    };
+
+    match block_on(player.set_resampler_config(config)) {
+        Ok(_) => 0,
+        Err(e) => e.code(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_add_http_header(name: *const c_char, value: *const c_char) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if name.is_null() || value.is_null() {
+        return -2;
+    }
+    let (name, value) = unsafe {
+        match (
+            CStr::from_ptr(name).to_str(),
+            CStr::from_ptr(value).to_str(),
+        ) {
+            (Ok(name), Ok(value)) => (name.to_string(), value.to_string()),
+            _ => return -2,
+        }
+    };
+
+    block_on(async {
+        let mut network = player.get_network_config().await;
+        network.headers.push(HttpHeader { name, value });
+        match player.set_network_config(network).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_clear_http_headers() {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async {
+        let mut network = player.get_network_config().await;
+        network.headers.clear();
+        _ = player.set_network_config(network).await;
+    });
+}
+
+// 1 while waiting on a network source, 0 otherwise
+#[unsafe(no_mangle)]
+pub extern "C" fn player_is_buffering() -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async { player.is_buffering().await as i32 })
+}
+
+// 1 for live streams, 0 otherwise
+#[unsafe(no_mangle)]
+pub extern "C" fn player_is_live() -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async { player.is_live().await as i32 })
+}
+
+// Applies from the next load
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_bit_perfect_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_bit_perfect_enabled(enabled).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_is_bit_perfect_enabled() -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async { player.is_bit_perfect_enabled().await as i32 })
+}
+
+// 1 while the current playback reaches the device untouched
+#[unsafe(no_mangle)]
+pub extern "C" fn player_is_bit_perfect() -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async { player.is_bit_perfect().await as i32 })
+}
+
+// Copies the UTF-8 title, without a terminator, if it fits in len. Returns its length, 0 if there isn't one
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_stream_title(out_title: *mut u8, len: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let title = match block_on(async { player.get_stream_title().await }) {
+        Some(title) => title,
+        None => return 0,
+    };
+
+    if !out_title.is_null() && len >= 0 && title.len() <= len as usize {
+        unsafe {
+            std::ptr::copy_nonoverlapping(title.as_ptr(), out_title, title.len());
+        }
+    }
+    title.len() as i32
+}
+
+// Fills ids and bitrates for up to len variants, lowest bitrate first. Returns how many there are
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_variants(out_ids: *mut u32, out_bitrates: *mut u64, len: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let variants = block_on(async { player.get_variants().await });
+
+    if !out_ids.is_null() && !out_bitrates.is_null() && len > 0 {
+        for (i, variant) in variants.iter().take(len as usize).enumerate() {
+            unsafe {
+                *out_ids.add(i) = variant.id;
+                *out_bitrates.add(i) = variant.bitrate;
+            }
+        }
+    }
+    variants.len() as i32
+}
+
+// The variant id being played, -1 if there's no choice of variants
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_current_variant() -> i64 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        player
+            .get_current_variant()
+            .await
+            .map_or(-1, |id| id as i64)
+    })
+}
+
+// Fills container indexes and channel counts for up to len audio streams. Returns how many there are
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_audio_streams(
+    out_indexes: *mut u32,
+    out_channels: *mut u32,
+    len: i32,
+) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let streams = block_on(async { player.get_audio_streams().await });
+
+    if !out_indexes.is_null() && !out_channels.is_null() && len > 0 {
+        for (i, stream) in streams.iter().take(len as usize).enumerate() {
+            unsafe {
+                *out_indexes.add(i) = stream.index;
+                *out_channels.add(i) = stream.channels;
+            }
+        }
+    }
+    streams.len() as i32
+}
+
+// Copies the language tag of the audio stream at position in the list like player_get_stream_title. -2 if there's no such stream
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_audio_stream_language(
+    position: i32,
+    out_language: *mut u8,
+    len: i32,
+) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let streams = block_on(async { player.get_audio_streams().await });
+    let stream = match usize::try_from(position)
+        .ok()
+        .and_then(|position| streams.get(position))
+    {
+        Some(stream) => stream,
+        None => return -2,
+    };
+    let language = match &stream.language {
+        Some(language) => language,
+        None => return 0,
+    };
+
+    if !out_language.is_null() && len >= 0 && language.len() <= len as usize {
+        unsafe {
+            std::ptr::copy_nonoverlapping(language.as_ptr(), out_language, language.len());
+        }
+    }
+    language.len() as i32
+}
+
+// Container index of the audio stream being played, -1 if nothing is loaded
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_current_audio_stream() -> i64 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        player
+            .get_current_audio_stream()
+            .await
+            .map_or(-1, |index| index as i64)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_select_audio_stream(index: u32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.select_audio_stream(index).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+// A negative id picks automatically
+#[unsafe(no_mangle)]
+pub extern "C" fn player_select_variant(id: i64) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let selection = if id < 0 {
+        VariantSelection::Auto
+    } else {
+        VariantSelection::Fixed { id: id as u32 }
+    };
+
+    block_on(async {
+        match player.select_variant(selection).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_balance(balance: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        match player.set_balance(balance).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_mono(mono: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_mono(mono).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_channels_swapped(swap: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_channels_swapped(swap).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_channel_mute(left: bool, right: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_channel_mute(left, right).await });
+}
+
+///Row major 2x2 matrix: left from left, left from right, right from left, right from right
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_channel_routing(ll: f32, lr: f32, rl: f32, rr: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let routing = ChannelMatrix {
+        left_from_left: ll,
+        left_from_right: lr,
+        right_from_left: rl,
+        right_from_right: rr,
+    };
+
+    block_on(async {
+        match player.set_channel_routing(routing).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_reset_channels() {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.reset_channels().await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_crossfeed_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_crossfeed_enabled(enabled).await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_crossfeed(cutoff: f32, feed_db: f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    block_on(async {
+        if let Err(e) = player.set_crossfeed_cutoff(cutoff).await {
+            return e.code();
+        }
+        match player.set_crossfeed_feed(feed_db).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_apply_crossfeed_preset(preset: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let preset = match preset {
+        0 => CrossfeedPreset::Default,
+        1 => CrossfeedPreset::ChuMoy,
+        2 => CrossfeedPreset::JanMeier,
+        _ => return -2,
+    };
+
+    block_on(async { player.apply_crossfeed_preset(preset).await });
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_load_impulse_response(file_path: *const c_char) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let path = unsafe {
+        match CStr::from_ptr(file_path).to_str() {
+            Ok(s) => s,
+            Err(_) => return -2,
+        }
+    };
+
+    block_on(async {
+        match player.load_impulse_response(path.to_string()).await {
+            Ok(_) => 0,
+            Err(e) => e.code(),
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_clear_impulse_response() {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.clear_impulse_response().await });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_convolution_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    block_on(async { player.set_convolution_enabled(enabled).await });
+}
+
+///Writes left peak, left RMS, right peak, right RMS into out_levels, in dBFS. Needs room for 4 floats
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_levels(out_levels: *mut f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if out_levels.is_null() {
+        return -2;
+    }
+
+    // Lock free, no need to go through the runtime
+    let levels = player.get_levels();
+    let values = [
+        levels.left.peak_db,
+        levels.left.rms_db,
+        levels.right.peak_db,
+        levels.right.rms_db,
+    ];
+    unsafe {
+        std::ptr::copy_nonoverlapping(values.as_ptr(), out_levels, values.len());
+    }
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_true_peak(out_levels: *mut f32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if out_levels.is_null() {
+        return -2;
+    }
+
+    let levels = player.get_levels();
+    let (Some(left), Some(right)) = (levels.left.true_peak_db, levels.right.true_peak_db) else {
+        return -1;
+    };
+    unsafe {
+        *out_levels = left;
+        *out_levels.add(1) = right;
+    }
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_true_peak_metering_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    player.set_true_peak_metering_enabled(enabled);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_spectrum_enabled(enabled: bool) {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return,
+    };
+
+    player.set_spectrum_enabled(enabled);
+}
+
+///scale is 0 for linear, 1 for logarithmic
+#[unsafe(no_mangle)]
+pub extern "C" fn player_set_spectrum_config(
+    fft_size: u32,
+    scale: i32,
+    bands: u32,
+    smoothing: f32,
+) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    let scale = match scale {
+        0 => SpectrumScale::Linear,
+        1 => SpectrumScale::Logarithmic,
+        _ => return -2,
+    };
+
+    let config = SpectrumConfig {
+        fft_size,
+        scale,
+        bands,
+        smoothing,
+    };
+
+    match player.set_spectrum_config(config) {
+        Ok(_) => 0,
+        Err(e) => e.code(),
+    }
+}
+
+///Copies up to len magnitudes into out_values. Returns how many were written
+#[unsafe(no_mangle)]
+pub extern "C" fn player_get_spectrum(out_values: *mut f32, len: i32) -> i32 {
+    let player = match current_player() {
+        Some(p) => p,
+        None => return -1,
+    };
+
+    if out_values.is_null() || len < 0 {
+        return -2;
+    }
+
+    let values = player.get_spectrum();
+    let count = values.len().min(len as usize);
+    unsafe {
+        std::ptr::copy_nonoverlapping(values.as_ptr(), out_values, count);
+    }
+    count as i32
+}
+
+fn get_waveform_job(id: i32) -> Option<Arc<WaveformJob>> {
+    WAVEFORM_JOBS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(job_id, _)| *job_id == id)
+        .map(|(_, job)| job.clone())
+}
+
+///Starts generating a waveform overview. Returns a job handle, or a negative error code
+#[unsafe(no_mangle)]
+pub extern "C" fn waveform_start(file_path: *const c_char, bucket_count: u32) -> i32 {
+    if file_path.is_null() {
+        return -2;
+    }
+
+    let path = unsafe {
+        match CStr::from_ptr(file_path).to_str() {
+            Ok(s) => s.to_string(),
+            Err(_) => return -2,
+        }
+    };
+
+    let job = match WaveformJob::start(path, bucket_count, None) {
+        Ok(job) => job,
+        Err(e) => return e.code(),
+    };
+
+    let id = NEXT_WAVEFORM_ID.fetch_add(1, Ordering::Relaxed);
+    WAVEFORM_JOBS.lock().unwrap().push((id, job));
+    id
+}
+
+///0.0 to 1.0, or -1.0 for an unknown handle
+#[unsafe(no_mangle)]
+pub extern "C" fn waveform_get_progress(id: i32) -> f32 {
+    match get_waveform_job(id) {
+        Some(job) => job.get_progress(),
+        None => -1.0,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn waveform_cancel(id: i32) {
+    if let Some(job) = get_waveform_job(id) {
+        job.cancel();
+    }
+}
+
+///Copies the packed waveform into out_bytes if it fits. Returns the packed size, 0 while running, or an error code.
+///Call with a null buffer to get the size first
+#[unsafe(no_mangle)]
+pub extern "C" fn waveform_get_bytes(id: i32, out_bytes: *mut u8, len: i32) -> i32 {
+    let job = match get_waveform_job(id) {
+        Some(job) => job,
+        None => return -1,
+    };
+
+    let waveform = match job.get_result() {
+        Ok(Some(waveform)) => waveform,
+        Ok(None) => return 0,
+        Err(e) => return e.code(),
+    };
+
+    let bytes = waveform.to_bytes();
+    if !out_bytes.is_null() && len >= 0 && bytes.len() <= len as usize {
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), out_bytes, bytes.len());
+        }
+    }
+    bytes.len() as i32
+}
+
+///Cancels the job if it's still running and forgets the handle
+#[unsafe(no_mangle)]
+pub extern "C" fn waveform_free(id: i32) {
+    WAVEFORM_JOBS
+        .lock()
+        .unwrap()
+        .retain(|(job_id, _)| *job_id != id);
 }
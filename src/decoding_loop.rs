@@ -1,74 +1,192 @@
-use crate::{engine::AudioFifo, singletons::set_decoder_eof, structs::Decoder};
+use crate::{
+    adaptive::packet_span,
+    engine::AudioFifo,
+    enums::{EngineSignal, PlayerError},
+    events::PlayerEvent,
+    resampler::ResamplerConfig,
+    shared::SharedState,
+    singletons::set_decoder_eof,
+    source::{MediaInput, MediaSource},
+    structs::Decoder,
+};
 #[allow(unused_imports)]
-use ffmpeg_next::{self as av, ffi::AVAudioFifo, frame::Audio as AudioFrame, media, sys};
+use ffmpeg_next::{self as av, Packet, ffi::AVAudioFifo, frame::Audio as AudioFrame, media, sys};
+
+use crossbeam_channel::Sender;
+use soxr_ax::{Soxr, format::Interleaved};
 
 use std::{
     ffi::c_void,
     sync::{Arc, Mutex, atomic::Ordering},
+    thread,
+    time::Duration,
 };
 
+//ffmpeg already retries and reconnects network reads itself, so this many failures in a row means the source is gone
+const MAX_READ_ERRORS: u32 = 20;
+
+//Attempts at reopening a live stream that dropped, backing off from half a second to 8
+const MAX_LIVE_RECONNECTS: u32 = 6;
+
 pub fn decode(
     decoder_handle: Arc<Mutex<Decoder>>,
     sample_rate_handle: Arc<Mutex<i32>>,
     buffer_handle: Arc<Mutex<AudioFifo>>,
     target_buffer_size: i32,
+    shared: &SharedState,
+    signal_tx: &Sender<EngineSignal>,
 ) -> Result<bool, i32> {
     let mut m_decoder = decoder_handle.lock().unwrap();
-    let mut format_ctx = m_decoder.format_ctx.take().unwrap();
+    // Nothing is loaded, e.g. a buffer request that came in after a failed load
+    let Some(mut format_ctx) = m_decoder.format_ctx.take() else {
+        return Err(-1);
+    };
     drop(m_decoder);
 
     let mut _frames_written = 0;
+    let mut read_errors = 0;
 
     //Decoding loop
-    for (stream, packet) in format_ctx.packets() {
+    loop {
+        let mut packet = Packet::empty();
+        match packet.read(&mut format_ctx) {
+            Ok(_) => read_errors = 0,
+            // Live streams don't end, so this is the server dropping us
+            Err(av::Error::Eof) => {
+                if reconnect_live(&decoder_handle, &mut format_ctx) {
+                    continue;
+                }
+                // A live stream that stayed away didn't end, it failed
+                let lost = {
+                    let m_decoder = decoder_handle.lock().unwrap();
+                    m_decoder.live.is_some()
+                        && !m_decoder.main_decoder_cancel_flag.load(Ordering::Relaxed)
+                };
+                if lost {
+                    _ = signal_tx.send(EngineSignal::Event(PlayerEvent::Error {
+                        error: PlayerError::Code(-1),
+                    }));
+                }
+                break;
+            }
+            Err(av::Error::Other {
+                errno: av::error::EAGAIN,
+            }) => {
+                // A stream that keeps saying try again would otherwise hold up a seek or a new load
+                let mut m_decoder = decoder_handle.lock().unwrap();
+                if m_decoder.main_decoder_cancel_flag.load(Ordering::Relaxed) {
+                    m_decoder.format_ctx = Some(format_ctx);
+                    return Ok(false);
+                }
+                drop(m_decoder);
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(_) => {
+                read_errors += 1;
+                if read_errors >= MAX_READ_ERRORS {
+                    if reconnect_live(&decoder_handle, &mut format_ctx) {
+                        read_errors = 0;
+                        continue;
+                    }
+                    _ = signal_tx.send(EngineSignal::Event(PlayerEvent::Error {
+                        error: PlayerError::Code(-1),
+                    }));
+                    break;
+                }
+                continue;
+            }
+        }
+
         let mut m_decoder = decoder_handle.lock().unwrap();
 
         //Check if loop needs to be interrupted
         if m_decoder.main_decoder_cancel_flag.load(Ordering::Relaxed) {
             m_decoder.format_ctx = Some(format_ctx);
-            println!("Interrupting decoder");
             return Ok::<bool, i32>(false);
         }
 
-        if stream.index() != m_decoder.audio_stream_index {
+        // Adaptive streams follow the selection. The switch happens where the new variant lines up with the old
+        let switch_to = match m_decoder.adaptive.as_mut() {
+            Some(adaptive) => {
+                adaptive.update(&mut format_ctx, shared.get_buffering());
+                let span = packet_span(&format_ctx, &packet);
+                adaptive
+                    .is_switch(packet.stream(), span)
+                    .then_some(packet.stream())
+            }
+            None => None,
+        };
+        if let Some(index) = switch_to {
+            let sample_rate = *sample_rate_handle.lock().unwrap() as f64;
+            _ = switch_stream(&mut m_decoder, &mut format_ctx, index, sample_rate, shared);
+        }
+
+        // New ICY title
+        if let Some(live) = m_decoder.live.as_mut()
+            && let Some(title) = live.poll_title(&format_ctx)
+        {
+            _ = signal_tx.send(EngineSignal::Event(PlayerEvent::MetadataChanged { title }));
+        }
+
+        if packet.stream() != m_decoder.audio_stream_index {
             continue;
         }
 
-        match m_decoder.decoder.send_packet(&packet) {
+        // Opened by the load before decoding starts. soxr isn't there while bit-perfect
+        let Decoder {
+            format_ctx: format_ctx_slot,
+            decoder: Some(decoder),
+            resampler: Some(resampler),
+            soxr_resampler,
+            ..
+        } = &mut *m_decoder
+        else {
+            m_decoder.format_ctx = Some(format_ctx);
+            return Err(-1);
+        };
+
+        match decoder.send_packet(&packet) {
             Ok(_) => {}
             Err(ffmpeg_next::Error::InvalidData) => {
-                let _ = m_decoder.decoder.flush();
-                continue;
-            }
-            Err(e) => {
-                eprintln!("Decoder error on packet, skipping: {}", e);
+                let _ = decoder.flush();
                 continue;
             }
+            // A bad packet only loses a few milliseconds, skip it
+            Err(_) => continue,
         }
 
         let mut frame = AudioFrame::empty();
 
-        while m_decoder.decoder.receive_frame(&mut frame).is_ok() {
+        while decoder.receive_frame(&mut frame).is_ok() {
             let mut resampled_frame = AudioFrame::empty();
-            _ = m_decoder.resampler.run(&frame, &mut resampled_frame);
+            _ = resampler.run(&frame, &mut resampled_frame);
 
-            //Convert ffmpeg's raw bytes into soxr's required array types
+            //Convert ffmpeg's raw bytes into soxr's required array types. The plane can be padded past the last frame
             let input_samples: &[[i32; 2]] = bytemuck::cast_slice(resampled_frame.data(0));
-            let mut output_buf = vec![
-                [0i32; 2];
-                (input_samples.len() as usize
-                    * *sample_rate_handle.lock().unwrap() as usize)
-                    / m_decoder.decoder.rate() as usize
-            ];
-
-            let res = m_decoder
-                .soxr_resampler
-                .process(input_samples, &mut output_buf)
-                .unwrap();
+            let input_samples =
+                &input_samples[..resampled_frame.samples().min(input_samples.len())];
+
+            // Bit-perfect playback is already at the device's rate and goes straight through
+            let output_buf;
+            let output_samples: &[[i32; 2]] = match soxr_resampler {
+                Some(soxr_resampler) => {
+                    let mut buf = vec![
+                        [0i32; 2];
+                        (input_samples.len() as usize
+                            * *sample_rate_handle.lock().unwrap() as usize)
+                            / decoder.rate() as usize
+                    ];
+                    let res = soxr_resampler.process(input_samples, &mut buf).unwrap();
+                    output_buf = buf;
+                    &output_buf[..res.output_frames]
+                }
+                None => input_samples,
+            };
 
             let mut soxr_frame = AudioFrame::new(
                 av::format::Sample::I32(av::format::sample::Type::Packed),
-                res.output_frames,
+                output_samples.len(),
                 av::ChannelLayout::STEREO,
             );
 
@@ -77,7 +195,7 @@ pub fn decode(
 
             let data_plane = soxr_frame.data_mut(0);
             let dst_slice: &mut [[i32; 2]] = bytemuck::cast_slice_mut(data_plane);
-            dst_slice[..res.output_frames].copy_from_slice(&output_buf[..res.output_frames]);
+            dst_slice[..output_samples.len()].copy_from_slice(output_samples);
 
             unsafe {
                 let data_ptr0 = soxr_frame.data_mut(0).as_mut_ptr() as *mut c_void;
@@ -95,8 +213,16 @@ pub fn decode(
 
                 _frames_written += written;
                 let current_size = sys::av_audio_fifo_size(buffer_handle.lock().unwrap().0);
+
+                // Enough has come back for the audio thread to carry on
+                if shared.get_buffering()
+                    && current_size as u32 >= shared.get_rebuffer_frames()
+                    && shared.set_buffering(false)
+                {
+                    _ = signal_tx.send(EngineSignal::Event(PlayerEvent::BufferingEnded));
+                }
                 if current_size >= target_buffer_size {
-                    m_decoder.format_ctx = Some(format_ctx);
+                    *format_ctx_slot = Some(format_ctx);
                     return Ok(false); // Not EOF, just buffer full
                 }
             }
@@ -109,5 +235,146 @@ pub fn decode(
 
     set_decoder_eof(true);
 
+    // Nothing more is coming, so stop waiting for it
+    if shared.set_buffering(false) {
+        _ = signal_tx.send(EngineSignal::Event(PlayerEvent::BufferingEnded));
+    }
+
     Ok(true)
 }
+
+///Reopens a live stream after it dropped or stalled. False if this isn't live, the load was cancelled or the
+///server stayed away
+fn reconnect_live(decoder_handle: &Arc<Mutex<Decoder>>, format_ctx: &mut MediaInput) -> bool {
+    let (url, network, cancel_flag) = {
+        let m_decoder = decoder_handle.lock().unwrap();
+        match &m_decoder.live {
+            Some(live) => (
+                live.url.clone(),
+                live.network.clone(),
+                m_decoder.main_decoder_cancel_flag.clone(),
+            ),
+            None => return false,
+        }
+    };
+
+    for attempt in 0..MAX_LIVE_RECONNECTS {
+        thread::sleep(Duration::from_millis(500 << attempt.min(4)));
+        if cancel_flag.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let Ok(input) = MediaSource::from(url.as_str()).open(&network) else {
+            continue;
+        };
+        let Some(index) = input
+            .streams()
+            .best(media::Type::Audio)
+            .map(|stream| stream.index())
+        else {
+            continue;
+        };
+
+        // Same station, so the codec and resamplers carry on. Only the demuxer is new
+        let mut input = input;
+        let mut m_decoder = decoder_handle.lock().unwrap();
+        // Adaptive streams carry on with the variant they were on
+        let index = match &m_decoder.adaptive {
+            Some(adaptive) => {
+                adaptive.apply(&mut input);
+                adaptive.current() as usize
+            }
+            None => index,
+        };
+        m_decoder.audio_stream_index = index;
+        if let Some(decoder) = m_decoder.decoder.as_mut() {
+            decoder.flush();
+        }
+        *format_ctx = input;
+        return true;
+    }
+
+    false
+}
+
+///Opens the codec for a stream, plus the sample format conversion in front of soxr. Nothing changes on failure
+pub fn open_codec(m_decoder: &mut Decoder, input: &MediaInput, index: usize) -> Result<(), i32> {
+    let codec_params = input.stream(index).ok_or(-1)?.parameters();
+    let codec_ctx = av::codec::context::Context::from_parameters(codec_params).map_err(|_| -1)?;
+    let decoder = codec_ctx.decoder().audio().map_err(|_| -1)?;
+
+    //This is just for sample size conversion since soxr only does resampling
+    let resampler = av::software::resampling::Context::get(
+        decoder.format(),
+        decoder.channel_layout(),
+        decoder.rate(),
+        av::format::Sample::I32(av::format::sample::Type::Packed),
+        decoder.channel_layout(),
+        decoder.rate(),
+    )
+    .map_err(|_| -1)?;
+
+    m_decoder.decoder = Some(decoder);
+    m_decoder.resampler = Some(resampler);
+    Ok(())
+}
+
+///Sets soxr up from the codec's rate to the output rate
+pub fn open_resampler(
+    m_decoder: &mut Decoder,
+    sample_rate: f64,
+    resampler_config: ResamplerConfig,
+) -> Result<(), i32> {
+    let input_rate = m_decoder.decoder.as_ref().ok_or(-1)?.rate();
+
+    let mut soxr_resampler = Soxr::<Interleaved<i32, 2>>::new_with_params(
+        input_rate as f64,
+        sample_rate,
+        resampler_config.quality_spec(),
+        resampler_config.runtime_spec(),
+    )
+    .map_err(|_| -1)?;
+
+    //Prime the resampler. At higher quality levels there's artifacting at the start due to lack of previous data
+    let silence: Vec<[i32; 2]> = vec![[0, 0]; input_rate as usize];
+    let mut dummy_output: Vec<[i32; 2]> = vec![[0, 0]; input_rate as usize];
+    _ = soxr_resampler.process(&silence, &mut dummy_output);
+
+    m_decoder.soxr_resampler = Some(soxr_resampler);
+    Ok(())
+}
+
+///Moves decoding over to another stream of the same input. soxr only starts over if the rate changed
+pub fn switch_stream(
+    m_decoder: &mut Decoder,
+    format_ctx: &mut MediaInput,
+    index: usize,
+    sample_rate: f64,
+    shared: &SharedState,
+) -> Result<(), i32> {
+    let codec_format = |m_decoder: &Decoder| {
+        m_decoder
+            .decoder
+            .as_ref()
+            .map(|decoder| (decoder.rate(), decoder.format()))
+            .ok_or(-1)
+    };
+    let previous = codec_format(m_decoder)?;
+    open_codec(m_decoder, format_ctx, index)?;
+    let current = codec_format(m_decoder)?;
+    if current.0 != previous.0 {
+        let resampler_config = m_decoder.resampler_config;
+        open_resampler(m_decoder, sample_rate, resampler_config)?;
+    }
+    // The device stays as it is, so another rate or sample format can't be bit-perfect
+    if current != previous && m_decoder.output_format.bit_perfect {
+        m_decoder.output_format.bit_perfect = false;
+        shared.set_bit_perfect(false);
+    }
+
+    m_decoder.audio_stream_index = index;
+    if let Some(adaptive) = m_decoder.adaptive.as_mut() {
+        adaptive.commit(format_ctx);
+    }
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//dither.rs
+
+//TPDF dither with optional noise shaping for 16 and 24-bit devices. Runs on the engine's i32 samples
+//just before they're cut down to the device's depth, so the rounding error becomes a steady noise floor
+//instead of distortion that follows quiet passages. Bit-perfect output is never dithered
+
+use crate::{enums::NoiseShaping, handoff::Stage};
+
+//Error feedback filters, newest error first. Designed for 44.1 kHz, at higher rates the noise lands higher up
+const SIMPLE: [f64; 1] = [1.0];
+const LIPSHITZ: [f64; 5] = [2.033, -2.165, 1.959, -1.590, 0.6149];
+const F_WEIGHTED: [f64; 9] = [
+    2.412, -3.370, 3.937, -4.174, 3.353, -2.205, 1.281, -0.569, 0.0847,
+];
+
+const MAX_ORDER: usize = 9;
+
+//Dither and rounding never err by more than this many steps. More means the output clipped, and feeding
+//that back would only make the filter ring
+const MAX_ERROR: f64 = 1.5;
+
+impl NoiseShaping {
+    fn coefficients(&self) -> &'static [f64] {
+        match self {
+            Self::Flat => &[],
+            Self::Simple => &SIMPLE,
+            Self::Lipshitz => &LIPSHITZ,
+            Self::FWeighted => &F_WEIGHTED,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Dither {
+    enabled: bool,
+    noise_shaping: NoiseShaping,
+    errors: [[f64; MAX_ORDER]; 2], // Per channel, newest first
+    rng: u32,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Dither {
+            enabled: true,
+            noise_shaping: NoiseShaping::Flat,
+            errors: [[0.0; MAX_ORDER]; 2],
+            rng: 0x9E37_79B9,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.reset();
+    }
+
+    pub fn get_noise_shaping(&self) -> NoiseShaping {
+        self.noise_shaping
+    }
+
+    pub fn set_noise_shaping(&mut self, noise_shaping: NoiseShaping) {
+        self.noise_shaping = noise_shaping;
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.errors = [[0.0; MAX_ORDER]; 2];
+    }
+
+    ///Quantises interleaved stereo samples to bits in place, so converting to the device's type only drops zeros
+    pub fn process(&mut self, data: &mut [i32], bits: u32) {
+        if !self.enabled || bits == 0 || bits >= 32 {
+            return;
+        }
+        let shift = 32 - bits;
+
+        // Already at the device's depth, like silence or untouched 16-bit audio. Nothing would be lost
+        let mask = (1i32 << shift) - 1;
+        if data.iter().all(|sample| sample & mask == 0) {
+            self.reset();
+            return;
+        }
+
+        let step = (1i64 << shift) as f64;
+        let max = ((1i64 << (bits - 1)) - 1) as f64;
+        let min = -((1i64 << (bits - 1)) as f64);
+        let coefficients = self.noise_shaping.coefficients();
+        let mut rng = self.rng;
+
+        for frame in data.chunks_exact_mut(2) {
+            for (sample, errors) in frame.iter_mut().zip(self.errors.iter_mut()) {
+                let feedback: f64 = coefficients
+                    .iter()
+                    .zip(errors.iter())
+                    .map(|(coefficient, error)| coefficient * error)
+                    .sum();
+                let wanted = *sample as f64 / step - feedback;
+
+                // Two uniform values make a triangular one, a step wide either way
+                let noise = uniform(&mut rng) - uniform(&mut rng);
+                let quantised = (wanted + noise).round().clamp(min, max);
+
+                errors.copy_within(0..MAX_ORDER - 1, 1);
+                errors[0] = (quantised - wanted).clamp(-MAX_ERROR, MAX_ERROR);
+                *sample = (quantised as i32) << shift;
+            }
+        }
+        self.rng = rng;
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stage for Dither {
+    fn follow(&mut self, control: &Self) {
+        if (self.enabled, self.noise_shaping) != (control.enabled, control.noise_shaping) {
+            self.enabled = control.enabled;
+            self.noise_shaping = control.noise_shaping;
+            self.reset();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+    }
+}
+
+//xorshift32. Only has to be fast and free of audible patterns
+fn uniform(state: &mut u32) -> f64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    x as f64 / 4_294_967_296.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHAPES: [NoiseShaping; 4] = [
+        NoiseShaping::Flat,
+        NoiseShaping::Simple,
+        NoiseShaping::Lipshitz,
+        NoiseShaping::FWeighted,
+    ];
+
+    //A quiet sine that doesn't fit in 16 bits
+    fn signal(frames: usize) -> Vec<i32> {
+        (0..frames * 2)
+            .map(|i| ((i / 2) as f64 * 0.05).sin() * 2_000_000_000.0 / 7.0)
+            .map(|x| x as i32 | 0x1234)
+            .collect()
+    }
+
+    #[test]
+    fn output_fits_the_device_depth() {
+        for noise_shaping in SHAPES {
+            for bits in [16, 24] {
+                let mut dither = Dither::new();
+                dither.set_noise_shaping(noise_shaping);
+
+                let input = signal(4096);
+                let mut data = input.clone();
+                dither.process(&mut data, bits);
+
+                let mask = (1i32 << (32 - bits)) - 1;
+                assert!(data.iter().all(|sample| sample & mask == 0));
+                assert_ne!(data, input);
+            }
+        }
+    }
+
+    #[test]
+    fn handles_full_scale() {
+        for noise_shaping in SHAPES {
+            let mut dither = Dither::new();
+            dither.set_noise_shaping(noise_shaping);
+
+            let mut data: Vec<i32> = (0..4096)
+                .map(|i| if i % 4 < 2 { i32::MAX } else { i32::MIN })
+                .collect();
+            dither.process(&mut data, 16);
+            assert!(data.iter().all(|sample| sample & 0xFFFF == 0));
+        }
+    }
+
+    #[test]
+    fn leaves_fitting_audio_alone() {
+        let mut dither = Dither::new();
+        dither.set_noise_shaping(NoiseShaping::FWeighted);
+
+        let mut silence = vec![0; 1024];
+        dither.process(&mut silence, 16);
+        assert!(silence.iter().all(|sample| *sample == 0));
+
+        // 16-bit audio on a 16-bit device
+        let input: Vec<i32> = signal(1024).iter().map(|sample| sample & !0xFFFF).collect();
+        let mut data = input.clone();
+        dither.process(&mut data, 16);
+        assert_eq!(data, input);
+
+        // Nothing to cut on a 32-bit device
+        let input = signal(1024);
+        let mut data = input.clone();
+        dither.process(&mut data, 32);
+        assert_eq!(data, input);
+    }
+
+    #[test]
+    fn disabled_is_a_no_op() {
+        let mut dither = Dither::new();
+        dither.set_enabled(false);
+
+        let input = signal(1024);
+        let mut data = input.clone();
+        dither.process(&mut data, 16);
+        assert_eq!(data, input);
+    }
+
+    #[test]
+    fn keeps_levels_between_steps() {
+        // A quarter of a 16-bit step. Plain rounding would lose it, dither keeps it on average
+        for noise_shaping in SHAPES {
+            let mut dither = Dither::new();
+            dither.set_noise_shaping(noise_shaping);
+
+            let mut data = vec![1 << 14; 65536];
+            dither.process(&mut data, 16);
+            let mean = data.iter().map(|sample| *sample as f64).sum::<f64>() / data.len() as f64;
+            assert!(
+                (mean / 65536.0 - 0.25).abs() < 0.02,
+                "{noise_shaping:?} {mean}"
+            );
+        }
+    }
+}
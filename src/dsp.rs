@@ -0,0 +1,660 @@
+//dsp.rs
+
+//User processing chain. Runs inside the output stage, see output.rs
+
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender, bounded};
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+//Most processors the chain holds. Room for all of them is reserved up front, so edits never allocate on the
+//audio thread
+pub const MAX_PROCESSORS: usize = 32;
+
+//How long an edit waits for the audio thread before the engine makes it itself. Only happens while nothing is
+//pulling audio, e.g. while paused
+const EDIT_WAIT: Duration = Duration::from_millis(250);
+
+///A processing stage on the output path. Runs on the audio thread so it must not block or allocate.
+pub trait AudioProcessor: Send {
+    ///Processes interleaved stereo frames in place. Samples are normalised to -1.0..=1.0
+    fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32);
+
+    ///Clears internal state such as filter memory and delay lines. Called on seek and clear
+    fn reset(&mut self);
+
+    ///Sizes buffers for a sample rate. Called off the audio thread when the processor is added and whenever the
+    ///device is opened at another rate
+    fn prepare(&mut self, _sample_rate: u32) {}
+
+    ///Latency added by this processor, in frames
+    fn latency(&self) -> u32 {
+        0
+    }
+}
+
+struct ProcessorSlot {
+    id: u64,
+    bypassed: bool,
+    processor: Box<dyn AudioProcessor>,
+}
+
+///Ordered list of processors. Ids are handed out on insert and stay valid until the processor is removed
+pub struct ProcessorChain {
+    slots: Vec<ProcessorSlot>,
+    next_id: u64,
+}
+
+impl ProcessorChain {
+    pub fn new() -> Self {
+        ProcessorChain {
+            slots: Vec::with_capacity(MAX_PROCESSORS),
+            next_id: 1,
+        }
+    }
+
+    ///Inserts a processor at the given position, or at the end if none is given. Returns its id
+    pub fn insert(&mut self, index: Option<usize>, processor: Box<dyn AudioProcessor>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.insert_with_id(index, id, processor);
+        id
+    }
+
+    fn insert_with_id(
+        &mut self,
+        index: Option<usize>,
+        id: u64,
+        mut processor: Box<dyn AudioProcessor>,
+    ) {
+        processor.reset();
+        let slot = ProcessorSlot {
+            id,
+            bypassed: false,
+            processor,
+        };
+
+        let index = index.unwrap_or(self.slots.len()).min(self.slots.len());
+        self.slots.insert(index, slot);
+    }
+
+    pub fn remove(&mut self, id: u64) -> Result<Box<dyn AudioProcessor>, i32> {
+        let index = self.position(id)?;
+        Ok(self.slots.remove(index).processor)
+    }
+
+    ///Moves a processor to a new position. Indices past the end move it to the end
+    pub fn move_to(&mut self, id: u64, index: usize) -> Result<(), i32> {
+        let from = self.position(id)?;
+        let slot = self.slots.remove(from);
+        let index = index.min(self.slots.len());
+        self.slots.insert(index, slot);
+        Ok(())
+    }
+
+    pub fn set_bypassed(&mut self, id: u64, bypassed: bool) -> Result<(), i32> {
+        let index = self.position(id)?;
+        let slot = &mut self.slots[index];
+
+        // Drop stale filter memory so re-enabling doesn't replay old state
+        if slot.bypassed && !bypassed {
+            slot.processor.reset();
+        }
+        slot.bypassed = bypassed;
+        Ok(())
+    }
+
+    pub fn is_bypassed(&self, id: u64) -> Result<bool, i32> {
+        let index = self.position(id)?;
+        Ok(self.slots[index].bypassed)
+    }
+
+    ///Ids of all processors in processing order
+    pub fn ids(&self) -> Vec<u64> {
+        self.slots.iter().map(|slot| slot.id).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    pub fn reset(&mut self) {
+        for slot in &mut self.slots {
+            slot.processor.reset();
+        }
+    }
+
+    pub fn prepare(&mut self, sample_rate: u32) {
+        for slot in &mut self.slots {
+            slot.processor.prepare(sample_rate);
+        }
+    }
+
+    ///Total latency of all active processors in frames
+    pub fn latency(&self) -> u32 {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.bypassed)
+            .map(|slot| slot.processor.latency())
+            .sum()
+    }
+
+    ///True if at least one processor isn't bypassed
+    pub fn is_active(&self) -> bool {
+        self.slots.iter().any(|slot| !slot.bypassed)
+    }
+
+    ///Runs every active processor in order
+    pub fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        for slot in self.slots.iter_mut().filter(|slot| !slot.bypassed) {
+            slot.processor.process(frames, sample_rate);
+        }
+    }
+
+    fn position(&self, id: u64) -> Result<usize, i32> {
+        self.slots.iter().position(|slot| slot.id == id).ok_or(-1)
+    }
+}
+
+impl Default for ProcessorChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// <- CHAIN CONTROL ->
+
+enum ChainEdit {
+    Insert(Option<usize>, u64, Box<dyn AudioProcessor>),
+    Remove(u64),
+    Move(u64, usize),
+    Bypass(u64, bool),
+    Clear,
+    Reset,
+}
+
+///The chain the audio thread runs, along with the edits queued for it
+pub struct LiveChain {
+    chain: ProcessorChain,
+    edits: Receiver<ChainEdit>,
+    removed: Sender<(u64, Box<dyn AudioProcessor>)>, // Dropped or handed out by the engine, not here
+}
+
+impl LiveChain {
+    ///Applies every queued edit in order
+    pub fn apply_edits(&mut self) {
+        while let Ok(edit) = self.edits.try_recv() {
+            self.apply(edit);
+        }
+    }
+
+    fn apply(&mut self, edit: ChainEdit) {
+        match edit {
+            ChainEdit::Insert(index, id, processor) => {
+                self.chain.insert_with_id(index, id, processor)
+            }
+            ChainEdit::Remove(id) => {
+                if let Ok(processor) = self.chain.remove(id) {
+                    _ = self.removed.try_send((id, processor));
+                }
+            }
+            ChainEdit::Move(id, index) => _ = self.chain.move_to(id, index),
+            ChainEdit::Bypass(id, bypassed) => _ = self.chain.set_bypassed(id, bypassed),
+            ChainEdit::Clear => {
+                while let Some(slot) = self.chain.slots.pop() {
+                    _ = self.removed.try_send((slot.id, slot.processor));
+                }
+            }
+            ChainEdit::Reset => self.chain.reset(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.chain.is_active()
+    }
+
+    pub fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        self.chain.process(frames, sample_rate);
+    }
+}
+
+struct SlotInfo {
+    id: u64,
+    bypassed: bool,
+    latency: u32, // Asked for when the processor is added
+}
+
+///The engine's side of the processor chain. While a device is pulling audio the chain belongs to the audio
+///thread, so edits are queued for it instead of taking its lock. Ids, bypass flags and latencies are mirrored here
+///for the getters
+pub struct ChainControl {
+    slots: Vec<SlotInfo>,
+    next_id: u64,
+    sample_rate: u32, // Of the device, processors are prepared for it before they're queued
+    live: Arc<Mutex<LiveChain>>,
+    edits: Sender<ChainEdit>,
+    removed: Receiver<(u64, Box<dyn AudioProcessor>)>,
+}
+
+impl ChainControl {
+    pub fn new() -> Self {
+        // The live chain never holds more than the mirror does. Removed processors can pile up to a full chain
+        // plus a full queue of inserts before the engine collects them
+        let (edits, edits_rx) = bounded(MAX_PROCESSORS);
+        let (removed_tx, removed) = bounded(MAX_PROCESSORS * 2);
+        ChainControl {
+            slots: Vec::new(),
+            next_id: 1,
+            sample_rate: 0,
+            live: Arc::new(Mutex::new(LiveChain {
+                chain: ProcessorChain::new(),
+                edits: edits_rx,
+                removed: removed_tx,
+            })),
+            edits,
+            removed,
+        }
+    }
+
+    ///Handle for the output stage
+    pub fn live(&self) -> Arc<Mutex<LiveChain>> {
+        self.live.clone()
+    }
+
+    ///Inserts a processor at the given position, or at the end if none is given. Returns its id, or an error once
+    ///the chain holds MAX_PROCESSORS
+    pub fn insert(
+        &mut self,
+        index: Option<usize>,
+        mut processor: Box<dyn AudioProcessor>,
+    ) -> Result<u64, i32> {
+        if self.slots.len() >= MAX_PROCESSORS {
+            return Err(-1);
+        }
+        if self.sample_rate > 0 {
+            processor.prepare(self.sample_rate);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let slot = SlotInfo {
+            id,
+            bypassed: false,
+            latency: processor.latency(),
+        };
+        let position = index.unwrap_or(self.slots.len()).min(self.slots.len());
+        self.slots.insert(position, slot);
+        self.send(ChainEdit::Insert(index, id, processor));
+        Ok(id)
+    }
+
+    ///Removes a processor. It's dropped here once the audio thread lets go of it
+    pub fn remove(&mut self, id: u64) -> Result<(), i32> {
+        let index = self.position(id)?;
+        self.slots.remove(index);
+        self.send(ChainEdit::Remove(id));
+        Ok(())
+    }
+
+    ///Removes a processor and hands it back
+    pub fn take(&mut self, id: u64) -> Result<Box<dyn AudioProcessor>, i32> {
+        self.remove(id)?;
+
+        let deadline = Instant::now() + EDIT_WAIT;
+        while let Ok((removed, processor)) = self.removed.recv_deadline(deadline) {
+            if removed == id {
+                return Ok(processor);
+            }
+        }
+
+        // Nothing is pulling audio, e.g. while paused, so the chain's lock is free and the edit is made here
+        self.live.lock().unwrap().apply_edits();
+        while let Ok((removed, processor)) = self.removed.try_recv() {
+            if removed == id {
+                return Ok(processor);
+            }
+        }
+        Err(-1)
+    }
+
+    ///Moves a processor to a new position. Indices past the end move it to the end
+    pub fn move_to(&mut self, id: u64, index: usize) -> Result<(), i32> {
+        let from = self.position(id)?;
+        let slot = self.slots.remove(from);
+        self.slots.insert(index.min(self.slots.len()), slot);
+        self.send(ChainEdit::Move(id, index));
+        Ok(())
+    }
+
+    pub fn set_bypassed(&mut self, id: u64, bypassed: bool) -> Result<(), i32> {
+        let index = self.position(id)?;
+        self.slots[index].bypassed = bypassed;
+        self.send(ChainEdit::Bypass(id, bypassed));
+        Ok(())
+    }
+
+    pub fn is_bypassed(&self, id: u64) -> Result<bool, i32> {
+        let index = self.position(id)?;
+        Ok(self.slots[index].bypassed)
+    }
+
+    ///Ids of all processors in processing order
+    pub fn ids(&self) -> Vec<u64> {
+        self.slots.iter().map(|slot| slot.id).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.send(ChainEdit::Clear);
+    }
+
+    ///Asks the audio thread to clear every processor's state before its next block
+    pub fn reset(&mut self) {
+        self.send(ChainEdit::Reset);
+    }
+
+    ///Prepares every processor for a new device rate. Only while no output stage is running the chain, i.e.
+    ///before the stream is opened
+    pub fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        let mut live = self.live.lock().unwrap();
+        live.apply_edits();
+        live.chain.prepare(sample_rate);
+
+        // Latency can change with the rate
+        for slot in &mut self.slots {
+            if let Some(live_slot) = live
+                .chain
+                .slots
+                .iter()
+                .find(|live_slot| live_slot.id == slot.id)
+            {
+                slot.latency = live_slot.processor.latency();
+            }
+        }
+    }
+
+    ///Total latency of all active processors in frames
+    pub fn latency(&self) -> u32 {
+        self.slots
+            .iter()
+            .filter(|slot| !slot.bypassed)
+            .map(|slot| slot.latency)
+            .sum()
+    }
+
+    fn send(&mut self, edit: ChainEdit) {
+        // Whatever the audio thread handed back since the last edit is dropped here rather than there
+        while self.removed.try_recv().is_ok() {}
+
+        // Still full after the wait means nothing is pulling audio, so the chain's lock is free and the edit is
+        // made here
+        if let Err(SendTimeoutError::Timeout(edit)) = self.edits.send_timeout(edit, EDIT_WAIT) {
+            let mut live = self.live.lock().unwrap();
+            live.apply_edits();
+            live.apply(edit);
+        }
+    }
+
+    fn position(&self, id: u64) -> Result<usize, i32> {
+        self.slots.iter().position(|slot| slot.id == id).ok_or(-1)
+    }
+}
+
+impl Default for ChainControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// <- BUILT-IN PROCESSORS ->
+
+///Fixed gain stage
+pub struct Gain {
+    gain: f32,
+}
+
+impl Gain {
+    pub fn new(gain_db: f32) -> Self {
+        Gain {
+            gain: db_to_linear(gain_db),
+        }
+    }
+}
+
+impl AudioProcessor for Gain {
+    fn process(&mut self, frames: &mut [[f32; 2]], _sample_rate: u32) {
+        for frame in frames {
+            frame[0] *= self.gain;
+            frame[1] *= self.gain;
+        }
+    }
+
+    fn reset(&mut self) {}
+}
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+pub fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(f32::MIN_POSITIVE).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Adds an offset, so the order processors ran in shows in the result
+    struct Offset {
+        offset: f32,
+        latency: u32,
+    }
+
+    impl Offset {
+        fn boxed(offset: f32, latency: u32) -> Box<dyn AudioProcessor> {
+            Box::new(Offset { offset, latency })
+        }
+    }
+
+    impl AudioProcessor for Offset {
+        fn process(&mut self, frames: &mut [[f32; 2]], _sample_rate: u32) {
+            for frame in frames {
+                frame[0] = frame[0] * 2.0 + self.offset;
+                frame[1] = frame[1] * 2.0 + self.offset;
+            }
+        }
+
+        fn reset(&mut self) {}
+
+        fn latency(&self) -> u32 {
+            self.latency
+        }
+    }
+
+    //Latency of a millisecond at whatever rate it was prepared for
+    struct RateBound {
+        latency: u32,
+    }
+
+    impl AudioProcessor for RateBound {
+        fn process(&mut self, _frames: &mut [[f32; 2]], _sample_rate: u32) {}
+
+        fn reset(&mut self) {}
+
+        fn prepare(&mut self, sample_rate: u32) {
+            self.latency = sample_rate / 1000;
+        }
+
+        fn latency(&self) -> u32 {
+            self.latency
+        }
+    }
+
+    fn run(chain: &mut ProcessorChain) -> f32 {
+        let mut frames = [[0.0f32; 2]; 1];
+        chain.process(&mut frames, 48000);
+        frames[0][0]
+    }
+
+    #[test]
+    fn runs_processors_in_order() {
+        let mut chain = ProcessorChain::new();
+        let first = chain.insert(None, Offset::boxed(1.0, 0));
+        let second = chain.insert(None, Offset::boxed(3.0, 0));
+        assert_eq!(chain.ids(), vec![first, second]);
+        assert_eq!(run(&mut chain), 5.0);
+
+        chain.move_to(second, 0).unwrap();
+        assert_eq!(chain.ids(), vec![second, first]);
+        assert_eq!(run(&mut chain), 7.0);
+
+        let third = chain.insert(Some(1), Offset::boxed(0.0, 0));
+        assert_eq!(chain.ids(), vec![second, third, first]);
+    }
+
+    #[test]
+    fn bypassed_processors_are_skipped() {
+        let mut chain = ProcessorChain::new();
+        assert!(!chain.is_active());
+
+        let id = chain.insert(None, Offset::boxed(1.0, 64));
+        chain.insert(None, Offset::boxed(1.0, 32));
+        assert_eq!(chain.latency(), 96);
+
+        chain.set_bypassed(id, true).unwrap();
+        assert!(chain.is_bypassed(id).unwrap());
+        assert!(chain.is_active());
+        assert_eq!(chain.latency(), 32);
+        assert_eq!(run(&mut chain), 1.0);
+    }
+
+    #[test]
+    fn unknown_ids_are_rejected() {
+        let mut chain = ProcessorChain::new();
+        let id = chain.insert(None, Offset::boxed(1.0, 0));
+        assert!(chain.remove(id).is_ok());
+
+        assert!(chain.remove(id).is_err());
+        assert!(chain.move_to(id, 0).is_err());
+        assert!(chain.set_bypassed(id, true).is_err());
+        assert!(chain.is_bypassed(id).is_err());
+
+        // Ids aren't reused
+        assert_ne!(chain.insert(None, Offset::boxed(1.0, 0)), id);
+    }
+
+    fn run_live(live: &Mutex<LiveChain>) -> f32 {
+        let mut live = live.lock().unwrap();
+        live.apply_edits();
+        let mut frames = [[0.0f32; 2]; 1];
+        live.process(&mut frames, 48000);
+        frames[0][0]
+    }
+
+    #[test]
+    fn edits_reach_the_live_chain_on_its_next_block() {
+        let mut control = ChainControl::new();
+        let live = control.live();
+
+        let first = control.insert(None, Offset::boxed(1.0, 16)).unwrap();
+        let second = control.insert(None, Offset::boxed(3.0, 0)).unwrap();
+        assert_eq!(control.ids(), vec![first, second]);
+        assert_eq!(control.latency(), 16);
+        assert_eq!(run_live(&live), 5.0);
+
+        control.move_to(second, 0).unwrap();
+        control.set_bypassed(first, true).unwrap();
+        assert!(control.is_bypassed(first).unwrap());
+        assert_eq!(control.latency(), 0);
+        assert_eq!(run_live(&live), 3.0);
+
+        control.remove(second).unwrap();
+        assert!(control.remove(second).is_err());
+        assert_eq!(control.ids(), vec![first]);
+        assert_eq!(run_live(&live), 0.0);
+
+        control.clear();
+        assert!(control.ids().is_empty());
+        run_live(&live);
+        assert!(!live.lock().unwrap().is_active());
+    }
+
+    #[test]
+    fn taken_processors_come_back_from_the_audio_thread() {
+        let mut control = ChainControl::new();
+        let live = control.live();
+        let id = control.insert(None, Offset::boxed(1.0, 32)).unwrap();
+
+        // Something pulling audio, like a running stream
+        let running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let audio = {
+            let live = live.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                while running.load(std::sync::atomic::Ordering::Relaxed) {
+                    run_live(&live);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        assert_eq!(control.take(id).unwrap().latency(), 32);
+
+        running.store(false, std::sync::atomic::Ordering::Relaxed);
+        audio.join().unwrap();
+
+        // Nothing pulling audio, the edit is made without it
+        let id = control.insert(None, Offset::boxed(1.0, 8)).unwrap();
+        assert_eq!(control.take(id).unwrap().latency(), 8);
+        assert!(control.take(id).is_err());
+    }
+
+    #[test]
+    fn processors_are_prepared_for_the_device_rate() {
+        let mut control = ChainControl::new();
+        control
+            .insert(None, Box::new(RateBound { latency: 0 }))
+            .unwrap();
+        assert_eq!(control.latency(), 0);
+
+        // Already queued, prepared once the device is known
+        control.prepare(48000);
+        assert_eq!(control.latency(), 48);
+
+        // Added later, prepared straight away
+        control
+            .insert(None, Box::new(RateBound { latency: 0 }))
+            .unwrap();
+        assert_eq!(control.latency(), 96);
+
+        control.prepare(96000);
+        assert_eq!(control.latency(), 192);
+    }
+
+    #[test]
+    fn the_chain_holds_at_most_max_processors() {
+        let mut control = ChainControl::new();
+        for _ in 0..MAX_PROCESSORS {
+            control.insert(None, Offset::boxed(0.0, 0)).unwrap();
+        }
+        assert!(control.insert(None, Offset::boxed(0.0, 0)).is_err());
+
+        // Every insert made it to the live chain even though nothing was pulling audio
+        let live = control.live();
+        live.lock().unwrap().apply_edits();
+        assert_eq!(live.lock().unwrap().chain.ids().len(), MAX_PROCESSORS);
+    }
+
+    #[test]
+    fn gain_and_decibels_agree() {
+        assert!((db_to_linear(-6.0) - 0.501_187).abs() < 1e-6);
+        assert!((linear_to_db(db_to_linear(3.5)) - 3.5).abs() < 1e-5);
+
+        let mut frames = [[0.5f32, -0.5]];
+        Gain::new(6.0).process(&mut frames, 48000);
+        assert!((frames[0][0] - 0.5 * db_to_linear(6.0)).abs() < 1e-6);
+        assert_eq!(frames[0][0], -frames[0][1]);
+    }
+}
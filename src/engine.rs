@@ -1,50 +1,118 @@
 //engine.rs
 
 use crate::{
+    adaptive::{Adaptive, StreamVariant, VariantSelection},
     aurex::Player,
-    decoding_loop::decode,
-    enums::{CMD, EngineSignal, PlayerState, ResamplingQuality},
+    bitperfect::{OutputFormat, native_format},
+    channels::{ChannelMatrix, ChannelMixer},
+    convolution::{ConvolutionKernel, Convolver, ImpulseResponse, ImpulseResponseInfo},
+    crossfeed::Crossfeed,
+    decoding_loop::{decode, open_codec, open_resampler, switch_stream},
+    dither::Dither,
+    dsp::{AudioProcessor, ChainControl, db_to_linear, linear_to_db},
+    enums::{
+        CMD, CrossfeedPreset, EngineSignal, EqPreset, NoiseShaping, PlayerError, PlayerState,
+        ReplayGainMode, ResamplingQuality,
+    },
+    equalizer::{EqBand, Equalizer},
+    events::{MIN_POSITION_INTERVAL_MS, PlayerEvent, TrackInfo},
+    handoff::StageControl,
+    limiter::Limiter,
+    loudness::{self, Loudness, LoudnessInfo},
+    meter::MeterReadings,
+    network::{NetworkConfig, start_frames},
+    output::{DEFAULT_BLOCK_FRAMES, OutputSample, OutputStage},
+    radio::{self, LiveStream},
+    replaygain::{ReplayGain, ReplayGainInfo},
+    resampler::ResamplerConfig,
+    shared::SharedState,
     singletons::{
         self, add_played, get_decoder_eof, get_played, get_volume as f_get_volume, reset_played,
         set_decoder_eof, set_played, set_total, set_volume as f_set_volume,
     },
+    source::MediaSource,
+    spectrum::SpectrumTap,
+    streams::{AudioStreamInfo, list_audio_streams},
     structs::Decoder,
 };
 
 use ffmpeg_next::{self};
-use soxr_ax::{
-    Soxr,
-    format::{self},
-    params::{Interpolation, RuntimeSpec},
-};
 
-use cpal::Stream;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{I24, SampleFormat, Stream};
 
 use std::{
     ffi::c_void,
     i64,
-    mem::zeroed,
     sync::{
         Arc, Mutex, Weak,
         atomic::{AtomicBool, Ordering},
     },
-    thread::{self},
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use crossbeam_channel::{Receiver, Sender, unbounded};
-use tokio::runtime::Handle;
-use tokio::sync::Mutex as async_Mutex;
+use tokio::sync::{Mutex as async_Mutex, broadcast};
 
 #[allow(unused_imports)]
 use ffmpeg_next::{self as av, ffi::AVAudioFifo, frame::Audio as AudioFrame, media, sys};
 
+//Volume range. The top end is boost, the limiter engages by itself to keep it from clipping
+pub const MAX_VOLUME_DB: f32 = 12.0;
+pub const MIN_VOLUME_DB: f32 = -100.0;
+
+//Seconds decoded ahead for local files and custom sources. Network sources use NetworkConfig
+const LOCAL_BUFFER_S: u32 = 10;
+
+//Ramp time for plain volume changes. Just long enough to avoid zipper noise
+const VOLUME_DEZIPPER_MS: u32 = 30;
+
+//Events a slow subscriber can fall behind by before it starts missing them
+const EVENT_CAPACITY: usize = 256;
+
+//How long a seek waits for the decoder to let go of the input
+const SEEK_WAIT: Duration = Duration::from_secs(5);
+
+//How often the ticker thread wakes up, and how often it looks for a new default output device
+const TICK_MS: u64 = 10;
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+///Fade lengths for transport changes. 0 switches instantly
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct FadeDurations {
+    pub play_ms: u32,
+    pub pause_ms: u32,
+    pub seek_ms: u32,
+    pub stop_ms: u32, // Used by clear, and load when something is playing
+}
+
+impl Default for FadeDurations {
+    fn default() -> Self {
+        FadeDurations {
+            play_ms: 30,
+            pause_ms: 30,
+            seek_ms: 15,
+            stop_ms: 30,
+        }
+    }
+}
+
 pub struct AudioFifo(pub *mut AVAudioFifo);
 unsafe impl Send for AudioFifo {}
 
+//Freed with the last handle, so neither the decoder thread nor the audio callback can be left with a dangling FIFO
+impl Drop for AudioFifo {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { sys::av_audio_fifo_free(self.0) };
+        }
+    }
+}
+
 pub struct AudioEngine {
     stream: Option<Stream>,
+    output_format: OutputFormat, // What the stream was opened with
     buffer: Arc<Mutex<AudioFifo>>,
 
     #[allow(unused)]
@@ -53,68 +121,102 @@ pub struct AudioEngine {
     state: Arc<Mutex<PlayerState>>,
     initialised: bool,
     tx: Option<Sender<CMD>>,
-    duration: Arc<Mutex<f64>>, //Total duration in seconds, -1.0 if theres nothing to play
+    threads: Vec<JoinHandle<()>>, // Decoder, listening and ticker threads, joined on shutdown
+    shutting_down: Arc<AtomicBool>, // Set once by shutdown, the engine can't be used after
+    duration: Arc<Mutex<f64>>, //Total duration in seconds, -1.0 if theres nothing to play, 0.0 for live streams
     total_samples: Arc<Mutex<Option<u64>>>, // Total samples in current track
-    resampling_quality: ResamplingQuality,
+    resampler_config: ResamplerConfig, // Applied from the next load
     signal_receiver: Receiver<EngineSignal>,
-    callback: Box<dyn FnMut(EngineSignal, Arc<Player>) -> ()>,
+    signal_tx: Sender<EngineSignal>,
+    callback: Option<Box<dyn FnMut(PlayerEvent, Arc<Player>) + Send>>, // Moves to the listening thread on the first load
+    events: broadcast::Sender<PlayerEvent>,
     decoder: Arc<Mutex<Decoder>>,
+    // Control copies of the output stages. The stream's own copies follow them, see handoff.rs
+    processors: Mutex<ChainControl>,
+    equalizer: StageControl<Equalizer>,
+    crossfeed: StageControl<Crossfeed>,
+    channel_mixer: StageControl<ChannelMixer>,
+    convolver: StageControl<Convolver>,
+    retired_kernels: Vec<Arc<ConvolutionKernel>>, // Kept until the audio thread lets go of them
+    impulse_response: Option<ImpulseResponse>, // Kept to build the kernel again for a new output rate
+    replay_gain: Arc<Mutex<ReplayGain>>,
+    loudness: Arc<Mutex<Loudness>>,
+    limiter: StageControl<Limiter>,
+    dither: StageControl<Dither>,
+    levels: Arc<MeterReadings>,
+    spectrum: Arc<SpectrumTap>,
+    fade_durations: FadeDurations,
+    network: NetworkConfig,
+    shared: Arc<SharedState>, // Also held by the decoder thread and the output stage
 }
 
 impl AudioEngine {
     pub fn new(
         resampling_quality: Option<ResamplingQuality>,
-        callback: Box<dyn FnMut(EngineSignal, Arc<Player>) -> ()>,
+        callback: Box<dyn FnMut(PlayerEvent, Arc<Player>) + Send>,
     ) -> Result<Arc<async_Mutex<Self>>, i32> {
-        let m_resampling_quality = resampling_quality.unwrap_or(ResamplingQuality::High);
+        let resampler_config =
+            ResamplerConfig::from(resampling_quality.unwrap_or(ResamplingQuality::High));
         singletons::set_decoder_busy(false);
 
         let host = cpal::default_host();
         let device = host
             .default_output_device()
             .expect("No output device available");
-        let config = device
-            .default_output_config()
-            .expect("Failed to get default output config");
+        let default_output = OutputFormat::default_for(&device)?;
 
-        let sample_rate = config.sample_rate() as i32;
-        let channels = config.channels() as i32;
+        let sample_rate = default_output.sample_rate as i32;
+        let channels = default_output.channels as i32;
 
         let buffer_ptr =
             unsafe { sys::av_audio_fifo_alloc(sys::AVSampleFormat::AV_SAMPLE_FMT_S32, 2, 100) };
         let buffer = Arc::new(Mutex::new(AudioFifo(buffer_ptr)));
 
         let (signal_tx, signal_rx) = unbounded::<EngineSignal>();
+        let levels = Arc::new(MeterReadings::new());
+        let spectrum = Arc::new(SpectrumTap::new());
 
-        let decoder: Arc<Mutex<Decoder>>;
+        let decoder = Arc::new(Mutex::new(Decoder::new(resampler_config, default_output)));
 
-        unsafe {
-            decoder = Arc::new(Mutex::new(Decoder {
-                format_ctx: zeroed(),
-                decoder: zeroed(),
-                resampler: zeroed(),
-                soxr_resampler: zeroed(),
-                audio_stream_index: zeroed(),
-                main_decoder_cancel_flag: Arc::new(AtomicBool::new(false)),
-            }));
-        }
-
-        let engine = AudioEngine {
-            stream: Some(build_stream(&device, config.into(), buffer.clone(), signal_tx).unwrap()),
+        let mut engine = AudioEngine {
+            stream: None,
+            output_format: default_output,
             buffer: buffer,
             channels: channels,
             sample_rate: Arc::new(Mutex::new(sample_rate)),
             state: Arc::new(Mutex::new(PlayerState::EMPTY)),
             initialised: false,
             tx: None,
+            threads: Vec::new(),
+            shutting_down: Arc::new(AtomicBool::new(false)),
             duration: Arc::new(Mutex::new(-1.0)),
             total_samples: Arc::new(Mutex::new(None)),
-            resampling_quality: m_resampling_quality,
+            resampler_config,
             signal_receiver: signal_rx,
-            callback: callback,
+            signal_tx: signal_tx,
+            callback: Some(callback),
+            events: broadcast::channel(EVENT_CAPACITY).0,
             decoder: decoder,
+            processors: Mutex::new(ChainControl::new()),
+            equalizer: StageControl::new(Equalizer::new()),
+            crossfeed: StageControl::new(Crossfeed::new()),
+            channel_mixer: StageControl::new(ChannelMixer::new()),
+            convolver: StageControl::new(Convolver::new()),
+            retired_kernels: Vec::new(),
+            impulse_response: None,
+            replay_gain: Arc::new(Mutex::new(ReplayGain::new())),
+            loudness: Arc::new(Mutex::new(Loudness::new())),
+            limiter: StageControl::new(Limiter::new()),
+            dither: StageControl::new(Dither::new()),
+            levels: levels,
+            spectrum: spectrum,
+            fade_durations: FadeDurations::default(),
+            network: NetworkConfig::default(),
+            shared: Arc::new(SharedState::new()),
         };
 
+        engine.open_output(&device, default_output)?;
+
         Ok(Arc::new(async_Mutex::new(engine)))
     }
 
@@ -122,39 +224,79 @@ impl AudioEngine {
         *self.duration.lock().unwrap()
     }
 
-    ///Loads files. Automatically stops previous playback if any. Requires an arc clone of the player object because it's later passed as context to media end callback
+    ///Loads a file, URL or custom source. Automatically stops previous playback if any. Requires an arc clone of the player object because it's later passed as context to media end callback.
+    ///A stream index that isn't an audio stream of the input falls back to the best one
     pub async fn load(
         audio_engine: Arc<async_Mutex<Self>>,
-        file: &str,
+        source: MediaSource,
+        stream: Option<u32>,
         player: Weak<Player>,
     ) -> Result<(), i32> {
         // Clear any existing playback first
         let mut engine = audio_engine.lock().await;
+        if engine.shutting_down.load(Ordering::Relaxed) {
+            return Err(PlayerError::INVALID_STATE);
+        }
         engine.clear()?;
 
+        // A live stream keeps the decoder busy for as long as it plays. Stop it so the new source gets through
+        {
+            let mut decoder = engine.decoder.lock().unwrap();
+            decoder
+                .main_decoder_cancel_flag
+                .store(true, Ordering::Relaxed);
+            decoder.track = None;
+        }
+
         // Initialize decoder thread if needed
         if !engine.initialised {
             let (tx, rx) = unbounded::<CMD>();
             engine.tx = Some(tx);
-            _ = engine.spawn_decoder_thread(rx.clone());
-            _ = AudioEngine::spawn_listening_thread(
-                audio_engine.clone(),
+            let decoder_thread = engine.spawn_decoder_thread(rx);
+            let callback = engine.callback.take();
+            let listening_thread = AudioEngine::spawn_listening_thread(
+                Arc::downgrade(&audio_engine),
                 engine.signal_receiver.clone(),
+                callback,
+                engine.events.clone(),
                 player,
             );
+            let ticker_thread = engine.spawn_ticker_thread();
+            engine.threads = vec![decoder_thread, listening_thread, ticker_thread];
             engine.initialised = true;
         }
 
-        let resampling_quality = engine.resampling_quality;
-        _ = engine
-            .tx
-            .as_mut()
-            .unwrap()
-            .send(CMD::Start(file.to_string(), resampling_quality));
+        // Network sources buffer further ahead and can stall, so they get rebuffering
+        let sample_rate = (*engine.sample_rate.lock().unwrap()).max(0) as u32;
+        let (buffer_s, rebuffer_s) = if source.is_network() {
+            (engine.network.buffer_s, engine.network.rebuffer_s)
+        } else {
+            (LOCAL_BUFFER_S, 0)
+        };
+        let target = sample_rate * buffer_s;
+        engine
+            .shared
+            .set_buffer_marks(target, target / 2, sample_rate * rebuffer_s);
+
+        set_state(&engine.state, PlayerState::LOADING, &engine.signal_tx);
+        let resampler_config = engine.resampler_config;
+        let network = engine.network.clone();
+        let generation = engine.shared.next_load_generation();
+        _ = engine.tx.as_mut().unwrap().send(CMD::Start(
+            source,
+            resampler_config,
+            network,
+            stream,
+            generation,
+        ));
 
         Ok(())
     }
 
+    pub fn get_state(&self) -> PlayerState {
+        *self.state.lock().unwrap()
+    }
+
     pub fn get_progress(&self) -> Result<f64, i32> {
         let sample_rate = *self.sample_rate.lock().unwrap() as f64;
         if sample_rate <= 0.0 {
@@ -168,129 +310,1024 @@ impl AudioEngine {
         f_get_volume()
     }
 
+    ///Linear volume. Anything above 1.0 is boost, which engages the limiter
     pub fn set_volume(&self, volume: f32) {
-        f_set_volume(volume);
+        let max_volume = db_to_linear(MAX_VOLUME_DB);
+        let volume = if volume.is_nan() { 1.0 } else { volume };
+        self.shared.set_volume_ramp_ms(VOLUME_DEZIPPER_MS);
+        self.apply_volume(volume.clamp(0.0, max_volume));
+    }
+
+    ///Ramps the volume to a new level over the given time
+    pub fn fade_to(&self, volume: f32, duration_s: f32) {
+        let max_volume = db_to_linear(MAX_VOLUME_DB);
+        if volume.is_nan() {
+            return;
+        }
+        let duration_ms = if duration_s.is_finite() {
+            (duration_s.max(0.0) * 1000.0) as u32
+        } else {
+            0
+        };
+        self.shared.set_volume_ramp_ms(duration_ms);
+        self.apply_volume(volume.clamp(0.0, max_volume));
+    }
+
+    //Hands the volume to the output stage and tells the app
+    fn apply_volume(&self, volume: f32) {
+        if volume != f_get_volume() {
+            f_set_volume(volume);
+            _ = self
+                .signal_tx
+                .send(EngineSignal::Event(PlayerEvent::VolumeChanged { volume }));
+        }
+    }
+
+    ///Every event the callback gets. Handed out so subscribing doesn't need the engine
+    pub fn event_sender(&self) -> broadcast::Sender<PlayerEvent> {
+        self.events.clone()
     }
 
-    //Clears the audio buffer
+    ///Meter readings. Handed out so they can be read without locking the engine
+    pub fn levels(&self) -> Arc<MeterReadings> {
+        self.levels.clone()
+    }
+
+    ///Visualiser tap, also read without locking the engine
+    pub fn spectrum_tap(&self) -> Arc<SpectrumTap> {
+        self.spectrum.clone()
+    }
+
+    pub fn get_fade_durations(&self) -> FadeDurations {
+        self.fade_durations
+    }
+
+    pub fn set_fade_durations(&mut self, fade_durations: FadeDurations) {
+        self.fade_durations = fade_durations;
+    }
+
+    pub fn get_position_interval(&self) -> u32 {
+        self.shared.get_position_interval_ms()
+    }
+
+    ///How often position updates are sent while playing, in milliseconds. 0 turns them off
+    pub fn set_position_interval(&self, interval_ms: u32) -> Result<(), i32> {
+        if interval_ms != 0 && interval_ms < MIN_POSITION_INTERVAL_MS {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        self.shared.set_position_interval_ms(interval_ms);
+        Ok(())
+    }
+
+    ///What's loaded, None while loading or if nothing is
+    pub fn get_track_info(&self) -> Option<TrackInfo> {
+        self.decoder.lock().unwrap().track.clone()
+    }
+
+    pub fn get_resampler_config(&self) -> ResamplerConfig {
+        self.resampler_config
+    }
+
+    ///Applies from the next load
+    pub fn set_resampler_config(&mut self, resampler_config: ResamplerConfig) -> Result<(), i32> {
+        resampler_config.validate()?;
+        self.resampler_config = resampler_config;
+        Ok(())
+    }
+
+    pub fn get_network_config(&self) -> NetworkConfig {
+        self.network.clone()
+    }
+
+    ///Applies from the next load
+    pub fn set_network_config(&mut self, network: NetworkConfig) -> Result<(), i32> {
+        network.validate()?;
+        self.network = network;
+        Ok(())
+    }
+
+    ///Whether output is held back waiting for a network source
+    pub fn is_buffering(&self) -> bool {
+        self.shared.get_buffering()
+    }
+
+    ///Whether a live stream is loaded. These have no duration and can't seek
+    pub fn is_live(&self) -> bool {
+        self.shared.get_live()
+    }
+
+    pub fn is_bit_perfect_enabled(&self) -> bool {
+        self.decoder.lock().unwrap().bit_perfect
+    }
+
+    ///Takes effect from the next load
+    pub fn set_bit_perfect_enabled(&self, enabled: bool) {
+        self.decoder.lock().unwrap().bit_perfect = enabled;
+    }
+
+    ///Whether what's playing reaches the device untouched
+    pub fn is_bit_perfect(&self) -> bool {
+        self.shared.get_bit_perfect()
+    }
+
+    ///Bitrate variants of the loaded HLS/DASH stream, lowest first. Empty for anything else
+    pub fn get_variants(&self) -> Vec<StreamVariant> {
+        self.decoder
+            .lock()
+            .unwrap()
+            .adaptive
+            .as_ref()
+            .map(|adaptive| adaptive.variants())
+            .unwrap_or_default()
+    }
+
+    ///The variant being played, None if there's no choice of variants
+    pub fn get_current_variant(&self) -> Option<u32> {
+        self.decoder
+            .lock()
+            .unwrap()
+            .adaptive
+            .as_ref()
+            .map(|adaptive| adaptive.current())
+    }
+
+    pub fn get_variant_selection(&self) -> VariantSelection {
+        self.decoder.lock().unwrap().variant_selection
+    }
+
+    ///Applies to the loaded stream straight away and to whatever is loaded next. A fixed id has to be one of get_variants
+    pub fn select_variant(&self, selection: VariantSelection) -> Result<(), i32> {
+        let mut decoder = self.decoder.lock().unwrap();
+        if let Some(adaptive) = decoder.adaptive.as_mut() {
+            adaptive.select(selection)?;
+        }
+        decoder.variant_selection = selection;
+        Ok(())
+    }
+
+    ///Audio streams of the loaded input in container order
+    pub fn get_audio_streams(&self) -> Vec<AudioStreamInfo> {
+        self.decoder.lock().unwrap().audio_streams.clone()
+    }
+
+    ///Index of the audio stream being played, None if nothing is loaded
+    pub fn get_current_audio_stream(&self) -> Option<u32> {
+        if matches!(self.get_state(), PlayerState::EMPTY | PlayerState::LOADING) {
+            return None;
+        }
+        Some(self.decoder.lock().unwrap().audio_stream_index as u32)
+    }
+
+    ///Switches to another audio stream of the loaded input and carries on from the same position.
+    ///HLS/DASH variants go through select_variant instead
+    pub fn select_audio_stream(&mut self, index: u32) -> Result<(), i32> {
+        if matches!(self.get_state(), PlayerState::EMPTY | PlayerState::LOADING) {
+            return Err(PlayerError::INVALID_STATE);
+        }
+        {
+            let decoder = self.decoder.lock().unwrap();
+            if decoder.adaptive.is_some() || self.shared.get_live() {
+                return Err(-1);
+            }
+            if !decoder
+                .audio_streams
+                .iter()
+                .any(|stream| stream.index == index)
+            {
+                return Err(PlayerError::INVALID_ARGUMENT);
+            }
+            if decoder.audio_stream_index == index as usize {
+                return Ok(());
+            }
+        }
+
+        let position = self.get_progress()?;
+        self.seek_to(position, Some(index as usize))
+    }
+
+    ///Latest ICY StreamTitle of the live stream, if it sent one
+    pub fn get_stream_title(&self) -> Option<String> {
+        self.decoder
+            .lock()
+            .unwrap()
+            .live
+            .as_ref()
+            .and_then(|live| live.title.clone())
+    }
+
+    pub fn get_volume_db(&self) -> f32 {
+        let volume = f_get_volume();
+        if volume <= 0.0 {
+            return f32::NEG_INFINITY;
+        }
+        linear_to_db(volume)
+    }
+
+    ///Volume in dB. Up to +12 dB, anything at or below -100 dB mutes
+    pub fn set_volume_db(&self, volume_db: f32) {
+        if volume_db.is_nan() {
+            return;
+        }
+        if volume_db <= MIN_VOLUME_DB {
+            self.set_volume(0.0);
+            return;
+        }
+        self.set_volume(db_to_linear(volume_db.min(MAX_VOLUME_DB)));
+    }
+
+    pub fn is_crossfeed_enabled(&self) -> bool {
+        self.crossfeed.get(|crossfeed| crossfeed.is_enabled())
+    }
+
+    pub fn set_crossfeed_enabled(&self, enabled: bool) {
+        self.crossfeed
+            .update(|crossfeed| crossfeed.set_enabled(enabled));
+    }
+
+    pub fn get_crossfeed_cutoff(&self) -> f32 {
+        self.crossfeed.get(|crossfeed| crossfeed.get_cutoff())
+    }
+
+    pub fn set_crossfeed_cutoff(&self, cutoff: f32) -> Result<(), i32> {
+        self.crossfeed
+            .update(|crossfeed| crossfeed.set_cutoff(cutoff))
+    }
+
+    pub fn get_crossfeed_feed(&self) -> f32 {
+        self.crossfeed.get(|crossfeed| crossfeed.get_feed())
+    }
+
+    pub fn set_crossfeed_feed(&self, feed_db: f32) -> Result<(), i32> {
+        self.crossfeed
+            .update(|crossfeed| crossfeed.set_feed(feed_db))
+    }
+
+    pub fn apply_crossfeed_preset(&self, preset: CrossfeedPreset) {
+        self.crossfeed
+            .update(|crossfeed| crossfeed.apply_preset(preset));
+    }
+
+    ///Rate the output device runs at, which impulse responses are built for
+    pub fn get_output_sample_rate(&self) -> u32 {
+        self.output_format.sample_rate
+    }
+
+    ///Swaps in an impulse response and turns convolution on. The kernel is built by the caller without the engine
+    ///locked, against get_output_sample_rate
+    pub fn set_impulse_response(
+        &mut self,
+        impulse_response: ImpulseResponse,
+        kernel: ConvolutionKernel,
+    ) -> Result<ImpulseResponseInfo, i32> {
+        // The output was reopened at another rate while the kernel was being built
+        let sample_rate = self.output_format.sample_rate;
+        let kernel = if kernel.sample_rate() == sample_rate {
+            kernel
+        } else {
+            ConvolutionKernel::new(&impulse_response, sample_rate, self.resampler_config)?
+        };
+        let info = kernel.info();
+
+        self.swap_kernel(Some(Arc::new(kernel)), true);
+        self.impulse_response = Some(impulse_response);
+        Ok(info)
+    }
+
+    pub fn clear_impulse_response(&mut self) {
+        self.swap_kernel(None, false);
+        self.impulse_response = None;
+    }
+
+    //The old kernel is kept until the audio thread's copy and the handoff slots are done with it, so its memory is
+    //never freed on the audio thread
+    fn swap_kernel(&mut self, kernel: Option<Arc<ConvolutionKernel>>, enabled: bool) {
+        let old = self.convolver.update(|convolver| {
+            let old = convolver.kernel();
+            convolver.set_kernel(kernel);
+            convolver.set_enabled(enabled);
+            old
+        });
+        self.retired_kernels
+            .retain(|kernel| Arc::strong_count(kernel) > 1);
+        self.retired_kernels.extend(old);
+    }
+
+    //Builds the kernel again when the output comes up at a rate it wasn't made for
+    fn rebuild_kernel(&mut self, sample_rate: u32) {
+        let Some(impulse_response) = self.impulse_response.as_ref() else {
+            return;
+        };
+        if self.convolver.get(|convolver| convolver.kernel_rate()) == Some(sample_rate) {
+            return;
+        }
+
+        match ConvolutionKernel::new(impulse_response, sample_rate, self.resampler_config) {
+            Ok(kernel) => {
+                let enabled = self.convolver.get(|convolver| convolver.is_enabled());
+                self.swap_kernel(Some(Arc::new(kernel)), enabled);
+            }
+            Err(error) => {
+                // Better off than running at the wrong rate. Reported like any other failure
+                self.swap_kernel(None, false);
+                self.impulse_response = None;
+                _ = self.signal_tx.send(EngineSignal::Event(PlayerEvent::Error {
+                    error: PlayerError::Code(error),
+                }));
+            }
+        }
+    }
+
+    pub fn get_impulse_response_info(&self) -> Option<ImpulseResponseInfo> {
+        self.convolver.get(|convolver| convolver.get_info())
+    }
+
+    pub fn is_convolution_enabled(&self) -> bool {
+        self.convolver.get(|convolver| convolver.is_enabled())
+    }
+
+    pub fn set_convolution_enabled(&self, enabled: bool) {
+        self.convolver
+            .update(|convolver| convolver.set_enabled(enabled));
+    }
+
+    pub fn get_balance(&self) -> f32 {
+        self.channel_mixer.get(|mixer| mixer.get_balance())
+    }
+
+    pub fn set_balance(&self, balance: f32) -> Result<(), i32> {
+        self.channel_mixer
+            .update(|mixer| mixer.set_balance(balance))
+    }
+
+    pub fn is_mono(&self) -> bool {
+        self.channel_mixer.get(|mixer| mixer.is_mono())
+    }
+
+    pub fn set_mono(&self, mono: bool) {
+        self.channel_mixer.update(|mixer| mixer.set_mono(mono));
+    }
+
+    pub fn is_channels_swapped(&self) -> bool {
+        self.channel_mixer.get(|mixer| mixer.is_swapped())
+    }
+
+    pub fn set_channels_swapped(&self, swap: bool) {
+        self.channel_mixer.update(|mixer| mixer.set_swapped(swap));
+    }
+
+    pub fn get_channel_mute(&self) -> (bool, bool) {
+        self.channel_mixer.get(|mixer| mixer.get_muted())
+    }
+
+    pub fn set_channel_mute(&self, left: bool, right: bool) {
+        self.channel_mixer
+            .update(|mixer| mixer.set_muted(left, right));
+    }
+
+    pub fn get_channel_routing(&self) -> ChannelMatrix {
+        self.channel_mixer.get(|mixer| mixer.get_routing())
+    }
+
+    pub fn set_channel_routing(&self, routing: ChannelMatrix) -> Result<(), i32> {
+        self.channel_mixer
+            .update(|mixer| mixer.set_routing(routing))
+    }
+
+    pub fn reset_channels(&self) {
+        self.channel_mixer.update(|mixer| mixer.reset_all());
+    }
+
+    pub fn is_limiter_enabled(&self) -> bool {
+        self.limiter.get(|limiter| limiter.is_enabled())
+    }
+
+    pub fn set_limiter_enabled(&self, enabled: bool) {
+        self.limiter.update(|limiter| limiter.set_enabled(enabled));
+    }
+
+    pub fn get_limiter_ceiling(&self) -> f32 {
+        self.limiter.get(|limiter| limiter.get_ceiling())
+    }
+
+    pub fn set_limiter_ceiling(&self, ceiling_db: f32) -> Result<(), i32> {
+        self.limiter
+            .update(|limiter| limiter.set_ceiling(ceiling_db))
+    }
+
+    pub fn get_limiter_knee(&self) -> f32 {
+        self.limiter.get(|limiter| limiter.get_knee())
+    }
+
+    pub fn set_limiter_knee(&self, knee_db: f32) -> Result<(), i32> {
+        self.limiter.update(|limiter| limiter.set_knee(knee_db))
+    }
+
+    pub fn get_limiter_release(&self) -> f32 {
+        self.limiter.get(|limiter| limiter.get_release())
+    }
+
+    pub fn set_limiter_release(&self, release_ms: f32) -> Result<(), i32> {
+        self.limiter
+            .update(|limiter| limiter.set_release(release_ms))
+    }
+
+    pub fn is_dither_enabled(&self) -> bool {
+        self.dither.get(|dither| dither.is_enabled())
+    }
+
+    pub fn set_dither_enabled(&self, enabled: bool) {
+        self.dither.update(|dither| dither.set_enabled(enabled));
+    }
+
+    pub fn get_noise_shaping(&self) -> NoiseShaping {
+        self.dither.get(|dither| dither.get_noise_shaping())
+    }
+
+    pub fn set_noise_shaping(&self, noise_shaping: NoiseShaping) {
+        self.dither
+            .update(|dither| dither.set_noise_shaping(noise_shaping));
+    }
+
+    ///Reported by the audio thread, reading it never holds up playback
+    pub fn get_limiter_reduction(&self) -> f32 {
+        self.limiter.get(|limiter| limiter.get_reduction())
+    }
+
+    ///Adds a processor to the output chain. Appended at the end if no index is given. Fails once the chain holds
+    ///MAX_PROCESSORS
+    pub fn add_processor(
+        &self,
+        processor: Box<dyn AudioProcessor>,
+        index: Option<usize>,
+    ) -> Result<u64, i32> {
+        self.processors.lock().unwrap().insert(index, processor)
+    }
+
+    pub fn remove_processor(&self, id: u64) -> Result<(), i32> {
+        self.processors.lock().unwrap().remove(id)
+    }
+
+    ///Removes a processor and hands it back once the audio thread lets go of it
+    pub fn take_processor(&self, id: u64) -> Result<Box<dyn AudioProcessor>, i32> {
+        self.processors.lock().unwrap().take(id)
+    }
+
+    pub fn move_processor(&self, id: u64, index: usize) -> Result<(), i32> {
+        self.processors.lock().unwrap().move_to(id, index)
+    }
+
+    pub fn set_processor_bypassed(&self, id: u64, bypassed: bool) -> Result<(), i32> {
+        self.processors.lock().unwrap().set_bypassed(id, bypassed)
+    }
+
+    pub fn is_processor_bypassed(&self, id: u64) -> Result<bool, i32> {
+        self.processors.lock().unwrap().is_bypassed(id)
+    }
+
+    pub fn get_processor_ids(&self) -> Vec<u64> {
+        self.processors.lock().unwrap().ids()
+    }
+
+    pub fn clear_processors(&self) {
+        self.processors.lock().unwrap().clear();
+    }
+
+    ///Latency of the processor chain in seconds
+    pub fn get_processing_latency(&self) -> f64 {
+        let sample_rate = *self.sample_rate.lock().unwrap() as f64;
+        if sample_rate <= 0.0 {
+            return 0.0;
+        }
+        let frames = self.processors.lock().unwrap().latency()
+            + self.convolver.get(|convolver| convolver.latency())
+            + self.limiter.get(|limiter| limiter.get_latency());
+        frames as f64 / sample_rate
+    }
+
+    pub fn is_eq_enabled(&self) -> bool {
+        self.equalizer.get(|equalizer| equalizer.is_enabled())
+    }
+
+    pub fn set_eq_enabled(&self, enabled: bool) {
+        self.equalizer
+            .update(|equalizer| equalizer.set_enabled(enabled));
+    }
+
+    pub fn get_eq_bands(&self) -> Vec<EqBand> {
+        self.equalizer.get(|equalizer| equalizer.get_bands())
+    }
+
+    pub fn set_eq_bands(&self, bands: Vec<EqBand>) -> Result<(), i32> {
+        self.equalizer
+            .update(|equalizer| equalizer.set_bands(bands))
+    }
+
+    pub fn set_eq_band(&self, index: usize, band: EqBand) -> Result<(), i32> {
+        self.equalizer
+            .update(|equalizer| equalizer.set_band(index, band))
+    }
+
+    pub fn set_graphic_eq(&self, gains: &[f32]) -> Result<(), i32> {
+        self.equalizer
+            .update(|equalizer| equalizer.set_graphic(gains))
+    }
+
+    pub fn apply_eq_preset(&self, preset: EqPreset) {
+        self.equalizer
+            .update(|equalizer| equalizer.apply_preset(preset));
+    }
+
+    pub fn get_eq_preamp(&self) -> f32 {
+        self.equalizer.get(|equalizer| equalizer.get_preamp())
+    }
+
+    pub fn set_eq_preamp(&self, preamp_db: f32) -> Result<(), i32> {
+        self.equalizer
+            .update(|equalizer| equalizer.set_preamp(preamp_db))
+    }
+
+    pub fn get_replay_gain_mode(&self) -> ReplayGainMode {
+        self.replay_gain.lock().unwrap().get_mode()
+    }
+
+    pub fn set_replay_gain_mode(&self, mode: ReplayGainMode) {
+        self.replay_gain.lock().unwrap().set_mode(mode);
+        refresh_track_gain(
+            self.replay_gain.clone(),
+            self.loudness.clone(),
+            self.shared.clone(),
+        );
+    }
+
+    pub fn get_replay_gain_preamp(&self) -> f32 {
+        self.replay_gain.lock().unwrap().get_preamp()
+    }
+
+    pub fn set_replay_gain_preamp(&self, preamp_db: f32) -> Result<(), i32> {
+        self.replay_gain.lock().unwrap().set_preamp(preamp_db)?;
+        refresh_track_gain(
+            self.replay_gain.clone(),
+            self.loudness.clone(),
+            self.shared.clone(),
+        );
+        Ok(())
+    }
+
+    pub fn get_replay_gain_prevent_clipping(&self) -> bool {
+        self.replay_gain.lock().unwrap().get_prevent_clipping()
+    }
+
+    pub fn set_replay_gain_prevent_clipping(&self, prevent_clipping: bool) {
+        self.replay_gain
+            .lock()
+            .unwrap()
+            .set_prevent_clipping(prevent_clipping);
+        refresh_track_gain(
+            self.replay_gain.clone(),
+            self.loudness.clone(),
+            self.shared.clone(),
+        );
+    }
+
+    ///Tags of the loaded track, None if it has none
+    pub fn get_replay_gain_info(&self) -> Option<ReplayGainInfo> {
+        self.replay_gain.lock().unwrap().get_info()
+    }
+
+    ///Linear gain currently applied by ReplayGain
+    pub fn get_replay_gain(&self) -> f32 {
+        self.replay_gain.lock().unwrap().gain().unwrap_or(1.0)
+    }
+
+    pub fn is_loudness_normalization_enabled(&self) -> bool {
+        self.loudness.lock().unwrap().is_enabled()
+    }
+
+    ///Measures untagged tracks in the background and normalises them to the target loudness
+    pub fn set_loudness_normalization_enabled(&self, enabled: bool) {
+        self.loudness.lock().unwrap().set_enabled(enabled);
+        refresh_track_gain(
+            self.replay_gain.clone(),
+            self.loudness.clone(),
+            self.shared.clone(),
+        );
+    }
+
+    pub fn get_loudness_target(&self) -> f32 {
+        self.loudness.lock().unwrap().get_target()
+    }
+
+    pub fn set_loudness_target(&self, target_lufs: f32) -> Result<(), i32> {
+        self.loudness.lock().unwrap().set_target(target_lufs)?;
+        refresh_track_gain(
+            self.replay_gain.clone(),
+            self.loudness.clone(),
+            self.shared.clone(),
+        );
+        Ok(())
+    }
+
+    ///Measurement of the loaded track. None until its scan finishes
+    pub fn get_measured_loudness(&self) -> Option<LoudnessInfo> {
+        self.loudness.lock().unwrap().get_current()
+    }
+
+    pub fn get_cached_loudness(&self, path: &str) -> Option<LoudnessInfo> {
+        self.loudness.lock().unwrap().get_cached(path)
+    }
+
+    pub fn clear_loudness_cache(&self) {
+        self.loudness.lock().unwrap().clear_cache();
+        refresh_track_gain(
+            self.replay_gain.clone(),
+            self.loudness.clone(),
+            self.shared.clone(),
+        );
+    }
+
+    //Unloads whatever is loaded. Possible from any state, a load in progress is dropped
     pub fn clear(&mut self) -> Result<(), i32> {
         // Stop playback if active
-        if *self.state.lock().unwrap() == PlayerState::PLAYING {
-            self.pause()?;
+        if self.get_state() == PlayerState::PLAYING {
+            self.stop_output(self.fade_durations.stop_ms)?;
         }
 
+        self.shared.next_load_generation();
+        self.shared.set_live(false);
+        self.shared.set_bit_perfect(false);
+        self.reset_output();
+        // Nothing is loaded, so its scan has nothing to measure for
+        self.loudness.lock().unwrap().set_current(None);
+
+        set_state(&self.state, PlayerState::EMPTY, &self.signal_tx);
+
+        Ok(())
+    }
+
+    //Drops everything buffered for the output and the position with it
+    fn reset_output(&self) {
         reset_played();
 
+        // Whatever was buffering is gone
+        if self.shared.set_buffering(false) {
+            _ = self
+                .signal_tx
+                .send(EngineSignal::Event(PlayerEvent::BufferingEnded));
+        }
+        self.shared.set_underrun(false);
+
         // Clear the FIFO buffer
         unsafe {
             sys::av_audio_fifo_reset(self.buffer.lock().unwrap().0);
         }
 
-        *self.state.lock().unwrap() = PlayerState::EMPTY;
+        // Old filter state belongs to audio that's no longer coming. Cleared by the audio thread before its next block
+        self.equalizer.reset();
+        self.processors.lock().unwrap().reset();
+        self.crossfeed.reset();
+        self.convolver.reset();
+        self.limiter.reset();
+        self.dither.reset();
+    }
+
+    //Plays. Called while loading it waits for the load
+    pub fn play(&mut self) -> Result<(), i32> {
+        self.play_with_fade(self.fade_durations.play_ms)
+    }
+
+    fn play_with_fade(&mut self, fade_ms: u32) -> Result<(), i32> {
+        match self.get_state() {
+            PlayerState::PLAYING => return Ok(()),
+            PlayerState::EMPTY => return Err(PlayerError::INVALID_STATE),
+            _ => {}
+        }
+
+        self.start_output(fade_ms)?;
+        // Cleared or reloaded while waiting for audio
+        if !set_state(&self.state, PlayerState::PLAYING, &self.signal_tx) {
+            if let Some(stream) = self.stream.as_ref() {
+                _ = stream.pause();
+            }
+            return Err(PlayerError::INVALID_STATE);
+        }
 
         Ok(())
     }
 
-    //Plays
-    pub fn play(&mut self) -> Result<(), i32> {
+    //Waits for enough audio and starts the device. Leaves the state alone
+    fn start_output(&mut self, fade_ms: u32) -> Result<(), i32> {
         //Check if we have enough samples for playback so it doesnt cause artifacting
         let mut size = unsafe { sys::av_audio_fifo_size(self.buffer.lock().unwrap().0) };
 
         let sample_rate = { self.sample_rate.lock().unwrap().clone() };
-        let minimum_samples = sample_rate * 5;
-
-        while size <= minimum_samples && !get_decoder_eof() {
+        // Network sources only wait for the rebuffer amount, so they start as soon as they reasonably can. The
+        // decoder stops once the buffer holds the target, which is always enough
+        let minimum_samples = start_frames(
+            self.shared.get_target_buffer_frames(),
+            self.shared.get_rebuffer_frames(),
+            sample_rate.max(0) as u32,
+        ) as i32;
+
+        while size < minimum_samples && !get_decoder_eof() {
+            // Loading failed, nothing is coming
+            if self.get_state() == PlayerState::EMPTY {
+                return Err(-1);
+            }
             let buffer = self.buffer.lock().unwrap().0;
             size = unsafe { sys::av_audio_fifo_size(buffer) };
             thread::sleep(Duration::from_millis(10));
         }
 
-        if *self.state.lock().unwrap() != PlayerState::PLAYING {
-            self.stream.as_ref().unwrap().play().map_err(|_| -1)?;
-            *self.state.lock().unwrap() = PlayerState::PLAYING;
+        // The track decides how the device is opened, e.g. at its own rate for bit-perfect
+        let wanted = self.decoder.lock().unwrap().output_format;
+        if wanted != self.output_format {
+            self.reopen_output(wanted)?;
+            // Whatever the device settled on, so it isn't tried again on every play
+            self.decoder.lock().unwrap().output_format = self.output_format;
         }
+        self.shared.set_bit_perfect(self.output_format.bit_perfect);
 
+        self.shared.set_transport_target(1.0, fade_ms);
+        self.stream.as_ref().ok_or(-1)?.play().map_err(|_| -1)?;
+
+        Ok(())
+    }
+
+    //Opens the device with a new output stage. Its stages start from the engine's settings, sized for the rate
+    fn open_output(&mut self, device: &cpal::Device, format: OutputFormat) -> Result<(), i32> {
+        let sample_rate = format.sample_rate;
+        self.processors.lock().unwrap().prepare(sample_rate);
+        let output_stage = OutputStage::new(
+            self.equalizer.follower(sample_rate),
+            self.processors.lock().unwrap().live(),
+            self.crossfeed.follower(sample_rate),
+            self.channel_mixer.follower(sample_rate),
+            self.convolver.follower(sample_rate),
+            self.limiter.follower(sample_rate),
+            self.dither.follower(sample_rate),
+            self.levels.clone(),
+            self.spectrum.clone(),
+            self.shared.clone(),
+        );
+        let stream = open_stream(
+            device,
+            format,
+            self.buffer.clone(),
+            output_stage,
+            self.shared.clone(),
+            self.signal_tx.clone(),
+        )?;
+
+        self.stream = Some(stream);
+        self.output_format = format;
+        self.rebuild_kernel(format.sample_rate);
         Ok(())
     }
 
-    //Pauses playback
+    //Reopens the default device in another format. If the device turns it down after all, its own sample format at
+    //the same rate still plays what's buffered correctly, just not bit-perfect
+    fn reopen_output(&mut self, format: OutputFormat) -> Result<(), i32> {
+        let device = cpal::default_host().default_output_device().ok_or(-1)?;
+        // Devices in exclusive mode can't be opened twice
+        self.stream = None;
+        if self.open_output(&device, format).is_ok() {
+            return Ok(());
+        }
+
+        let fallback = OutputFormat {
+            sample_rate: format.sample_rate,
+            bit_perfect: false,
+            ..OutputFormat::default_for(&device)?
+        };
+        self.open_output(&device, fallback)
+    }
+
+    //Pauses playback. Does nothing unless playing
     pub fn pause(&mut self) -> Result<(), i32> {
-        if *self.state.lock().unwrap() != PlayerState::PAUSED {
-            self.stream.as_ref().unwrap().pause().map_err(|_| -1)?;
-            *self.state.lock().unwrap() = PlayerState::PAUSED;
+        self.pause_with_fade(self.fade_durations.pause_ms)
+    }
+
+    fn pause_with_fade(&mut self, fade_ms: u32) -> Result<(), i32> {
+        match self.get_state() {
+            PlayerState::PLAYING => {
+                self.stop_output(fade_ms)?;
+                set_state(&self.state, PlayerState::PAUSED, &self.signal_tx);
+                Ok(())
+            }
+            PlayerState::EMPTY => Err(PlayerError::INVALID_STATE),
+            _ => Ok(()),
         }
+    }
 
+    //Fades out and stops the device. Leaves the state alone
+    fn stop_output(&mut self, fade_ms: u32) -> Result<(), i32> {
+        self.fade_out(fade_ms);
+        self.stream.as_ref().ok_or(-1)?.pause().map_err(|_| -1)?;
+        self.levels.clear();
+        self.spectrum.clear();
         Ok(())
     }
 
-    ///Spawns a thread to listen for any events that are triggered by the audio engine
+    ///Fades the output to silence and waits for the device to get there
+    fn fade_out(&self, fade_ms: u32) {
+        self.shared.set_transport_target(0.0, fade_ms);
+
+        // Nothing is being pulled through the output stage, so there's nothing to wait for
+        let size = unsafe { sys::av_audio_fifo_size(self.buffer.lock().unwrap().0) };
+        if fade_ms == 0 || size == 0 || self.get_state() != PlayerState::PLAYING {
+            return;
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(fade_ms as u64 + 100);
+        while self.shared.get_transport_level() > 0.0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    ///Unloads, cancels decoding, ends the engine's threads and lets go of the output device. Returns the threads
+    ///so they can be joined with the engine unlocked. Nothing works afterwards and later calls do nothing
+    pub fn shutdown(&mut self) -> Vec<JoinHandle<()>> {
+        if self.shutting_down.swap(true, Ordering::Relaxed) {
+            return Vec::new();
+        }
+        _ = self.clear();
+
+        // The decoder lets go at its next packet, then its command loop ends with the sender gone
+        self.decoder
+            .lock()
+            .unwrap()
+            .main_decoder_cancel_flag
+            .store(true, Ordering::Relaxed);
+        self.tx = None;
+        // Queued events, e.g. the StateChanged from clearing, are delivered before the listener stops
+        _ = self.signal_tx.send(EngineSignal::Stop);
+
+        // Dropping the stream closes the device
+        self.stream = None;
+        self.levels.clear();
+        self.spectrum.clear();
+
+        // A loudness scan stops at its next packet
+        let mut threads = std::mem::take(&mut self.threads);
+        let mut loudness = self.loudness.lock().unwrap();
+        loudness.set_current(None);
+        threads.append(&mut loudness.take_scan_threads());
+        threads
+    }
+
+    ///Spawns a thread to listen for any events that are triggered by the audio engine. Events go to subscribers
+    ///and then the callback, with the engine unlocked so the callback can use the player
     fn spawn_listening_thread(
-        engine: Arc<async_Mutex<Self>>,
+        engine: Weak<async_Mutex<Self>>,
         receiver: Receiver<EngineSignal>,
+        mut callback: Option<Box<dyn FnMut(PlayerEvent, Arc<Player>) + Send>>,
+        events: broadcast::Sender<PlayerEvent>,
         player: Weak<Player>,
-    ) -> Result<(), i32> {
-        tokio::task::spawn_blocking(move || {
-            let rt_handle = Handle::current();
-
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
             for signal in receiver {
                 let maybe_player = player.upgrade();
                 if maybe_player.is_none() {
                     break;
                 }
                 let player_arc = maybe_player.unwrap();
-                rt_handle.block_on(async {
-                    match signal {
-                        EngineSignal::MediaEnd => {
-                            set_decoder_eof(false);
-                            let mut m_engine = engine.lock().await;
-                            _ = m_engine.pause();
-                            _ = m_engine.clear();
-                            (m_engine.callback)(EngineSignal::MediaEnd, player_arc);
+                let mut deliver = |event: PlayerEvent| {
+                    // No subscribers isn't an error
+                    _ = events.send(event.clone());
+                    if let Some(callback) = callback.as_mut() {
+                        callback(event, player_arc.clone());
+                    }
+                };
+
+                match signal {
+                    EngineSignal::MediaEnd => {
+                        set_decoder_eof(false);
+                        if let Some(engine) = engine.upgrade() {
+                            _ = engine.blocking_lock().clear();
                         }
-                        EngineSignal::BufferLow => {
-                            if !get_decoder_eof() {
-                                let m_engine = engine.lock().await;
-                                if let Some(tx) = &m_engine.tx {
-                                    _ = tx.send(CMD::FillBuffer);
-                                }
+                        deliver(PlayerEvent::MediaEnd);
+                    }
+                    EngineSignal::BufferLow => {
+                        if !get_decoder_eof()
+                            && let Some(engine) = engine.upgrade()
+                        {
+                            let m_engine = engine.blocking_lock();
+                            if let Some(tx) = &m_engine.tx {
+                                _ = tx.send(CMD::FillBuffer);
                             }
                         }
                     }
-                });
+                    EngineSignal::Event(event) => deliver(event),
+                    EngineSignal::Stop => break,
+                }
             }
-        });
+        })
+    }
 
-        Ok(())
+    ///Sends position updates while playing and watches for the default output device changing
+    fn spawn_ticker_thread(&self) -> JoinHandle<()> {
+        let state = Arc::downgrade(&self.state);
+        let shutting_down = self.shutting_down.clone();
+        let sample_rate = self.sample_rate.clone();
+        let duration = self.duration.clone();
+        let signal_tx = self.signal_tx.clone();
+        let shared = self.shared.clone();
+
+        thread::spawn(move || {
+            let mut last_position = Instant::now();
+            let mut last_device_poll = Instant::now();
+            let mut device = default_device_name();
+
+            loop {
+                thread::sleep(Duration::from_millis(TICK_MS));
+                if shutting_down.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(state) = state.upgrade() else {
+                    break;
+                };
+
+                let interval_ms = shared.get_position_interval_ms();
+                if interval_ms > 0
+                    && last_position.elapsed() >= Duration::from_millis(interval_ms as u64)
+                {
+                    last_position = Instant::now();
+                    let rate = *sample_rate.lock().unwrap() as f64;
+                    if *state.lock().unwrap() == PlayerState::PLAYING && rate > 0.0 {
+                        _ = signal_tx.send(EngineSignal::Event(PlayerEvent::PositionUpdate {
+                            position_s: get_played() as f64 / rate,
+                            duration_s: *duration.lock().unwrap(),
+                        }));
+                    }
+                }
+
+                if last_device_poll.elapsed() >= DEVICE_POLL_INTERVAL {
+                    last_device_poll = Instant::now();
+                    let current = default_device_name();
+                    if current != device {
+                        if let Some(name) = &current {
+                            _ = signal_tx.send(EngineSignal::Event(PlayerEvent::DeviceChanged {
+                                name: name.clone(),
+                            }));
+                        }
+                        device = current;
+                    }
+                }
+            }
+        })
     }
 
     pub fn seek(&mut self, time_s: f64) -> Result<(), i32> {
-        loop {
-            let state = self.state.lock().unwrap();
+        self.seek_to(time_s, None)
+    }
 
-            if (*state == PlayerState::EMPTY) || (*state == PlayerState::LOADING) {
-                println!("Invalid state");
-                println!("State: {}", *state);
-                thread::sleep(Duration::from_millis(5));
-            } else {
-                break;
-            }
+    //Seeks, optionally moving over to another audio stream while the decoder is stopped. The state doesn't change
+    fn seek_to(&mut self, time_s: f64, stream: Option<usize>) -> Result<(), i32> {
+        // Wait for the load before seeking, e.g. on the Loaded event
+        if matches!(self.get_state(), PlayerState::EMPTY | PlayerState::LOADING) {
+            return Err(PlayerError::INVALID_STATE);
+        }
+        // There's nothing to seek to in a live stream
+        if self.shared.get_live() {
+            return Err(-1);
         }
 
-        let is_paused = { *self.state.lock().unwrap() == PlayerState::PAUSED };
-
-        _ = self.pause();
+        let was_playing = self.get_state() == PlayerState::PLAYING;
+        let seek_fade_ms = self.fade_durations.seek_ms;
 
-        {
-            let decoder = self.decoder.lock().unwrap();
-            decoder
-                .main_decoder_cancel_flag
-                .store(true, Ordering::Relaxed);
+        if was_playing {
+            self.stop_output(seek_fade_ms)?;
         }
 
-        _ = self.clear();
+        let switched = {
+            // The decoder has the input while it runs. It lets go at the next packet once cancelled
+            let decoder_handle = self.decoder.clone();
+            let deadline = Instant::now() + SEEK_WAIT;
+            let mut decoder = loop {
+                let decoder = decoder_handle.lock().unwrap();
+                decoder
+                    .main_decoder_cancel_flag
+                    .store(true, Ordering::Relaxed);
+                if decoder.format_ctx.is_some() {
+                    break decoder;
+                }
+                drop(decoder);
 
-        {
-            let mut decoder = self.decoder.lock().unwrap();
+                if Instant::now() >= deadline {
+                    decoder_handle
+                        .lock()
+                        .unwrap()
+                        .main_decoder_cancel_flag
+                        .store(false, Ordering::Relaxed);
+                    if was_playing && self.start_output(seek_fade_ms).is_err() {
+                        set_state(&self.state, PlayerState::PAUSED, &self.signal_tx);
+                    }
+                    return Err(-1);
+                }
+                thread::sleep(Duration::from_millis(2));
+            };
+
+            self.reset_output();
 
             let target_ts = (time_s * 1_000_000.0) as i64;
 
@@ -299,126 +1336,259 @@ impl AudioEngine {
                 .as_mut()
                 .unwrap()
                 .seek(target_ts, i64::MIN..i64::MAX);
-            decoder.decoder.flush();
-            let mut dump = AudioFrame::empty();
-            _ = decoder.resampler.flush(&mut dump);
-            _ = decoder.soxr_resampler.clear();
+
+            // A failed switch leaves the old stream playing
+            let switched = match stream {
+                Some(index) => {
+                    let sample_rate = *self.sample_rate.lock().unwrap() as f64;
+                    let mut input = decoder.format_ctx.take().unwrap();
+                    let result =
+                        switch_stream(&mut decoder, &mut input, index, sample_rate, &self.shared);
+                    if result.is_ok()
+                        && let Some(track) = decoder.track.as_mut()
+                    {
+                        track.set_stream(&input, index);
+                    }
+                    decoder.format_ctx = Some(input);
+                    result
+                }
+                None => Ok(()),
+            };
+
+            if let Some(codec) = decoder.decoder.as_mut() {
+                codec.flush();
+            }
+            if let Some(resampler) = decoder.resampler.as_mut() {
+                let mut dump = AudioFrame::empty();
+                _ = resampler.flush(&mut dump);
+            }
+            if let Some(soxr_resampler) = decoder.soxr_resampler.as_mut() {
+                _ = soxr_resampler.clear();
+            }
 
             decoder
                 .main_decoder_cancel_flag
                 .store(false, Ordering::Relaxed);
-        }
+
+            switched
+        };
 
         let tx = self.tx.as_ref().unwrap().clone();
         set_decoder_eof(false);
         _ = tx.send(CMD::Resume);
         set_played((time_s * (*self.sample_rate.lock().unwrap() as f64)) as u64);
 
-        if !is_paused {
-            _ = self.play();
+        _ = self
+            .signal_tx
+            .send(EngineSignal::Event(PlayerEvent::Seeked {
+                position_s: time_s,
+            }));
+
+        if was_playing && self.start_output(seek_fade_ms).is_err() {
+            set_state(&self.state, PlayerState::PAUSED, &self.signal_tx);
         }
 
-        Ok(())
+        switched
     }
 
     // <- DECODING LOGIC ->
-    fn spawn_decoder_thread(&mut self, rx: Receiver<CMD>) -> Result<(), i32> {
+    fn spawn_decoder_thread(&mut self, rx: Receiver<CMD>) -> JoinHandle<()> {
         let sample_rate_handle = self.sample_rate.clone();
         let buffer_handle = self.buffer.clone();
         let duration_handle = self.duration.clone();
         let total_samples_handle = self.total_samples.clone();
         let state_handle = self.state.clone();
+        let replay_gain_handle = self.replay_gain.clone();
+        let loudness_handle = self.loudness.clone();
+        let signal_tx = self.signal_tx.clone();
+        let shared = self.shared.clone();
 
         let decoder_handle = self.decoder.clone();
 
         thread::spawn(move || {
-            let target_buffer_size = (*sample_rate_handle.lock().unwrap() * 10) as i32; // 10 seconds buffered
-            let low_water_mark = (*sample_rate_handle.lock().unwrap() * 5) as i32; // refill at 5 seconds
-
             for cmd in rx {
-                if let CMD::Start(url, resampling_quality) = cmd {
+                // Marks are set by load for whatever is loaded now
+                let target_buffer_size = shared.get_target_buffer_frames() as i32;
+                let low_water_mark = shared.get_low_water_frames() as i32;
+
+                if let CMD::Start(source, resampler_config, network, stream, generation) = cmd {
+                    // Superseded by a later load or a clear before it got here
+                    if generation != shared.get_load_generation() {
+                        continue;
+                    }
+
+                    // Only local paths are scanned and cached by loudness. URLs are kept for live reconnects
+                    let path = source.path().map(str::to_string);
+                    let is_network = source.is_network();
+
+                    // Connecting can take a while, let the app show it
+                    if is_network && !shared.set_buffering(true) {
+                        _ = signal_tx.send(EngineSignal::Event(PlayerEvent::Buffering));
+                    }
+
+                    // Opened before taking the decoder so a slow server doesn't hold up seeks
+                    let input = match source.open(&network) {
+                        Ok(input) => input,
+                        Err(_) => {
+                            fail_load(&state_handle, &signal_tx, &shared, generation);
+                            continue;
+                        }
+                    };
+                    if generation != shared.get_load_generation() {
+                        continue;
+                    }
+
+                    let live = is_network && radio::is_live(&input);
+                    shared.set_live(live);
+
                     let mut m_decoder = decoder_handle.lock().unwrap();
-                    m_decoder.format_ctx =
-                        Some(av::format::input(&url).expect("Failed to open file."));
+                    m_decoder.format_ctx = Some(input);
+                    m_decoder.live = match (live, &path) {
+                        (true, Some(url)) => Some(LiveStream::new(url.clone(), network.clone())),
+                        _ => None,
+                    };
+                    m_decoder
+                        .main_decoder_cancel_flag
+                        .store(false, Ordering::Relaxed);
 
-                    //Populate duration
-                    let sample_rate = *sample_rate_handle.lock().unwrap() as f64;
-                    let mut duration = duration_handle.lock().unwrap();
-                    let mut total_samples = total_samples_handle.lock().unwrap();
-                    *duration = m_decoder.format_ctx.as_mut().unwrap().duration() as f64
-                        / f64::from(av::ffi::AV_TIME_BASE);
-                    *total_samples = Some((*duration * sample_rate) as u64);
-                    set_total(total_samples.unwrap());
-
-                    let audio_stream_index = m_decoder
-                        .format_ctx
-                        .as_mut()
-                        .unwrap()
-                        .streams()
-                        .best(media::Type::Audio)
-                        .expect("No audio stream found.")
-                        .index();
+                    // Whatever the previous source got in before it noticed the cancel
+                    unsafe {
+                        sys::av_audio_fifo_reset(buffer_handle.lock().unwrap().0);
+                    }
+
+                    //Populate duration. Live streams don't have one
+                    let duration = if live {
+                        0.0
+                    } else {
+                        m_decoder.format_ctx.as_mut().unwrap().duration() as f64
+                            / f64::from(av::ffi::AV_TIME_BASE)
+                    };
+                    *duration_handle.lock().unwrap() = duration;
+
+                    m_decoder.audio_streams =
+                        list_audio_streams(m_decoder.format_ctx.as_ref().unwrap());
+
+                    // Manifests with several variants start on the one the selection picks
+                    let variant_selection = m_decoder.variant_selection;
+                    m_decoder.adaptive =
+                        Adaptive::new(m_decoder.format_ctx.as_mut().unwrap(), variant_selection);
+                    let requested = stream.filter(|index| {
+                        m_decoder
+                            .audio_streams
+                            .iter()
+                            .any(|audio_stream| audio_stream.index == *index)
+                    });
+                    let audio_stream_index = match (&m_decoder.adaptive, requested) {
+                        (Some(adaptive), _) => Some(adaptive.current() as usize),
+                        (None, Some(index)) => Some(index as usize),
+                        (None, None) => m_decoder
+                            .format_ctx
+                            .as_ref()
+                            .unwrap()
+                            .streams()
+                            .best(media::Type::Audio)
+                            .map(|stream| stream.index()),
+                    };
+                    let Some(audio_stream_index) = audio_stream_index else {
+                        m_decoder.format_ctx = None;
+                        shared.set_live(false);
+                        fail_load(&state_handle, &signal_tx, &shared, generation);
+                        continue;
+                    };
 
                     m_decoder.audio_stream_index = audio_stream_index;
-                    let codec_params = m_decoder
-                        .format_ctx
-                        .as_mut()
-                        .unwrap()
-                        .streams()
-                        .nth(audio_stream_index)
-                        .expect("Stream Disappeared")
-                        .parameters();
-
-                    let codec_ctx = av::codec::context::Context::from_parameters(codec_params)
-                        .expect("Failed to allocate codec context");
-
-                    m_decoder.decoder = codec_ctx
-                        .decoder()
-                        .audio()
-                        .expect("Failed to open decoder.");
-
-                    //This is just for sample size conversion since soxr only does resampling
-                    m_decoder.resampler = av::software::resampling::Context::get(
-                        m_decoder.decoder.format(),
-                        m_decoder.decoder.channel_layout(),
-                        m_decoder.decoder.rate(),
-                        av::format::Sample::I32(av::format::sample::Type::Packed),
-                        m_decoder.decoder.channel_layout(),
-                        m_decoder.decoder.rate(),
-                    )
-                    .expect("Failed to init resampler");
-
-                    //Actual resamppling happens here
-                    let soxr_runtime = RuntimeSpec::new(0).with_interpolation(Interpolation::High);
-
-                    m_decoder.soxr_resampler =
-                        Soxr::<format::Interleaved<i32, 2>>::new_with_params(
-                            m_decoder.decoder.rate() as f64,
-                            sample_rate,
-                            resampling_quality
-                                .get_quality_spec()
-                                .expect("Failed to get quality spec for soxr."),
-                            soxr_runtime,
+                    m_decoder.resampler_config = resampler_config;
+
+                    //Pick up gain tags. Stream tags win over container tags
+                    {
+                        let format_ctx = m_decoder.format_ctx.as_ref().unwrap();
+                        let stream = format_ctx
+                            .stream(audio_stream_index)
+                            .expect("Stream Disappeared");
+                        let info = ReplayGainInfo::from_metadata(&[
+                            stream.metadata(),
+                            format_ctx.metadata(),
+                        ]);
+
+                        replay_gain_handle.lock().unwrap().set_info(info);
+                        // A scan would download a network source a second time, without the network settings,
+                        // and would never finish on a live stream. HLS/DASH variants can't be measured as one file
+                        let scannable = !is_network && !live && m_decoder.adaptive.is_none();
+                        let scan_path = path.clone().filter(|_| scannable);
+                        loudness_handle.lock().unwrap().set_current(scan_path);
+                        refresh_track_gain(
+                            replay_gain_handle.clone(),
+                            loudness_handle.clone(),
+                            shared.clone(),
+                        );
+                        // Nothing of this track has played yet, so there's nothing to glide from
+                        shared.step_track_gain();
+                    }
+                    let input = m_decoder.format_ctx.take().unwrap();
+                    let opened = open_codec(&mut m_decoder, &input, audio_stream_index);
+                    m_decoder.format_ctx = Some(input);
+
+                    // Bit-perfect follows the track when the device can. Everything else is resampled
+                    // to the device's default
+                    let opened = opened.and_then(|_| {
+                        let native = match (m_decoder.bit_perfect, m_decoder.decoder.as_ref()) {
+                            (true, Some(decoder)) => cpal::default_host()
+                                .default_output_device()
+                                .and_then(|device| native_format(&device, decoder)),
+                            _ => None,
+                        };
+                        let output_format = native.unwrap_or(m_decoder.default_output);
+                        m_decoder.output_format = output_format;
+                        *sample_rate_handle.lock().unwrap() = output_format.sample_rate as i32;
+
+                        if output_format.bit_perfect {
+                            m_decoder.soxr_resampler = None;
+                            return Ok(());
+                        }
+                        //Actual resamppling happens here
+                        open_resampler(
+                            &mut m_decoder,
+                            output_format.sample_rate as f64,
+                            resampler_config,
                         )
-                        .expect("Failed to setup soxr");
-
-                    //Prime the resampler. At higher quality levels there's artifacting at the start due to lack of previous data
-                    let silence: Vec<[i32; 2]> = vec![[0, 0]; (m_decoder.decoder.rate()) as usize];
-                    let mut dummy_output: Vec<[i32; 2]> =
-                        vec![[0, 0]; (m_decoder.decoder.rate()) as usize];
-                    _ = m_decoder
-                        .soxr_resampler
-                        .process(&silence, &mut dummy_output);
-
-                    let mut state = state_handle.lock().unwrap();
-                    *state = PlayerState::INITIALISED;
+                    });
+                    if opened.is_err() {
+                        m_decoder.format_ctx = None;
+                        shared.set_live(false);
+                        fail_load(&state_handle, &signal_tx, &shared, generation);
+                        continue;
+                    }
+
+                    let sample_rate = *sample_rate_handle.lock().unwrap() as f64;
+                    let total = (!live).then(|| (duration * sample_rate) as u64);
+                    *total_samples_handle.lock().unwrap() = total;
+                    set_total(total.unwrap_or(0));
+
+                    let info = TrackInfo::new(
+                        m_decoder.format_ctx.as_ref().unwrap(),
+                        audio_stream_index,
+                        path,
+                        duration,
+                        live,
+                    );
+                    m_decoder.track = Some(info.clone());
                     drop(m_decoder);
 
+                    // Cleared while setting up
+                    if generation != shared.get_load_generation()
+                        || !set_state(&state_handle, PlayerState::LOADED, &signal_tx)
+                    {
+                        continue;
+                    }
+                    _ = signal_tx.send(EngineSignal::Event(PlayerEvent::Loaded { info }));
+
                     _ = decode(
                         decoder_handle.clone(),
                         sample_rate_handle.clone(),
                         buffer_handle.clone(),
                         target_buffer_size,
+                        &shared,
+                        &signal_tx,
                     );
                 } else if let CMD::Resume = cmd {
                     _ = decode(
@@ -426,6 +1596,8 @@ impl AudioEngine {
                         sample_rate_handle.clone(),
                         buffer_handle.clone(),
                         target_buffer_size,
+                        &shared,
+                        &signal_tx,
                     );
                 } else if let CMD::FillBuffer = cmd {
                     let current_size =
@@ -437,103 +1609,296 @@ impl AudioEngine {
                             sample_rate_handle.clone(),
                             buffer_handle.clone(),
                             target_buffer_size,
+                            &shared,
+                            &signal_tx,
                         );
                     }
                 }
             }
-        });
 
-        Ok(())
+            // Shut down. Free ffmpeg's contexts here rather than wherever the last handle goes
+            decoder_handle.lock().unwrap().close();
+        })
     }
 }
 
 impl Drop for AudioEngine {
     fn drop(&mut self) {
-        let _ = self.pause();
-        unsafe {
-            if !self.buffer.lock().unwrap().0.is_null() {
-                sys::av_audio_fifo_free(self.buffer.lock().unwrap().0);
-            }
-        }
+        join_threads(self.shutdown());
     }
 }
 
 unsafe impl Send for AudioEngine {}
 unsafe impl Sync for AudioEngine {}
 
-fn build_stream(
+//Sets the state and tells the app if it changed. False if the state machine doesn't allow the change
+fn set_state(
+    state: &Mutex<PlayerState>,
+    new_state: PlayerState,
+    signal_tx: &Sender<EngineSignal>,
+) -> bool {
+    let mut state = state.lock().unwrap();
+    if !state.can_change_to(new_state) {
+        return false;
+    }
+    if *state != new_state {
+        *state = new_state;
+        _ = signal_tx.send(EngineSignal::Event(PlayerEvent::StateChanged {
+            state: new_state,
+        }));
+    }
+    true
+}
+
+//A load that can't go ahead. Nothing is coming, so stop waiting for it and tell the app. A load that was
+//superseded in the meantime isn't the app's concern any more
+fn fail_load(
+    state: &Mutex<PlayerState>,
+    signal_tx: &Sender<EngineSignal>,
+    shared: &SharedState,
+    generation: u64,
+) {
+    if generation != shared.get_load_generation() {
+        return;
+    }
+    set_state(state, PlayerState::EMPTY, signal_tx);
+    if shared.set_buffering(false) {
+        _ = signal_tx.send(EngineSignal::Event(PlayerEvent::BufferingEnded));
+    }
+    _ = signal_tx.send(EngineSignal::Event(PlayerEvent::LoadFailed));
+}
+
+///Waits for the engine's threads to end. The caller can be one of them, e.g. when the last reference to the
+///player goes in a callback, and that one finishes on its own
+pub fn join_threads(threads: Vec<JoinHandle<()>>) {
+    let current = thread::current().id();
+    for thread in threads {
+        if thread.thread().id() != current {
+            _ = thread.join();
+        }
+    }
+}
+
+fn default_device_name() -> Option<String> {
+    let device = cpal::default_host().default_output_device()?;
+    device
+        .description()
+        .ok()
+        .map(|description| description.name().to_string())
+}
+
+///Works out the track gain from the tags or the loudness measurement and kicks off a scan if one is needed.
+///Tags win when ReplayGain is on
+fn refresh_track_gain(
+    replay_gain: Arc<Mutex<ReplayGain>>,
+    loudness: Arc<Mutex<Loudness>>,
+    shared: Arc<SharedState>,
+) {
+    let tagged_gain = replay_gain.lock().unwrap().gain();
+
+    let mut m_loudness = loudness.lock().unwrap();
+    shared.set_track_gain(tagged_gain.or(m_loudness.gain()).unwrap_or(1.0));
+
+    if tagged_gain.is_some() || !m_loudness.needs_scan() {
+        return;
+    }
+
+    let (path, cancel_flag) = match m_loudness.begin_scan() {
+        Some(scan) => scan,
+        None => return,
+    };
+    drop(m_loudness);
+
+    let scan_loudness = loudness.clone();
+    let scan = loudness::spawn_scan(path, cancel_flag, move |path, info| {
+        scan_loudness.lock().unwrap().insert(path, info);
+
+        // Worked out from whatever is current, so a late scan can't put its gain on another track
+        let tagged_gain = replay_gain.lock().unwrap().gain();
+        let m_loudness = scan_loudness.lock().unwrap();
+        if tagged_gain.is_none() {
+            shared.set_track_gain(m_loudness.gain().unwrap_or(1.0));
+        }
+    });
+    loudness.lock().unwrap().add_scan_thread(scan);
+}
+
+//Builds the stream for the format's sample type. Formats the output stage can't write are asked for as i32
+fn open_stream(
+    device: &cpal::Device,
+    format: OutputFormat,
+    buffer: Arc<Mutex<AudioFifo>>,
+    mut output_stage: OutputStage,
+    shared: Arc<SharedState>,
+    signal_tx: Sender<EngineSignal>,
+) -> Result<Stream, i32> {
+    let config = format.config();
+    // Buffers are allocated here for the biggest block the device asks for, never in the callback
+    let frames = format
+        .max_buffer_frames(device)
+        .unwrap_or(DEFAULT_BLOCK_FRAMES);
+    output_stage.reserve(frames);
+
+    match format.sample_format {
+        SampleFormat::I16 => build_stream::<i16>(
+            device,
+            config,
+            frames,
+            buffer,
+            output_stage,
+            shared,
+            signal_tx,
+        ),
+        SampleFormat::I24 => build_stream::<I24>(
+            device,
+            config,
+            frames,
+            buffer,
+            output_stage,
+            shared,
+            signal_tx,
+        ),
+        SampleFormat::F32 => build_stream::<f32>(
+            device,
+            config,
+            frames,
+            buffer,
+            output_stage,
+            shared,
+            signal_tx,
+        ),
+        _ => build_stream::<i32>(
+            device,
+            config,
+            frames,
+            buffer,
+            output_stage,
+            shared,
+            signal_tx,
+        ),
+    }
+}
+
+fn build_stream<T: OutputSample>(
     device: &cpal::Device,
     config: cpal::StreamConfig,
+    block_frames: usize,
     buffer: Arc<Mutex<AudioFifo>>,
+    mut output_stage: OutputStage,
+    shared: Arc<SharedState>,
     signal_tx: Sender<EngineSignal>,
 ) -> Result<Stream, i32> {
-    let stream = device
-        .build_output_stream(
-            &config,
-            move |data: &mut [i32], _: &cpal::OutputCallbackInfo| {
-                unsafe {
-                    let buffer_guard = match buffer.lock() {
-                        Ok(guard) => guard,
-                        Err(_) => {
-                            // Lock contention, zero fill
-                            data.fill(0);
-                            return;
-                        }
-                    };
+    let error_tx = signal_tx.clone();
 
-                    let fifo = buffer_guard.0;
-                    if fifo.is_null() {
-                        data.fill(0);
-                        return;
-                    }
+    // Everything up to the device works on i32, the device's own type only comes in at the end. offset is how
+    // many frames of this callback came before data
+    let sample_rate = config.sample_rate;
+    let mut scratch: Vec<i32> = vec![0; block_frames * 2];
+    let mut fill = move |data: &mut [i32], info: &cpal::OutputCallbackInfo, offset: usize| {
+        unsafe {
+            let buffer_guard = match buffer.lock() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    // Lock contention, zero fill
+                    data.fill(0);
+                    return;
+                }
+            };
 
-                    let available = sys::av_audio_fifo_size(fifo);
-                    let frames_to_read = available.min(data.len() as i32 / 2); // 2 channels
-
-                    if frames_to_read > 0 {
-                        let mut data_ptrs = [data.as_mut_ptr() as *mut c_void];
-                        let got =
-                            sys::av_audio_fifo_read(fifo, data_ptrs.as_mut_ptr(), frames_to_read);
-
-                        if got > 0 {
-                            add_played(got as u64);
-
-                            // Apply volume
-                            let vol = f_get_volume();
-                            if vol != 1.0 {
-                                for sample in &mut data[..((got as usize) * 2)] {
-                                    let s = *sample as f32;
-                                    *sample =
-                                        (s * vol).clamp(i32::MIN as f32, i32::MAX as f32) as i32;
-                                }
-                            }
+            let fifo = buffer_guard.0;
+            if fifo.is_null() {
+                data.fill(0);
+                return;
+            }
 
-                            // Zero fill remaining
-                            if got < frames_to_read {
-                                let start = (got as usize) * 2;
-                                data[start..].fill(0);
-                            }
-                        } else {
-                            data.fill(0);
-                        }
+            let available = sys::av_audio_fifo_size(fifo);
+            let frames_wanted = data.len() as i32 / 2; // 2 channels
+            let frames_to_read = available.min(frames_wanted);
 
-                        // Check for low buffer
-                        if available < (config.sample_rate as i32 * 5) && !get_decoder_eof() {
-                            _ = signal_tx.try_send(EngineSignal::BufferLow);
-                        }
-                    } else {
-                        data.fill(0);
+            // Held back while a network source catches up. The decoder says when it has
+            let buffering = shared.get_buffering();
+            if buffering {
+                data.fill(0);
+                if !get_decoder_eof() {
+                    _ = signal_tx.try_send(EngineSignal::BufferLow);
+                }
+            } else if frames_to_read > 0 {
+                let mut data_ptrs = [data.as_mut_ptr() as *mut c_void];
+                let got = sys::av_audio_fifo_read(fifo, data_ptrs.as_mut_ptr(), frames_to_read);
+
+                if got > 0 {
+                    add_played(got as u64);
+
+                    // EQ, processors and volume
+                    output_stage.process(&mut data[..((got as usize) * 2)], sample_rate);
+                    output_stage.dither(&mut data[..((got as usize) * 2)], T::BITS);
+
+                    // Zero fill remaining
+                    if got < frames_to_read {
+                        let start = (got as usize) * 2;
+                        data[start..].fill(0);
                     }
+                } else {
+                    data.fill(0);
+                }
 
-                    // Check for EOF. The decoder is done AND the buffer is fully drained.
-                    let remaining = sys::av_audio_fifo_size(fifo);
-                    if get_decoder_eof() && remaining == 0 {
-                        _ = signal_tx.try_send(EngineSignal::MediaEnd);
+                // Check for low buffer
+                if available < shared.get_low_water_frames() as i32 && !get_decoder_eof() {
+                    _ = signal_tx.try_send(EngineSignal::BufferLow);
+                }
+            } else {
+                data.fill(0);
+            }
+
+            // Ran dry before the end. Reported once per underrun, not once per callback
+            let starved = frames_to_read < frames_wanted && !get_decoder_eof();
+            if !starved {
+                shared.set_underrun(false);
+            } else if !buffering && !shared.set_underrun(true) {
+                _ = signal_tx.try_send(EngineSignal::Event(PlayerEvent::BufferUnderrun));
+            }
+
+            // Sources that can stall wait for some audio rather than stuttering
+            if starved && shared.get_rebuffer_frames() > 0 && !shared.set_buffering(true) {
+                _ = signal_tx.try_send(EngineSignal::Event(PlayerEvent::Buffering));
+            }
+
+            // Visualiser tap, stamped with when this buffer reaches the speakers
+            let timestamp = info.timestamp();
+            let delay = timestamp
+                .playback
+                .duration_since(&timestamp.callback)
+                .unwrap_or_default()
+                + Duration::from_secs_f64(offset as f64 / sample_rate.max(1) as f64);
+            output_stage.capture(data, sample_rate, delay);
+
+            // Check for EOF. The decoder is done AND the buffer is fully drained.
+            let remaining = sys::av_audio_fifo_size(fifo);
+            if get_decoder_eof() && remaining == 0 {
+                _ = signal_tx.try_send(EngineSignal::MediaEnd);
+            }
+        }
+    };
+
+    let stream = device
+        .build_output_stream(
+            &config,
+            move |out: &mut [T], info: &cpal::OutputCallbackInfo| {
+                // A bigger block than the device said it would ask for is filled in pieces
+                let block = scratch.len();
+                for (index, out) in out.chunks_mut(block).enumerate() {
+                    let data = &mut scratch[..out.len()];
+                    fill(data, info, index * block / 2);
+
+                    for (out, sample) in out.iter_mut().zip(data.iter()) {
+                        *out = T::from_i32(*sample);
                     }
                 }
             },
-            |err| {
-                eprintln!("Stream error: {}", err);
+            move |_| {
+                _ = error_tx.try_send(EngineSignal::Event(PlayerEvent::Error {
+                    error: PlayerError::Code(-1),
+                }));
             },
             None,
         )
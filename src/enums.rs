@@ -1,14 +1,37 @@
+use crate::{
+    crossfeed::Crossfeed,
+    dsp::{AudioProcessor, Gain},
+    equalizer::{EqBand, Equalizer},
+    events::PlayerEvent,
+    limiter::Limiter,
+    network::NetworkConfig,
+    resampler::ResamplerConfig,
+    source::MediaSource,
+};
+
 use soxr_ax::params::{QualityFlags, QualityRecipe, QualitySpec};
 use std::fmt;
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
 pub enum PlayerState {
-    LOADING = 0,
-    LOADED,
+    LOADING = 0, // Opening the source
+    LOADED,      // Ready to play, nothing has been played yet
     PLAYING,
     PAUSED,
-    EMPTY,
-    INITIALISED,
+    EMPTY, // Nothing loaded, or the load failed
+}
+
+impl PlayerState {
+    ///Clearing and loading work from anywhere, everything else follows a load
+    pub fn can_change_to(self, next: PlayerState) -> bool {
+        match (self, next) {
+            (_, PlayerState::EMPTY | PlayerState::LOADING) => true,
+            (PlayerState::LOADING, PlayerState::LOADED) => true,
+            (PlayerState::LOADED | PlayerState::PAUSED, PlayerState::PLAYING) => true,
+            (PlayerState::PLAYING, PlayerState::PAUSED) => true,
+            _ => self == next,
+        }
+    }
 }
 
 impl fmt::Display for PlayerState {
@@ -19,40 +42,72 @@ impl fmt::Display for PlayerState {
             PlayerState::PLAYING => "playing",
             PlayerState::PAUSED => "paused",
             PlayerState::EMPTY => "empty",
-            PlayerState::INITIALISED => "initialised",
         };
         write!(f, "{}", s)
     }
 }
 
-#[derive(PartialEq, uniffi::Enum)]
+//What the engine's threads tell the listening thread. Events are passed on to the app as they are
+#[derive(PartialEq)]
 pub enum EngineSignal {
     MediaEnd,
     BufferLow,
+    Event(PlayerEvent),
+    Stop, // Sent by shutdown, ends the listening thread
 }
-#[derive(PartialEq)]
 pub enum CMD {
-    Start(String, ResamplingQuality),
+    Start(
+        MediaSource,
+        ResamplerConfig,
+        NetworkConfig,
+        Option<u32>,
+        u64,
+    ), // Audio stream to play, None picks the best one. Then the load generation
     Resume,
     FillBuffer,
 }
 
-#[derive(uniffi::Error, Debug)]
+#[derive(Clone, PartialEq, uniffi::Error, Debug)]
 pub enum PlayerError {
     Code(i32),
+    InvalidState, // Not possible in the current state, e.g. seeking with nothing loaded. Check get_state
+}
+
+impl PlayerError {
+    ///Code for an argument out of range or not recognised
+    pub const INVALID_ARGUMENT: i32 = -2;
+    ///Code the engine and the C API use for InvalidState
+    pub const INVALID_STATE: i32 = -3;
+
+    pub fn code(&self) -> i32 {
+        match self {
+            PlayerError::Code(c) => *c,
+            PlayerError::InvalidState => Self::INVALID_STATE,
+        }
+    }
+}
+
+impl From<i32> for PlayerError {
+    fn from(code: i32) -> Self {
+        match code {
+            Self::INVALID_STATE => PlayerError::InvalidState,
+            c => PlayerError::Code(c),
+        }
+    }
 }
 
 impl fmt::Display for PlayerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PlayerError::Code(c) => write!(f, "PlayerError code {}", c),
+            PlayerError::InvalidState => write!(f, "PlayerError invalid state"),
         }
     }
 }
 
 impl std::error::Error for PlayerError {}
 
-#[derive(Clone, Copy, PartialEq, uniffi::Enum)]
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
 pub enum ResamplingQuality {
     Quick = 0,
     Low,
@@ -62,31 +117,254 @@ pub enum ResamplingQuality {
 }
 
 impl ResamplingQuality {
-    pub fn get_quality_spec(&self) -> Result<QualitySpec, PlayerError> {
-        #[allow(unused)]
-        let mut recipe = QualityRecipe::default();
+    pub fn recipe(&self) -> QualityRecipe {
         match self {
-            Self::Quick => {
-                recipe = QualityRecipe::Quick;
+            Self::Quick => QualityRecipe::Quick,
+            Self::Low => QualityRecipe::Low,
+            Self::Medium => QualityRecipe::Medium,
+            Self::High => QualityRecipe::high(),
+            Self::VeryHigh => QualityRecipe::very_high(),
+        }
+    }
+
+    pub fn get_quality_spec(&self) -> QualitySpec {
+        QualitySpec::configure(
+            self.recipe(),
+            soxr_ax::params::Rolloff::Small,
+            QualityFlags::HighPrecisionClock | QualityFlags::DoublePrecision,
+        )
+    }
+}
+
+///Processors that ship with the engine. Lets the bindings build a chain without writing native DSP. They work
+///like the player's own EQ, crossfeed and limiter, but as many as needed and wherever they go in the chain
+#[derive(Clone, PartialEq, Debug, uniffi::Enum)]
+pub enum BuiltinEffect {
+    Gain {
+        gain_db: f32,
+    },
+    Equalizer {
+        bands: Vec<EqBand>,
+        preamp_db: f32,
+    },
+    Crossfeed {
+        cutoff: f32,
+        feed_db: f32,
+    },
+    Limiter {
+        ceiling_db: f32,
+        knee_db: f32,
+        release_ms: f32,
+    },
+}
+
+impl BuiltinEffect {
+    ///Fails with the setting's error if a value is out of range
+    pub fn build(&self) -> Result<Box<dyn AudioProcessor>, i32> {
+        match self {
+            Self::Gain { gain_db } => Ok(Box::new(Gain::new(*gain_db))),
+            Self::Equalizer { bands, preamp_db } => {
+                let mut equalizer = Equalizer::new();
+                equalizer.set_bands(bands.clone())?;
+                equalizer.set_preamp(*preamp_db)?;
+                equalizer.set_enabled(true);
+                Ok(Box::new(equalizer))
             }
-            Self::Low => {
-                recipe = QualityRecipe::Low;
+            Self::Crossfeed { cutoff, feed_db } => {
+                let mut crossfeed = Crossfeed::new();
+                crossfeed.set_cutoff(*cutoff)?;
+                crossfeed.set_feed(*feed_db)?;
+                crossfeed.set_enabled(true);
+                Ok(Box::new(crossfeed))
             }
-            Self::Medium => {
-                recipe = QualityRecipe::Medium;
+            Self::Limiter {
+                ceiling_db,
+                knee_db,
+                release_ms,
+            } => {
+                let mut limiter = Limiter::new();
+                limiter.set_ceiling(*ceiling_db)?;
+                limiter.set_knee(*knee_db)?;
+                limiter.set_release(*release_ms)?;
+                limiter.set_enabled(true);
+                Ok(Box::new(limiter))
             }
-            Self::High => {
-                recipe = QualityRecipe::high();
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum FilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+///Graphic EQ presets. Gains live in equalizer.rs
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum EqPreset {
+    Flat,
+    BassBoost,
+    BassCut,
+    TrebleBoost,
+    TrebleCut,
+    Vocal,
+    Rock,
+    Pop,
+    Jazz,
+    Classical,
+    Electronic,
+    Loudness,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+///Frequency axis of the spectrum analyser
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum SpectrumScale {
+    Linear,
+    Logarithmic,
+}
+
+///Noise shaping for dithered output. Filters live in dither.rs
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum NoiseShaping {
+    Flat,      // Plain TPDF, the noise spread evenly
+    Simple,    // First order, tilts the noise towards the top
+    Lipshitz,  // 5 taps, E-weighted
+    FWeighted, // 9 taps, follows the ear's sensitivity at low levels
+}
+
+///Crossfeed settings from bs2b. Settings live in crossfeed.rs
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum CrossfeedPreset {
+    Default,  // 700 Hz, 4.5 dB
+    ChuMoy,   // 700 Hz, 6 dB
+    JanMeier, // 650 Hz, 9.5 dB
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_transitions() {
+        use PlayerState::*;
+
+        // Rows are the current state, columns the next in the order of ALL
+        const ALL: [PlayerState; 5] = [EMPTY, LOADING, LOADED, PLAYING, PAUSED];
+        let allowed = [
+            (EMPTY, [true, true, false, false, false]),
+            (LOADING, [true, true, true, false, false]),
+            (LOADED, [true, true, true, true, false]),
+            (PLAYING, [true, true, false, true, true]),
+            (PAUSED, [true, true, false, true, true]),
+        ];
+
+        for (from, row) in allowed {
+            for (to, expected) in ALL.into_iter().zip(row) {
+                assert_eq!(from.can_change_to(to), expected, "{from} -> {to}");
             }
-            Self::VeryHigh => {
-                recipe = QualityRecipe::very_high();
+        }
+    }
+
+    #[test]
+    fn builtin_effects_check_their_settings() {
+        let eq_band = |gain_db| EqBand {
+            filter_type: FilterType::Peaking,
+            frequency: 1000.0,
+            gain_db,
+            q: 1.0,
+        };
+
+        let valid = [
+            BuiltinEffect::Gain { gain_db: -6.0 },
+            BuiltinEffect::Equalizer {
+                bands: vec![eq_band(3.0)],
+                preamp_db: -3.0,
+            },
+            BuiltinEffect::Crossfeed {
+                cutoff: 700.0,
+                feed_db: 4.5,
+            },
+            BuiltinEffect::Limiter {
+                ceiling_db: -1.0,
+                knee_db: 2.0,
+                release_ms: 100.0,
+            },
+        ];
+        assert!(valid.iter().all(|effect| effect.build().is_ok()));
+
+        let invalid = [
+            BuiltinEffect::Equalizer {
+                bands: vec![eq_band(f32::NAN)],
+                preamp_db: 0.0,
+            },
+            BuiltinEffect::Crossfeed {
+                cutoff: 50.0,
+                feed_db: 4.5,
+            },
+            BuiltinEffect::Limiter {
+                ceiling_db: 3.0,
+                knee_db: 2.0,
+                release_ms: 100.0,
+            },
+        ];
+        assert!(invalid.iter().all(|effect| effect.build().is_err()));
+    }
+
+    #[test]
+    fn builtin_effects_process_in_the_chain() {
+        const RATE: u32 = 48000;
+        let mut processors = crate::dsp::ChainControl::new();
+        processors.prepare(RATE);
+        let limiter = BuiltinEffect::Limiter {
+            ceiling_db: -6.0,
+            knee_db: 0.0,
+            release_ms: 100.0,
+        };
+        processors.insert(None, limiter.build().unwrap()).unwrap();
+        assert!(processors.latency() > 0);
+
+        // A full scale square wave comes out under the ceiling once the limiter has faded in
+        let live = processors.live();
+        let mut live = live.lock().unwrap();
+        live.apply_edits();
+        let mut peak = 0.0f32;
+        for block in 0..50 {
+            let mut frames: Vec<[f32; 2]> = (0..480)
+                .map(|i| if i % 2 == 0 { [1.0, -1.0] } else { [-1.0, 1.0] })
+                .collect();
+            live.process(&mut frames, RATE);
+            if block >= 10 {
+                peak = frames
+                    .iter()
+                    .fold(peak, |peak, frame| peak.max(frame[0].abs()));
             }
         }
+        assert!(
+            peak > 0.4 && peak <= crate::dsp::db_to_linear(-6.0) + 1e-4,
+            "{peak}"
+        );
+    }
 
-        Ok(QualitySpec::configure(
-            recipe,
-            soxr_ax::params::Rolloff::Small,
-            QualityFlags::HighPrecisionClock | QualityFlags::DoublePrecision,
-        ))
+    #[test]
+    fn error_codes_round_trip() {
+        assert_eq!(
+            PlayerError::from(PlayerError::INVALID_STATE),
+            PlayerError::InvalidState
+        );
+        assert_eq!(PlayerError::InvalidState.code(), PlayerError::INVALID_STATE);
+        assert_eq!(
+            PlayerError::from(PlayerError::INVALID_ARGUMENT),
+            PlayerError::Code(PlayerError::INVALID_ARGUMENT)
+        );
     }
 }
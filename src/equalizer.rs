@@ -0,0 +1,625 @@
+//equalizer.rs
+
+use crate::{
+    dsp::{AudioProcessor, db_to_linear},
+    enums::{EqPreset, FilterType},
+    handoff::Stage,
+};
+
+use std::{f32::consts::FRAC_1_SQRT_2, f64::consts::PI};
+
+//Centre frequencies of the graphic EQ. One band per octave
+pub const GRAPHIC_EQ_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+//Q for one octave wide peaking bands
+const GRAPHIC_EQ_Q: f32 = 1.41;
+
+pub const MAX_EQ_BANDS: usize = 32;
+pub const MAX_EQ_GAIN_DB: f32 = 24.0;
+
+//Time constant coefficient changes glide over so slider moves don't zip
+const COEFFICIENT_SMOOTHING_S: f64 = 0.01;
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct EqBand {
+    pub filter_type: FilterType,
+    pub frequency: f32, // Hz
+    pub gain_db: f32,   // Ignored by the pass filters
+    pub q: f32,
+}
+
+impl EqBand {
+    ///Largest lift the band gives any frequency, in dB
+    pub fn peak_db(&self) -> f32 {
+        match self.filter_type {
+            FilterType::Peaking | FilterType::LowShelf | FilterType::HighShelf => {
+                self.gain_db.max(0.0)
+            }
+            // Resonant pass filters peak around the cutoff
+            FilterType::LowPass | FilterType::HighPass if self.q > FRAC_1_SQRT_2 => {
+                let q = self.q as f64;
+                (20.0 * (q / (1.0 - 1.0 / (4.0 * q * q)).sqrt()).log10()) as f32
+            }
+            FilterType::LowPass | FilterType::HighPass => 0.0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), i32> {
+        if !self.frequency.is_finite() || self.frequency <= 0.0 {
+            return Err(-1);
+        }
+        if !self.q.is_finite() || self.q <= 0.0 {
+            return Err(-1);
+        }
+        if !self.gain_db.is_finite() || self.gain_db.abs() > MAX_EQ_GAIN_DB {
+            return Err(-1);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    const IDENTITY: Coefficients = Coefficients {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+
+    //RBJ audio EQ cookbook
+    fn from_band(band: &EqBand, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f64;
+
+        // Keep the centre below nyquist or the filter blows up
+        let frequency = (band.frequency as f64).min(sample_rate * 0.49);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * band.q as f64);
+        let a = 10f64.powf(band.gain_db as f64 / 40.0);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter_type {
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos_w0,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos_w0,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                a * ((a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                (a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha,
+            ),
+            FilterType::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos_w0 + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                a * ((a + 1.0) + (a - 1.0) * cos_w0 - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos_w0 + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                (a + 1.0) - (a - 1.0) * cos_w0 - sqrt_a_alpha,
+            ),
+            FilterType::LowPass => (
+                (1.0 - cos_w0) / 2.0,
+                1.0 - cos_w0,
+                (1.0 - cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::HighPass => (
+                (1.0 + cos_w0) / 2.0,
+                -(1.0 + cos_w0),
+                (1.0 + cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+        };
+
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    fn approach(&mut self, target: &Coefficients, k: f64) {
+        self.b0 += (target.b0 - self.b0) * k;
+        self.b1 += (target.b1 - self.b1) * k;
+        self.b2 += (target.b2 - self.b2) * k;
+        self.a1 += (target.a1 - self.a1) * k;
+        self.a2 += (target.a2 - self.a2) * k;
+    }
+}
+
+///Transposed direct form II biquad with one state pair per channel
+#[derive(Clone, Copy)]
+struct Biquad {
+    current: Coefficients,
+    target: Coefficients,
+    state: [[f64; 2]; 2],
+}
+
+impl Biquad {
+    fn new() -> Self {
+        Biquad {
+            current: Coefficients::IDENTITY,
+            target: Coefficients::IDENTITY,
+            state: [[0.0; 2]; 2],
+        }
+    }
+
+    fn reset(&mut self) {
+        self.current = self.target;
+        self.state = [[0.0; 2]; 2];
+    }
+
+    #[inline]
+    fn tick(&mut self, channel: usize, x: f64) -> f64 {
+        let c = &self.current;
+        let s = &mut self.state[channel];
+        let y = c.b0 * x + s[0];
+        s[0] = c.b1 * x - c.a1 * y + s[1];
+        s[1] = c.b2 * x - c.a2 * y;
+        y
+    }
+}
+
+#[derive(Clone)]
+struct BandState {
+    band: EqBand,
+    filter: Biquad,
+    dirty: bool,
+}
+
+///Multi-band parametric EQ
+#[derive(Clone)]
+pub struct Equalizer {
+    enabled: bool,
+    preamp_db: f32,
+    bands: Vec<BandState>,
+    sample_rate: u32,
+    smoothing: f64,
+}
+
+impl Equalizer {
+    pub fn new() -> Self {
+        Equalizer {
+            enabled: false,
+            preamp_db: 0.0,
+            bands: Vec::new(),
+            sample_rate: 0,
+            smoothing: 1.0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.enabled {
+            self.reset();
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn get_preamp(&self) -> f32 {
+        self.preamp_db
+    }
+
+    pub fn set_preamp(&mut self, preamp_db: f32) -> Result<(), i32> {
+        if !preamp_db.is_finite() || preamp_db.abs() > MAX_EQ_GAIN_DB {
+            return Err(-1);
+        }
+        self.preamp_db = preamp_db;
+        Ok(())
+    }
+
+    ///Most the EQ can lift the signal by, in dB. Overlapping bands add up, so this adds every band's peak
+    pub fn max_gain_db(&self) -> f32 {
+        let boost: f32 = self.bands.iter().map(|state| state.band.peak_db()).sum();
+        self.preamp_db + boost
+    }
+
+    pub fn get_bands(&self) -> Vec<EqBand> {
+        self.bands.iter().map(|state| state.band).collect()
+    }
+
+    ///Replaces every band. Filters with the same index glide to their new response instead of restarting
+    pub fn set_bands(&mut self, bands: Vec<EqBand>) -> Result<(), i32> {
+        if bands.len() > MAX_EQ_BANDS {
+            return Err(-1);
+        }
+        for band in &bands {
+            band.validate()?;
+        }
+
+        self.bands.truncate(bands.len());
+        for (index, band) in bands.into_iter().enumerate() {
+            if let Some(state) = self.bands.get_mut(index) {
+                state.band = band;
+                state.dirty = true;
+            } else {
+                self.bands.push(BandState {
+                    band,
+                    filter: Biquad::new(),
+                    dirty: true,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_band(&mut self, index: usize, band: EqBand) -> Result<(), i32> {
+        band.validate()?;
+        let state = self.bands.get_mut(index).ok_or(-1)?;
+        state.band = band;
+        state.dirty = true;
+        Ok(())
+    }
+
+    ///Switches to the 10 band graphic EQ with the given gains, lowest band first
+    pub fn set_graphic(&mut self, gains: &[f32]) -> Result<(), i32> {
+        self.set_bands(graphic_bands(gains)?)
+    }
+
+    pub fn apply_preset(&mut self, preset: EqPreset) {
+        // Presets are in range by construction
+        _ = self.set_graphic(&preset.gains());
+    }
+
+    fn update_coefficients(&mut self, sample_rate: u32) {
+        let rate_changed = sample_rate != self.sample_rate;
+        if rate_changed {
+            self.sample_rate = sample_rate;
+            self.smoothing = 1.0 - (-1.0 / (COEFFICIENT_SMOOTHING_S * sample_rate as f64)).exp();
+        }
+
+        for state in &mut self.bands {
+            if state.dirty || rate_changed {
+                state.filter.target = Coefficients::from_band(&state.band, sample_rate);
+                state.dirty = false;
+
+                // A new rate means the old state is meaningless, jump straight there
+                if rate_changed {
+                    state.filter.reset();
+                }
+            }
+        }
+    }
+}
+
+///Bands of the 10 band graphic EQ for the given gains, lowest band first
+pub fn graphic_bands(gains: &[f32]) -> Result<Vec<EqBand>, i32> {
+    if gains.len() != GRAPHIC_EQ_FREQUENCIES.len() {
+        return Err(-1);
+    }
+
+    Ok(GRAPHIC_EQ_FREQUENCIES
+        .iter()
+        .zip(gains)
+        .map(|(frequency, gain_db)| EqBand {
+            filter_type: FilterType::Peaking,
+            frequency: *frequency,
+            gain_db: *gain_db,
+            q: GRAPHIC_EQ_Q,
+        })
+        .collect())
+}
+
+impl Default for Equalizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioProcessor for Equalizer {
+    fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        if !self.enabled || sample_rate == 0 {
+            return;
+        }
+
+        self.update_coefficients(sample_rate);
+
+        let preamp = db_to_linear(self.preamp_db) as f64;
+        let k = self.smoothing;
+
+        for frame in frames.iter_mut() {
+            let mut left = frame[0] as f64 * preamp;
+            let mut right = frame[1] as f64 * preamp;
+
+            for state in &mut self.bands {
+                let filter = &mut state.filter;
+                if filter.current != filter.target {
+                    let target = filter.target;
+                    filter.current.approach(&target, k);
+                }
+                left = filter.tick(0, left);
+                right = filter.tick(1, right);
+            }
+
+            frame[0] = left as f32;
+            frame[1] = right as f32;
+        }
+    }
+
+    fn reset(&mut self) {
+        for state in &mut self.bands {
+            state.filter.reset();
+        }
+    }
+}
+
+impl Stage for Equalizer {
+    fn follow(&mut self, control: &Self) {
+        self.set_enabled(control.enabled);
+        self.preamp_db = control.preamp_db;
+
+        // Room for every band is reserved in prepare, so this never allocates
+        self.bands.truncate(control.bands.len());
+        for (index, band) in control.bands.iter().map(|state| state.band).enumerate() {
+            match self.bands.get_mut(index) {
+                Some(state) if state.band == band => {}
+                Some(state) => {
+                    state.band = band;
+                    state.dirty = true;
+                }
+                None => self.bands.push(BandState {
+                    band,
+                    filter: Biquad::new(),
+                    dirty: true,
+                }),
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+    }
+
+    fn prepare(&mut self, _sample_rate: u32) {
+        self.bands.reserve(MAX_EQ_BANDS);
+    }
+}
+
+impl EqPreset {
+    ///Graphic EQ gains in dB, lowest band first
+    pub fn gains(&self) -> [f32; 10] {
+        match self {
+            Self::Flat => [0.0; 10],
+            Self::BassBoost => [6.0, 5.0, 4.0, 2.5, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Self::BassCut => [-6.0, -5.0, -4.0, -2.5, -1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Self::TrebleBoost => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.5, 3.0, 4.5, 6.0],
+            Self::TrebleCut => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.5, -3.0, -4.5, -6.0],
+            Self::Vocal => [-2.0, -1.5, -1.0, 0.5, 2.0, 3.0, 3.0, 2.0, 0.5, 0.0],
+            Self::Rock => [4.5, 3.5, 2.0, 0.5, -1.0, -0.5, 1.5, 3.0, 3.5, 4.0],
+            Self::Pop => [-1.0, 0.5, 2.0, 3.0, 3.5, 2.5, 1.0, 0.0, -0.5, -1.0],
+            Self::Jazz => [3.0, 2.0, 1.0, 1.5, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0],
+            Self::Classical => [4.0, 3.0, 2.0, 1.0, -1.0, -1.0, 0.0, 2.0, 3.0, 4.0],
+            Self::Electronic => [5.0, 4.0, 1.5, 0.0, -1.5, 1.5, 0.5, 1.5, 4.0, 5.0],
+            Self::Loudness => [6.0, 4.5, 2.5, 0.5, 0.0, 0.0, 0.0, 1.0, 2.5, 3.5],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    //Magnitude of the response at frequency, in dB
+    fn response_db(c: &Coefficients, frequency: f64) -> f64 {
+        let w = 2.0 * PI * frequency / RATE as f64;
+        let (sin1, cos1) = w.sin_cos();
+        let (sin2, cos2) = (2.0 * w).sin_cos();
+        let num = (c.b0 + c.b1 * cos1 + c.b2 * cos2, -c.b1 * sin1 - c.b2 * sin2);
+        let den = (1.0 + c.a1 * cos1 + c.a2 * cos2, -c.a1 * sin1 - c.a2 * sin2);
+        let magnitude = num.0.hypot(num.1) / den.0.hypot(den.1);
+        20.0 * magnitude.log10()
+    }
+
+    fn band(filter_type: FilterType, frequency: f32, gain_db: f32, q: f32) -> EqBand {
+        EqBand {
+            filter_type,
+            frequency,
+            gain_db,
+            q,
+        }
+    }
+
+    #[test]
+    fn peaking_hits_its_gain_at_the_centre() {
+        for gain_db in [-12.0, -3.0, 6.0, 12.0] {
+            let c = Coefficients::from_band(&band(FilterType::Peaking, 1000.0, gain_db, 1.0), RATE);
+            assert!((response_db(&c, 1000.0) - gain_db as f64).abs() < 1e-6);
+            assert!(response_db(&c, 20.0).abs() < 0.1);
+            assert!(response_db(&c, 20000.0).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn peak_estimates_cover_the_response() {
+        assert_eq!(band(FilterType::Peaking, 1000.0, -6.0, 1.0).peak_db(), 0.0);
+        assert_eq!(
+            band(FilterType::HighShelf, 4000.0, 6.0, 0.707).peak_db(),
+            6.0
+        );
+        assert_eq!(band(FilterType::HighPass, 80.0, 0.0, 0.5).peak_db(), 0.0);
+
+        let resonant = band(FilterType::LowPass, 1000.0, 0.0, 4.0);
+        let c = Coefficients::from_band(&resonant, RATE);
+        let measured = (900..1100)
+            .map(|frequency| response_db(&c, frequency as f64))
+            .fold(f64::MIN, f64::max);
+        assert!((resonant.peak_db() as f64 - measured).abs() < 0.1);
+
+        let mut equalizer = Equalizer::new();
+        equalizer
+            .set_bands(vec![
+                band(FilterType::Peaking, 100.0, 6.0, 1.0),
+                band(FilterType::Peaking, 200.0, 3.0, 1.0),
+            ])
+            .unwrap();
+        equalizer.set_preamp(-9.0).unwrap();
+        assert_eq!(equalizer.max_gain_db(), 0.0);
+    }
+
+    #[test]
+    fn shelves_reach_their_gain_at_the_far_end() {
+        let low = Coefficients::from_band(&band(FilterType::LowShelf, 200.0, 6.0, 0.707), RATE);
+        assert!((response_db(&low, 0.0) - 6.0).abs() < 1e-6);
+        assert!(response_db(&low, 20000.0).abs() < 0.05);
+
+        let high = Coefficients::from_band(&band(FilterType::HighShelf, 4000.0, -6.0, 0.707), RATE);
+        assert!((response_db(&high, RATE as f64 / 2.0) + 6.0).abs() < 1e-6);
+        assert!(response_db(&high, 20.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn pass_filters_are_3db_down_at_the_cutoff() {
+        let q = std::f32::consts::FRAC_1_SQRT_2;
+        let low = Coefficients::from_band(&band(FilterType::LowPass, 1000.0, 0.0, q), RATE);
+        assert!(response_db(&low, 0.0).abs() < 1e-6);
+        assert!((response_db(&low, 1000.0) + 3.01).abs() < 0.01);
+        assert!(response_db(&low, 10000.0) < -35.0);
+
+        let high = Coefficients::from_band(&band(FilterType::HighPass, 1000.0, 0.0, q), RATE);
+        assert!(response_db(&high, RATE as f64 / 2.0).abs() < 1e-6);
+        assert!((response_db(&high, 1000.0) + 3.01).abs() < 0.01);
+        assert!(response_db(&high, 100.0) < -35.0);
+    }
+
+    #[test]
+    fn centre_above_nyquist_stays_stable() {
+        let c = Coefficients::from_band(&band(FilterType::Peaking, 30000.0, 6.0, 1.0), RATE);
+        // Both poles inside the unit circle
+        assert!(c.a2.abs() < 1.0);
+        assert!(c.a1.abs() < 1.0 + c.a2);
+    }
+
+    #[test]
+    fn rejects_bad_bands() {
+        assert!(
+            band(FilterType::Peaking, 1000.0, 6.0, 1.0)
+                .validate()
+                .is_ok()
+        );
+        assert!(band(FilterType::Peaking, 0.0, 6.0, 1.0).validate().is_err());
+        assert!(
+            band(FilterType::Peaking, 1000.0, 6.0, 0.0)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            band(FilterType::Peaking, 1000.0, 30.0, 1.0)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            band(FilterType::Peaking, f32::NAN, 6.0, 1.0)
+                .validate()
+                .is_err()
+        );
+
+        let mut equalizer = Equalizer::new();
+        assert!(equalizer.set_graphic(&[0.0; 9]).is_err());
+        assert!(
+            equalizer
+                .set_bands(vec![
+                    band(FilterType::Peaking, 1000.0, 0.0, 1.0);
+                    MAX_EQ_BANDS + 1
+                ])
+                .is_err()
+        );
+        assert!(
+            equalizer
+                .set_band(0, band(FilterType::Peaking, 1000.0, 0.0, 1.0))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn presets_fit_the_graphic_eq() {
+        let presets = [
+            EqPreset::Flat,
+            EqPreset::BassBoost,
+            EqPreset::BassCut,
+            EqPreset::TrebleBoost,
+            EqPreset::TrebleCut,
+            EqPreset::Vocal,
+            EqPreset::Rock,
+            EqPreset::Pop,
+            EqPreset::Jazz,
+            EqPreset::Classical,
+            EqPreset::Electronic,
+            EqPreset::Loudness,
+        ];
+        for preset in presets {
+            let mut equalizer = Equalizer::new();
+            equalizer.apply_preset(preset);
+
+            let bands = equalizer.get_bands();
+            assert_eq!(bands.len(), GRAPHIC_EQ_FREQUENCIES.len());
+            for ((band, frequency), gain_db) in
+                bands.iter().zip(GRAPHIC_EQ_FREQUENCIES).zip(preset.gains())
+            {
+                assert_eq!(band.filter_type, FilterType::Peaking);
+                assert_eq!(band.frequency, frequency);
+                assert_eq!(band.gain_db, gain_db);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_preset_leaves_the_signal_alone() {
+        let mut equalizer = Equalizer::new();
+        equalizer.apply_preset(EqPreset::Flat);
+        equalizer.set_enabled(true);
+
+        let input: Vec<[f32; 2]> = (0..4800)
+            .map(|i| {
+                let x = (2.0 * PI * 440.0 * i as f64 / RATE as f64).sin() as f32 * 0.5;
+                [x, -x]
+            })
+            .collect();
+        let mut frames = input.clone();
+        equalizer.process(&mut frames, RATE);
+
+        for (out, expected) in frames.iter().zip(&input) {
+            assert!((out[0] - expected[0]).abs() < 1e-5);
+            assert!((out[1] - expected[1]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn disabled_is_a_no_op() {
+        let mut equalizer = Equalizer::new();
+        equalizer.apply_preset(EqPreset::BassBoost);
+
+        let mut frames = vec![[0.25f32, -0.25]; 64];
+        equalizer.process(&mut frames, RATE);
+        assert!(frames.iter().all(|frame| *frame == [0.25, -0.25]));
+    }
+}
@@ -0,0 +1,101 @@
+//events.rs
+
+//Everything the player reports to the app. Events go through the engine's signal channel and the
+//listening thread hands them on, so the Rust closure, PlayerCallback and the Dart queue see the same ones
+
+use crate::{
+    enums::{PlayerError, PlayerState},
+    streams::audio_format,
+};
+
+use ffmpeg_next::format::context::Input;
+
+//Position updates can't come faster than this, they'd only flood the callback
+pub const MIN_POSITION_INTERVAL_MS: u32 = 50;
+
+#[derive(Clone, PartialEq, Debug, uniffi::Record)]
+pub struct TrackInfo {
+    pub path: Option<String>, // None for in-memory and callback sources
+    pub duration_s: f64,      // 0.0 for live streams
+    pub codec: String,
+    pub sample_rate: u32, // Of the source, before resampling
+    pub channels: u32,
+    pub bit_rate: u64,     // Bits per second, 0 if unknown
+    pub audio_stream: u32, // Container index of the stream being played
+    pub is_live: bool,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl TrackInfo {
+    ///Stream tags win over container tags
+    pub fn new(
+        input: &Input,
+        audio_stream: usize,
+        path: Option<String>,
+        duration_s: f64,
+        is_live: bool,
+    ) -> Self {
+        let mut info = TrackInfo {
+            path,
+            duration_s,
+            codec: String::new(),
+            sample_rate: 0,
+            channels: 0,
+            bit_rate: 0,
+            audio_stream: audio_stream as u32,
+            is_live,
+            title: None,
+            artist: None,
+            album: None,
+        };
+        info.set_stream(input, audio_stream);
+
+        let tag = |key: &str| {
+            input
+                .stream(audio_stream)
+                .and_then(|stream| stream.metadata().get(key).map(str::to_string))
+                .or_else(|| input.metadata().get(key).map(str::to_string))
+        };
+        info.title = tag("title");
+        info.artist = tag("artist");
+        info.album = tag("album");
+        info
+    }
+
+    ///Picks up the format of another stream of the same input after a switch
+    pub fn set_stream(&mut self, input: &Input, audio_stream: usize) {
+        let Some(stream) = input.stream(audio_stream) else {
+            return;
+        };
+        let parameters = stream.parameters();
+        let (sample_rate, channels) = audio_format(&parameters);
+
+        self.audio_stream = audio_stream as u32;
+        self.codec = parameters.id().name().to_string();
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        self.bit_rate = unsafe { (*parameters.as_ptr()).bit_rate.max(0) as u64 };
+        if self.bit_rate == 0 {
+            self.bit_rate = input.bit_rate().max(0) as u64;
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, uniffi::Enum)]
+pub enum PlayerEvent {
+    MediaEnd,
+    StateChanged { state: PlayerState },
+    PositionUpdate { position_s: f64, duration_s: f64 }, // While playing, every position interval
+    Seeked { position_s: f64 },
+    Loaded { info: TrackInfo }, // Decoding started, the track can be played
+    LoadFailed,                 // The source couldn't be opened or has no playable audio
+    Error { error: PlayerError }, // Something went wrong after loading, e.g. the source died or the device failed
+    Buffering,                    // A network source ran dry or is connecting, output is held
+    BufferingEnded,               // Enough is buffered again, or the source ended
+    BufferUnderrun,               // The output ran out of audio before the end, so there was a gap
+    MetadataChanged { title: String }, // A live stream announced a new title
+    DeviceChanged { name: String }, // The system's default output device changed
+    VolumeChanged { volume: f32 }, // Linear, after clamping. For fades this is the target
+}
@@ -0,0 +1,308 @@
+//handoff.rs
+
+//How stage settings get from control calls to the audio thread without either side waiting. The engine edits a
+//control copy of each stage and publishes it through a triple buffer. The audio thread keeps its own copy, which
+//takes the newest settings at the start of a callback and keeps its filter memory. If nothing new came in it just
+//carries on with the last settings it took, so a stage is never skipped
+
+use std::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+//Set on the middle slot's index when it holds something the reader hasn't taken yet
+const FRESH: usize = 4;
+
+///A stage whose settings are edited on a control copy and followed by a copy on the audio thread
+pub trait Stage: Clone + Send + 'static {
+    ///Takes the control copy's settings and keeps this copy's running state. Runs on the audio thread, so it must
+    ///not block or allocate
+    fn follow(&mut self, control: &Self);
+
+    ///Drops running state such as filter memory and delay lines. Called on seek and stop
+    fn clear(&mut self);
+
+    ///Sizes buffers for the stream's rate. Runs when the stream is built, before the audio thread has the stage
+    fn prepare(&mut self, _sample_rate: u32) {}
+}
+
+struct Slots<T> {
+    slots: [UnsafeCell<T>; 3],
+    middle: AtomicUsize,
+}
+
+//A slot is only ever touched by the side that owns its index. Ownership changes hands through the middle swap
+unsafe impl<T: Send> Sync for Slots<T> {}
+
+struct Writer<T> {
+    slots: Arc<Slots<T>>,
+    back: usize,
+}
+
+impl<T: Clone> Writer<T> {
+    fn publish(&mut self, value: &T) {
+        // The back slot belongs to the writer until it's swapped into the middle
+        unsafe { (*self.slots.slots[self.back].get()).clone_from(value) };
+        let previous = self.slots.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & !FRESH;
+    }
+}
+
+struct Reader<T> {
+    slots: Arc<Slots<T>>,
+    front: usize,
+}
+
+impl<T> Reader<T> {
+    //The newest published value, if there is one the reader hasn't taken yet
+    fn take(&mut self) -> Option<&T> {
+        if self.slots.middle.load(Ordering::Relaxed) & FRESH == 0 {
+            return None;
+        }
+        let previous = self.slots.middle.swap(self.front, Ordering::AcqRel);
+        self.front = previous & !FRESH;
+
+        // The front slot belongs to the reader until it's swapped back into the middle
+        Some(unsafe { &*self.slots.slots[self.front].get() })
+    }
+}
+
+fn triple_buffer<T: Clone>(initial: &T) -> (Writer<T>, Reader<T>) {
+    let slots = Arc::new(Slots {
+        slots: [
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial.clone()),
+        ],
+        middle: AtomicUsize::new(1),
+    });
+
+    (
+        Writer {
+            slots: slots.clone(),
+            back: 2,
+        },
+        Reader { slots, front: 0 },
+    )
+}
+
+struct ControlState<T> {
+    control: T,
+    writer: Option<Writer<T>>, // Feeds the newest output stage, None until there is one
+}
+
+///The engine's side of a stage. Setters edit the control copy and publish it, getters read it. The audio thread
+///never touches it, so holding it doesn't hold up playback
+pub struct StageControl<T: Stage> {
+    state: Mutex<ControlState<T>>,
+    resets: Arc<AtomicU64>,
+}
+
+impl<T: Stage> StageControl<T> {
+    pub fn new(stage: T) -> Self {
+        StageControl {
+            state: Mutex::new(ControlState {
+                control: stage,
+                writer: None,
+            }),
+            resets: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn get<R>(&self, read: impl FnOnce(&T) -> R) -> R {
+        read(&self.state.lock().unwrap().control)
+    }
+
+    ///Edits the control copy and publishes the result to the audio thread
+    pub fn update<R>(&self, edit: impl FnOnce(&mut T) -> R) -> R {
+        let mut state = self.state.lock().unwrap();
+        let ControlState { control, writer } = &mut *state;
+        let result = edit(control);
+        if let Some(writer) = writer {
+            writer.publish(control);
+        }
+        result
+    }
+
+    ///Asks the audio thread's copy to drop its running state at the start of its next callback
+    pub fn reset(&self) {
+        self.resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///Copy for a new output stage, prepared for its rate. From now on only this copy gets updates
+    pub fn follower(&self, sample_rate: u32) -> StageFollower<T> {
+        let mut state = self.state.lock().unwrap();
+        let (writer, reader) = triple_buffer(&state.control);
+        state.writer = Some(writer);
+
+        let mut stage = state.control.clone();
+        stage.clear();
+        stage.prepare(sample_rate);
+        StageFollower {
+            stage,
+            reader,
+            resets: self.resets.clone(),
+            seen_resets: self.resets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+///The audio thread's copy of a stage
+pub struct StageFollower<T: Stage> {
+    stage: T,
+    reader: Reader<T>,
+    resets: Arc<AtomicU64>,
+    seen_resets: u64,
+}
+
+impl<T: Stage> StageFollower<T> {
+    ///Takes settings published since the last call and any pending reset. Never waits
+    pub fn sync(&mut self) {
+        if let Some(control) = self.reader.take() {
+            self.stage.follow(control);
+        }
+
+        let resets = self.resets.load(Ordering::Relaxed);
+        if resets != self.seen_resets {
+            self.seen_resets = resets;
+            self.stage.clear();
+        }
+    }
+}
+
+impl<T: Stage> Deref for StageFollower<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.stage
+    }
+}
+
+impl<T: Stage> DerefMut for StageFollower<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.stage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Counter {
+        setting: u32,
+        running: u32, // Stands in for filter memory
+        rate: u32,
+    }
+
+    impl Stage for Counter {
+        fn follow(&mut self, control: &Self) {
+            self.setting = control.setting;
+        }
+
+        fn clear(&mut self) {
+            self.running = 0;
+        }
+
+        fn prepare(&mut self, sample_rate: u32) {
+            self.rate = sample_rate;
+        }
+    }
+
+    #[test]
+    fn followers_take_settings_on_sync_and_keep_their_state() {
+        let control = StageControl::new(Counter::default());
+        let mut follower = control.follower(48000);
+        assert_eq!(follower.rate, 48000);
+        follower.running = 7;
+
+        control.update(|counter| counter.setting = 1);
+        assert_eq!(follower.setting, 0);
+        follower.sync();
+        assert_eq!((follower.setting, follower.running), (1, 7));
+
+        // Nothing new, the last settings stay
+        follower.sync();
+        assert_eq!(follower.setting, 1);
+        assert_eq!(control.get(|counter| counter.setting), 1);
+    }
+
+    #[test]
+    fn only_the_newest_update_is_taken() {
+        let control = StageControl::new(Counter::default());
+        let mut follower = control.follower(48000);
+        for setting in 1..=10 {
+            control.update(|counter| counter.setting = setting);
+        }
+        follower.sync();
+        assert_eq!(follower.setting, 10);
+
+        // Updates interleaved with syncs never hand back an older slot
+        for setting in 11..=20 {
+            control.update(|counter| counter.setting = setting);
+            control.update(|counter| counter.setting += 100);
+            follower.sync();
+            assert_eq!(follower.setting, setting + 100);
+        }
+    }
+
+    #[test]
+    fn resets_clear_running_state_once() {
+        let control = StageControl::new(Counter::default());
+        let mut follower = control.follower(48000);
+        follower.running = 3;
+
+        control.reset();
+        follower.sync();
+        assert_eq!(follower.running, 0);
+
+        follower.running = 5;
+        follower.sync();
+        assert_eq!(follower.running, 5);
+    }
+
+    #[test]
+    fn new_followers_start_from_the_control_copy() {
+        let control = StageControl::new(Counter::default());
+        let mut old = control.follower(44100);
+        control.update(|counter| counter.setting = 4);
+
+        let mut new = control.follower(96000);
+        assert_eq!((new.setting, new.rate), (4, 96000));
+
+        // Updates only go to the newest follower
+        control.update(|counter| counter.setting = 9);
+        old.sync();
+        new.sync();
+        assert_eq!(old.setting, 4);
+        assert_eq!(new.setting, 9);
+    }
+
+    #[test]
+    fn updates_cross_threads() {
+        let control = Arc::new(StageControl::new(Counter::default()));
+        let mut follower = control.follower(48000);
+
+        let writer = {
+            let control = control.clone();
+            std::thread::spawn(move || {
+                for setting in 1..=10_000 {
+                    control.update(|counter| counter.setting = setting);
+                }
+            })
+        };
+
+        // Settings only ever move forward
+        let mut last = 0;
+        while last < 10_000 {
+            follower.sync();
+            assert!(follower.setting >= last);
+            last = follower.setting;
+        }
+        writer.join().unwrap();
+    }
+}
@@ -1,9 +1,36 @@
+pub mod adaptive;
 pub mod aurex;
+mod bitperfect;
+pub mod blocking;
+pub mod channels;
+pub mod convolution;
+pub mod crossfeed;
 pub mod dart_bindings;
 mod decoding_loop;
+pub mod dither;
+pub mod dsp;
 pub mod engine;
 pub mod enums;
+pub mod equalizer;
+pub mod events;
+mod handoff;
+pub mod limiter;
+pub mod loudness;
+pub mod meter;
+pub mod network;
+mod output;
+pub mod radio;
+mod ramp;
+pub mod replaygain;
+pub mod resampler;
+mod shared;
 mod singletons;
+pub mod source;
+pub mod spectrum;
+pub mod streams;
 mod structs;
+#[cfg(test)]
+mod testing;
+pub mod waveform;
 
 uniffi::setup_scaffolding!();
@@ -0,0 +1,536 @@
+//limiter.rs
+
+//Look-ahead brickwall limiter. Last stage before the device so boosted volume and hot EQ settings
+//get caught here instead of being hard clipped. Engages by itself while anything boosts, enabling it keeps it on
+
+use crate::{
+    dsp::{AudioProcessor, db_to_linear, linear_to_db},
+    handoff::Stage,
+    ramp::{Ramp, ms_to_frames},
+};
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+pub const DEFAULT_CEILING_DB: f32 = -0.1;
+pub const DEFAULT_KNEE_DB: f32 = 1.0;
+pub const DEFAULT_RELEASE_MS: f32 = 50.0;
+const LOOKAHEAD_S: f64 = 0.0015;
+
+//Crossfade between the dry and the delayed, limited signal when the limiter engages or lets go
+const ENGAGE_RAMP_MS: u32 = 10;
+
+//What the audio thread's copy last did, read by the engine's copy
+#[derive(Default)]
+struct LimiterStatus {
+    reduction_db: AtomicU32, // f32 bits
+    latency: AtomicU32,
+}
+
+#[derive(Clone)]
+pub struct Limiter {
+    enabled: bool,
+    boosted: bool, // Set by the output stage while the signal can go over full scale
+    mix: Ramp,
+    ceiling_db: f32,
+    knee_db: f32,
+    release_ms: f32,
+
+    // Derived from the settings and the sample rate
+    sample_rate: u32,
+    ceiling: f32,
+    knee_start: f32,
+    release_coeff: f32,
+    lookahead: usize,
+
+    delay: Vec<[f32; 2]>,
+    smoothing: Vec<f32>,
+    position: usize,
+    window: VecDeque<(usize, f32)>, // Running minimum of the gain over the look-ahead
+    counter: usize,
+    envelope: f32,
+    smoothing_sum: f64,
+    reduction_db: f32,
+    status: Arc<LimiterStatus>, // Shared by every copy
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        let mut limiter = Limiter {
+            enabled: false,
+            boosted: false,
+            mix: Ramp::new(0.0),
+            ceiling_db: DEFAULT_CEILING_DB,
+            knee_db: DEFAULT_KNEE_DB,
+            release_ms: DEFAULT_RELEASE_MS,
+            sample_rate: 0,
+            ceiling: 1.0,
+            knee_start: 1.0,
+            release_coeff: 0.0,
+            lookahead: 0,
+            delay: Vec::new(),
+            smoothing: Vec::new(),
+            position: 0,
+            window: VecDeque::new(),
+            counter: 0,
+            envelope: 1.0,
+            smoothing_sum: 0.0,
+            reduction_db: 0.0,
+            status: Arc::new(LimiterStatus::default()),
+        };
+        limiter.update_thresholds();
+        limiter
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.is_active() {
+            self.reset();
+        }
+        self.enabled = enabled;
+    }
+
+    ///Engages the limiter while not enabled. The output stage sets this whenever volume, track gain, EQ or
+    ///routing can push the signal over full scale
+    pub fn set_boosted(&mut self, boosted: bool) {
+        if boosted && !self.is_active() {
+            self.reset();
+        }
+        self.boosted = boosted;
+    }
+
+    ///Enabled or boosted
+    pub fn is_engaged(&self) -> bool {
+        self.enabled || self.boosted
+    }
+
+    ///Engaged, or still fading out after letting go
+    pub fn is_active(&self) -> bool {
+        self.is_engaged() || self.mix.current() != 0.0
+    }
+
+    pub fn get_ceiling(&self) -> f32 {
+        self.ceiling_db
+    }
+
+    ///Output never goes above this. Between -12 and 0 dBFS
+    pub fn set_ceiling(&mut self, ceiling_db: f32) -> Result<(), i32> {
+        if !ceiling_db.is_finite() || !(-12.0..=0.0).contains(&ceiling_db) {
+            return Err(-1);
+        }
+        self.ceiling_db = ceiling_db;
+        self.update_thresholds();
+        Ok(())
+    }
+
+    pub fn get_knee(&self) -> f32 {
+        self.knee_db
+    }
+
+    ///Width of the soft knee in dB, centred on the ceiling. 0 is a hard knee
+    pub fn set_knee(&mut self, knee_db: f32) -> Result<(), i32> {
+        if !knee_db.is_finite() || !(0.0..=12.0).contains(&knee_db) {
+            return Err(-1);
+        }
+        self.knee_db = knee_db;
+        self.update_thresholds();
+        Ok(())
+    }
+
+    pub fn get_release(&self) -> f32 {
+        self.release_ms
+    }
+
+    pub fn set_release(&mut self, release_ms: f32) -> Result<(), i32> {
+        if !release_ms.is_finite() || !(1.0..=2000.0).contains(&release_ms) {
+            return Err(-1);
+        }
+        self.release_ms = release_ms;
+        self.update_release();
+        Ok(())
+    }
+
+    ///Gain reduction applied to the last processed block, in dB. Positive means the limiter is working. Any copy
+    ///reports what the audio thread's copy did
+    pub fn get_reduction(&self) -> f32 {
+        f32::from_bits(self.status.reduction_db.load(Ordering::Relaxed))
+    }
+
+    ///Latency the audio thread's copy adds right now, in frames
+    pub fn get_latency(&self) -> u32 {
+        self.status.latency.load(Ordering::Relaxed)
+    }
+
+    fn report(&self) {
+        let status = &self.status;
+        let reduction_db = if self.is_active() {
+            self.reduction_db
+        } else {
+            0.0
+        };
+        status
+            .reduction_db
+            .store(reduction_db.to_bits(), Ordering::Relaxed);
+        status.latency.store(self.latency(), Ordering::Relaxed);
+    }
+
+    fn update_thresholds(&mut self) {
+        self.ceiling = db_to_linear(self.ceiling_db);
+        self.knee_start = db_to_linear(self.ceiling_db - self.knee_db / 2.0);
+    }
+
+    fn update_release(&mut self) {
+        if self.sample_rate > 0 {
+            let release_frames = self.release_ms / 1000.0 * self.sample_rate as f32;
+            self.release_coeff = 1.0 - (-1.0 / release_frames).exp();
+        }
+    }
+
+    //Only reallocates when the device rate changes. Done when the stream is built, never on the audio thread
+    fn resize(&mut self, sample_rate: u32) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+
+        self.sample_rate = sample_rate;
+        self.lookahead = ((LOOKAHEAD_S * sample_rate as f64) as usize).max(1);
+        self.delay = vec![[0.0; 2]; self.lookahead];
+        self.smoothing = vec![1.0; self.lookahead];
+        self.window = VecDeque::with_capacity(self.lookahead + 2);
+        self.update_release();
+        self.reset();
+    }
+
+    //Soft knee gain computer. Output level is monotonic and tops out at the ceiling
+    #[inline]
+    fn target_gain(&self, peak: f32) -> f32 {
+        if peak <= self.knee_start {
+            return 1.0;
+        }
+
+        let over = linear_to_db(peak) - self.ceiling_db;
+        let half_knee = self.knee_db / 2.0;
+        let reduction = if over >= half_knee || self.knee_db <= 0.0 {
+            over
+        } else {
+            (over + half_knee) * (over + half_knee) / (2.0 * self.knee_db)
+        };
+
+        db_to_linear(-reduction)
+    }
+
+    ///Latency in frames
+    pub fn lookahead(&self) -> u32 {
+        self.lookahead as u32
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioProcessor for Limiter {
+    fn process(&mut self, frames: &mut [[f32; 2]], sample_rate: u32) {
+        // Buffers are sized for the stream's rate in prepare
+        if !self.is_active() || sample_rate != self.sample_rate {
+            return;
+        }
+        let target = if self.is_engaged() { 1.0 } else { 0.0 };
+        self.mix
+            .set_target(target, ms_to_frames(ENGAGE_RAMP_MS, sample_rate));
+
+        let lookahead = self.lookahead;
+        let mut min_gain = 1.0f32;
+
+        for frame in frames.iter_mut() {
+            let peak = frame[0].abs().max(frame[1].abs());
+            let target = self.target_gain(peak);
+
+            // Running minimum over the last lookahead + 1 targets
+            while let Some(&(_, gain)) = self.window.back() {
+                if gain >= target {
+                    self.window.pop_back();
+                } else {
+                    break;
+                }
+            }
+            self.window.push_back((self.counter, target));
+            while let Some(&(index, _)) = self.window.front() {
+                if index + lookahead < self.counter {
+                    self.window.pop_front();
+                } else {
+                    break;
+                }
+            }
+            let held = self.window.front().map(|(_, gain)| *gain).unwrap_or(1.0);
+            self.counter = self.counter.wrapping_add(1);
+
+            // Attack is instant here, the box filter below spreads it over the look-ahead
+            self.envelope = if held < self.envelope {
+                held
+            } else {
+                self.envelope + (held - self.envelope) * self.release_coeff
+            };
+
+            let position = self.position;
+            self.smoothing_sum += (self.envelope - self.smoothing[position]) as f64;
+            self.smoothing[position] = self.envelope;
+            let gain = (self.smoothing_sum / lookahead as f64) as f32;
+
+            let delayed = self.delay[position];
+            self.delay[position] = *frame;
+            self.position = (position + 1) % lookahead;
+
+            // The clamp only catches float rounding, the gain already keeps peaks under the ceiling
+            let limited = [
+                (delayed[0] * gain).clamp(-self.ceiling, self.ceiling),
+                (delayed[1] * gain).clamp(-self.ceiling, self.ceiling),
+            ];
+            let mix = self.mix.tick();
+            if mix == 1.0 {
+                *frame = limited;
+            } else {
+                frame[0] += (limited[0] - frame[0]) * mix;
+                frame[1] += (limited[1] - frame[1]) * mix;
+            }
+
+            min_gain = min_gain.min(gain);
+        }
+
+        self.reduction_db = -linear_to_db(min_gain);
+        self.report();
+    }
+
+    fn reset(&mut self) {
+        self.delay.fill([0.0; 2]);
+        self.smoothing.fill(1.0);
+        self.smoothing_sum = self.smoothing.len() as f64;
+        self.window.clear();
+        self.position = 0;
+        self.envelope = 1.0;
+        self.reduction_db = 0.0;
+    }
+
+    fn latency(&self) -> u32 {
+        if self.is_engaged() {
+            self.lookahead()
+        } else {
+            0
+        }
+    }
+
+    fn prepare(&mut self, sample_rate: u32) {
+        Stage::prepare(self, sample_rate);
+    }
+}
+
+impl Stage for Limiter {
+    fn follow(&mut self, control: &Self) {
+        self.set_enabled(control.enabled);
+        if (self.ceiling_db, self.knee_db) != (control.ceiling_db, control.knee_db) {
+            self.ceiling_db = control.ceiling_db;
+            self.knee_db = control.knee_db;
+            self.update_thresholds();
+        }
+        if self.release_ms != control.release_ms {
+            self.release_ms = control.release_ms;
+            self.update_release();
+        }
+        self.report();
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+        self.report();
+    }
+
+    fn prepare(&mut self, sample_rate: u32) {
+        self.resize(sample_rate);
+        self.report();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 48000;
+
+    fn sine(amplitude: f32, frames: usize) -> Vec<[f32; 2]> {
+        (0..frames)
+            .map(|i| {
+                let x = amplitude * (2.0 * PI * 440.0 * i as f32 / RATE as f32).sin();
+                [x, -x]
+            })
+            .collect()
+    }
+
+    fn prepared() -> Limiter {
+        let mut limiter = Limiter::new();
+        Stage::prepare(&mut limiter, RATE);
+        limiter
+    }
+
+    //Enabled, with the crossfade from dry already done
+    fn enabled() -> Limiter {
+        let mut limiter = prepared();
+        limiter.set_enabled(true);
+        limiter.process(&mut vec![[0.0; 2]; RATE as usize / 10], RATE);
+        limiter
+    }
+
+    #[test]
+    fn latency_is_the_lookahead() {
+        let mut limiter = prepared();
+        assert_eq!(limiter.latency(), 0);
+
+        limiter.set_enabled(true);
+        assert_eq!(limiter.latency(), 72);
+        assert_eq!(limiter.lookahead(), 72);
+        assert_eq!(limiter.get_latency(), 0);
+        limiter.process(&mut [[0.0; 2]], RATE);
+        assert_eq!(limiter.get_latency(), 72);
+
+        Stage::prepare(&mut limiter, 96000);
+        assert_eq!(limiter.latency(), 144);
+    }
+
+    #[test]
+    fn boost_engages_it_without_a_jump() {
+        let mut limiter = prepared();
+        assert!(!limiter.is_active());
+
+        limiter.set_boosted(true);
+        assert!(limiter.is_engaged());
+        let input = sine(4.0, 4800);
+        let mut frames = input.clone();
+        limiter.process(&mut frames, RATE);
+
+        // Starts out dry and moves over to the limited signal, never dropping out while the delay fills
+        assert_eq!(frames[0], input[0]);
+        let lookahead = limiter.lookahead() as usize;
+        assert!(
+            frames[1..lookahead]
+                .iter()
+                .all(|frame| frame[0] != 0.0 || frame[1] != 0.0)
+        );
+        let ceiling = db_to_linear(DEFAULT_CEILING_DB);
+        assert!(frames[480..].iter().flatten().all(|x| x.abs() <= ceiling));
+        assert!(limiter.get_reduction() > 11.0);
+
+        // And back to dry once the boost is gone
+        limiter.set_boosted(false);
+        assert_eq!(limiter.latency(), 0);
+        let input = sine(0.5, 4800);
+        let mut frames = input.clone();
+        limiter.process(&mut frames, RATE);
+        assert!(!limiter.is_active());
+        assert_eq!(frames[480..], input[480..]);
+        assert_eq!(limiter.get_reduction(), 0.0);
+
+        // Idle again, so it leaves the signal alone
+        let mut frames = input.clone();
+        limiter.process(&mut frames, RATE);
+        assert_eq!(frames, input);
+    }
+
+    #[test]
+    fn nothing_runs_before_prepare() {
+        let mut limiter = Limiter::new();
+        limiter.set_enabled(true);
+        let mut frames = sine(4.0, 480);
+        let input = frames.clone();
+        limiter.process(&mut frames, RATE);
+        assert_eq!(frames, input);
+    }
+
+    #[test]
+    fn quiet_input_is_only_delayed() {
+        let mut limiter = enabled();
+        let input = sine(0.5, 4800);
+        let mut frames = input.clone();
+        limiter.process(&mut frames, RATE);
+
+        let lookahead = limiter.lookahead() as usize;
+        assert!(frames[..lookahead].iter().all(|frame| *frame == [0.0; 2]));
+        assert_eq!(frames[lookahead..], input[..input.len() - lookahead]);
+        assert_eq!(limiter.get_reduction(), 0.0);
+    }
+
+    #[test]
+    fn loud_input_stays_under_the_ceiling() {
+        for ceiling_db in [DEFAULT_CEILING_DB, -3.0, -6.0] {
+            let mut limiter = enabled();
+            limiter.set_ceiling(ceiling_db).unwrap();
+            let ceiling = db_to_linear(ceiling_db);
+
+            // 12 dB over full scale, with a sudden jump the look-ahead has to catch
+            let mut input = sine(0.25, 4800);
+            input.extend(sine(4.0, 48000));
+            let mut frames = input.clone();
+            for block in frames.chunks_mut(512) {
+                limiter.process(block, RATE);
+            }
+
+            assert!(frames.iter().flatten().all(|x| x.abs() <= ceiling));
+            assert!(limiter.get_reduction() > 11.0);
+
+            // Held gain once settled, so the wave keeps its shape instead of being clipped flat
+            let lookahead = limiter.lookahead() as usize;
+            let settled = &frames[frames.len() - 4800..];
+            let source = &input[input.len() - 4800 - lookahead..input.len() - lookahead];
+            let ratios: Vec<f32> = settled
+                .iter()
+                .zip(source)
+                .filter(|(_, source)| source[0].abs() > 0.5)
+                .map(|(out, source)| out[0] / source[0])
+                .collect();
+            let (min, max) = ratios
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), ratio| {
+                    (min.min(*ratio), max.max(*ratio))
+                });
+            assert!(max - min < 1e-3);
+
+            let peak = settled
+                .iter()
+                .flatten()
+                .fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!(peak > ceiling * 0.99);
+        }
+    }
+
+    #[test]
+    fn disabled_is_a_no_op() {
+        let mut limiter = prepared();
+        let mut frames = sine(4.0, 480);
+        let input = frames.clone();
+        limiter.process(&mut frames, RATE);
+        assert_eq!(frames, input);
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let mut limiter = Limiter::new();
+        assert!(limiter.set_ceiling(0.5).is_err());
+        assert!(limiter.set_ceiling(-13.0).is_err());
+        assert!(limiter.set_knee(-1.0).is_err());
+        assert!(limiter.set_release(0.0).is_err());
+        assert!(limiter.set_release(f32::INFINITY).is_err());
+        assert_eq!(limiter.get_ceiling(), DEFAULT_CEILING_DB);
+        assert_eq!(limiter.get_knee(), DEFAULT_KNEE_DB);
+        assert_eq!(limiter.get_release(), DEFAULT_RELEASE_MS);
+    }
+}
@@ -0,0 +1,534 @@
+//loudness.rs
+
+//EBU R128 loudness normalisation for files without ReplayGain tags. Files are measured by a
+//background decode that runs alongside playback and the result is cached per path
+
+use crate::dsp::db_to_linear;
+
+use ffmpeg_next::{self as av, frame::Audio as AudioFrame, media};
+
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+pub const DEFAULT_TARGET_LUFS: f32 = -18.0;
+pub const MIN_TARGET_LUFS: f32 = -40.0;
+pub const MAX_TARGET_LUFS: f32 = -5.0;
+
+//Normalisation never pushes the measured peak above this. -1 dBFS
+const PEAK_CEILING: f32 = 0.891;
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct LoudnessInfo {
+    pub integrated_lufs: f32,
+    pub peak: f32, // Linear sample peak
+}
+
+///Per-channel biquad used for the K-weighting pre-filter
+#[derive(Clone, Copy)]
+struct KFilter {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [[f64; 2]; 2],
+}
+
+impl KFilter {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        KFilter {
+            b,
+            a,
+            state: [[0.0; 2]; 2],
+        }
+    }
+
+    #[inline]
+    fn tick(&mut self, channel: usize, x: f64) -> f64 {
+        let s = &mut self.state[channel];
+        let y = self.b[0] * x + s[0];
+        s[0] = self.b[1] * x - self.a[0] * y + s[1];
+        s[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+///ITU BS.1770 integrated loudness meter with R128 gating, for stereo input
+pub struct LoudnessMeter {
+    shelf: KFilter,
+    high_pass: KFilter,
+    mono: bool,
+    block_size: usize, // 100 ms in frames
+    block_fill: usize,
+    block_energy: f64,
+    blocks: Vec<f64>, // Mean square of every 100 ms block
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    ///Mono sources are duplicated to stereo by the decoder, only count them once
+    pub fn new(sample_rate: u32, mono: bool) -> Self {
+        let rate = sample_rate as f64;
+
+        // Stage 1, high shelf modelling the head. Coefficients for any rate, as in libebur128
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = KFilter::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        // Stage 2, RLB high pass
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = KFilter::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        LoudnessMeter {
+            shelf,
+            high_pass,
+            mono,
+            block_size: (sample_rate as usize / 10).max(1),
+            block_fill: 0,
+            block_energy: 0.0,
+            blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, frames: &[[f32; 2]]) {
+        let channels = if self.mono { 1 } else { 2 };
+
+        for frame in frames {
+            for (channel, sample) in frame.iter().enumerate().take(channels) {
+                self.peak = self.peak.max(sample.abs());
+                let filtered = self.shelf.tick(channel, *sample as f64);
+                let filtered = self.high_pass.tick(channel, filtered);
+                self.block_energy += filtered * filtered;
+            }
+
+            self.block_fill += 1;
+            if self.block_fill == self.block_size {
+                self.blocks.push(self.block_energy / self.block_size as f64);
+                self.block_fill = 0;
+                self.block_energy = 0.0;
+            }
+        }
+    }
+
+    ///Integrated loudness over everything pushed so far. None if it was all below the absolute gate
+    pub fn integrated(&self) -> Option<f64> {
+        // 400 ms gating blocks with 75% overlap, built from the 100 ms blocks
+        let gating_blocks: Vec<f64> = self
+            .blocks
+            .windows(4)
+            .map(|window| window.iter().sum::<f64>() / 4.0)
+            .collect();
+
+        let above_absolute: Vec<f64> = gating_blocks
+            .into_iter()
+            .filter(|energy| energy_to_lufs(*energy) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if above_absolute.is_empty() {
+            return None;
+        }
+
+        let relative_gate =
+            energy_to_lufs(above_absolute.iter().sum::<f64>() / above_absolute.len() as f64)
+                + RELATIVE_GATE_LU;
+
+        let gated: Vec<f64> = above_absolute
+            .into_iter()
+            .filter(|energy| energy_to_lufs(*energy) > relative_gate)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+
+        Some(energy_to_lufs(
+            gated.iter().sum::<f64>() / gated.len() as f64,
+        ))
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.max(f64::MIN_POSITIVE).log10()
+}
+
+///Decodes a whole file and measures it. Returns None if cancelled or the file can't be read
+pub fn measure_file(path: &str, cancel_flag: &AtomicBool) -> Option<LoudnessInfo> {
+    let mut format_ctx = av::format::input(path).ok()?;
+    let stream = format_ctx.streams().best(media::Type::Audio)?;
+    let stream_index = stream.index();
+
+    let codec_ctx = av::codec::context::Context::from_parameters(stream.parameters()).ok()?;
+    let mut decoder = codec_ctx.decoder().audio().ok()?;
+
+    // WAV, raw PCM and some MKV tracks don't say, swr needs a layout to start
+    let layout = if decoder.channel_layout().is_empty() {
+        av::ChannelLayout::default(decoder.channels() as i32)
+    } else {
+        decoder.channel_layout()
+    };
+    let mut resampler = av::software::resampling::Context::get(
+        decoder.format(),
+        layout,
+        decoder.rate(),
+        av::format::Sample::F32(av::format::sample::Type::Packed),
+        av::ChannelLayout::STEREO,
+        decoder.rate(),
+    )
+    .ok()?;
+
+    let mut meter = LoudnessMeter::new(decoder.rate(), decoder.channels() == 1);
+    let mut frame = AudioFrame::empty();
+
+    for (stream, packet) in format_ctx.packets() {
+        if cancel_flag.load(Ordering::Relaxed) {
+            return None;
+        }
+        if stream.index() != stream_index {
+            continue;
+        }
+        if decoder.send_packet(&packet).is_err() {
+            continue;
+        }
+
+        while decoder.receive_frame(&mut frame).is_ok() {
+            push_frame(&mut meter, &mut resampler, &frame);
+        }
+    }
+
+    _ = decoder.send_eof();
+    while decoder.receive_frame(&mut frame).is_ok() {
+        push_frame(&mut meter, &mut resampler, &frame);
+    }
+
+    Some(LoudnessInfo {
+        integrated_lufs: meter.integrated()? as f32,
+        peak: meter.peak(),
+    })
+}
+
+fn push_frame(
+    meter: &mut LoudnessMeter,
+    resampler: &mut av::software::resampling::Context,
+    frame: &AudioFrame,
+) {
+    let mut converted = AudioFrame::empty();
+    if resampler.run(frame, &mut converted).is_err() {
+        return;
+    }
+
+    // The plane is padded, only take the frames that are actually there
+    let bytes = converted.samples() * std::mem::size_of::<[f32; 2]>();
+    let samples: &[[f32; 2]] = bytemuck::cast_slice(&converted.data(0)[..bytes]);
+    meter.push(samples);
+}
+
+///Normalisation settings, the measurement cache and the state of the current track
+pub struct Loudness {
+    enabled: bool,
+    target_lufs: f32,
+    cache: HashMap<String, LoudnessInfo>,
+    current_path: Option<String>,
+    scan_path: Option<String>, // Track the running scan belongs to
+    scan_cancel_flag: Arc<AtomicBool>,
+    scan_threads: Vec<JoinHandle<()>>, // Joined on shutdown. Finished ones go as new scans start
+}
+
+impl Loudness {
+    pub fn new() -> Self {
+        Loudness {
+            enabled: false,
+            target_lufs: DEFAULT_TARGET_LUFS,
+            cache: HashMap::new(),
+            current_path: None,
+            scan_path: None,
+            scan_cancel_flag: Arc::new(AtomicBool::new(false)),
+            scan_threads: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn get_target(&self) -> f32 {
+        self.target_lufs
+    }
+
+    pub fn set_target(&mut self, target_lufs: f32) -> Result<(), i32> {
+        if !target_lufs.is_finite() || !(MIN_TARGET_LUFS..=MAX_TARGET_LUFS).contains(&target_lufs) {
+            return Err(-1);
+        }
+        self.target_lufs = target_lufs;
+        Ok(())
+    }
+
+    ///Measurement of the current track, None until its scan finishes
+    pub fn get_current(&self) -> Option<LoudnessInfo> {
+        self.current_path
+            .as_ref()
+            .and_then(|path| self.cache.get(path))
+            .copied()
+    }
+
+    pub fn get_cached(&self, path: &str) -> Option<LoudnessInfo> {
+        self.cache.get(path).copied()
+    }
+
+    pub fn insert(&mut self, path: String, info: LoudnessInfo) {
+        if self.scan_path.as_ref() == Some(&path) {
+            self.scan_path = None;
+        }
+        self.cache.insert(path, info);
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    ///Switches to a new track and cancels the previous track's scan
+    pub fn set_current(&mut self, path: Option<String>) {
+        self.scan_cancel_flag.store(true, Ordering::Relaxed);
+        self.scan_path = None;
+        self.current_path = path;
+    }
+
+    ///True if the current track should be scanned and isn't already
+    pub fn needs_scan(&self) -> bool {
+        self.enabled
+            && self.scan_path.is_none()
+            && self
+                .current_path
+                .as_ref()
+                .is_some_and(|path| !self.cache.contains_key(path))
+    }
+
+    ///Marks the current track as being scanned and hands out a fresh cancel flag for it
+    pub fn begin_scan(&mut self) -> Option<(String, Arc<AtomicBool>)> {
+        let path = self.current_path.clone()?;
+
+        self.scan_cancel_flag.store(true, Ordering::Relaxed);
+        self.scan_cancel_flag = Arc::new(AtomicBool::new(false));
+        self.scan_path = Some(path.clone());
+
+        Some((path, self.scan_cancel_flag.clone()))
+    }
+
+    pub fn add_scan_thread(&mut self, thread: JoinHandle<()>) {
+        self.scan_threads.retain(|thread| !thread.is_finished());
+        self.scan_threads.push(thread);
+    }
+
+    ///Scan threads that may still be running, for the caller to join once the scans are cancelled
+    pub fn take_scan_threads(&mut self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut self.scan_threads)
+    }
+
+    ///Linear normalisation gain for the current track. None when disabled or not measured yet
+    pub fn gain(&self) -> Option<f32> {
+        if !self.enabled {
+            return None;
+        }
+
+        let info = self.get_current()?;
+        let gain = db_to_linear(self.target_lufs - info.integrated_lufs);
+
+        if info.peak > 0.0 {
+            Some(gain.min(PEAK_CEILING / info.peak))
+        } else {
+            Some(gain)
+        }
+    }
+}
+
+impl Default for Loudness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///Measures a file on a background thread. on_done runs if the scan finished
+pub fn spawn_scan<F>(path: String, cancel_flag: Arc<AtomicBool>, on_done: F) -> JoinHandle<()>
+where
+    F: FnOnce(String, LoudnessInfo) + Send + 'static,
+{
+    thread::spawn(move || {
+        if let Some(info) = measure_file(&path, &cancel_flag) {
+            on_done(path, info);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn sine(frequency: f64, amplitude_db: f32, seconds: f64) -> Vec<[f32; 2]> {
+        let amplitude = db_to_linear(amplitude_db) as f64;
+        (0..(seconds * RATE as f64) as usize)
+            .map(|i| {
+                let x = (amplitude * (2.0 * PI * frequency * i as f64 / RATE as f64).sin()) as f32;
+                [x, x]
+            })
+            .collect()
+    }
+
+    fn measure(meter: &mut LoudnessMeter, parts: &[(f32, f64)]) -> f64 {
+        for (amplitude_db, seconds) in parts {
+            meter.push(&sine(1000.0, *amplitude_db, *seconds));
+        }
+        meter.integrated().unwrap()
+    }
+
+    //EBU Tech 3341 test signals
+    #[test]
+    fn reads_the_reference_tone() {
+        let mut meter = LoudnessMeter::new(RATE, false);
+        let lufs = measure(&mut meter, &[(-23.0, 20.0)]);
+        assert!((lufs + 23.0).abs() < 0.1, "{lufs}");
+
+        let mut meter = LoudnessMeter::new(RATE, false);
+        let lufs = measure(&mut meter, &[(-33.0, 20.0)]);
+        assert!((lufs + 33.0).abs() < 0.1, "{lufs}");
+    }
+
+    #[test]
+    fn gates_quiet_passages() {
+        let mut meter = LoudnessMeter::new(RATE, false);
+        let lufs = measure(&mut meter, &[(-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0)]);
+        assert!((lufs + 23.0).abs() < 0.1, "{lufs}");
+
+        let mut meter = LoudnessMeter::new(RATE, false);
+        let lufs = measure(
+            &mut meter,
+            &[
+                (-72.0, 10.0),
+                (-36.0, 10.0),
+                (-23.0, 60.0),
+                (-36.0, 10.0),
+                (-72.0, 10.0),
+            ],
+        );
+        assert!((lufs + 23.0).abs() < 0.1, "{lufs}");
+    }
+
+    #[test]
+    fn counts_mono_once() {
+        // A full scale sine in one channel is -3.01 LUFS by definition
+        let mut meter = LoudnessMeter::new(44100, true);
+        let frames: Vec<[f32; 2]> = (0..44100 * 5)
+            .map(|i| {
+                let x = (2.0 * PI * 997.0 * i as f64 / 44100.0).sin() as f32;
+                [x, x]
+            })
+            .collect();
+        meter.push(&frames);
+        let lufs = meter.integrated().unwrap();
+        assert!((lufs + 3.01).abs() < 0.05, "{lufs}");
+        assert!((meter.peak() - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = LoudnessMeter::new(RATE, false);
+        meter.push(&vec![[0.0; 2]; RATE as usize * 2]);
+        assert_eq!(meter.integrated(), None);
+
+        // Less than one gating block
+        let mut meter = LoudnessMeter::new(RATE, false);
+        meter.push(&sine(1000.0, -23.0, 0.3));
+        assert_eq!(meter.integrated(), None);
+    }
+
+    #[test]
+    fn gain_reaches_the_target_below_the_ceiling() {
+        let mut loudness = Loudness::new();
+        loudness.set_current(Some("a".to_string()));
+        loudness.insert(
+            "a".to_string(),
+            LoudnessInfo {
+                integrated_lufs: -23.0,
+                peak: 0.1,
+            },
+        );
+        assert_eq!(loudness.gain(), None);
+
+        loudness.set_enabled(true);
+        assert_eq!(loudness.gain(), Some(db_to_linear(5.0)));
+
+        // A loud peak holds the gain back
+        loudness.insert(
+            "a".to_string(),
+            LoudnessInfo {
+                integrated_lufs: -23.0,
+                peak: 0.8,
+            },
+        );
+        assert_eq!(loudness.gain(), Some(PEAK_CEILING / 0.8));
+
+        assert!(loudness.set_target(MAX_TARGET_LUFS + 1.0).is_err());
+        assert!(loudness.set_target(f32::NAN).is_err());
+        assert_eq!(loudness.get_target(), DEFAULT_TARGET_LUFS);
+    }
+
+    #[test]
+    fn scans_each_track_once() {
+        let mut loudness = Loudness::new();
+        loudness.set_enabled(true);
+        assert!(!loudness.needs_scan());
+
+        loudness.set_current(Some("a".to_string()));
+        assert!(loudness.needs_scan());
+        let (path, cancel_flag) = loudness.begin_scan().unwrap();
+        assert_eq!(path, "a");
+        assert!(!loudness.needs_scan());
+
+        // Moving on cancels the scan
+        loudness.set_current(Some("b".to_string()));
+        assert!(cancel_flag.load(Ordering::Relaxed));
+        assert!(loudness.needs_scan());
+
+        loudness.insert(
+            "b".to_string(),
+            LoudnessInfo {
+                integrated_lufs: -20.0,
+                peak: 0.5,
+            },
+        );
+        assert!(!loudness.needs_scan());
+        assert_eq!(loudness.get_current().unwrap().integrated_lufs, -20.0);
+    }
+}
@@ -1,7 +1,8 @@
 //i know it's janky. just for testing
 
-use libaurex::aurex::Player;
+use libaurex::blocking::{BlockingPlayer, block_on};
 use libaurex::enums::ResamplingQuality;
+use libaurex::events::PlayerEvent;
 use std::collections::VecDeque;
 use std::fs;
 use std::io;
@@ -37,8 +38,7 @@ fn get_all_paths(dir: &str, recursive: bool) -> io::Result<VecDeque<PathBuf>> {
     Ok(paths)
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         println!("No files provided.");
@@ -65,9 +65,12 @@ async fn main() {
 
     let files = all_files.clone();
 
-    let player = Player::new(
+    let player = BlockingPlayer::new(
         Some(ResamplingQuality::VeryHigh),
-        Box::new(move |_event, player_arc| {
+        Box::new(move |event, player_arc| {
+            if event != PlayerEvent::MediaEnd {
+                return;
+            }
             println!("Media Ended.");
             let player = player_arc.clone();
             let file = files
@@ -76,19 +79,18 @@ async fn main() {
                 .as_mut()
                 .and_then(|list| list.pop_front());
 
-            tokio::spawn(async move {
-                match file {
-                    Some(file_path) => {
-                        if let Some(path_str) = file_path.to_str() {
-                            _ = player.clone().load(path_str).await;
-                            _ = player.play().await;
-                        }
-                    }
-                    None => {
-                        std::process::exit(0);
+            // The engine isn't locked during callbacks, so the next track can be loaded right here
+            match file {
+                Some(file_path) => {
+                    if let Some(path_str) = file_path.to_str() {
+                        _ = block_on(player.clone().load(path_str));
+                        _ = block_on(player.play());
                     }
                 }
-            });
+                None => {
+                    std::process::exit(0);
+                }
+            }
         }),
     )
     .unwrap();
@@ -100,18 +102,18 @@ async fn main() {
             .as_mut()
             .and_then(|list| list.pop_front());
 
-        _ = player.clone().load(file.unwrap().to_str().unwrap()).await;
-        _ = player.play().await;
+        _ = player.load(file.unwrap().to_str().unwrap());
+        _ = player.play();
     } else {
-        _ = player.clone().load(&args[1]).await;
-        _ = player.play().await;
+        _ = player.load(&args[1]);
+        _ = player.play();
     }
 
     loop {
         println!(
             "Progress: {}/{}",
-            player.get_progress().await.unwrap(),
-            player.get_duration().await
+            player.get_progress().unwrap(),
+            player.get_duration()
         );
         thread::sleep(Duration::from_secs(1));
     }
@@ -0,0 +1,313 @@
+//meter.rs
+
+//Output level metering. The audio thread measures every block it hands to cpal and publishes the
+//result through atomics, so the UI can poll at frame rate without taking any lock
+
+use crate::dsp::linear_to_db;
+
+use std::{
+    f64::consts::PI,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+//Readings below this are reported as this
+pub const METER_FLOOR_DB: f32 = -120.0;
+
+//Peak fall back, in dB per second. Close to a type I PPM
+const PEAK_FALL_DB_PER_S: f32 = 20.0;
+
+//RMS integration time. Roughly VU ballistics
+const RMS_WINDOW_S: f32 = 0.3;
+
+//True-peak interpolation, 4x oversampling with 12 taps per phase as in BS.1770-4
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct ChannelLevels {
+    pub peak_db: f32,
+    pub rms_db: f32,
+    pub true_peak_db: Option<f32>, // None while true-peak metering is off
+}
+
+///Levels of the audio going to the device, in dBFS
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct Levels {
+    pub left: ChannelLevels,
+    pub right: ChannelLevels,
+}
+
+///Shared between the audio thread and readers. f32s are stored as bits
+pub struct MeterReadings {
+    peak: [AtomicU32; 2],
+    rms: [AtomicU32; 2],
+    true_peak: [AtomicU32; 2],
+    true_peak_enabled: AtomicBool,
+}
+
+impl MeterReadings {
+    pub fn new() -> Self {
+        MeterReadings {
+            peak: [AtomicU32::new(0), AtomicU32::new(0)],
+            rms: [AtomicU32::new(0), AtomicU32::new(0)],
+            true_peak: [AtomicU32::new(0), AtomicU32::new(0)],
+            true_peak_enabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_true_peak_enabled(&self) -> bool {
+        self.true_peak_enabled.load(Ordering::Relaxed)
+    }
+
+    ///True-peak costs a 4x oversampling filter on the audio thread, so it's off by default
+    pub fn set_true_peak_enabled(&self, enabled: bool) {
+        self.true_peak_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Levels {
+        let true_peak_enabled = self.is_true_peak_enabled();
+        let channel = |index: usize| ChannelLevels {
+            peak_db: to_db(load(&self.peak[index])),
+            rms_db: to_db(load(&self.rms[index])),
+            true_peak_db: true_peak_enabled.then(|| to_db(load(&self.true_peak[index]))),
+        };
+
+        Levels {
+            left: channel(0),
+            right: channel(1),
+        }
+    }
+
+    ///Drops the meters to silence. Used when output stops and no more blocks arrive
+    pub fn clear(&self) {
+        for value in self.peak.iter().chain(&self.rms).chain(&self.true_peak) {
+            value.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for MeterReadings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn load(value: &AtomicU32) -> f32 {
+    f32::from_bits(value.load(Ordering::Relaxed))
+}
+
+fn store(value: &AtomicU32, level: f32) {
+    value.store(level.to_bits(), Ordering::Relaxed);
+}
+
+fn to_db(level: f32) -> f32 {
+    linear_to_db(level).max(METER_FLOOR_DB)
+}
+
+///Audio thread side. Owned by the output stage
+pub struct LevelMeter {
+    sample_rate: u32,
+    peak_fall: f32, // Per frame multiplier
+    rms_coeff: f32, // One pole smoothing of the mean square
+    interpolator: [[f32; TAPS_PER_PHASE]; OVERSAMPLING],
+
+    peak: [f32; 2],
+    mean_square: [f32; 2],
+    true_peak: [f32; 2],
+    history: [[f32; TAPS_PER_PHASE]; 2], // Newest sample first
+    block_peak: [f32; 2],
+    block_true_peak: [f32; 2],
+    block_frames: usize,
+}
+
+impl LevelMeter {
+    pub fn new() -> Self {
+        LevelMeter {
+            sample_rate: 0,
+            peak_fall: 0.0,
+            rms_coeff: 1.0,
+            interpolator: interpolation_filter(),
+            peak: [0.0; 2],
+            mean_square: [0.0; 2],
+            true_peak: [0.0; 2],
+            history: [[0.0; TAPS_PER_PHASE]; 2],
+            block_peak: [0.0; 2],
+            block_true_peak: [0.0; 2],
+            block_frames: 0,
+        }
+    }
+
+    fn prepare(&mut self, sample_rate: u32) {
+        if sample_rate == self.sample_rate || sample_rate == 0 {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.peak_fall = 10f32.powf(-PEAK_FALL_DB_PER_S / 20.0 / sample_rate as f32);
+        self.rms_coeff = 1.0 - (-1.0 / (RMS_WINDOW_S * sample_rate as f32)).exp();
+    }
+
+    ///Starts a block. Call push for every frame, then publish
+    pub fn begin(&mut self, sample_rate: u32) {
+        self.prepare(sample_rate);
+        self.block_peak = [0.0; 2];
+        self.block_true_peak = [0.0; 2];
+        self.block_frames = 0;
+    }
+
+    #[inline]
+    pub fn push(&mut self, frame: [f32; 2], true_peak: bool) {
+        for (channel, sample) in frame.into_iter().enumerate() {
+            let level = sample.abs();
+            self.block_peak[channel] = self.block_peak[channel].max(level);
+            self.mean_square[channel] +=
+                (sample * sample - self.mean_square[channel]) * self.rms_coeff;
+
+            if true_peak {
+                let history = &mut self.history[channel];
+                history.copy_within(..TAPS_PER_PHASE - 1, 1);
+                history[0] = sample;
+
+                let mut highest = level;
+                for phase in &self.interpolator {
+                    let value: f32 = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+                    highest = highest.max(value.abs());
+                }
+                self.block_true_peak[channel] = self.block_true_peak[channel].max(highest);
+            }
+        }
+        self.block_frames += 1;
+    }
+
+    pub fn publish(&mut self, readings: &MeterReadings) {
+        let fall = self.peak_fall.powi(self.block_frames as i32);
+
+        for (channel, peak) in self.peak.iter_mut().enumerate() {
+            // Instant attack, steady fall back
+            *peak = self.block_peak[channel].max(*peak * fall);
+            self.true_peak[channel] =
+                self.block_true_peak[channel].max(self.true_peak[channel] * fall);
+
+            store(&readings.peak[channel], *peak);
+            store(
+                &readings.rms[channel],
+                self.mean_square[channel].max(0.0).sqrt(),
+            );
+            store(&readings.true_peak[channel], self.true_peak[channel]);
+        }
+    }
+}
+
+impl Default for LevelMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//Windowed sinc low pass at the original nyquist, split into phases
+fn interpolation_filter() -> [[f32; TAPS_PER_PHASE]; OVERSAMPLING] {
+    let length = OVERSAMPLING * TAPS_PER_PHASE;
+    let centre = (length - 1) as f64 / 2.0;
+    let mut phases = [[0.0f32; TAPS_PER_PHASE]; OVERSAMPLING];
+
+    for n in 0..length {
+        let x = (n as f64 - centre) / OVERSAMPLING as f64;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * x).sin() / (PI * x)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / (length - 1) as f64).cos();
+        phases[n % OVERSAMPLING][n / OVERSAMPLING] = (sinc * window) as f32;
+    }
+
+    phases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn run(
+        meter: &mut LevelMeter,
+        readings: &MeterReadings,
+        frames: impl Iterator<Item = [f32; 2]>,
+    ) {
+        let true_peak = readings.is_true_peak_enabled();
+        let frames: Vec<[f32; 2]> = frames.collect();
+        for block in frames.chunks(512) {
+            meter.begin(RATE);
+            for frame in block {
+                meter.push(*frame, true_peak);
+            }
+            meter.publish(readings);
+        }
+    }
+
+    fn sine(
+        amplitude: f32,
+        frequency: f64,
+        phase: f64,
+        seconds: f64,
+    ) -> impl Iterator<Item = [f32; 2]> {
+        (0..(seconds * RATE as f64) as usize).map(move |i| {
+            let x =
+                amplitude * (2.0 * PI * frequency * i as f64 / RATE as f64 + phase).sin() as f32;
+            [x, x * 0.5]
+        })
+    }
+
+    #[test]
+    fn reads_sine_peak_and_rms() {
+        let readings = MeterReadings::new();
+        let mut meter = LevelMeter::new();
+        run(&mut meter, &readings, sine(0.5, 997.0, 0.0, 3.0));
+
+        let levels = readings.get();
+        assert!((levels.left.peak_db + 6.02).abs() < 0.05);
+        assert!((levels.left.rms_db + 9.03).abs() < 0.1);
+        assert!((levels.right.peak_db + 12.04).abs() < 0.05);
+        assert!((levels.right.rms_db + 15.05).abs() < 0.1);
+        assert_eq!(levels.left.true_peak_db, None);
+    }
+
+    #[test]
+    fn true_peak_finds_peaks_between_samples() {
+        let readings = MeterReadings::new();
+        readings.set_true_peak_enabled(true);
+        let mut meter = LevelMeter::new();
+
+        // A quarter of the rate, sampled 45 degrees off its crests. Every sample is 3 dB under the real peak
+        run(
+            &mut meter,
+            &readings,
+            sine(1.0, RATE as f64 / 4.0, PI / 4.0, 1.0),
+        );
+
+        let left = readings.get().left;
+        assert!((left.peak_db + 3.01).abs() < 0.05);
+        let true_peak_db = left.true_peak_db.unwrap();
+        assert!(true_peak_db > -0.5 && true_peak_db < 0.5, "{true_peak_db}");
+    }
+
+    #[test]
+    fn peak_falls_back_and_clears() {
+        let readings = MeterReadings::new();
+        let mut meter = LevelMeter::new();
+        run(&mut meter, &readings, sine(1.0, 997.0, 0.0, 0.1));
+        run(
+            &mut meter,
+            &readings,
+            std::iter::repeat_n([0.0; 2], RATE as usize),
+        );
+
+        let peak_db = readings.get().left.peak_db;
+        assert!((peak_db + PEAK_FALL_DB_PER_S).abs() < 0.5, "{peak_db}");
+
+        readings.clear();
+        let levels = readings.get();
+        assert_eq!(levels.left.peak_db, METER_FLOOR_DB);
+        assert_eq!(levels.right.rms_db, METER_FLOOR_DB);
+    }
+}
@@ -0,0 +1,445 @@
+//network.rs
+
+//Settings for URLs ffmpeg fetches itself. These map onto ffmpeg's http protocol options. It
+//already seeks with Range requests when the server allows it
+
+use crate::enums::PlayerError;
+
+use ffmpeg_next::Dictionary;
+
+//Decode-ahead bounds for network sources, in seconds
+pub const MIN_NETWORK_BUFFER_S: u32 = 1;
+pub const MAX_NETWORK_BUFFER_S: u32 = 600;
+
+//Audio wanted before playback starts when there's no rebuffer amount to go by
+const START_S: u32 = 5;
+
+///Frames to wait for before starting playback. Never more than the decoder fills the buffer to, or play would
+///wait forever
+pub fn start_frames(buffer_frames: u32, rebuffer_frames: u32, sample_rate: u32) -> u32 {
+    let wanted = match rebuffer_frames {
+        0 => sample_rate * START_S,
+        rebuffer => rebuffer,
+    };
+    wanted.min(buffer_frames)
+}
+
+#[derive(Clone, PartialEq, Debug, uniffi::Record)]
+pub struct HttpHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Debug, uniffi::Record)]
+pub struct NetworkConfig {
+    pub timeout_ms: u32,            // Connect and read timeout. 0 waits forever
+    pub reconnect: bool,            // Reconnect after dropped connections and network errors
+    pub reconnect_delay_max_s: u32, // Stop retrying once the backoff gets this long
+    pub buffer_s: u32, // Audio decoded ahead of the playhead. Local files always use 10
+    pub rebuffer_s: u32, // Audio needed after an underrun before playback carries on. 0 plays through stalls
+    pub user_agent: Option<String>,
+    pub headers: Vec<HttpHeader>, // Sent with every request, e.g. auth tokens
+}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<(), i32> {
+        if !(MIN_NETWORK_BUFFER_S..=MAX_NETWORK_BUFFER_S).contains(&self.buffer_s) {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        if self.rebuffer_s > self.buffer_s {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        // No line breaks anywhere, they would let a value inject its own headers
+        let valid_text = |text: &str| !text.contains(['\r', '\n']);
+        if self
+            .user_agent
+            .as_deref()
+            .is_some_and(|agent| !valid_text(agent))
+        {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        for header in &self.headers {
+            if header.name.is_empty()
+                || header.name.contains(':')
+                || !valid_text(&header.name)
+                || !valid_text(&header.value)
+            {
+                return Err(PlayerError::INVALID_ARGUMENT);
+            }
+        }
+        Ok(())
+    }
+
+    ///ffmpeg options for opening a URL with these settings
+    pub fn to_dictionary(&self) -> Dictionary<'static> {
+        let mut options = Dictionary::new();
+
+        if self.timeout_ms > 0 {
+            let timeout_us = (self.timeout_ms as u64 * 1000).to_string();
+            options.set("timeout", &timeout_us);
+            options.set("rw_timeout", &timeout_us);
+        }
+
+        // Ask Icecast/SHOUTcast servers for in-stream titles
+        options.set("icy", "1");
+
+        if self.reconnect {
+            options.set("reconnect", "1");
+            options.set("reconnect_streamed", "1");
+            options.set("reconnect_on_network_error", "1");
+            options.set(
+                "reconnect_delay_max",
+                &self.reconnect_delay_max_s.to_string(),
+            );
+        }
+
+        if let Some(agent) = &self.user_agent {
+            options.set("user_agent", agent);
+        }
+
+        if !self.headers.is_empty() {
+            let headers: String = self
+                .headers
+                .iter()
+                .map(|header| format!("{}: {}\r\n", header.name, header.value))
+                .collect();
+            options.set("headers", &headers);
+        }
+
+        options
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            timeout_ms: 10_000,
+            reconnect: true,
+            reconnect_delay_max_s: 30,
+            buffer_s: 30,
+            rebuffer_s: 2,
+            user_agent: None,
+            headers: Vec::new(),
+        }
+    }
+}
+
+///Whether a URL goes over http(s) and should get the network settings
+pub fn is_network_url(url: &str) -> bool {
+    url.split_once("://").is_some_and(|(scheme, _)| {
+        scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocking::{BlockingPlayer, block_on},
+        events::PlayerEvent,
+        source::MediaSource,
+        testing::{TestServer, respond, wav, write_head},
+    };
+
+    use ffmpeg_next::{self as av, Packet};
+
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const RATE: u32 = 8000;
+
+    fn wav_server(body: Vec<u8>) -> TestServer {
+        TestServer::new(move |request, stream| respond(stream, request, "audio/wav", &body))
+    }
+
+    //Bytes of audio in every packet until the end
+    fn read_to_end(input: &mut av::format::context::Input) -> usize {
+        let mut total = 0;
+        let mut packet = Packet::empty();
+        while packet.read(input).is_ok() {
+            total += packet.size();
+        }
+        total
+    }
+
+    //Waits for an event the test player sent, skipping the rest
+    fn wait_for(events: &mpsc::Receiver<PlayerEvent>, wanted: PlayerEvent) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(left) {
+                Ok(event) if event == wanted => return,
+                Ok(_) => {}
+                Err(_) => panic!("no {wanted:?}"),
+            }
+        }
+    }
+
+    fn test_player(network: NetworkConfig) -> (BlockingPlayer, mpsc::Receiver<PlayerEvent>) {
+        let (tx, rx) = mpsc::channel();
+        let player = BlockingPlayer::new(
+            None,
+            Box::new(move |event, _| {
+                _ = tx.send(event);
+            }),
+        )
+        .unwrap();
+        block_on(player.player().set_network_config(network)).unwrap();
+        (player, rx)
+    }
+
+    fn with_header(name: &str, value: &str) -> NetworkConfig {
+        NetworkConfig {
+            headers: vec![HttpHeader {
+                name: name.to_string(),
+                value: value.to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accepts_plain_headers() {
+        assert!(NetworkConfig::default().validate().is_ok());
+        assert!(
+            with_header("Authorization", "Bearer abc:def")
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            NetworkConfig {
+                user_agent: Some("aurex/1.0 (Linux)".to_string()),
+                ..Default::default()
+            }
+            .validate()
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        assert_eq!(
+            with_header("X-Token", "abc\r\nHost: evil").validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            with_header("X-Token", "abc\nCookie: a=b").validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            with_header("X-Token\r\nHost", "evil").validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            with_header("Host: evil\r\nX", "a").validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            with_header("X-Token:", "abc").validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            with_header("", "abc").validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            NetworkConfig {
+                user_agent: Some("aurex\r\nX-Injected: 1".to_string()),
+                ..Default::default()
+            }
+            .validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+    }
+
+    #[test]
+    fn rejects_buffer_outside_bounds() {
+        let buffer = |buffer_s, rebuffer_s| NetworkConfig {
+            buffer_s,
+            rebuffer_s,
+            ..Default::default()
+        };
+        assert!(buffer(MIN_NETWORK_BUFFER_S, 0).validate().is_ok());
+        assert!(
+            buffer(MAX_NETWORK_BUFFER_S, MAX_NETWORK_BUFFER_S)
+                .validate()
+                .is_ok()
+        );
+        assert_eq!(buffer(0, 0).validate(), Err(PlayerError::INVALID_ARGUMENT));
+        assert_eq!(
+            buffer(MAX_NETWORK_BUFFER_S + 1, 2).validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            buffer(10, 11).validate(),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+    }
+
+    #[test]
+    fn playback_starts_within_the_buffer() {
+        let rate = 48000;
+        // Local files and network sources that play through stalls
+        assert_eq!(start_frames(rate * 10, 0, rate), rate * 5);
+        // Less buffer than the usual start amount
+        assert_eq!(start_frames(rate * 2, 0, rate), rate * 2);
+        // Starts as soon as a rebuffer's worth is in
+        assert_eq!(start_frames(rate * 30, rate * 2, rate), rate * 2);
+        assert_eq!(start_frames(rate * 3, rate * 3, rate), rate * 3);
+    }
+
+    #[test]
+    fn seeks_with_range_requests() {
+        let body = wav(10.0, RATE, 440.0);
+        let length = body.len();
+        let server = wav_server(body);
+
+        let mut input = MediaSource::from(server.url("/tone.wav"))
+            .open(&NetworkConfig::default())
+            .unwrap();
+        input
+            .seek(5 * i64::from(av::ffi::AV_TIME_BASE), i64::MIN..i64::MAX)
+            .unwrap();
+        let mut packet = Packet::empty();
+        packet.read(&mut input).unwrap();
+
+        // Halfway in, fetched from there instead of read through
+        let ranges: Vec<u64> = server.requests().iter().filter_map(|r| r.range).collect();
+        assert!(
+            ranges
+                .iter()
+                .any(|start| (length / 3..length * 2 / 3).contains(&(*start as usize))),
+            "{ranges:?}"
+        );
+    }
+
+    #[test]
+    fn reconnects_after_a_dropped_connection() {
+        let body = wav(4.0, RATE, 440.0);
+        let length = body.len();
+        let audio = length - 44;
+
+        // The first response stops halfway, like a connection dropped mid-download
+        let dropped = AtomicBool::new(false);
+        let server = TestServer::new(move |request, stream| {
+            if dropped.swap(true, Ordering::Relaxed) {
+                respond(stream, request, "audio/wav", &body);
+            } else {
+                let start = write_head(stream, request, "audio/wav", body.len());
+                _ = stream.write_all(&body[start..length / 2]);
+            }
+        });
+
+        let network = NetworkConfig {
+            reconnect_delay_max_s: 1,
+            ..Default::default()
+        };
+        let mut input = MediaSource::from(server.url("/tone.wav"))
+            .open(&network)
+            .unwrap();
+        assert_eq!(read_to_end(&mut input), audio);
+
+        // Picked up where the first connection stopped
+        let requests = server.requests();
+        assert!(requests.len() >= 2);
+        assert_eq!(requests.last().unwrap().range, Some(length as u64 / 2));
+
+        // Without reconnecting the rest never comes
+        let server = TestServer::new({
+            let body = wav(4.0, RATE, 440.0);
+            move |request, stream| {
+                let start = write_head(stream, request, "audio/wav", body.len());
+                _ = stream.write_all(&body[start..length / 2]);
+            }
+        });
+        let network = NetworkConfig {
+            reconnect: false,
+            ..Default::default()
+        };
+        let mut input = MediaSource::from(server.url("/tone.wav"))
+            .open(&network)
+            .unwrap();
+        assert!(read_to_end(&mut input) < audio);
+    }
+
+    #[test]
+    fn sends_the_configured_headers() {
+        let server = wav_server(wav(1.0, RATE, 440.0));
+        let network = NetworkConfig {
+            user_agent: Some("aurex-test".to_string()),
+            ..with_header("Authorization", "Bearer abc")
+        };
+        MediaSource::from(server.url("/tone.wav"))
+            .open(&network)
+            .unwrap();
+
+        let request = &server.requests()[0];
+        assert_eq!(request.header("User-Agent"), Some("aurex-test"));
+        assert_eq!(request.header("Authorization"), Some("Bearer abc"));
+        assert_eq!(request.header("Icy-MetaData"), Some("1"));
+    }
+
+    #[test]
+    #[ignore = "needs an output device"]
+    fn stalled_streams_buffer_and_carry_on() {
+        let body = wav(6.0, 44100, 440.0);
+
+        // Two seconds straight away, then nothing for three
+        let server = TestServer::new(move |request, stream| {
+            let start = write_head(stream, request, "audio/wav", body.len());
+            let first = (start + 44 + 44100 * 4 * 2).min(body.len());
+            _ = stream.write_all(&body[start..first]);
+            _ = stream.flush();
+            thread::sleep(Duration::from_secs(3));
+            _ = stream.write_all(&body[first..]);
+        });
+
+        let (player, events) = test_player(NetworkConfig {
+            buffer_s: 1,
+            rebuffer_s: 1,
+            ..Default::default()
+        });
+        player.load(&server.url("/stall.wav")).unwrap();
+        player.play().unwrap();
+
+        wait_for(&events, PlayerEvent::Buffering);
+        wait_for(&events, PlayerEvent::BufferingEnded);
+        wait_for(&events, PlayerEvent::MediaEnd);
+        player.shutdown();
+    }
+
+    #[test]
+    #[ignore = "needs an output device"]
+    fn short_buffers_still_start() {
+        let server = wav_server(wav(3.0, 44100, 440.0));
+
+        // Less buffer than the usual start amount, and a rebuffer as big as the buffer
+        for (buffer_s, rebuffer_s) in [(1, 0), (2, 2)] {
+            let (player, events) = test_player(NetworkConfig {
+                buffer_s,
+                rebuffer_s,
+                ..Default::default()
+            });
+            player.load(&server.url("/short.wav")).unwrap();
+            player.play().unwrap();
+            wait_for(&events, PlayerEvent::MediaEnd);
+            player.shutdown();
+        }
+    }
+
+    #[test]
+    fn recognises_network_urls() {
+        assert!(is_network_url("http://example.com/a.mp3"));
+        assert!(is_network_url("HTTPS://example.com/a.flac"));
+        assert!(!is_network_url("file:///music/a.flac"));
+        assert!(!is_network_url("/music/http://a.flac"));
+        assert!(!is_network_url("rtsp://example.com/live"));
+    }
+}
@@ -0,0 +1,416 @@
+//output.rs
+
+//Everything that happens to the audio between the FIFO and the device
+
+use crate::{
+    channels::ChannelMixer,
+    convolution::Convolver,
+    crossfeed::Crossfeed,
+    dither::Dither,
+    dsp::{AudioProcessor, LiveChain, db_to_linear},
+    equalizer::Equalizer,
+    handoff::StageFollower,
+    limiter::Limiter,
+    meter::{LevelMeter, MeterReadings},
+    ramp::{Ramp, ms_to_frames},
+    shared::SharedState,
+    singletons::get_volume,
+    spectrum::SpectrumTap,
+};
+
+use cpal::{I24, SizedSample};
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// Scale between the engine's i32 samples and the normalised f32 samples the stages work on
+const I32_SCALE: f32 = 2_147_483_648.0;
+
+//Scratch size until the stream says how big its blocks get
+pub const DEFAULT_BLOCK_FRAMES: usize = 4096;
+
+//Track gain changes mid-track, e.g. a finished loudness scan or a new preamp, glide over this so they aren't
+//heard as a jump. A new track starts straight at its own gain
+const TRACK_GAIN_RAMP_MS: u32 = 1000;
+
+///Device sample types the output can write. Samples are i32 all the way until this conversion
+pub trait OutputSample: SizedSample + Send + 'static {
+    const BITS: u32; // Depth the samples are dithered to, 32 for none
+
+    fn from_i32(sample: i32) -> Self;
+}
+
+impl OutputSample for i16 {
+    const BITS: u32 = 16;
+
+    fn from_i32(sample: i32) -> Self {
+        (sample >> 16) as i16
+    }
+}
+
+impl OutputSample for I24 {
+    const BITS: u32 = 24;
+
+    fn from_i32(sample: i32) -> Self {
+        I24::new_unchecked(sample >> 8)
+    }
+}
+
+impl OutputSample for i32 {
+    const BITS: u32 = 32;
+
+    fn from_i32(sample: i32) -> Self {
+        sample
+    }
+}
+
+//Float keeps more than any DAC resolves at every level, so it isn't dithered
+impl OutputSample for f32 {
+    const BITS: u32 = 32;
+
+    fn from_i32(sample: i32) -> Self {
+        sample as f32 / I32_SCALE
+    }
+}
+
+///Owned by the cpal callback. Each stage is the audio thread's own copy, which takes the engine's settings at the
+///start of a callback without waiting on it. See handoff.rs
+pub struct OutputStage {
+    equalizer: StageFollower<Equalizer>,
+    processors: Arc<Mutex<LiveChain>>, // Only locked by the engine while nothing is pulling audio
+    crossfeed: StageFollower<Crossfeed>,
+    channels: StageFollower<ChannelMixer>,
+    convolver: StageFollower<Convolver>,
+    limiter: StageFollower<Limiter>,
+    dither: StageFollower<Dither>,
+    track_gain: Ramp,
+    track_gain_steps: u32, // Last step_track_gain seen
+    volume: Ramp,
+    transport: Ramp, // Play/pause/seek/stop fades
+    meter: LevelMeter,
+    levels: Arc<MeterReadings>,
+    spectrum: Arc<SpectrumTap>,
+    shared: Arc<SharedState>,
+    scratch: Vec<[f32; 2]>,
+}
+
+impl OutputStage {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        equalizer: StageFollower<Equalizer>,
+        processors: Arc<Mutex<LiveChain>>,
+        crossfeed: StageFollower<Crossfeed>,
+        channels: StageFollower<ChannelMixer>,
+        convolver: StageFollower<Convolver>,
+        limiter: StageFollower<Limiter>,
+        dither: StageFollower<Dither>,
+        levels: Arc<MeterReadings>,
+        spectrum: Arc<SpectrumTap>,
+        shared: Arc<SharedState>,
+    ) -> Self {
+        OutputStage {
+            equalizer,
+            processors,
+            crossfeed,
+            channels,
+            convolver,
+            limiter,
+            dither,
+            track_gain: Ramp::new(shared.get_track_gain()),
+            track_gain_steps: shared.get_track_gain_steps(),
+            volume: Ramp::new(get_volume()),
+            transport: Ramp::new(0.0),
+            meter: LevelMeter::new(),
+            levels,
+            spectrum,
+            shared,
+            scratch: vec![[0.0; 2]; DEFAULT_BLOCK_FRAMES],
+        }
+    }
+
+    ///Processes interleaved stereo i32 samples in place
+    pub fn process(&mut self, data: &mut [i32], sample_rate: u32) {
+        // Take whatever the engine changed since the last callback. Nothing here waits on a control call, each
+        // stage carries on with the last settings it took
+        self.equalizer.sync();
+        self.crossfeed.sync();
+        self.channels.sync();
+        self.convolver.sync();
+        self.limiter.sync();
+        // The engine only takes the chain's lock once nothing has pulled audio for a while, so this gets it
+        let mut processors = self.processors.try_lock().ok();
+        if let Some(chain) = processors.as_mut() {
+            chain.apply_edits();
+        }
+
+        // Bit-perfect playback skips all of it, fades included. The settings stay for later
+        let dsp = !self.shared.get_bit_perfect();
+        let eq_active = dsp && self.equalizer.is_enabled();
+        let chain_active = dsp && processors.as_ref().is_some_and(|chain| chain.is_active());
+        let crossfeed_active = dsp && self.crossfeed.is_active();
+        let channels_active = dsp && self.channels.is_active();
+        let convolver_active = dsp && self.convolver.is_active();
+        let true_peak = self.levels.is_true_peak_enabled();
+        self.meter.begin(sample_rate);
+
+        let track_gain_steps = self.shared.get_track_gain_steps();
+        if !dsp {
+            self.track_gain.jump(1.0);
+        } else if track_gain_steps != self.track_gain_steps {
+            self.track_gain.jump(self.shared.get_track_gain());
+        } else {
+            self.track_gain.set_target(
+                self.shared.get_track_gain(),
+                ms_to_frames(TRACK_GAIN_RAMP_MS, sample_rate),
+            );
+        }
+        self.track_gain_steps = track_gain_steps;
+
+        if dsp {
+            self.volume.set_target(
+                get_volume(),
+                ms_to_frames(self.shared.get_volume_ramp_ms(), sample_rate),
+            );
+        } else {
+            self.volume.jump(1.0);
+        }
+        let (transport_target, transport_ms) = self.shared.get_transport_target();
+        if dsp {
+            self.transport
+                .set_target(transport_target, ms_to_frames(transport_ms, sample_rate));
+        } else {
+            // Play, pause, seek and stop cut straight in and out, so no sample is ever scaled
+            self.transport.jump(transport_target);
+        }
+
+        // Anything that can take the signal over full scale engages the limiter, enabled or not
+        let mut boost = self.volume.target().max(self.volume.current())
+            * self.track_gain.target().max(self.track_gain.current());
+        if eq_active {
+            boost *= db_to_linear(self.equalizer.max_gain_db());
+        }
+        if channels_active {
+            boost *= self.channels.max_gain();
+        }
+        self.limiter.set_boosted(dsp && boost > 1.0);
+        let limiter_active = dsp && self.limiter.is_active();
+
+        // Nothing to do, keep the path bit exact. The limiter always runs while engaged so its delay stays constant
+        if !eq_active
+            && !chain_active
+            && !crossfeed_active
+            && !channels_active
+            && !convolver_active
+            && !limiter_active
+            && self.track_gain.is_unity()
+            && self.volume.is_unity()
+            && self.transport.is_unity()
+        {
+            self.shared.set_transport_level(1.0);
+            for pair in data.chunks_exact(2) {
+                self.meter.push(
+                    [pair[0] as f32 / I32_SCALE, pair[1] as f32 / I32_SCALE],
+                    true_peak,
+                );
+            }
+            self.meter.publish(&self.levels);
+            return;
+        }
+
+        // The scratch buffer is sized when the stream is built. A bigger request is worked through in pieces
+        // rather than allocating here
+        let block_frames = self.scratch.len();
+        for block in data.chunks_mut(block_frames * 2) {
+            let frames = block.len() / 2;
+            let scratch = &mut self.scratch[..frames];
+            for (frame, pair) in scratch.iter_mut().zip(block.chunks_exact(2)) {
+                *frame = [pair[0] as f32 / I32_SCALE, pair[1] as f32 / I32_SCALE];
+            }
+
+            // ReplayGain / loudness normalisation goes first so everything after sees the normalised level
+            if !self.track_gain.is_unity() {
+                for frame in scratch.iter_mut() {
+                    let gain = self.track_gain.tick();
+                    frame[0] *= gain;
+                    frame[1] *= gain;
+                }
+            }
+
+            if eq_active {
+                self.equalizer.process(scratch, sample_rate);
+            }
+
+            if let Some(chain) = processors.as_mut().filter(|_| chain_active) {
+                chain.process(scratch, sample_rate);
+            }
+
+            if crossfeed_active {
+                self.crossfeed.process(scratch, sample_rate);
+            }
+
+            // Balance, mono, swap, mute and routing. Before volume so the limiter sees what actually goes out
+            if channels_active {
+                self.channels.process(scratch, sample_rate);
+            }
+
+            // Room / headphone correction sees the final stereo image
+            if convolver_active {
+                self.convolver.process(scratch, sample_rate);
+            }
+
+            // Apply volume, ramped per sample so changes don't zip
+            if !self.volume.is_unity() {
+                for frame in scratch.iter_mut() {
+                    let vol = self.volume.tick();
+                    frame[0] *= vol;
+                    frame[1] *= vol;
+                }
+            }
+
+            if limiter_active {
+                self.limiter.process(scratch, sample_rate);
+            }
+
+            // Transport fade goes last so it also fades out whatever the limiter is holding
+            if !self.transport.is_unity() {
+                for frame in scratch.iter_mut() {
+                    let level = self.transport.tick();
+                    frame[0] *= level;
+                    frame[1] *= level;
+                }
+            }
+
+            // Meter exactly what goes to the device
+            for frame in scratch.iter() {
+                self.meter.push(*frame, true_peak);
+            }
+
+            for (frame, pair) in scratch.iter().zip(block.chunks_exact_mut(2)) {
+                // Float to int casts saturate so this doubles as the clamp
+                pair[0] = (frame[0] * I32_SCALE) as i32;
+                pair[1] = (frame[1] * I32_SCALE) as i32;
+            }
+        }
+        self.shared.set_transport_level(self.transport.current());
+        self.meter.publish(&self.levels);
+    }
+
+    ///Sizes the scratch buffer for the largest block the device asks for. Called before the stream starts, so
+    ///the audio thread never allocates
+    pub fn reserve(&mut self, frames: usize) {
+        if self.scratch.len() < frames {
+            self.scratch.resize(frames, [0.0; 2]);
+        }
+    }
+
+    ///Takes processed samples down to the device's bit depth. Bit-perfect output goes out untouched
+    pub fn dither(&mut self, data: &mut [i32], bits: u32) {
+        if self.shared.get_bit_perfect() {
+            return;
+        }
+        self.dither.sync();
+        self.dither.process(data, bits);
+    }
+
+    ///Hands the whole device buffer, silence included, to the visualiser tap. delay is how long until it's heard
+    pub fn capture(&self, data: &[i32], sample_rate: u32, delay: Duration) {
+        if !self.spectrum.is_enabled() {
+            return;
+        }
+        let frames = data
+            .chunks_exact(2)
+            .map(|pair| [pair[0] as f32 / I32_SCALE, pair[1] as f32 / I32_SCALE]);
+        self.spectrum.write(frames, sample_rate, delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dsp::ChainControl, handoff::StageControl};
+
+    const RATE: u32 = 48000;
+    const BLOCK: usize = 480;
+
+    fn output_stage(shared: Arc<SharedState>) -> OutputStage {
+        // Stands in for play, which fades the transport in
+        shared.set_transport_target(1.0, 0);
+        OutputStage::new(
+            StageControl::new(Equalizer::new()).follower(RATE),
+            ChainControl::new().live(),
+            StageControl::new(Crossfeed::new()).follower(RATE),
+            StageControl::new(ChannelMixer::new()).follower(RATE),
+            StageControl::new(Convolver::new()).follower(RATE),
+            StageControl::new(Limiter::new()).follower(RATE),
+            StageControl::new(Dither::new()).follower(RATE),
+            Arc::new(MeterReadings::new()),
+            Arc::new(SpectrumTap::new()),
+            shared,
+        )
+    }
+
+    //Runs a block of constant level through the stage. Left channel of what comes out
+    fn run(stage: &mut OutputStage, level: f32) -> Vec<f32> {
+        let mut data = vec![(level * I32_SCALE) as i32; BLOCK * 2];
+        stage.process(&mut data, RATE);
+        data.iter()
+            .step_by(2)
+            .map(|&sample| sample as f32 / I32_SCALE)
+            .collect()
+    }
+
+    #[test]
+    fn track_gain_glides_mid_track_and_steps_on_a_new_one() {
+        let shared = Arc::new(SharedState::new());
+        let mut stage = output_stage(shared.clone());
+        assert!(run(&mut stage, 0.25).iter().all(|&x| x == 0.25));
+
+        // A finished scan or a new preamp mid-track
+        shared.set_track_gain(0.5);
+        let block = run(&mut stage, 0.25);
+        assert!(block.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(block.iter().all(|&x| x > 0.24));
+
+        for _ in 0..(TRACK_GAIN_RAMP_MS * RATE / 1000) as usize / BLOCK {
+            run(&mut stage, 0.25);
+        }
+        assert!(
+            run(&mut stage, 0.25)
+                .iter()
+                .all(|&x| (x - 0.125).abs() < 1e-6)
+        );
+
+        // Nothing of a new track has played, so it starts straight at its own gain
+        shared.set_track_gain(1.0);
+        shared.step_track_gain();
+        assert!(run(&mut stage, 0.25).iter().all(|&x| x == 0.25));
+    }
+
+    #[test]
+    fn boost_engages_the_limiter() {
+        let shared = Arc::new(SharedState::new());
+        let mut stage = output_stage(shared.clone());
+        assert!(!stage.limiter.is_enabled());
+
+        shared.set_track_gain(4.0);
+        shared.step_track_gain();
+        for _ in 0..10 {
+            run(&mut stage, 0.5);
+        }
+        assert!(stage.limiter.is_engaged());
+        let ceiling = db_to_linear(stage.limiter.get_ceiling());
+        assert!(ceiling < 1.0);
+        assert!(run(&mut stage, 0.5).iter().all(|&x| x <= ceiling));
+
+        // Back at unity it lets go, and the path is bit exact again
+        shared.set_track_gain(1.0);
+        shared.step_track_gain();
+        for _ in 0..10 {
+            run(&mut stage, 0.5);
+        }
+        assert!(!stage.limiter.is_active());
+        assert!(run(&mut stage, 0.5).iter().all(|&x| x == 0.5));
+    }
+}
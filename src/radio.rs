@@ -0,0 +1,232 @@
+//radio.rs
+
+//Live streams, Icecast and SHOUTcast in particular. They have no duration, can't seek and drop
+//out now and then. ICY metadata is interleaved with the audio and ffmpeg's http protocol keeps
+//the latest block as an option on the IO context
+
+use crate::{network::NetworkConfig, source::MediaInput};
+
+use ffmpeg_next::sys;
+
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    ptr,
+    time::{Duration, Instant},
+};
+
+//How often the ICY block is looked at. Stations change titles every few minutes at most
+const ICY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+//From opt.h
+const AV_OPT_SEARCH_CHILDREN: c_int = 1;
+
+///Everything needed to reconnect a live stream, and what it last told us
+pub struct LiveStream {
+    pub url: String,
+    pub network: NetworkConfig,
+    pub title: Option<String>,
+    last_poll: Option<Instant>,
+}
+
+impl LiveStream {
+    pub fn new(url: String, network: NetworkConfig) -> Self {
+        LiveStream {
+            url,
+            network,
+            title: None,
+            last_poll: None,
+        }
+    }
+
+    ///Returns the title if it changed since the last poll. Cheap to call for every packet
+    pub fn poll_title(&mut self, input: &MediaInput) -> Option<String> {
+        if self
+            .last_poll
+            .is_some_and(|last| last.elapsed() < ICY_POLL_INTERVAL)
+        {
+            return None;
+        }
+        self.last_poll = Some(Instant::now());
+
+        let title = parse_stream_title(&io_option(input, c"icy_metadata_packet")?)?;
+        if self.title.as_deref() == Some(title.as_str()) {
+            return None;
+        }
+        self.title = Some(title.clone());
+        Some(title)
+    }
+}
+
+///Whether an opened network input is live: there's no duration, or it's an Icecast/SHOUTcast server
+pub fn is_live(input: &MediaInput) -> bool {
+    input.duration() <= 0
+        || io_option(input, c"icy_metadata_headers").is_some_and(|headers| !headers.is_empty())
+}
+
+///Pulls the title out of a block like StreamTitle='Artist - Title';StreamUrl='';
+pub fn parse_stream_title(packet: &str) -> Option<String> {
+    const FIELD: &str = "StreamTitle='";
+    let start = packet.find(FIELD)? + FIELD.len();
+    let rest = &packet[start..];
+
+    // Titles can have quotes in them, so the field ends at '; rather than the next quote
+    let end = rest.find("';").or_else(|| rest.rfind('\''))?;
+    let title = rest[..end].trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+//A string option on the input's IO context or the protocol under it. None for custom IO
+fn io_option(input: &MediaInput, name: &CStr) -> Option<String> {
+    unsafe {
+        let pb = (*input.as_ptr()).pb;
+        if pb.is_null() {
+            return None;
+        }
+
+        let mut value: *mut u8 = ptr::null_mut();
+        if sys::av_opt_get(
+            pb as *mut c_void,
+            name.as_ptr(),
+            AV_OPT_SEARCH_CHILDREN,
+            &mut value,
+        ) < 0
+            || value.is_null()
+        {
+            return None;
+        }
+
+        let text = CStr::from_ptr(value as *const c_char)
+            .to_string_lossy()
+            .into_owned();
+        sys::av_free(value as *mut c_void);
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        source::MediaSource,
+        testing::{TestServer, respond, wav},
+    };
+
+    use ffmpeg_next::Packet;
+
+    use std::io::Write;
+
+    const RATE: u32 = 8000;
+    const METAINT: usize = 4000;
+
+    //Interleaves a metadata block after every METAINT bytes like Icecast does. Blocks are empty unless title gives
+    //a title for that block
+    fn with_metadata(audio: &[u8], title: impl Fn(usize) -> Option<&'static str>) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (n, chunk) in audio.chunks(METAINT).enumerate() {
+            body.extend_from_slice(chunk);
+            if chunk.len() < METAINT {
+                break;
+            }
+            let text = title(n).map_or(String::new(), |title| format!("StreamTitle='{title}';"));
+            let blocks = text.len().div_ceil(16);
+            body.push(blocks as u8);
+            body.extend_from_slice(text.as_bytes());
+            body.resize(body.len() + blocks * 16 - text.len(), 0);
+        }
+        body
+    }
+
+    //Sends metadata only to clients that ask for it, with no length since the stream never ends
+    fn icecast_server(audio: Vec<u8>, body: Vec<u8>) -> TestServer {
+        TestServer::new(move |request, stream| {
+            let icy = request.header("Icy-MetaData") == Some("1");
+            let metaint = match icy {
+                true => format!("icy-metaint: {METAINT}\r\n"),
+                false => String::new(),
+            };
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: audio/wav\r\nicy-name: Test FM\r\n{metaint}Connection: close\r\n\r\n"
+            );
+            _ = stream.write_all(head.as_bytes());
+            _ = stream.write_all(if icy { &body } else { &audio });
+        })
+    }
+
+    #[test]
+    fn icecast_streams_are_live_with_titles_and_clean_audio() {
+        let audio = wav(8.0, RATE, 440.0);
+        let middle = audio.len() / METAINT / 2;
+        let body = with_metadata(&audio, |n| match n {
+            0 => Some("First"),
+            n if n == middle => Some("Second"),
+            _ => None,
+        });
+        let server = icecast_server(audio.clone(), body);
+
+        let source = MediaSource::from(server.url("/stream"));
+        let mut input = source.open(&NetworkConfig::default()).unwrap();
+        assert!(is_live(&input));
+        assert!(
+            server
+                .requests()
+                .iter()
+                .all(|request| request.header("Icy-MetaData") == Some("1"))
+        );
+
+        let mut live = LiveStream::new(server.url("/stream"), NetworkConfig::default());
+        let mut titles = Vec::new();
+        let mut pcm = Vec::new();
+        let mut packet = Packet::empty();
+        while packet.read(&mut input).is_ok() {
+            pcm.extend_from_slice(packet.data().unwrap_or_default());
+            live.last_poll = None;
+            titles.extend(live.poll_title(&input));
+        }
+
+        // Each title once, in order, and none of the metadata left in the audio
+        assert_eq!(titles, ["First", "Second"]);
+        assert_eq!(live.title.as_deref(), Some("Second"));
+        assert!(pcm == audio[44..]);
+    }
+
+    #[test]
+    fn files_over_http_are_not_live() {
+        let audio = wav(1.0, RATE, 440.0);
+        let server =
+            TestServer::new(move |request, stream| respond(stream, request, "audio/wav", &audio));
+        let input = MediaSource::from(server.url("/file.wav"))
+            .open(&NetworkConfig::default())
+            .unwrap();
+        assert!(!is_live(&input));
+    }
+
+    #[test]
+    fn parses_stream_titles() {
+        assert_eq!(
+            parse_stream_title("StreamTitle='Artist - Title';StreamUrl='';").as_deref(),
+            Some("Artist - Title")
+        );
+        assert_eq!(
+            parse_stream_title("StreamTitle='Guns N' Roses - Don't Cry';").as_deref(),
+            Some("Guns N' Roses - Don't Cry")
+        );
+        // Some servers leave off the closing semicolon
+        assert_eq!(
+            parse_stream_title("StreamTitle='Title'").as_deref(),
+            Some("Title")
+        );
+        assert_eq!(
+            parse_stream_title("StreamUrl='http://a';StreamTitle=' Padded ';").as_deref(),
+            Some("Padded")
+        );
+    }
+
+    #[test]
+    fn ignores_empty_and_malformed_titles() {
+        assert_eq!(parse_stream_title("StreamTitle='';"), None);
+        assert_eq!(parse_stream_title("StreamTitle='   ';"), None);
+        assert_eq!(parse_stream_title("StreamTitle='unterminated"), None);
+        assert_eq!(parse_stream_title("StreamUrl='http://a';"), None);
+        assert_eq!(parse_stream_title(""), None);
+    }
+}
@@ -0,0 +1,147 @@
+//ramp.rs
+
+//Per-sample linear gain ramp. Used by the output stage for volume changes and transport fades
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ramp {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: u32,
+}
+
+impl Ramp {
+    pub fn new(value: f32) -> Self {
+        Ramp {
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+        }
+    }
+
+    ///Starts a ramp towards target over the given number of frames. A new target mid-ramp starts from where it is
+    pub fn set_target(&mut self, target: f32, frames: u32) {
+        if target == self.target {
+            return;
+        }
+
+        self.target = target;
+        if frames == 0 {
+            self.current = target;
+            self.remaining = 0;
+        } else {
+            self.step = (target - self.current) / frames as f32;
+            self.remaining = frames;
+        }
+    }
+
+    ///Goes straight to target, cutting short any ramp in progress
+    pub fn jump(&mut self, target: f32) {
+        self.target = target;
+        self.current = target;
+        self.remaining = 0;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.remaining == 0
+    }
+
+    ///Settled at exactly 1.0, so the gain can be skipped
+    pub fn is_unity(&self) -> bool {
+        self.is_settled() && self.current == 1.0
+    }
+
+    #[inline]
+    pub fn tick(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            // Land exactly on the target so is_unity holds afterwards
+            self.current = if self.remaining == 0 {
+                self.target
+            } else {
+                self.current + self.step
+            };
+        }
+        self.current
+    }
+}
+
+pub fn ms_to_frames(ms: u32, sample_rate: u32) -> u32 {
+    (ms as u64 * sample_rate as u64 / 1000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaches_the_target_in_the_given_frames() {
+        let mut ramp = Ramp::new(0.0);
+        ramp.set_target(1.0, 4);
+        assert_eq!(ramp.current(), 0.0);
+        assert!(!ramp.is_settled());
+
+        let values: Vec<f32> = (0..4).map(|_| ramp.tick()).collect();
+        assert_eq!(values, [0.25, 0.5, 0.75, 1.0]);
+        assert!(ramp.is_unity());
+
+        // Stays put once settled
+        assert_eq!(ramp.tick(), 1.0);
+    }
+
+    #[test]
+    fn zero_frames_and_jumps_apply_straight_away() {
+        let mut ramp = Ramp::new(1.0);
+        ramp.set_target(0.5, 0);
+        assert_eq!(ramp.current(), 0.5);
+        assert!(ramp.is_settled());
+
+        ramp.set_target(0.0, 100);
+        ramp.tick();
+        ramp.jump(1.0);
+        assert_eq!(ramp.tick(), 1.0);
+        assert!(ramp.is_unity());
+    }
+
+    #[test]
+    fn retargeting_starts_from_where_it_is() {
+        let mut ramp = Ramp::new(0.0);
+        ramp.set_target(1.0, 10);
+        for _ in 0..5 {
+            ramp.tick();
+        }
+        let midway = ramp.current();
+        assert!((midway - 0.5).abs() < 1e-6);
+
+        // Back down over 5 frames, without jumping up first
+        ramp.set_target(0.0, 5);
+        let first = ramp.tick();
+        assert!(first < midway && (first - 0.4).abs() < 1e-6);
+        for _ in 0..4 {
+            ramp.tick();
+        }
+        assert_eq!(ramp.current(), 0.0);
+        assert!(ramp.is_settled());
+
+        // The same target again doesn't restart anything
+        ramp.set_target(0.0, 5);
+        assert!(ramp.is_settled());
+    }
+
+    #[test]
+    fn converts_milliseconds() {
+        assert_eq!(ms_to_frames(30, 48000), 1440);
+        assert_eq!(ms_to_frames(0, 48000), 0);
+        // Ten minutes at 384 kHz would overflow in 32 bits on the way
+        assert_eq!(ms_to_frames(600_000, 384000), 230_400_000);
+    }
+}
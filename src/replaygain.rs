@@ -0,0 +1,266 @@
+//replaygain.rs
+
+use crate::{dsp::db_to_linear, enums::ReplayGainMode};
+
+use ffmpeg_next::DictionaryRef;
+
+//R128 tags are relative to -23 LUFS, ReplayGain to -18 LUFS
+const R128_TO_REPLAYGAIN_DB: f32 = 5.0;
+
+pub const MAX_PREAMP_DB: f32 = 15.0;
+
+///Gain tags of the loaded track. Gains in dB, peaks as linear sample values
+#[derive(Clone, Copy, PartialEq, Debug, Default, uniffi::Record)]
+pub struct ReplayGainInfo {
+    pub track_gain_db: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain_db: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGainInfo {
+    ///Reads REPLAYGAIN_* and R128_* tags. Dictionaries are searched in order, so pass the stream's before the container's
+    pub fn from_metadata(dictionaries: &[DictionaryRef]) -> Option<Self> {
+        let lookup = |key: &str| dictionaries.iter().find_map(|dict| dict.get(key));
+
+        let mut info = ReplayGainInfo {
+            track_gain_db: lookup("REPLAYGAIN_TRACK_GAIN").and_then(parse_gain),
+            track_peak: lookup("REPLAYGAIN_TRACK_PEAK").and_then(parse_peak),
+            album_gain_db: lookup("REPLAYGAIN_ALBUM_GAIN").and_then(parse_gain),
+            album_peak: lookup("REPLAYGAIN_ALBUM_PEAK").and_then(parse_peak),
+        };
+
+        // Opus files carry Q7.8 fixed point R128 gains instead
+        if info.track_gain_db.is_none() {
+            info.track_gain_db = lookup("R128_TRACK_GAIN").and_then(parse_r128);
+        }
+        if info.album_gain_db.is_none() {
+            info.album_gain_db = lookup("R128_ALBUM_GAIN").and_then(parse_r128);
+        }
+
+        if info.track_gain_db.is_none() && info.album_gain_db.is_none() {
+            return None;
+        }
+
+        Some(info)
+    }
+}
+
+fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .or_else(|| value.strip_suffix("DB"))
+        .unwrap_or(value);
+
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|gain| gain.is_finite())
+}
+
+fn parse_peak(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|peak| peak.is_finite() && *peak > 0.0)
+}
+
+fn parse_r128(value: &str) -> Option<f32> {
+    let q78 = value.trim().parse::<i16>().ok()?;
+    Some(q78 as f32 / 256.0 + R128_TO_REPLAYGAIN_DB)
+}
+
+///User settings plus the tags of whatever is loaded
+pub struct ReplayGain {
+    mode: ReplayGainMode,
+    preamp_db: f32,
+    prevent_clipping: bool,
+    info: Option<ReplayGainInfo>,
+}
+
+impl ReplayGain {
+    pub fn new() -> Self {
+        ReplayGain {
+            mode: ReplayGainMode::Off,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+            info: None,
+        }
+    }
+
+    pub fn get_mode(&self) -> ReplayGainMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ReplayGainMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_preamp(&self) -> f32 {
+        self.preamp_db
+    }
+
+    pub fn set_preamp(&mut self, preamp_db: f32) -> Result<(), i32> {
+        if !preamp_db.is_finite() || preamp_db.abs() > MAX_PREAMP_DB {
+            return Err(-1);
+        }
+        self.preamp_db = preamp_db;
+        Ok(())
+    }
+
+    pub fn get_prevent_clipping(&self) -> bool {
+        self.prevent_clipping
+    }
+
+    pub fn set_prevent_clipping(&mut self, prevent_clipping: bool) {
+        self.prevent_clipping = prevent_clipping;
+    }
+
+    pub fn get_info(&self) -> Option<ReplayGainInfo> {
+        self.info
+    }
+
+    pub fn set_info(&mut self, info: Option<ReplayGainInfo>) {
+        self.info = info;
+    }
+
+    ///Linear gain to apply to the current track. None when off or untagged
+    pub fn gain(&self) -> Option<f32> {
+        let info = self.info?;
+
+        // Fall back to the other mode's tags when the preferred one is missing
+        let (gain_db, peak) = match self.mode {
+            ReplayGainMode::Off => return None,
+            ReplayGainMode::Track => (
+                info.track_gain_db.or(info.album_gain_db),
+                info.track_peak.or(info.album_peak),
+            ),
+            ReplayGainMode::Album => (
+                info.album_gain_db.or(info.track_gain_db),
+                info.album_peak.or(info.track_peak),
+            ),
+        };
+
+        let mut gain = db_to_linear(gain_db? + self.preamp_db);
+
+        if self.prevent_clipping
+            && let Some(peak) = peak
+        {
+            gain = gain.min(1.0 / peak);
+        }
+
+        Some(gain)
+    }
+}
+
+impl Default for ReplayGain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(
+        track_gain_db: f32,
+        track_peak: f32,
+        album_gain_db: f32,
+        album_peak: f32,
+    ) -> ReplayGain {
+        let mut replaygain = ReplayGain::new();
+        replaygain.set_info(Some(ReplayGainInfo {
+            track_gain_db: Some(track_gain_db),
+            track_peak: Some(track_peak),
+            album_gain_db: Some(album_gain_db),
+            album_peak: Some(album_peak),
+        }));
+        replaygain
+    }
+
+    #[test]
+    fn parses_r128_as_replaygain() {
+        // Q7.8 relative to -23 LUFS, moved to the -18 LUFS reference
+        assert_eq!(parse_r128("0"), Some(5.0));
+        assert_eq!(parse_r128("-2560"), Some(-5.0));
+        assert_eq!(parse_r128(" 384 "), Some(6.5));
+        assert_eq!(parse_r128("-32768"), Some(-123.0));
+        assert_eq!(parse_r128("32768"), None);
+        assert_eq!(parse_r128("1.5"), None);
+        assert_eq!(parse_r128(""), None);
+    }
+
+    #[test]
+    fn parses_gains_and_peaks() {
+        assert_eq!(parse_gain("-6.50 dB"), Some(-6.5));
+        assert_eq!(parse_gain("+2.1db"), Some(2.1));
+        assert_eq!(parse_gain("3"), Some(3.0));
+        assert_eq!(parse_gain("inf dB"), None);
+        assert_eq!(parse_gain("loud"), None);
+
+        assert_eq!(parse_peak("0.988"), Some(0.988));
+        assert_eq!(parse_peak("0"), None);
+        assert_eq!(parse_peak("-1"), None);
+        assert_eq!(parse_peak("NaN"), None);
+    }
+
+    #[test]
+    fn picks_the_mode_and_falls_back() {
+        let mut replaygain = tagged(-6.0, 0.5, -3.0, 0.5);
+        assert_eq!(replaygain.gain(), None);
+
+        replaygain.set_mode(ReplayGainMode::Track);
+        assert_eq!(replaygain.gain(), Some(db_to_linear(-6.0)));
+        replaygain.set_mode(ReplayGainMode::Album);
+        assert_eq!(replaygain.gain(), Some(db_to_linear(-3.0)));
+
+        replaygain.set_info(Some(ReplayGainInfo {
+            track_gain_db: Some(-6.0),
+            ..Default::default()
+        }));
+        assert_eq!(replaygain.gain(), Some(db_to_linear(-6.0)));
+
+        replaygain.set_info(None);
+        assert_eq!(replaygain.gain(), None);
+    }
+
+    #[test]
+    fn clipping_prevention_caps_gain_at_the_peak() {
+        let mut replaygain = tagged(6.0, 0.8, 6.0, 0.8);
+        replaygain.set_mode(ReplayGainMode::Track);
+        assert_eq!(replaygain.gain(), Some(1.0 / 0.8));
+
+        // Gains that keep the peak under full scale aren't touched
+        replaygain.set_info(Some(ReplayGainInfo {
+            track_gain_db: Some(1.0),
+            track_peak: Some(0.5),
+            ..Default::default()
+        }));
+        assert_eq!(replaygain.gain(), Some(db_to_linear(1.0)));
+
+        replaygain.set_info(Some(ReplayGainInfo {
+            track_gain_db: Some(6.0),
+            track_peak: Some(0.8),
+            ..Default::default()
+        }));
+        replaygain.set_prevent_clipping(false);
+        assert_eq!(replaygain.gain(), Some(db_to_linear(6.0)));
+    }
+
+    #[test]
+    fn preamp_is_added_and_bounded() {
+        let mut replaygain = tagged(-6.0, 0.1, -6.0, 0.1);
+        replaygain.set_mode(ReplayGainMode::Track);
+        replaygain.set_preamp(4.0).unwrap();
+        assert_eq!(replaygain.gain(), Some(db_to_linear(-2.0)));
+
+        assert!(replaygain.set_preamp(MAX_PREAMP_DB + 1.0).is_err());
+        assert!(replaygain.set_preamp(f32::NAN).is_err());
+        assert_eq!(replaygain.get_preamp(), 4.0);
+    }
+}
@@ -0,0 +1,273 @@
+//resampler.rs
+
+//soxr tuning. ResamplingQuality picks one of soxr's recipes, ResamplerConfig starts from one and sets
+//the filter by hand: where the passband ends and the stopband begins, the phase response, the rolloff,
+//the precision and how many threads soxr may use. Checked when it's set, so a bad value never reaches
+//soxr mid-load
+
+use crate::enums::{PlayerError, ResamplingQuality};
+
+use soxr_ax::params::{Interpolation, QualityFlags, QualitySpec, Rolloff, RuntimeSpec};
+
+//soxr's precision range in bits. Below this a recipe would be picked instead, above it there's no gain
+pub const MIN_RESAMPLER_PRECISION: f64 = 15.0;
+pub const MAX_RESAMPLER_PRECISION: f64 = 33.0;
+
+pub const MAX_RESAMPLER_THREADS: u32 = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum PhaseResponse {
+    Linear,       // Symmetric ringing, no phase distortion. soxr's default
+    Intermediate, // Between the two
+    Minimum,      // No pre-ringing, but phase shifts near the cutoff
+}
+
+impl PhaseResponse {
+    fn value(&self) -> f64 {
+        match self {
+            Self::Linear => 50.0,
+            Self::Intermediate => 25.0,
+            Self::Minimum => 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Enum)]
+pub enum ResamplerRolloff {
+    Small,     // At most 0.01 dB down at the end of the passband
+    Medium,    // At most 0.35 dB
+    Chebyshev, // No compensation, the passband end is where the response is 3 dB down
+}
+
+impl ResamplerRolloff {
+    fn rolloff(&self) -> Rolloff {
+        match self {
+            Self::Small => Rolloff::Small,
+            Self::Medium => Rolloff::Medium,
+            Self::Chebyshev => Rolloff::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct ResamplerConfig {
+    pub quality: ResamplingQuality, // Recipe the rest goes on top of. Quick only takes threads
+    pub passband_end: f64, // Fraction of nyquist kept flat, above 0.0 and below stopband_begin
+    pub stopband_begin: f64, // Fraction of nyquist where rejection is full, at most 1.0
+    pub phase: PhaseResponse,
+    pub rolloff: ResamplerRolloff, // Low and Medium always use Medium
+    pub precision: f64,            // Bits, 15 to 33
+    pub threads: u32,              // 0 lets soxr decide
+}
+
+impl ResamplerConfig {
+    ///What soxr uses for a recipe on its own, as a starting point for tuning
+    pub fn preset(quality: ResamplingQuality) -> Self {
+        let spec = quality.get_quality_spec();
+
+        ResamplerConfig {
+            quality,
+            passband_end: spec.passband_end(),
+            stopband_begin: spec.stopband_begin(),
+            phase: PhaseResponse::Linear,
+            // soxr forces this for its low quality recipes
+            rolloff: match quality {
+                ResamplingQuality::Low | ResamplingQuality::Medium => ResamplerRolloff::Medium,
+                _ => ResamplerRolloff::Small,
+            },
+            precision: spec.precision(),
+            threads: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), i32> {
+        if self.threads > MAX_RESAMPLER_THREADS {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        // Cubic interpolation, there's no filter to shape
+        if self.quality == ResamplingQuality::Quick {
+            return Ok(());
+        }
+        if !self.precision.is_finite()
+            || !(MIN_RESAMPLER_PRECISION..=MAX_RESAMPLER_PRECISION).contains(&self.precision)
+        {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        if !self.passband_end.is_finite() || !self.stopband_begin.is_finite() {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        if self.passband_end <= 0.0
+            || self.passband_end >= self.stopband_begin
+            || self.stopband_begin > 1.0
+        {
+            return Err(PlayerError::INVALID_ARGUMENT);
+        }
+        Ok(())
+    }
+
+    pub fn quality_spec(&self) -> QualitySpec {
+        let spec = QualitySpec::configure(
+            self.quality.recipe(),
+            self.rolloff.rolloff(),
+            QualityFlags::HighPrecisionClock | QualityFlags::DoublePrecision,
+        );
+        if self.quality == ResamplingQuality::Quick {
+            return spec;
+        }
+
+        spec.with_precision(self.precision)
+            .with_phase_response(self.phase.value())
+            .with_passband_end(self.passband_end)
+            .with_stopband_begin(self.stopband_begin)
+    }
+
+    pub fn runtime_spec(&self) -> RuntimeSpec {
+        RuntimeSpec::new(self.threads).with_interpolation(Interpolation::High)
+    }
+}
+
+impl From<ResamplingQuality> for ResamplerConfig {
+    fn from(quality: ResamplingQuality) -> Self {
+        ResamplerConfig::preset(quality)
+    }
+}
+
+impl Default for ResamplerConfig {
+    fn default() -> Self {
+        ResamplerConfig::preset(ResamplingQuality::High)
+    }
+}
+
+///soxr's own settings for a quality level, to start tuning from
+#[uniffi::export]
+pub fn resampler_preset(quality: ResamplingQuality) -> ResamplerConfig {
+    ResamplerConfig::preset(quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [ResamplingQuality; 5] = [
+        ResamplingQuality::Quick,
+        ResamplingQuality::Low,
+        ResamplingQuality::Medium,
+        ResamplingQuality::High,
+        ResamplingQuality::VeryHigh,
+    ];
+
+    #[test]
+    fn presets_are_valid() {
+        for quality in QUALITIES {
+            let config = ResamplerConfig::preset(quality);
+            assert_eq!(config.quality, quality);
+            assert_eq!(config.validate(), Ok(()));
+            assert_eq!(ResamplerConfig::from(quality), config);
+        }
+        assert_eq!(
+            ResamplerConfig::default(),
+            ResamplerConfig::preset(ResamplingQuality::High)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_filters() {
+        let high = ResamplerConfig::preset(ResamplingQuality::High);
+        let check = |config: ResamplerConfig| config.validate();
+
+        assert_eq!(
+            check(ResamplerConfig {
+                passband_end: 0.0,
+                ..high
+            }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            check(ResamplerConfig {
+                passband_end: -0.5,
+                ..high
+            }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            check(ResamplerConfig {
+                passband_end: 0.95,
+                stopband_begin: 0.95,
+                ..high
+            }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            check(ResamplerConfig {
+                stopband_begin: 1.01,
+                ..high
+            }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            check(ResamplerConfig {
+                passband_end: f64::NAN,
+                ..high
+            }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            check(ResamplerConfig {
+                stopband_begin: f64::INFINITY,
+                ..high
+            }),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            check(ResamplerConfig {
+                passband_end: 0.5,
+                stopband_begin: 1.0,
+                ..high
+            }),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_precision_outside_soxr_range() {
+        let high = ResamplerConfig::preset(ResamplingQuality::High);
+        let precision = |precision| ResamplerConfig { precision, ..high }.validate();
+
+        assert_eq!(precision(MIN_RESAMPLER_PRECISION), Ok(()));
+        assert_eq!(precision(MAX_RESAMPLER_PRECISION), Ok(()));
+        assert_eq!(
+            precision(MIN_RESAMPLER_PRECISION - 1.0),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(
+            precision(MAX_RESAMPLER_PRECISION + 1.0),
+            Err(PlayerError::INVALID_ARGUMENT)
+        );
+        assert_eq!(precision(f64::NAN), Err(PlayerError::INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn quick_only_checks_threads() {
+        let quick = ResamplerConfig {
+            passband_end: f64::NAN,
+            precision: 0.0,
+            ..ResamplerConfig::preset(ResamplingQuality::Quick)
+        };
+        assert_eq!(quick.validate(), Ok(()));
+
+        for quality in QUALITIES {
+            let threads = |threads| {
+                ResamplerConfig {
+                    threads,
+                    ..ResamplerConfig::preset(quality)
+                }
+                .validate()
+            };
+            assert_eq!(threads(MAX_RESAMPLER_THREADS), Ok(()));
+            assert_eq!(
+                threads(MAX_RESAMPLER_THREADS + 1),
+                Err(PlayerError::INVALID_ARGUMENT)
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//shared.rs
+
+//State the engine, the decoder thread and the audio thread all look at. Each engine has its own, so players in
+//the same process never see each other's. Only atomics, so the audio thread can read it without waiting
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+pub struct SharedState {
+    // How long the output stage takes to reach a new volume. Short by default to dezipper, longer for fade_to
+    volume_ramp_ms: AtomicU32,
+
+    // Transport fade. The engine sets a target of 0.0 or 1.0, the output stage ramps to it and reports back the level
+    transport_target: AtomicU32,
+    transport_ramp_ms: AtomicU32,
+    transport_level: AtomicU32,
+
+    // Linear gain for the loaded track, from ReplayGain tags or loudness normalisation. Stacks with the user volume
+    track_gain: AtomicU32,
+    // Bumped when a new track starts. The output stage steps straight to that track's gain instead of gliding there
+    track_gain_steps: AtomicU32,
+
+    // Buffer marks for the loaded source, in frames. The decoder fills up to the target and the audio thread asks
+    // for a refill below the low water mark. Rebuffer is how much has to be back after an underrun before playback
+    // carries on, 0 for sources that can't stall
+    target_buffer_frames: AtomicU32,
+    low_water_frames: AtomicU32,
+    rebuffer_frames: AtomicU32,
+
+    // Set while a live stream is loaded. Seeking is off and a dropped connection is reopened rather than ending
+    live: AtomicBool,
+    // Set while output is held back waiting for a network source to catch up
+    buffering: AtomicBool,
+    // Set while the output is starved. Only the first callback of an underrun reports it
+    underrun: AtomicBool,
+
+    // How often position updates go out while playing, 0 turns them off
+    position_interval_ms: AtomicU32,
+    // Bumped by every load and clear. The decoder drops a load that was superseded before it got to it
+    load_generation: AtomicU64,
+    // Set while the current playback is bit-perfect. The output stage leaves the samples alone then
+    bit_perfect: AtomicBool,
+}
+
+impl SharedState {
+    pub fn new() -> Self {
+        SharedState {
+            volume_ramp_ms: AtomicU32::new(30),
+            transport_target: AtomicU32::new(0.0f32.to_bits()),
+            transport_ramp_ms: AtomicU32::new(0),
+            transport_level: AtomicU32::new(0.0f32.to_bits()),
+            track_gain: AtomicU32::new(1.0f32.to_bits()),
+            track_gain_steps: AtomicU32::new(0),
+            target_buffer_frames: AtomicU32::new(0),
+            low_water_frames: AtomicU32::new(0),
+            rebuffer_frames: AtomicU32::new(0),
+            live: AtomicBool::new(false),
+            buffering: AtomicBool::new(false),
+            underrun: AtomicBool::new(false),
+            position_interval_ms: AtomicU32::new(500),
+            load_generation: AtomicU64::new(0),
+            bit_perfect: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_volume_ramp_ms(&self, ms: u32) {
+        self.volume_ramp_ms.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn get_volume_ramp_ms(&self) -> u32 {
+        self.volume_ramp_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_transport_target(&self, target: f32, ramp_ms: u32) {
+        self.transport_ramp_ms.store(ramp_ms, Ordering::Relaxed);
+        self.transport_target
+            .store(target.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get_transport_target(&self) -> (f32, u32) {
+        let target = f32::from_bits(self.transport_target.load(Ordering::Relaxed));
+        (target, self.transport_ramp_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn set_transport_level(&self, level: f32) {
+        self.transport_level
+            .store(level.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get_transport_level(&self) -> f32 {
+        f32::from_bits(self.transport_level.load(Ordering::Relaxed))
+    }
+
+    pub fn set_track_gain(&self, gain: f32) {
+        self.track_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get_track_gain(&self) -> f32 {
+        f32::from_bits(self.track_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn step_track_gain(&self) {
+        self.track_gain_steps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_track_gain_steps(&self) -> u32 {
+        self.track_gain_steps.load(Ordering::Relaxed)
+    }
+
+    pub fn set_buffer_marks(&self, target: u32, low_water: u32, rebuffer: u32) {
+        self.target_buffer_frames.store(target, Ordering::Relaxed);
+        self.low_water_frames.store(low_water, Ordering::Relaxed);
+        self.rebuffer_frames.store(rebuffer, Ordering::Relaxed);
+    }
+
+    pub fn get_target_buffer_frames(&self) -> u32 {
+        self.target_buffer_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn get_low_water_frames(&self) -> u32 {
+        self.low_water_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn get_rebuffer_frames(&self) -> u32 {
+        self.rebuffer_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn get_live(&self) -> bool {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    pub fn set_live(&self, flag: bool) {
+        self.live.store(flag, Ordering::Relaxed);
+    }
+
+    pub fn get_buffering(&self) -> bool {
+        self.buffering.load(Ordering::Relaxed)
+    }
+
+    ///Returns the previous value so only the caller that flips it sends the signal
+    pub fn set_buffering(&self, flag: bool) -> bool {
+        self.buffering.swap(flag, Ordering::Relaxed)
+    }
+
+    ///Returns the previous value so an underrun is only reported once
+    pub fn set_underrun(&self, flag: bool) -> bool {
+        self.underrun.swap(flag, Ordering::Relaxed)
+    }
+
+    pub fn set_position_interval_ms(&self, ms: u32) {
+        self.position_interval_ms.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn get_position_interval_ms(&self) -> u32 {
+        self.position_interval_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn next_load_generation(&self) -> u64 {
+        self.load_generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get_load_generation(&self) -> u64 {
+        self.load_generation.load(Ordering::Relaxed)
+    }
+
+    pub fn get_bit_perfect(&self) -> bool {
+        self.bit_perfect.load(Ordering::Relaxed)
+    }
+
+    pub fn set_bit_perfect(&self, flag: bool) {
+        self.bit_perfect.store(flag, Ordering::Relaxed);
+    }
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,464 @@
+//source.rs
+
+//Where the decoder gets its bytes from. Paths and URLs go straight to ffmpeg, everything else is
+//fed through a custom AVIOContext that reads from a Rust Read + Seek
+
+use crate::network::{NetworkConfig, is_network_url};
+
+#[allow(unused_imports)]
+use ffmpeg_next::{self as av, format::context::Input, sys};
+
+use std::{
+    ffi::{c_int, c_void},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    ops::{Deref, DerefMut},
+    ptr,
+};
+
+//Size of the buffer ffmpeg reads through
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+//From avio.h and avformat.h
+const AVSEEK_SIZE: c_int = 0x10000;
+const AVSEEK_FORCE: c_int = 0x20000;
+const AVFMT_FLAG_CUSTOM_IO: c_int = 0x0080;
+
+///Anything the decoder can read and seek in
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+///Supplies bytes from the app side. For encrypted caches, bundled assets or a custom transport
+#[uniffi::export(callback_interface)]
+pub trait ByteSource: Send + Sync {
+    ///Up to max_len bytes from the current position. Empty means end of stream
+    fn read(&self, max_len: u32) -> Vec<u8>;
+    ///Moves to an absolute byte offset. Returns false if that isn't possible
+    fn seek(&self, offset: u64) -> bool;
+    ///Total length in bytes, None if unknown. Some formats need it to seek
+    fn size(&self) -> Option<u64>;
+}
+
+//Turns a ByteSource into a Read + Seek, tracking the position on our side
+struct CallbackReader {
+    source: Box<dyn ByteSource>,
+    position: u64,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.source.read(buf.len().min(u32::MAX as usize) as u32);
+        // Never trust the other side to respect max_len
+        let count = bytes.len().min(buf.len());
+        buf[..count].copy_from_slice(&bytes[..count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for CallbackReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self
+                .source
+                .size()
+                .and_then(|size| size.checked_add_signed(offset)),
+        }
+        .ok_or(io::ErrorKind::InvalidInput)?;
+
+        if target != self.position && !self.source.seek(target) {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        self.position = target;
+        Ok(target)
+    }
+}
+
+///What to play
+pub enum MediaSource {
+    Url(String), // Anything av::format::input accepts, files included
+    Reader(Box<dyn ReadSeek>),
+}
+
+impl MediaSource {
+    ///Plays straight from memory. The bytes are owned by the decoder until the next load
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        MediaSource::Reader(Box::new(Cursor::new(bytes)))
+    }
+
+    pub fn from_reader<R: ReadSeek + 'static>(reader: R) -> Self {
+        MediaSource::Reader(Box::new(reader))
+    }
+
+    pub fn from_callback(source: Box<dyn ByteSource>) -> Self {
+        MediaSource::Reader(Box::new(CallbackReader {
+            source,
+            position: 0,
+        }))
+    }
+
+    ///The path or URL, None for custom sources. Loudness scans and caching only work with these
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            MediaSource::Url(url) => Some(url),
+            MediaSource::Reader(_) => None,
+        }
+    }
+
+    ///Whether this is fetched over http(s), and so can stall
+    pub fn is_network(&self) -> bool {
+        self.path().is_some_and(is_network_url)
+    }
+
+    ///Opens the source. network only applies to http(s) URLs
+    pub fn open(self, network: &NetworkConfig) -> Result<MediaInput, i32> {
+        match self {
+            MediaSource::Url(url) if is_network_url(&url) => Ok(MediaInput {
+                input: av::format::input_with_dictionary(&url, network.to_dictionary())
+                    .map_err(|_| -1)?,
+                _io: None,
+            }),
+            MediaSource::Url(url) => Ok(MediaInput {
+                input: av::format::input(&url).map_err(|_| -1)?,
+                _io: None,
+            }),
+            MediaSource::Reader(reader) => open_custom(reader),
+        }
+    }
+}
+
+impl From<&str> for MediaSource {
+    fn from(url: &str) -> Self {
+        MediaSource::Url(url.to_string())
+    }
+}
+
+impl From<String> for MediaSource {
+    fn from(url: String) -> Self {
+        MediaSource::Url(url)
+    }
+}
+
+impl From<Vec<u8>> for MediaSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        MediaSource::from_bytes(bytes)
+    }
+}
+
+///An opened input. Derefs to the ffmpeg Input and keeps the custom IO alive behind it
+pub struct MediaInput {
+    input: Input, // Declared first so it's closed before the IO it reads from is freed
+    _io: Option<CustomIo>,
+}
+
+unsafe impl Send for MediaInput {}
+
+impl Deref for MediaInput {
+    type Target = Input;
+
+    fn deref(&self) -> &Input {
+        &self.input
+    }
+}
+
+impl DerefMut for MediaInput {
+    fn deref_mut(&mut self) -> &mut Input {
+        &mut self.input
+    }
+}
+
+//The AVIOContext and the reader its opaque pointer refers to
+struct CustomIo {
+    context: *mut sys::AVIOContext,
+    reader: *mut Box<dyn ReadSeek>,
+}
+
+impl Drop for CustomIo {
+    fn drop(&mut self) {
+        unsafe {
+            // ffmpeg may have swapped the buffer for its own, so free whatever it holds now
+            if !self.context.is_null() {
+                sys::av_freep(&mut (*self.context).buffer as *mut _ as *mut c_void);
+                sys::avio_context_free(&mut self.context);
+            }
+            drop(Box::from_raw(self.reader));
+        }
+    }
+}
+
+fn open_custom(reader: Box<dyn ReadSeek>) -> Result<MediaInput, i32> {
+    unsafe {
+        let buffer = sys::av_malloc(IO_BUFFER_SIZE) as *mut u8;
+        if buffer.is_null() {
+            return Err(-1);
+        }
+
+        let reader = Box::into_raw(Box::new(reader));
+        let context = sys::avio_alloc_context(
+            buffer,
+            IO_BUFFER_SIZE as c_int,
+            0,
+            reader as *mut c_void,
+            Some(read_packet),
+            None,
+            Some(seek),
+        );
+        if context.is_null() {
+            sys::av_free(buffer as *mut c_void);
+            drop(Box::from_raw(reader));
+            return Err(-1);
+        }
+        // From here on CustomIo frees everything, whichever way this goes
+        let io = CustomIo { context, reader };
+
+        let mut format_ctx = sys::avformat_alloc_context();
+        if format_ctx.is_null() {
+            return Err(-1);
+        }
+        (*format_ctx).pb = io.context;
+        (*format_ctx).flags |= AVFMT_FLAG_CUSTOM_IO;
+
+        // Frees the context itself on failure
+        if sys::avformat_open_input(
+            &mut format_ctx,
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        ) < 0
+        {
+            return Err(-1);
+        }
+
+        // Wrapped straight away so it's closed on every path below
+        let input = Input::wrap(format_ctx);
+        if sys::avformat_find_stream_info(format_ctx, ptr::null_mut()) < 0 {
+            return Err(-1);
+        }
+
+        Ok(MediaInput {
+            input,
+            _io: Some(io),
+        })
+    }
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let reader = unsafe { &mut *(opaque as *mut Box<dyn ReadSeek>) };
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf, buf_size.max(0) as usize) };
+
+    loop {
+        match reader.read(buf) {
+            Ok(0) => return sys::AVERROR_EOF,
+            Ok(count) => return count as c_int,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return sys::AVERROR_EXTERNAL,
+        }
+    }
+}
+
+unsafe extern "C" fn seek(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = unsafe { &mut *(opaque as *mut Box<dyn ReadSeek>) };
+
+    // ffmpeg asking for the total size. Find the end and come back
+    if whence & AVSEEK_SIZE != 0 {
+        let size = reader.stream_position().and_then(|position| {
+            let end = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(position))?;
+            Ok(end)
+        });
+        return size.map_or(-1, |size| size as i64);
+    }
+
+    let pos = match whence & !AVSEEK_FORCE {
+        0 => SeekFrom::Start(offset.max(0) as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    reader.seek(pos).map_or(-1, |position| position as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::wav;
+
+    use ffmpeg_next::Packet;
+
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    const RATE: u32 = 8000;
+
+    //Bytes of audio in every packet until the end
+    fn read_to_end(input: &mut Input) -> usize {
+        let mut total = 0;
+        let mut packet = Packet::empty();
+        while packet.read(input).is_ok() {
+            total += packet.size();
+        }
+        total
+    }
+
+    //Sets its flag when dropped, so tests can see the reader freed
+    struct Tracked {
+        inner: Cursor<Vec<u8>>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Read for Tracked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Tracked {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    //A ByteSource over memory, with or without telling its size
+    struct Memory {
+        bytes: Vec<u8>,
+        position: Mutex<usize>,
+        sized: bool,
+    }
+
+    impl ByteSource for Memory {
+        fn read(&self, max_len: u32) -> Vec<u8> {
+            let mut position = self.position.lock().unwrap();
+            let end = (*position + max_len as usize).min(self.bytes.len());
+            let bytes = self.bytes[*position..end].to_vec();
+            *position = end;
+            bytes
+        }
+
+        fn seek(&self, offset: u64) -> bool {
+            if offset > self.bytes.len() as u64 {
+                return false;
+            }
+            *self.position.lock().unwrap() = offset as usize;
+            true
+        }
+
+        fn size(&self) -> Option<u64> {
+            self.sized.then_some(self.bytes.len() as u64)
+        }
+    }
+
+    #[test]
+    fn plays_from_memory_and_seeks() {
+        let bytes = wav(1.0, RATE, 440.0);
+        let data_len = bytes.len() - 44;
+        let mut input = MediaSource::from_bytes(bytes)
+            .open(&NetworkConfig::default())
+            .unwrap();
+        assert_eq!(input.streams().count(), 1);
+        assert_eq!(read_to_end(&mut input), data_len);
+
+        // Back to the start reads it all again, halfway reads about half
+        input.seek(0, ..).unwrap();
+        assert_eq!(read_to_end(&mut input), data_len);
+        input
+            .seek(i64::from(av::ffi::AV_TIME_BASE) / 2, ..)
+            .unwrap();
+        let rest = read_to_end(&mut input) as i64;
+        assert!((rest - data_len as i64 / 2).abs() <= 4096, "{rest}");
+    }
+
+    #[test]
+    fn reads_and_seeks_through_the_callbacks() {
+        let bytes = wav(0.1, RATE, 440.0);
+        let len = bytes.len();
+        let mut reader: Box<dyn ReadSeek> = Box::new(Cursor::new(bytes));
+        let opaque = &mut reader as *mut Box<dyn ReadSeek> as *mut c_void;
+        let mut buf = [0u8; 16];
+
+        unsafe {
+            assert_eq!(read_packet(opaque, buf.as_mut_ptr(), 16), 16);
+            assert_eq!(&buf[..4], b"RIFF");
+
+            // Asking for the size leaves the position alone
+            assert_eq!(seek(opaque, 0, AVSEEK_SIZE), len as i64);
+            assert_eq!(seek(opaque, 0, 1), 16);
+
+            assert_eq!(seek(opaque, -4, 2 | AVSEEK_FORCE), len as i64 - 4);
+            assert_eq!(read_packet(opaque, buf.as_mut_ptr(), 16), 4);
+            assert_eq!(read_packet(opaque, buf.as_mut_ptr(), 16), sys::AVERROR_EOF);
+
+            assert_eq!(seek(opaque, 8, 0), 8);
+            assert_eq!(read_packet(opaque, buf.as_mut_ptr(), 4), 4);
+            assert_eq!(&buf[..4], b"WAVE");
+            assert_eq!(seek(opaque, 0, 3), -1);
+        }
+    }
+
+    #[test]
+    fn frees_the_reader_after_the_input() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let reader = Tracked {
+            inner: Cursor::new(wav(0.5, RATE, 440.0)),
+            dropped: dropped.clone(),
+        };
+        let mut input = MediaSource::from_reader(reader)
+            .open(&NetworkConfig::default())
+            .unwrap();
+        read_to_end(&mut input);
+        assert!(!dropped.load(Ordering::Relaxed));
+
+        // The input is closed first, a read on the way out would find the reader still there
+        drop(input);
+        assert!(dropped.load(Ordering::Relaxed));
+
+        // Freed on failure too
+        let dropped = Arc::new(AtomicBool::new(false));
+        let reader = Tracked {
+            inner: Cursor::new(vec![0; 1024]),
+            dropped: dropped.clone(),
+        };
+        let opened = MediaSource::from_reader(reader).open(&NetworkConfig::default());
+        assert!(opened.is_err());
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn plays_from_a_byte_source() {
+        let bytes = wav(0.5, RATE, 440.0);
+        let data_len = bytes.len() - 44;
+        for sized in [true, false] {
+            let source = Memory {
+                bytes: bytes.clone(),
+                position: Mutex::new(0),
+                sized,
+            };
+            let mut input = MediaSource::from_callback(Box::new(source))
+                .open(&NetworkConfig::default())
+                .unwrap();
+            assert_eq!(read_to_end(&mut input), data_len);
+        }
+
+        // Seeking from the end needs the size
+        let mut reader = CallbackReader {
+            source: Box::new(Memory {
+                bytes,
+                position: Mutex::new(0),
+                sized: false,
+            }),
+            position: 0,
+        };
+        assert!(reader.seek(SeekFrom::End(-4)).is_err());
+        assert_eq!(reader.seek(SeekFrom::Current(8)).unwrap(), 8);
+        assert!(reader.seek(SeekFrom::Current(-9)).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+//spectrum.rs
+
+//Visualiser tap. The audio thread copies what it hands to cpal into a lock-free ring, along with
+//when that block will actually be heard. Readers pick the window that is playing right now and
+//run the FFT on their own thread
+
+use crate::{enums::SpectrumScale, meter::METER_FLOOR_DB};
+
+use realfft::{RealFftPlanner, RealToComplex, num_complex::Complex};
+
+use std::{
+    f64::consts::PI,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+pub const MIN_FFT_SIZE: u32 = 64;
+pub const MAX_FFT_SIZE: u32 = 8192;
+
+//Ring length. Room for the largest window plus any sane device latency
+const TAP_CAPACITY: usize = 32768;
+
+//Log scale bands cover this range, capped at nyquist
+const LOG_MIN_FREQUENCY: f64 = 20.0;
+const LOG_MAX_FREQUENCY: f64 = 20000.0;
+
+#[derive(Clone, Copy, PartialEq, Debug, uniffi::Record)]
+pub struct SpectrumConfig {
+    pub fft_size: u32, // Power of two, 64 to 8192
+    pub scale: SpectrumScale,
+    pub bands: u32,     // Output values. 0 with a linear scale returns every bin
+    pub smoothing: f32, // 0.0 is none, closer to 1.0 is slower
+}
+
+impl SpectrumConfig {
+    pub fn validate(&self) -> Result<(), i32> {
+        if !self.fft_size.is_power_of_two()
+            || !(MIN_FFT_SIZE..=MAX_FFT_SIZE).contains(&self.fft_size)
+        {
+            return Err(-1);
+        }
+        if self.bands > self.fft_size / 2
+            || (self.scale == SpectrumScale::Logarithmic && self.bands == 0)
+        {
+            return Err(-1);
+        }
+        if !self.smoothing.is_finite() || !(0.0..1.0).contains(&self.smoothing) {
+            return Err(-1);
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpectrumConfig {
+    fn default() -> Self {
+        SpectrumConfig {
+            fft_size: 2048,
+            scale: SpectrumScale::Logarithmic,
+            bands: 64,
+            smoothing: 0.6,
+        }
+    }
+}
+
+///Lock-free ring of the mono mix sent to the device. Written by the audio thread only
+pub struct SpectrumTap {
+    enabled: AtomicBool,
+    samples: Box<[AtomicU32]>, // f32 bits
+    written: AtomicU64,        // Frames written in total
+    block_start: AtomicU64,    // First frame of the newest block
+    block_time_ns: AtomicU64,  // When the newest block was written, relative to epoch
+    delay_ns: AtomicU64,       // How long after that it reaches the speakers
+    sample_rate: AtomicU32,
+    epoch: Instant,
+}
+
+impl SpectrumTap {
+    pub fn new() -> Self {
+        SpectrumTap {
+            enabled: AtomicBool::new(false),
+            samples: (0..TAP_CAPACITY).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicU64::new(0),
+            block_start: AtomicU64::new(0),
+            block_time_ns: AtomicU64::new(0),
+            delay_ns: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(0),
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    ///Audio thread only. delay is how long until the first frame of this block is heard
+    pub fn write<I>(&self, frames: I, sample_rate: u32, delay: Duration)
+    where
+        I: Iterator<Item = [f32; 2]>,
+    {
+        let start = self.written.load(Ordering::Relaxed);
+        let mut position = start;
+        for frame in frames {
+            let mono = (frame[0] + frame[1]) * 0.5;
+            self.samples[(position % TAP_CAPACITY as u64) as usize]
+                .store(mono.to_bits(), Ordering::Relaxed);
+            position += 1;
+        }
+
+        let now = self.epoch.elapsed().as_nanos() as u64;
+        self.block_start.store(start, Ordering::Relaxed);
+        self.block_time_ns.store(now, Ordering::Relaxed);
+        self.delay_ns
+            .store(delay.as_nanos() as u64, Ordering::Relaxed);
+        self.sample_rate.store(sample_rate, Ordering::Relaxed);
+        self.written.store(position, Ordering::Release);
+    }
+
+    ///Fills out with the frames ending at the one playing right now. Returns the sample rate, None before any audio
+    pub fn read(&self, out: &mut [f32]) -> Option<u32> {
+        let written = self.written.load(Ordering::Acquire);
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        if written == 0 || sample_rate == 0 {
+            return None;
+        }
+
+        // Work out which frame is at the speaker, from when the block was written and its delay
+        let block_start = self.block_start.load(Ordering::Relaxed) as i128;
+        let block_time = self.block_time_ns.load(Ordering::Relaxed) as i128;
+        let delay = self.delay_ns.load(Ordering::Relaxed) as i128;
+        let now = self.epoch.elapsed().as_nanos() as i128;
+        let heard = block_start + (now - block_time - delay) * sample_rate as i128 / 1_000_000_000;
+
+        // Stay inside what's been written and hasn't been overwritten yet
+        let oldest = (written as i128 - TAP_CAPACITY as i128 + 1).max(0);
+        let end = heard.clamp(oldest, written as i128);
+
+        for (i, sample) in out.iter_mut().rev().enumerate() {
+            let position = end - 1 - i as i128;
+            *sample = if position < oldest {
+                0.0
+            } else {
+                f32::from_bits(
+                    self.samples[(position % TAP_CAPACITY as i128) as usize]
+                        .load(Ordering::Relaxed),
+                )
+            };
+        }
+
+        Some(sample_rate)
+    }
+
+    ///Rate of the last block written, 0 before any audio
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    ///Forgets everything written so far
+    pub fn clear(&self) {
+        for sample in self.samples.iter() {
+            sample.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for SpectrumTap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+///Turns tap windows into band magnitudes. Lives on the reader's side, never touches the audio thread
+pub struct SpectrumAnalyzer {
+    config: SpectrumConfig,
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    window_gain: f32,
+    input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+    smoothed: Vec<f32>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(config: SpectrumConfig) -> Self {
+        let size = config.fft_size as usize;
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(size);
+
+        // Hann window
+        let window: Vec<f32> = (0..size)
+            .map(|n| (0.5 - 0.5 * (2.0 * PI * n as f64 / size as f64).cos()) as f32)
+            .collect();
+        let window_gain = window.iter().sum::<f32>();
+
+        SpectrumAnalyzer {
+            config,
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            fft,
+            window,
+            window_gain,
+            magnitudes: vec![0.0; size / 2],
+            smoothed: Vec::new(),
+        }
+    }
+
+    pub fn get_config(&self) -> SpectrumConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: SpectrumConfig) -> Result<(), i32> {
+        config.validate()?;
+        if config.fft_size != self.config.fft_size {
+            *self = SpectrumAnalyzer::new(config);
+        } else {
+            self.config = config;
+            self.smoothed.clear();
+        }
+        Ok(())
+    }
+
+    ///Magnitudes in dBFS, a full scale sine reads 0. Empty if the tap has nothing yet
+    pub fn analyze(&mut self, tap: &SpectrumTap) -> Vec<f32> {
+        let Some(sample_rate) = tap.read(&mut self.input) else {
+            return Vec::new();
+        };
+
+        for (sample, weight) in self.input.iter_mut().zip(&self.window) {
+            *sample *= weight;
+        }
+        if self
+            .fft
+            .process(&mut self.input, &mut self.spectrum)
+            .is_err()
+        {
+            return Vec::new();
+        }
+
+        let scale = 2.0 / self.window_gain;
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.spectrum) {
+            *magnitude = bin.norm() * scale;
+        }
+
+        let values = self.group(sample_rate);
+
+        // Smoothed on linear magnitudes, reset whenever the layout changes
+        if self.smoothed.len() != values.len() {
+            self.smoothed = values.clone();
+        }
+        let smoothing = self.config.smoothing;
+        self.smoothed
+            .iter_mut()
+            .zip(&values)
+            .map(|(previous, value)| {
+                *previous = *previous * smoothing + value * (1.0 - smoothing);
+                (20.0 * previous.max(f32::MIN_POSITIVE).log10()).max(METER_FLOOR_DB)
+            })
+            .collect()
+    }
+
+    ///Centre frequency of every value analyze returns, in Hz
+    pub fn frequencies(&self, sample_rate: u32) -> Vec<f32> {
+        self.band_edges(sample_rate)
+            .windows(2)
+            .map(|edges| match self.config.scale {
+                SpectrumScale::Linear => ((edges[0] + edges[1]) / 2.0) as f32,
+                SpectrumScale::Logarithmic => (edges[0] * edges[1]).sqrt() as f32,
+            })
+            .collect()
+    }
+
+    //Band edges in Hz, one more than the number of bands
+    fn band_edges(&self, sample_rate: u32) -> Vec<f64> {
+        let nyquist = sample_rate as f64 / 2.0;
+        let bins = self.magnitudes.len();
+        let bin_width = sample_rate as f64 / self.config.fft_size as f64;
+
+        match self.config.scale {
+            SpectrumScale::Linear => {
+                let bands = if self.config.bands == 0 {
+                    bins
+                } else {
+                    self.config.bands as usize
+                };
+                (0..=bands)
+                    .map(|i| i as f64 * bins as f64 / bands as f64 * bin_width)
+                    .collect()
+            }
+            SpectrumScale::Logarithmic => {
+                let bands = self.config.bands as usize;
+                let high = LOG_MAX_FREQUENCY.min(nyquist);
+                let low = LOG_MIN_FREQUENCY.min(high / 2.0);
+                (0..=bands)
+                    .map(|i| low * (high / low).powf(i as f64 / bands as f64))
+                    .collect()
+            }
+        }
+    }
+
+    //Loudest bin in each band. Bands narrower than a bin take the bin they sit in
+    fn group(&self, sample_rate: u32) -> Vec<f32> {
+        let bin_width = sample_rate as f64 / self.config.fft_size as f64;
+        let last = self.magnitudes.len() - 1;
+
+        self.band_edges(sample_rate)
+            .windows(2)
+            .map(|edges| {
+                let low = ((edges[0] / bin_width).round() as usize).min(last);
+                let high = ((edges[1] / bin_width).round() as usize).clamp(low + 1, last + 1);
+                self.magnitudes[low..high]
+                    .iter()
+                    .copied()
+                    .fold(0.0, f32::max)
+            })
+            .collect()
+    }
+}
+
+impl Default for SpectrumAnalyzer {
+    fn default() -> Self {
+        Self::new(SpectrumConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn config(fft_size: u32, scale: SpectrumScale, bands: u32) -> SpectrumConfig {
+        SpectrumConfig {
+            fft_size,
+            scale,
+            bands,
+            smoothing: 0.0,
+        }
+    }
+
+    //Writes a sine in small blocks like the audio thread does, so the newest frames are the ones playing
+    fn tap_with_sine(frequency: f64) -> SpectrumTap {
+        let tap = SpectrumTap::new();
+        let frames: Vec<[f32; 2]> = (0..16384)
+            .map(|i| {
+                let x = (2.0 * PI * frequency * i as f64 / RATE as f64).sin() as f32;
+                [x, x]
+            })
+            .collect();
+        for block in frames.chunks(64) {
+            tap.write(block.iter().copied(), RATE, Duration::ZERO);
+        }
+        tap
+    }
+
+    fn loudest(values: &[f32]) -> usize {
+        (0..values.len())
+            .max_by(|a, b| values[*a].total_cmp(&values[*b]))
+            .unwrap()
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        assert!(SpectrumConfig::default().validate().is_ok());
+        assert!(config(1024, SpectrumScale::Linear, 0).validate().is_ok());
+
+        assert!(config(1000, SpectrumScale::Linear, 0).validate().is_err());
+        assert!(
+            config(MIN_FFT_SIZE / 2, SpectrumScale::Linear, 0)
+                .validate()
+                .is_err()
+        );
+        assert!(
+            config(MAX_FFT_SIZE * 2, SpectrumScale::Linear, 0)
+                .validate()
+                .is_err()
+        );
+        assert!(config(1024, SpectrumScale::Linear, 513).validate().is_err());
+        assert!(
+            config(1024, SpectrumScale::Logarithmic, 0)
+                .validate()
+                .is_err()
+        );
+
+        let smoothing = |smoothing| SpectrumConfig {
+            smoothing,
+            ..Default::default()
+        };
+        assert!(smoothing(1.0).validate().is_err());
+        assert!(smoothing(f32::NAN).validate().is_err());
+
+        let mut analyzer = SpectrumAnalyzer::default();
+        assert!(analyzer.set_config(smoothing(1.0)).is_err());
+        assert_eq!(analyzer.get_config(), SpectrumConfig::default());
+    }
+
+    #[test]
+    fn full_scale_sine_reads_zero_db_in_its_bin() {
+        let bin_width = RATE as f64 / 2048.0;
+        let tap = tap_with_sine(64.0 * bin_width);
+        let mut analyzer = SpectrumAnalyzer::new(config(2048, SpectrumScale::Linear, 0));
+
+        let values = analyzer.analyze(&tap);
+        assert_eq!(values.len(), 1024);
+        assert_eq!(loudest(&values), 64);
+        assert!(values[64].abs() < 0.1, "{}", values[64]);
+        assert_eq!(analyzer.frequencies(RATE).len(), values.len());
+    }
+
+    #[test]
+    fn log_bands_find_the_tone() {
+        let tap = tap_with_sine(1000.0);
+        let mut analyzer = SpectrumAnalyzer::new(config(4096, SpectrumScale::Logarithmic, 32));
+
+        let values = analyzer.analyze(&tap);
+        assert_eq!(values.len(), 32);
+
+        let frequencies = analyzer.frequencies(RATE);
+        assert!(frequencies.windows(2).all(|pair| pair[0] < pair[1]));
+        let band = loudest(&values);
+        assert!(
+            (frequencies[band] / 1000.0).log2().abs() < 0.2,
+            "{}",
+            frequencies[band]
+        );
+    }
+
+    #[test]
+    fn nothing_before_audio() {
+        let tap = SpectrumTap::new();
+        assert!(SpectrumAnalyzer::default().analyze(&tap).is_empty());
+        assert_eq!(tap.sample_rate(), 0);
+    }
+}
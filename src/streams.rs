@@ -0,0 +1,149 @@
+//streams.rs
+
+//Audio streams in multi-track containers, MKV and MP4 with commentary or dubs in particular
+
+use crate::enums::PlayerError;
+
+use ffmpeg_next::{
+    self as av,
+    codec::Parameters,
+    format::{context::Input, stream::Disposition},
+    media,
+};
+
+#[derive(Clone, PartialEq, Debug, uniffi::Record)]
+pub struct AudioStreamInfo {
+    pub index: u32,               // Container stream index, pass to select_audio_stream
+    pub language: Option<String>, // Usually an ISO 639-2 code such as "eng"
+    pub title: Option<String>,
+    pub codec: String,
+    pub channels: u32,
+    pub sample_rate: u32,
+    pub is_default: bool, // Flagged as the default track by the container
+}
+
+///Sample rate and channel count from a stream's codec parameters
+pub fn audio_format(parameters: &Parameters) -> (u32, u32) {
+    unsafe {
+        let raw = parameters.as_ptr();
+        (
+            (*raw).sample_rate as u32,
+            (*raw).ch_layout.nb_channels as u32,
+        )
+    }
+}
+
+///Every audio stream in the input, in container order
+pub fn list_audio_streams(input: &Input) -> Vec<AudioStreamInfo> {
+    input
+        .streams()
+        .filter(|stream| stream.parameters().medium() == media::Type::Audio)
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let (sample_rate, channels) = audio_format(&parameters);
+            let metadata = stream.metadata();
+            AudioStreamInfo {
+                index: stream.index() as u32,
+                language: metadata.get("language").map(str::to_string),
+                title: metadata.get("title").map(str::to_string),
+                codec: parameters.id().name().to_string(),
+                channels,
+                sample_rate,
+                is_default: stream.disposition().contains(Disposition::DEFAULT),
+            }
+        })
+        .collect()
+}
+
+///Lists the audio streams of a file without loading it, so one can be picked for load_with_stream
+#[uniffi::export]
+pub fn probe_audio_streams(path: String) -> Result<Vec<AudioStreamInfo>, PlayerError> {
+    let input = av::format::input(&path).map_err(|_| PlayerError::Code(-1))?;
+    Ok(list_audio_streams(&input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ffmpeg_next::Dictionary;
+
+    use std::fs;
+
+    //An MKV with a stereo English track and a mono French commentary flagged as the default. Headers only, that's
+    //all the listing reads
+    fn write_tracks(path: &std::path::Path) {
+        let codec = av::encoder::find(av::codec::Id::PCM_S16LE).unwrap();
+        let mut output = av::format::output_as(path, "matroska").unwrap();
+
+        let tracks = [
+            (av::ChannelLayout::STEREO, 44100, "eng", "Main", false),
+            (av::ChannelLayout::MONO, 22050, "fra", "Commentary", true),
+        ];
+        for (layout, rate, language, title, is_default) in tracks {
+            let context = av::codec::context::Context::new_with_codec(codec);
+            let mut encoder = context.encoder().audio().unwrap();
+            encoder.set_rate(rate);
+            encoder.set_channel_layout(layout);
+            encoder.set_format(av::format::Sample::I16(av::format::sample::Type::Packed));
+            encoder.set_time_base((1, rate));
+            let encoder = encoder.open_as(codec).unwrap();
+
+            let mut stream = output.add_stream(codec).unwrap();
+            stream.set_parameters(&encoder);
+            let mut metadata = Dictionary::new();
+            metadata.set("language", language);
+            metadata.set("title", title);
+            stream.set_metadata(metadata);
+            if is_default {
+                unsafe { (*stream.as_mut_ptr()).disposition = Disposition::DEFAULT.bits() };
+            }
+        }
+        output.write_header().unwrap();
+        output.write_trailer().unwrap();
+    }
+
+    #[test]
+    fn lists_every_audio_stream() {
+        let path = std::env::temp_dir().join(format!("aurex-streams-{}.mkv", std::process::id()));
+        write_tracks(&path);
+
+        let streams = probe_audio_streams(path.to_str().unwrap().to_string()).unwrap();
+        let input = av::format::input(&path).unwrap();
+        assert_eq!(list_audio_streams(&input), streams);
+        _ = fs::remove_file(&path);
+
+        assert_eq!(
+            streams,
+            [
+                AudioStreamInfo {
+                    index: 0,
+                    language: Some("eng".to_string()),
+                    title: Some("Main".to_string()),
+                    codec: "pcm_s16le".to_string(),
+                    channels: 2,
+                    sample_rate: 44100,
+                    is_default: false,
+                },
+                AudioStreamInfo {
+                    index: 1,
+                    language: Some("fra".to_string()),
+                    title: Some("Commentary".to_string()),
+                    codec: "pcm_s16le".to_string(),
+                    channels: 1,
+                    sample_rate: 22050,
+                    is_default: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn probing_a_missing_file_fails() {
+        let path = std::env::temp_dir().join("aurex-streams-missing.mkv");
+        assert_eq!(
+            probe_audio_streams(path.to_str().unwrap().to_string()),
+            Err(PlayerError::Code(-1))
+        );
+    }
+}
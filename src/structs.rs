@@ -1,7 +1,16 @@
 use ffmpeg_next::codec::decoder::audio::Audio;
-use ffmpeg_next::format::context::input::Input;
 use ffmpeg_next::software::resampling::context::Context as Resampler;
 
+use crate::{
+    adaptive::{Adaptive, VariantSelection},
+    bitperfect::OutputFormat,
+    events::TrackInfo,
+    radio::LiveStream,
+    resampler::ResamplerConfig,
+    source::MediaInput,
+    streams::AudioStreamInfo,
+};
+
 use soxr_ax::Soxr;
 use soxr_ax::format::Interleaved;
 
@@ -9,12 +18,55 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 
 pub struct Decoder {
-    pub format_ctx: Option<Input>,
-    pub decoder: Audio,
-    pub resampler: Resampler,
-    pub soxr_resampler: Soxr<Interleaved<i32, 2>>,
+    pub format_ctx: Option<MediaInput>,
+    pub decoder: Option<Audio>, // Set once a codec has been opened for the current stream
+    pub resampler: Option<Resampler>,
+    pub soxr_resampler: Option<Soxr<Interleaved<i32, 2>>>, // None while bit-perfect, the rates match
     pub audio_stream_index: usize,
     pub main_decoder_cancel_flag: Arc<AtomicBool>,
+    pub live: Option<LiveStream>, // Set while playing a live network stream
+    pub adaptive: Option<Adaptive>, // Set while playing an HLS/DASH manifest with several variants
+    pub variant_selection: VariantSelection, // Applied to every manifest loaded
+    pub audio_streams: Vec<AudioStreamInfo>, // Audio streams of the loaded input
+    pub track: Option<TrackInfo>, // What's loaded, once decoding has started
+    pub resampler_config: ResamplerConfig,
+    pub bit_perfect: bool, // Bit-perfect mode, applied from the next load
+    pub default_output: OutputFormat, // What the device is opened with when a track can't be bit-perfect
+    pub output_format: OutputFormat,  // What the loaded track wants the device opened with
+}
+
+impl Decoder {
+    pub fn new(resampler_config: ResamplerConfig, default_output: OutputFormat) -> Self {
+        Decoder {
+            format_ctx: None,
+            decoder: None,
+            resampler: None,
+            soxr_resampler: None,
+            audio_stream_index: 0,
+            main_decoder_cancel_flag: Arc::new(AtomicBool::new(false)),
+            live: None,
+            adaptive: None,
+            variant_selection: VariantSelection::Auto,
+            audio_streams: Vec::new(),
+            track: None,
+            resampler_config,
+            bit_perfect: false,
+            default_output,
+            output_format: default_output,
+        }
+    }
+
+    ///Frees the input and every ffmpeg and soxr context. Called by the decoder thread on its way out
+    pub fn close(&mut self) {
+        self.format_ctx = None;
+        self.decoder = None;
+        self.resampler = None;
+        self.soxr_resampler = None;
+        self.live = None;
+        self.adaptive = None;
+        self.audio_streams.clear();
+        self.track = None;
+    }
 }
 
 unsafe impl Send for Decoder {}
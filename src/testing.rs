@@ -0,0 +1,197 @@
+//testing.rs
+
+//Shared by the tests. Test audio is generated rather than checked in, and network sources are served from a
+//local HTTP server so nothing goes out to the internet
+
+use std::{
+    f32::consts::PI,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+///16-bit stereo WAV of a sine, left and right in opposite phase
+pub fn wav(seconds: f32, sample_rate: u32, frequency: f32) -> Vec<u8> {
+    let frames = (seconds * sample_rate as f32) as u32;
+    let data_len = frames * 4;
+
+    let mut bytes = Vec::with_capacity(44 + data_len as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+    bytes.extend_from_slice(&4u16.to_le_bytes());
+    bytes.extend_from_slice(&16u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+
+    for frame in 0..frames {
+        let x = 0.5 * (2.0 * PI * frequency * frame as f32 / sample_rate as f32).sin();
+        let sample = (x * i16::MAX as f32) as i16;
+        bytes.extend_from_slice(&sample.to_le_bytes());
+        bytes.extend_from_slice(&(-sample).to_le_bytes());
+    }
+    bytes
+}
+
+///A request the server got
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub path: String,
+    pub range: Option<u64>, // Start of the Range header, if it had one
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+type Handler = dyn Fn(&Request, &mut TcpStream) + Send + Sync;
+
+///Local HTTP server. Every connection gets one request, answered by the handler on its own thread, so handlers
+///can stall or drop the connection to act like a flaky server
+pub struct TestServer {
+    address: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn new(handler: impl Fn(&Request, &mut TcpStream) + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let thread = {
+            let requests = requests.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Ok(mut stream) = stream else {
+                        continue;
+                    };
+                    let requests = requests.clone();
+                    let handler = handler.clone();
+                    thread::spawn(move || {
+                        let Some(request) = read_request(&stream) else {
+                            return;
+                        };
+                        requests.lock().unwrap().push(request.clone());
+                        handler(&request, &mut stream);
+                    });
+                }
+            })
+        };
+
+        TestServer {
+            address,
+            requests,
+            stopping,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    ///Every request so far, oldest first
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // The accept loop only looks at the flag when a connection comes in
+        self.stopping.store(true, Ordering::Relaxed);
+        _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let path = line.split_whitespace().nth(1)?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let range = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("range"))
+        .and_then(|(_, value)| value.strip_prefix("bytes="))
+        .and_then(|value| value.split('-').next())
+        .and_then(|start| start.parse().ok());
+
+    Some(Request {
+        path,
+        range,
+        headers,
+    })
+}
+
+///Writes the status line and headers for a body of length bytes, honouring the request's Range like a static
+///file server. Returns where in the body the response starts
+pub fn write_head(
+    stream: &mut TcpStream,
+    request: &Request,
+    content_type: &str,
+    length: usize,
+) -> usize {
+    let start = request.range.map_or(0, |start| start as usize).min(length);
+    let head = match request.range {
+        Some(_) => format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+            start,
+            length.saturating_sub(1),
+            length
+        ),
+        None => "HTTP/1.1 200 OK\r\n".to_string(),
+    };
+    let head = format!(
+        "{head}Content-Type: {content_type}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+        length - start
+    );
+    _ = stream.write_all(head.as_bytes());
+    start
+}
+
+///Answers with the whole body, or the part the request's Range asks for
+pub fn respond(stream: &mut TcpStream, request: &Request, content_type: &str, body: &[u8]) {
+    let start = write_head(stream, request, content_type, body.len());
+    _ = stream.write_all(&body[start..]);
+}
@@ -0,0 +1,488 @@
+//waveform.rs
+
+//Waveform overviews for scrubbers. Files are decoded on their own thread straight through ffmpeg,
+//nothing here touches a Player. Results pack into a small binary blob apps can cache on disk
+
+use crate::enums::PlayerError;
+
+use ffmpeg_next::{self as av, frame::Audio as AudioFrame, media};
+
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+pub const MAX_WAVEFORM_BUCKETS: u32 = 1_000_000;
+
+//Frames per bucket during the decode. Merged down to the requested resolution at the end, so the
+//length of the file doesn't have to be known up front
+const FINE_BUCKET_FRAMES: usize = 256;
+
+const MAGIC: &[u8; 4] = b"AXWF";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 4 + 2 + 4 + 8 + 4;
+const BUCKET_LEN: usize = 6;
+
+///Levels of one slice of the file, mono mixed. min and max are -1.0 to 1.0
+#[derive(Clone, Copy, PartialEq, Debug, Default, uniffi::Record)]
+pub struct WaveformBucket {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
+
+#[derive(Clone, PartialEq, Debug, uniffi::Record)]
+pub struct Waveform {
+    pub sample_rate: u32,
+    pub duration: f64, // Seconds
+    pub buckets: Vec<WaveformBucket>,
+}
+
+impl Waveform {
+    ///Little endian header then 16 bit min, max and rms per bucket. About 6 bytes a bucket
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.buckets.len() * BUCKET_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.duration.to_le_bytes());
+        bytes.extend_from_slice(&(self.buckets.len() as u32).to_le_bytes());
+
+        for bucket in &self.buckets {
+            bytes.extend_from_slice(&quantise(bucket.min).to_le_bytes());
+            bytes.extend_from_slice(&quantise(bucket.max).to_le_bytes());
+            bytes.extend_from_slice(&quantise(bucket.rms).to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, i32> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return Err(-1);
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != FORMAT_VERSION {
+            return Err(-1);
+        }
+
+        let sample_rate = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+        let duration = f64::from_le_bytes(bytes[10..18].try_into().unwrap());
+        let count = u32::from_le_bytes(bytes[18..22].try_into().unwrap()) as usize;
+
+        let body = &bytes[HEADER_LEN..];
+        if body.len() != count * BUCKET_LEN {
+            return Err(-1);
+        }
+
+        let buckets = body
+            .chunks_exact(BUCKET_LEN)
+            .map(|chunk| WaveformBucket {
+                min: dequantise(i16::from_le_bytes([chunk[0], chunk[1]])),
+                max: dequantise(i16::from_le_bytes([chunk[2], chunk[3]])),
+                rms: dequantise(i16::from_le_bytes([chunk[4], chunk[5]])),
+            })
+            .collect();
+
+        Ok(Waveform {
+            sample_rate,
+            duration,
+            buckets,
+        })
+    }
+}
+
+fn quantise(value: f32) -> i16 {
+    (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+fn dequantise(value: i16) -> f32 {
+    value as f32 / i16::MAX as f32
+}
+
+///Running totals for one bucket
+#[derive(Clone, Copy)]
+struct Accumulator {
+    min: f32,
+    max: f32,
+    sum_squares: f64,
+    frames: usize,
+}
+
+impl Accumulator {
+    const EMPTY: Accumulator = Accumulator {
+        min: f32::MAX,
+        max: f32::MIN,
+        sum_squares: 0.0,
+        frames: 0,
+    };
+
+    fn push(&mut self, sample: f32) {
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+        self.sum_squares += (sample * sample) as f64;
+        self.frames += 1;
+    }
+
+    fn merge(&mut self, other: &Accumulator) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum_squares += other.sum_squares;
+        self.frames += other.frames;
+    }
+
+    fn bucket(&self) -> WaveformBucket {
+        if self.frames == 0 {
+            return WaveformBucket::default();
+        }
+        WaveformBucket {
+            min: self.min,
+            max: self.max,
+            rms: (self.sum_squares / self.frames as f64).sqrt() as f32,
+        }
+    }
+}
+
+///Decodes the whole file into bucket_count buckets. progress gets 0.0 to 1.0 as it goes
+pub fn generate_waveform<F>(
+    path: &str,
+    bucket_count: u32,
+    cancel_flag: &AtomicBool,
+    mut progress: F,
+) -> Result<Waveform, i32>
+where
+    F: FnMut(f32),
+{
+    if bucket_count == 0 || bucket_count > MAX_WAVEFORM_BUCKETS {
+        return Err(PlayerError::INVALID_ARGUMENT);
+    }
+
+    let mut format_ctx = av::format::input(path).map_err(|_| -1)?;
+    let duration = format_ctx.duration() as f64 / f64::from(av::ffi::AV_TIME_BASE);
+    let stream = format_ctx.streams().best(media::Type::Audio).ok_or(-1)?;
+    let stream_index = stream.index();
+
+    let codec_ctx =
+        av::codec::context::Context::from_parameters(stream.parameters()).map_err(|_| -1)?;
+    let mut decoder = codec_ctx.decoder().audio().map_err(|_| -1)?;
+    let sample_rate = decoder.rate();
+
+    // WAV, raw PCM and some MKV tracks don't say, swr needs a layout to start
+    let layout = if decoder.channel_layout().is_empty() {
+        av::ChannelLayout::default(decoder.channels() as i32)
+    } else {
+        decoder.channel_layout()
+    };
+    let mut resampler = av::software::resampling::Context::get(
+        decoder.format(),
+        layout,
+        sample_rate,
+        av::format::Sample::F32(av::format::sample::Type::Packed),
+        av::ChannelLayout::STEREO,
+        sample_rate,
+    )
+    .map_err(|_| -1)?;
+
+    // Only used for progress, the buckets don't depend on it
+    let expected_frames = (duration.max(0.0) * sample_rate as f64) as u64;
+
+    let mut fine: Vec<Accumulator> = Vec::new();
+    let mut current = Accumulator::EMPTY;
+    let mut decoded: u64 = 0;
+    let mut reported = 0.0f32;
+    let mut frame = AudioFrame::empty();
+
+    let mut consume = |frame: &AudioFrame, fine: &mut Vec<Accumulator>| {
+        let mut converted = AudioFrame::empty();
+        if resampler.run(frame, &mut converted).is_err() {
+            return;
+        }
+
+        // The plane is padded, only take the frames that are actually there
+        let bytes = converted.samples() * std::mem::size_of::<[f32; 2]>();
+        let samples: &[[f32; 2]] = bytemuck::cast_slice(&converted.data(0)[..bytes]);
+
+        for pair in samples {
+            current.push((pair[0] + pair[1]) * 0.5);
+            if current.frames == FINE_BUCKET_FRAMES {
+                fine.push(current);
+                current = Accumulator::EMPTY;
+            }
+        }
+
+        decoded += samples.len() as u64;
+        if expected_frames > 0 {
+            // Stay under 1.0 until it's actually done, durations are estimates
+            let done = (decoded as f32 / expected_frames as f32).min(0.99);
+            if done - reported >= 0.01 {
+                reported = done;
+                progress(done);
+            }
+        }
+    };
+
+    for (stream, packet) in format_ctx.packets() {
+        if cancel_flag.load(Ordering::Relaxed) {
+            return Err(-3);
+        }
+        if stream.index() != stream_index || decoder.send_packet(&packet).is_err() {
+            continue;
+        }
+        while decoder.receive_frame(&mut frame).is_ok() {
+            consume(&frame, &mut fine);
+        }
+    }
+
+    _ = decoder.send_eof();
+    while decoder.receive_frame(&mut frame).is_ok() {
+        consume(&frame, &mut fine);
+    }
+
+    if current.frames > 0 {
+        fine.push(current);
+    }
+    if fine.is_empty() {
+        return Err(-1);
+    }
+
+    // Merge down to the requested resolution. With more buckets than data each one repeats its nearest slice
+    let fine_count = fine.len();
+    let buckets = (0..bucket_count as usize)
+        .map(|i| {
+            let start = i * fine_count / bucket_count as usize;
+            let end = ((i + 1) * fine_count / bucket_count as usize).max(start + 1);
+            let mut total = Accumulator::EMPTY;
+            for slice in &fine[start.min(fine_count - 1)..end.min(fine_count)] {
+                total.merge(slice);
+            }
+            total.bucket()
+        })
+        .collect();
+
+    progress(1.0);
+
+    Ok(Waveform {
+        sample_rate,
+        duration: if duration > 0.0 {
+            duration
+        } else {
+            decoded as f64 / sample_rate as f64
+        },
+        buckets,
+    })
+}
+
+#[uniffi::export(callback_interface)]
+pub trait WaveformCallback: Send + Sync {
+    fn on_progress(&self, progress: f32);
+    ///None if it failed or was cancelled
+    fn on_finished(&self, waveform: Option<Waveform>);
+}
+
+///A waveform being generated in the background
+#[derive(uniffi::Object)]
+pub struct WaveformJob {
+    cancel_flag: Arc<AtomicBool>,
+    progress: Arc<AtomicU32>, // f32 bits
+    result: Arc<Mutex<Option<Result<Waveform, i32>>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[uniffi::export]
+impl WaveformJob {
+    ///Starts decoding path on a new thread. bucket_count is the number of points wanted, up to 1,000,000
+    #[uniffi::constructor]
+    pub fn start(
+        path: String,
+        bucket_count: u32,
+        callback: Option<Box<dyn WaveformCallback>>,
+    ) -> Result<Arc<Self>, PlayerError> {
+        if bucket_count == 0 || bucket_count > MAX_WAVEFORM_BUCKETS {
+            return Err(PlayerError::Code(PlayerError::INVALID_ARGUMENT));
+        }
+
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(AtomicU32::new(0));
+        let result = Arc::new(Mutex::new(None));
+
+        let thread_cancel_flag = cancel_flag.clone();
+        let thread_progress = progress.clone();
+        let thread_result = result.clone();
+
+        let handle = thread::spawn(move || {
+            let waveform = generate_waveform(&path, bucket_count, &thread_cancel_flag, |done| {
+                thread_progress.store(done.to_bits(), Ordering::Relaxed);
+                if let Some(callback) = &callback {
+                    callback.on_progress(done);
+                }
+            });
+
+            if let Some(callback) = &callback {
+                callback.on_finished(waveform.as_ref().ok().cloned());
+            }
+            *thread_result.lock().unwrap() = Some(waveform);
+        });
+
+        Ok(Arc::new(WaveformJob {
+            cancel_flag,
+            progress,
+            result,
+            handle: Mutex::new(Some(handle)),
+        }))
+    }
+
+    pub fn cancel(&self) {
+        self.cancel_flag.store(true, Ordering::Relaxed);
+    }
+
+    ///0.0 to 1.0
+    pub fn get_progress(&self) -> f32 {
+        f32::from_bits(self.progress.load(Ordering::Relaxed))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.result.lock().unwrap().is_some()
+    }
+
+    ///None while still running. Code -3 if it was cancelled
+    pub fn get_result(&self) -> Result<Option<Waveform>, PlayerError> {
+        match self.result.lock().unwrap().as_ref() {
+            None => Ok(None),
+            Some(Ok(waveform)) => Ok(Some(waveform.clone())),
+            Some(Err(code)) => Err(PlayerError::Code(*code)),
+        }
+    }
+
+    ///Blocks until the job is done
+    pub fn wait(&self) -> Result<Waveform, PlayerError> {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            _ = handle.join();
+        }
+        self.get_result()?.ok_or(PlayerError::Code(-1))
+    }
+}
+
+impl Drop for WaveformJob {
+    fn drop(&mut self) {
+        // Nobody is left to read the result
+        self.cancel();
+    }
+}
+
+///Packs a waveform for caching
+#[uniffi::export]
+pub fn waveform_to_bytes(waveform: Waveform) -> Vec<u8> {
+    waveform.to_bytes()
+}
+
+///Reads a waveform packed by waveform_to_bytes
+#[uniffi::export]
+pub fn waveform_from_bytes(bytes: Vec<u8>) -> Result<Waveform, PlayerError> {
+    Waveform::from_bytes(&bytes).map_err(PlayerError::Code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waveform(buckets: usize) -> Waveform {
+        Waveform {
+            sample_rate: 44100,
+            duration: 215.372,
+            buckets: (0..buckets)
+                .map(|i| {
+                    let level = i as f32 / buckets as f32;
+                    WaveformBucket {
+                        min: -level,
+                        max: level * 0.75,
+                        rms: level * 0.3,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        for buckets in [0, 1, 1000] {
+            let original = waveform(buckets);
+            let bytes = original.to_bytes();
+            assert_eq!(bytes.len(), HEADER_LEN + buckets * BUCKET_LEN);
+
+            let read = Waveform::from_bytes(&bytes).unwrap();
+            assert_eq!(read.sample_rate, original.sample_rate);
+            assert_eq!(read.duration, original.duration);
+            assert_eq!(read.buckets.len(), buckets);
+
+            // Within half a 16 bit step
+            let step = 0.5 / i16::MAX as f32;
+            for (read, original) in read.buckets.iter().zip(&original.buckets) {
+                assert!((read.min - original.min).abs() <= step);
+                assert!((read.max - original.max).abs() <= step);
+                assert!((read.rms - original.rms).abs() <= step);
+            }
+
+            // A second trip changes nothing
+            assert_eq!(read.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn out_of_range_levels_are_clamped() {
+        let mut original = waveform(1);
+        original.buckets[0] = WaveformBucket {
+            min: -1.5,
+            max: 2.0,
+            rms: 1.0,
+        };
+        let read = Waveform::from_bytes(&original.to_bytes()).unwrap();
+        assert_eq!(read.buckets[0].min, -1.0);
+        assert_eq!(read.buckets[0].max, 1.0);
+        assert_eq!(read.buckets[0].rms, 1.0);
+    }
+
+    #[test]
+    fn rejects_damaged_blobs() {
+        let bytes = waveform(10).to_bytes();
+
+        assert!(Waveform::from_bytes(&[]).is_err());
+        assert!(Waveform::from_bytes(&bytes[..HEADER_LEN - 1]).is_err());
+        assert!(Waveform::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(Waveform::from_bytes(&longer).is_err());
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(Waveform::from_bytes(&magic).is_err());
+
+        let mut version = bytes.clone();
+        version[4] = FORMAT_VERSION as u8 + 1;
+        assert!(Waveform::from_bytes(&version).is_err());
+    }
+
+    #[test]
+    fn merged_buckets_keep_extremes_and_energy() {
+        let mut first = Accumulator::EMPTY;
+        let mut second = Accumulator::EMPTY;
+        for sample in [0.5, -0.25, 0.5, -0.25] {
+            first.push(sample);
+        }
+        for sample in [-0.5, 0.0, 0.0, 0.0] {
+            second.push(sample);
+        }
+        first.merge(&second);
+
+        let bucket = first.bucket();
+        assert_eq!(bucket.min, -0.5);
+        assert_eq!(bucket.max, 0.5);
+        assert!((bucket.rms - (0.875f32 / 8.0).sqrt()).abs() < 1e-6);
+
+        assert_eq!(Accumulator::EMPTY.bucket(), WaveformBucket::default());
+    }
+}